@@ -1,11 +1,34 @@
-use building_blocks::{Ping, PingResponse};
+use building_blocks::{Deserializer, Error, Ping, PingResponse};
+use serde::Deserialize;
 use std::{
     io::{self, BufRead, BufReader, Write},
     net::TcpStream,
 };
 
+const ADDR: &str = "127.0.0.1:6380";
+
+/// A connection that all requests are sent on, opened again if the server
+/// closed it, e.g. because it was idle.
+struct Connection {
+    stream: TcpStream,
+    de: Deserializer<BufReader<TcpStream>>,
+}
+
+impl Connection {
+    fn open() -> io::Result<Connection> {
+        let stream = TcpStream::connect(ADDR)?;
+        let de = Deserializer::new(BufReader::new(stream.try_clone()?));
+        Ok(Connection { stream, de })
+    }
+
+    fn send(&mut self, req: &Ping) -> building_blocks::Result<PingResponse> {
+        building_blocks::to_writer(&mut self.stream, req)?;
+        PingResponse::deserialize(&mut self.de)
+    }
+}
+
 fn main() {
-    let mut stream = TcpStream::connect("127.0.0.1:6380").unwrap();
+    let mut conn = Connection::open().unwrap();
     let out = io::stdout();
 
     for l in io::stdin().lock().lines().map(Result::unwrap) {
@@ -17,11 +40,19 @@ fn main() {
             ["PING", rest] => Ping::with_msg(rest),
             _ => continue,
         };
-        building_blocks::to_writer(&mut stream, &req).unwrap();
-        let rsp: PingResponse = building_blocks::from_reader(BufReader::new(&mut stream)).unwrap();
+        let rsp = match conn.send(&req) {
+            Err(Error::Eof) | Err(Error::Io(_)) => {
+                Connection::open().map_err(Error::from).and_then(|new| {
+                    conn = new;
+                    conn.send(&req)
+                })
+            }
+            rsp => rsp,
+        };
         let rsp_content = match rsp {
-            PingResponse::Pong => "PONG".to_owned(),
-            PingResponse::Echo(s) => s,
+            Ok(PingResponse::Pong) => "PONG".to_owned(),
+            Ok(PingResponse::Echo(s)) => s,
+            Err(e) => format!("(error) {}", e),
         };
         writeln!(&mut w, "{}", rsp_content).unwrap();
     }
@@ -1,16 +1,39 @@
-use building_blocks::{Ping, PingResponse};
-use std::{io::BufReader, net::TcpListener};
+use building_blocks::Error;
+use std::{
+    io::{self, BufReader},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+/// How long a connection may go without a request before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 fn main() {
     let listener = TcpListener::bind("127.0.0.1:6380").unwrap();
     for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-
-        let req: Ping = building_blocks::from_reader(BufReader::new(&mut stream)).unwrap();
-        let rsp = match req.msg {
-            None => PingResponse::Pong,
-            Some(msg) => PingResponse::Echo(msg),
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("connection failed: {}", e);
+                continue;
+            }
         };
-        building_blocks::to_writer(&mut stream, &rsp).unwrap();
+        thread::spawn(move || match serve(&stream) {
+            Ok(()) => {}
+            Err(Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => eprintln!("connection closed: {}", e),
+        });
     }
 }
+
+/// Answers the requests on a connection until the peer closes it or it is
+/// idle for longer than `IDLE_TIMEOUT`.
+fn serve(stream: &TcpStream) -> building_blocks::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    building_blocks::serve_pings(BufReader::new(stream), stream)
+}
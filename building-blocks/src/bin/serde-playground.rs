@@ -1,4 +1,4 @@
-use std::{fs::File, io::BufReader, io::Read, iter, str};
+use std::{fs::File, io::BufReader, iter, str};
 
 use bson::{doc, Document};
 use rand::{
@@ -21,6 +21,7 @@ struct Move {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[allow(dead_code)]
 struct BsonSucks {
     values: Vec<Move>,
 }
@@ -1,20 +1,36 @@
 use crate::{Error, Result};
 use serde::{
-    de::{self, IntoDeserializer},
-    forward_to_deserialize_any, Deserialize,
+    de::{self, value::StrDeserializer, IntoDeserializer},
+    forward_to_deserialize_any,
 };
 use std::{convert::TryFrom, io::BufRead, str};
 
 pub struct Deserializer<R> {
     reader: R,
     buffer: Vec<u8>,
+    peeked: Option<Header>,
+}
+
+/// The type and length of a RESP value. The contents of strings and
+/// errors are left in the deserializer's buffer.
+#[derive(Clone, Copy, Debug)]
+enum Header {
+    SimpleString,
+    Error,
+    Integer(i64),
+    BulkString(Option<usize>),
+    Array(Option<usize>),
 }
 
 impl<R: BufRead> Deserializer<R> {
-    fn new(reader: R) -> Self {
+    /// Creates a deserializer reading from `reader`. Several values can be
+    /// read in a row from the same deserializer, e.g. one request after
+    /// another from a connection.
+    pub fn new(reader: R) -> Self {
         Deserializer {
             reader,
             buffer: Vec::new(),
+            peeked: None,
         }
     }
 }
@@ -29,21 +45,89 @@ where
     Ok(t)
 }
 
+/// Implements each of the `methods` of `de::Deserializer` by calling
+/// `target`.
+macro_rules! forward_to {
+    ($target:ident: $($method:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: de::Visitor<'de>,
+            {
+                self.$target(visitor)
+            }
+        )*
+    };
+}
+
 impl<'de, R: BufRead> de::Deserializer<'de> for &mut Deserializer<R> {
     type Error = Error;
 
+    /// Reads whatever value comes next: a string for simple strings and
+    /// errors as well as bulk strings that are UTF-8, bytes for other
+    /// bulk strings, a unit for nulls, and a sequence for arrays.
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        todo!();
+        match self.read_header()? {
+            Header::Integer(i) => visitor.visit_i64(i),
+            Header::BulkString(None) | Header::Array(None) => visitor.visit_unit(),
+            Header::Array(Some(len)) => visitor.visit_seq(Command {
+                de: self,
+                remaining: len,
+            }),
+            Header::SimpleString | Header::Error | Header::BulkString(Some(_)) => {
+                match str::from_utf8(&self.buffer) {
+                    Ok(s) => visitor.visit_str(s),
+                    Err(_) => visitor.visit_bytes(&self.buffer),
+                }
+            }
+        }
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.read_header()? {
+            Header::Integer(0) => visitor.visit_bool(false),
+            Header::Integer(1) => visitor.visit_bool(true),
+            Header::Integer(_) => Err(Error::Message("expected 0 or 1".to_owned())),
+            _ => Err(Error::ExpectedInt),
+        }
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        let s = self.parse_any_str()?;
+        let f = s
+            .parse()
+            .map_err(|_| Error::Message(format!("invalid float: '{}'", s)))?;
+        visitor.visit_f64(f)
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_bytes(self.parse_any_bytes()?)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_byte_buf(self.parse_any_bytes()?.to_vec())
     }
 
     fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_str(&self.parse_any_str()?)
+        visitor.visit_str(self.parse_any_str()?)
     }
 
     fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
@@ -53,51 +137,136 @@ impl<'de, R: BufRead> de::Deserializer<'de> for &mut Deserializer<R> {
         visitor.visit_string(self.parse_any_str()?.to_owned())
     }
 
+    /// All integers are read as `i64`, the visitor checks their range.
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.read_header()? {
+            Header::Integer(i) => visitor.visit_i64(i),
+            _ => Err(Error::ExpectedInt),
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.read_header()? {
+            Header::BulkString(None) | Header::Array(None) => visitor.visit_unit(),
+            _ => Err(Error::Message("expected null".to_owned())),
+        }
+    }
+
+    /// Unit structs are their name in upper case.
+    fn deserialize_unit_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        let found = self.parse_any_str()?;
+        if found != name.to_uppercase() {
+            return Err(Error::Message(format!(
+                "invalid unit struct: '{}', expected '{}'",
+                found,
+                name.to_uppercase()
+            )));
+        }
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
     fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        println!("option");
-        let buf = self.read_next_item()?;
-        if buf.starts_with(b"*-1") || buf.starts_with(b"$-1") {
-            visitor.visit_none()
-        } else {
-            visitor.visit_some(self)
+        match self.read_header()? {
+            Header::BulkString(None) | Header::Array(None) => visitor.visit_none(),
+            header => {
+                self.peeked = Some(header);
+                visitor.visit_some(self)
+            }
         }
     }
 
-    fn deserialize_struct<V>(
+    /// Sequences and tuples are arrays of their elements.
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.read_header()? {
+            Header::Array(Some(len)) => visitor.visit_seq(Command {
+                de: self,
+                remaining: len,
+            }),
+            _ => Err(Error::ExpectedArray),
+        }
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.read_header()? {
+            Header::Array(Some(n)) if n == len => visitor.visit_seq(Command {
+                de: self,
+                remaining: len,
+            }),
+            Header::Array(Some(_)) => Err(Error::InvalidLen),
+            _ => Err(Error::ExpectedArray),
+        }
+    }
+
+    fn deserialize_tuple_struct<V>(
         self,
-        name: &'static str,
-        _fields: &'static [&'static str],
+        _name: &'static str,
+        len: usize,
         visitor: V,
     ) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        let buf = self.read_next_item()?;
-        if buf[0] != b'*' {
-            return Err(Error::ExpectedArray);
+        self.deserialize_tuple(len, visitor)
+    }
+
+    /// Maps are arrays of their keys and values in turn.
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.read_header()? {
+            Header::Array(Some(len)) if len % 2 == 0 => visitor.visit_map(Command {
+                de: self,
+                remaining: len,
+            }),
+            Header::Array(Some(_)) => Err(Error::InvalidLen),
+            _ => Err(Error::ExpectedArray),
         }
+    }
 
-        let len = match self.parse_len()? {
-            Some(s) => s,
-            None => {
-                return Err(Error::ExpectedArray);
-            }
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        let len = match self.read_header()? {
+            Header::Array(Some(len)) => len,
+            _ => return Err(Error::ExpectedArray),
         };
-
         if len == 0 {
             return Err(Error::InvalidCommand);
         }
 
-        let _ = self.read_next_item()?;
-        println!("pre cmd");
-        let cmd_name = match self.parse_bulk_string()? {
-            Some(s) => s,
-            None => return Err(Error::ExpectedBulkString),
-        };
-        let cmd_name = str::from_utf8(cmd_name)?;
+        let cmd_name = self.parse_any_str()?;
         if cmd_name != name.to_uppercase() {
             return Err(Error::Message(format!(
                 "invalid command: '{}', expected '{}'",
@@ -105,7 +274,9 @@ impl<'de, R: BufRead> de::Deserializer<'de> for &mut Deserializer<R> {
                 name.to_uppercase()
             )));
         }
-        println!("post cmd");
+        if len - 1 != fields.len() {
+            return Err(Error::InvalidLen);
+        }
 
         visitor.visit_seq(Command {
             de: &mut *self,
@@ -113,38 +284,61 @@ impl<'de, R: BufRead> de::Deserializer<'de> for &mut Deserializer<R> {
         })
     }
 
+    /// Unit variants are a single string holding the variant name, all
+    /// other variants an array of the variant name followed by the fields.
+    /// An error reply fails with `Error::Reply`.
     fn deserialize_enum<V>(
         self,
         _name: &'static str,
-        _variants: &'static [&'static str],
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        let _ = self.read_next_item()?;
-        // super scuffed implementation that only works for 'PONG'.
-        // TODO: Fix enum deserialization
-        let s = self.parse_any_str()?;
-        visitor.visit_enum(s.into_deserializer())
+        match self.read_header()? {
+            Header::Array(Some(len)) if len > 0 => {
+                let variant = self.parse_variant(variants)?;
+                visitor.visit_enum(Enum {
+                    de: self,
+                    variant,
+                    remaining: len - 1,
+                })
+            }
+            Header::Error => Err(Error::Reply(
+                String::from_utf8_lossy(&self.buffer).into_owned(),
+            )),
+            header => {
+                self.peeked = Some(header);
+                let variant: StrDeserializer<Error> =
+                    self.parse_variant(variants)?.into_deserializer();
+                visitor.visit_enum(variant)
+            }
+        }
     }
 
     forward_to_deserialize_any! {
-        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
-            bytes byte_buf unit unit_struct newtype_struct seq tuple
-            tuple_struct map identifier ignored_any
+        i128 u128 ignored_any
     }
+
+    forward_to! { deserialize_i64:
+        deserialize_i8 deserialize_i16 deserialize_i32
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    forward_to! { deserialize_f64: deserialize_f32 }
+
+    forward_to! { deserialize_str: deserialize_char deserialize_identifier }
 }
 
 impl<R: BufRead> Deserializer<R> {
     fn read_next_item(&mut self) -> Result<&[u8]> {
         self.buffer.clear();
         let len = self.reader.read_until(b'\n', &mut self.buffer)?;
-        dbg!(str::from_utf8(&self.buffer)?);
 
         if len == 0 {
             return Err(Error::Eof);
-        } else if !self.buffer.ends_with(&[b'\r', b'\n']) {
+        } else if !self.buffer.ends_with(b"\r\n") {
             return Err(Error::InvalidFormat(b'\n'));
         }
 
@@ -152,15 +346,54 @@ impl<R: BufRead> Deserializer<R> {
         Ok(&self.buffer)
     }
 
-    fn parse_any_str(&mut self) -> Result<&str> {
-        let first = self.buffer.get(0).ok_or(Error::InvalidFormat(b'\r'))?;
-        let bytes = match first {
-            b'$' => self.parse_bulk_string()?.ok_or(Error::ExpectedBulkString)?,
-            b'+' | b'-' => &self.buffer[1..],
-            b => return Err(Error::InvalidFormat(*b)),
+    /// Reads the header of the next value, along with the contents of bulk
+    /// strings. Returns the peeked header instead if there is one.
+    fn read_header(&mut self) -> Result<Header> {
+        if let Some(header) = self.peeked.take() {
+            return Ok(header);
+        }
+
+        let first = *self
+            .read_next_item()?
+            .first()
+            .ok_or(Error::InvalidFormat(b'\r'))?;
+        let header = match first {
+            b'+' => Header::SimpleString,
+            b'-' => Header::Error,
+            b':' => Header::Integer(str::from_utf8(&self.buffer[1..])?.parse()?),
+            b'$' => Header::BulkString(self.parse_bulk_string()?.map(<[u8]>::len)),
+            b'*' => Header::Array(self.parse_len()?),
+            b => return Err(Error::InvalidFormat(b)),
         };
-        let s = std::str::from_utf8(bytes)?;
-        Ok(s)
+        if let Header::SimpleString | Header::Error = header {
+            self.buffer.remove(0);
+        }
+        Ok(header)
+    }
+
+    fn parse_any_str(&mut self) -> Result<&str> {
+        match self.read_header()? {
+            Header::SimpleString | Header::Error | Header::BulkString(Some(_)) => {
+                Ok(str::from_utf8(&self.buffer)?)
+            }
+            _ => Err(Error::ExpectedBulkString),
+        }
+    }
+
+    fn parse_any_bytes(&mut self) -> Result<&[u8]> {
+        match self.read_header()? {
+            Header::SimpleString | Header::Error | Header::BulkString(Some(_)) => Ok(&self.buffer),
+            _ => Err(Error::ExpectedBulkString),
+        }
+    }
+
+    fn parse_variant(&mut self, variants: &'static [&'static str]) -> Result<&'static str> {
+        let name = self.parse_any_str()?;
+        variants
+            .iter()
+            .find(|variant| variant.to_uppercase() == name)
+            .copied()
+            .ok_or_else(|| Error::UnknownCommand(name.to_owned()))
     }
 
     fn parse_bulk_string(&mut self) -> Result<Option<&[u8]>> {
@@ -176,8 +409,7 @@ impl<R: BufRead> Deserializer<R> {
         self.buffer.resize(len + 2, 0);
         let buf = &mut self.buffer;
         self.reader.read_exact(buf)?;
-        dbg!(str::from_utf8(&buf)?);
-        if !buf.ends_with(&[b'\r', b'\n']) {
+        if !buf.ends_with(b"\r\n") {
             return Err(Error::InvalidLen);
         }
         self.buffer.truncate(len);
@@ -210,12 +442,42 @@ impl<'a, 'de, R: BufRead> de::SeqAccess<'de> for Command<'a, R> {
         if self.remaining == 0 {
             return Ok(None);
         }
+        self.remaining -= 1;
         seed.deserialize(&mut *self.de).map(Some)
     }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'a, 'de, R: BufRead> de::MapAccess<'de> for Command<'a, R> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        de::SeqAccess::next_element_seed(self, seed)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining / 2)
+    }
 }
 
 struct Enum<'a, R> {
     de: &'a mut Deserializer<R>,
+    variant: &'static str,
+    remaining: usize,
 }
 
 impl<'a, 'de, R: BufRead> de::EnumAccess<'de> for Enum<'a, R> {
@@ -226,7 +488,8 @@ impl<'a, 'de, R: BufRead> de::EnumAccess<'de> for Enum<'a, R> {
     where
         V: de::DeserializeSeed<'de>,
     {
-        let val = seed.deserialize(&mut *self.de)?;
+        let variant: StrDeserializer<Error> = self.variant.into_deserializer();
+        let val = seed.deserialize(variant)?;
         Ok((val, self))
     }
 }
@@ -235,27 +498,117 @@ impl<'a, 'de, R: BufRead> de::VariantAccess<'de> for Enum<'a, R> {
     type Error = Error;
 
     fn unit_variant(self) -> Result<()> {
-        todo!();
+        self.expect_fields(0)
     }
 
     fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
     where
         T: de::DeserializeSeed<'de>,
     {
-        todo!()
+        self.expect_fields(1)?;
+        seed.deserialize(self.de)
     }
 
     fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        todo!()
+        self.expect_fields(len)?;
+        visitor.visit_seq(Command {
+            de: self.de,
+            remaining: len,
+        })
     }
 
     fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        todo!()
+        self.tuple_variant(fields.len(), visitor)
+    }
+}
+
+impl<'a, R> Enum<'a, R> {
+    fn expect_fields(&self, len: usize) -> Result<()> {
+        if self.remaining == len {
+            Ok(())
+        } else {
+            Err(Error::InvalidLen)
+        }
+    }
+}
+
+#[test]
+fn test_enum() {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Response {
+        Ok,
+        Value(Option<String>),
+        Moved { from: String, to: String },
     }
+
+    let input: &[u8] = b"$2\r\nOK\r\n*2\r\n$5\r\nVALUE\r\n$-1\r\n*2\r\n$5\r\nVALUE\r\n$1\r\nv\r\n\
+                         *3\r\n$5\r\nMOVED\r\n$1\r\na\r\n$1\r\nb\r\n";
+    let mut de = Deserializer::new(input);
+    let mut next = || Response::deserialize(&mut de).unwrap();
+    assert_eq!(next(), Response::Ok);
+    assert_eq!(next(), Response::Value(None));
+    assert_eq!(next(), Response::Value(Some("v".to_owned())));
+    assert_eq!(
+        next(),
+        Response::Moved {
+            from: "a".to_owned(),
+            to: "b".to_owned()
+        }
+    );
+}
+
+#[test]
+fn test_error_reply() {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    enum Response {
+        Ok,
+    }
+
+    let input: &[u8] = b"-ERR no\r\n*1\r\n$4\r\nMOVE\r\n$2\r\nOK\r\n";
+    let mut de = Deserializer::new(input);
+    assert!(matches!(Response::deserialize(&mut de), Err(Error::Reply(msg)) if msg == "ERR no"));
+    assert!(matches!(
+        Response::deserialize(&mut de),
+        Err(Error::UnknownCommand(name)) if name == "MOVE"
+    ));
+}
+
+#[test]
+fn test_seq() {
+    use serde::Deserialize;
+
+    let input: &[u8] = b"*2\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n*2\r\n$1\r\nb\r\n$1\r\n2\r\n*0\r\n";
+    let mut de = Deserializer::new(input);
+    let pairs = Vec::<(String, String)>::deserialize(&mut de).unwrap();
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned())
+        ]
+    );
+    assert!(Vec::<String>::deserialize(&mut de).unwrap().is_empty());
+
+    let input: &[u8] = b"*1\r\n$1\r\na\r\n";
+    let mut de = Deserializer::new(input);
+    assert!(<(String, String)>::deserialize(&mut de).is_err());
+}
+
+#[test]
+fn test_integer() {
+    use serde::Deserialize;
+
+    let input: &[u8] = b"*2\r\n:-3\r\n:42\r\n";
+    let mut de = Deserializer::new(input);
+    assert_eq!(Vec::<i64>::deserialize(&mut de).unwrap(), vec![-3, 42]);
 }
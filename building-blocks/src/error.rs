@@ -15,6 +15,9 @@ pub enum Error {
     /// Length of sequence not available during serialization.
     #[error("sequence length not available")]
     LenNotAvailable,
+    /// Unsigned integer too large for a RESP integer.
+    #[error("integer out of range")]
+    IntOutOfRange,
 
     /// Unexpected EOF.
     #[error("unexpected EOF")]
@@ -34,6 +37,12 @@ pub enum Error {
     /// Encountered an empty bulk array when expecting command.
     #[error("empty bulk array is not a valid command")]
     InvalidCommand,
+    /// Encountered a command, i.e. enum variant, that is not known.
+    #[error("unknown command '{0}'")]
+    UnknownCommand(String),
+    /// Encountered an error reply where a value was expected.
+    #[error("error reply: {0}")]
+    Reply(String),
 
     /// Did not encounter array when expected
     #[error("expected array")]
@@ -0,0 +1,392 @@
+//! Decoding RESP values from input that arrives in pieces, e.g. from a
+//! non-blocking socket.
+
+use crate::{parse, Error, RedisValue, Result};
+use std::{
+    convert::TryFrom,
+    io::{self, Read},
+    mem, str,
+};
+
+/// How many bytes `FrameDecoder::read_from` reads at most at once.
+const READ_SIZE: usize = 4096;
+
+/// The room a line gets even when it would not fit within the maximum
+/// frame size, enough for the header of any array or bulk string.
+const HEADER_ROOM: usize = 32;
+
+/// The outcome of [`FrameDecoder::decode`] and
+/// [`FrameDecoder::decode_frame`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Decoded<T> {
+    /// The next complete value, borrowing from the decoder's buffer.
+    Value(T),
+    /// The buffered input ends within a value.
+    NeedMore,
+    /// The next value was larger than the maximum frame size. It was
+    /// skipped as it arrived rather than buffered.
+    TooLarge,
+}
+
+/// Accumulates input and yields the complete RESP values in it.
+///
+/// Unlike the [`Deserializer`](crate::Deserializer), it never waits for
+/// input: bytes are handed to it as they arrive, and decoding stops with
+/// [`Decoded::NeedMore`] at the end of them.
+///
+/// ```
+/// # use building_blocks::{Decoded, FrameDecoder, RedisValue};
+/// let mut decoder = FrameDecoder::new();
+/// decoder.extend(b"+OK\r\n:4");
+/// assert_eq!(decoder.decode().unwrap(), Decoded::Value(RedisValue::Str(b"OK")));
+/// assert_eq!(decoder.decode().unwrap(), Decoded::NeedMore);
+/// decoder.extend(b"2\r\n");
+/// assert_eq!(decoder.decode().unwrap(), Decoded::Value(RedisValue::Int(42)));
+/// ```
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // the offset of the first byte in `buffer` not yet decoded
+    start: usize,
+    // the offset of the first byte of the value at `start` not yet
+    // scanned for where it ends
+    scanned: usize,
+    max_frame_size: usize,
+    scan: Scan,
+}
+
+/// How far the value being decoded has been scanned.
+#[derive(Debug)]
+struct Scan {
+    // the number of values still to be scanned, counting array elements
+    pending: usize,
+    // the bytes of a bulk string still to come, with its "\r\n"
+    body: usize,
+    // the bytes of the value so far, including those skipped
+    size: usize,
+    too_large: bool,
+    // whether the rest of a line too long for any header is skipped
+    skipping_line: bool,
+}
+
+impl Scan {
+    fn new() -> Self {
+        Scan {
+            pending: 1,
+            body: 0,
+            size: 0,
+            too_large: false,
+            skipping_line: false,
+        }
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            start: 0,
+            scanned: 0,
+            max_frame_size: usize::MAX,
+            scan: Scan::new(),
+        }
+    }
+}
+
+impl FrameDecoder {
+    /// Creates a decoder without any input.
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+
+    /// Sets the most bytes a value may take up. A larger value is skipped
+    /// as it arrives, without buffering it, and decoded as
+    /// [`Decoded::TooLarge`], so that the input can still be decoded from
+    /// the next value on. Unlimited by default.
+    pub fn with_max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Returns the number of bytes buffered but not decoded yet.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// Returns whether the input so far ends within a value, rather than
+    /// between two of them. Unlike [`FrameDecoder::buffered`], this counts
+    /// the bytes of a value that is skipped for being too large.
+    pub fn is_within_value(&self) -> bool {
+        self.buffered() > 0 || self.scan.size > 0
+    }
+
+    /// Appends `bytes` to the input.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.discard_decoded();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Reads once from `reader` and appends what it returns to the input.
+    /// Returns the number of bytes read, which is 0 at the end of the
+    /// input.
+    ///
+    /// # Errors
+    ///
+    /// Passes on the errors of `reader`, including `WouldBlock` from a
+    /// non-blocking reader that has nothing to read.
+    pub fn read_from<R: Read>(&mut self, mut reader: R) -> io::Result<usize> {
+        self.discard_decoded();
+        let end = self.buffer.len();
+        self.buffer.resize(end + READ_SIZE, 0);
+        let result = reader.read(&mut self.buffer[end..]);
+        self.buffer.truncate(end + *result.as_ref().unwrap_or(&0));
+        result
+    }
+
+    /// Decodes the next value of the input, if it is complete.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the input is not valid RESP, in which case the decoder
+    /// cannot continue.
+    pub fn decode(&mut self) -> Result<Decoded<RedisValue<'_>>> {
+        Ok(match self.decode_frame()? {
+            Decoded::Value(frame) => Decoded::Value(parse(frame)?.1),
+            Decoded::NeedMore => Decoded::NeedMore,
+            Decoded::TooLarge => Decoded::TooLarge,
+        })
+    }
+
+    /// Finds the end of the next value of the input, if it is complete,
+    /// and returns its bytes as they are, e.g. to deserialize them.
+    ///
+    /// Only the headers of arrays and bulk strings are looked at, which is
+    /// all it takes to tell where the value ends. Anything else, like a
+    /// line with an unknown type, is left for the caller to reject, and
+    /// the values after it can still be decoded.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the header of an array or bulk string is malformed, or a
+    /// line does not end with `\r\n`. The end of the value cannot be told
+    /// then, and the decoder cannot continue.
+    pub fn decode_frame(&mut self) -> Result<Decoded<&[u8]>> {
+        loop {
+            if self.scan.body > 0 {
+                let scanned = (self.buffer.len() - self.scanned).min(self.scan.body);
+                self.scan.body -= scanned;
+                self.advance(scanned);
+                if self.scan.body > 0 {
+                    return Ok(Decoded::NeedMore);
+                }
+                continue;
+            }
+            if self.scan.pending == 0 {
+                let scan = mem::replace(&mut self.scan, Scan::new());
+                let frame = self.start..self.scanned;
+                self.start = self.scanned;
+                if scan.too_large {
+                    return Ok(Decoded::TooLarge);
+                }
+                return Ok(Decoded::Value(&self.buffer[frame]));
+            }
+            let rest = &self.buffer[self.scanned..];
+            let newline = rest.iter().position(|&b| b == b'\n');
+            if self.scan.skipping_line {
+                match newline {
+                    Some(i) => {
+                        self.advance(i + 1);
+                        self.scan.skipping_line = false;
+                        self.scan.pending -= 1;
+                        continue;
+                    }
+                    None => {
+                        self.advance(rest.len());
+                        return Ok(Decoded::NeedMore);
+                    }
+                }
+            }
+            let room = (self.max_frame_size.saturating_add(1))
+                .saturating_sub(self.scan.size)
+                .max(HEADER_ROOM);
+            let len = match newline {
+                Some(i) if i < room => i + 1,
+                None if rest.len() < room => return Ok(Decoded::NeedMore),
+                // a line too long for any header, which is skipped
+                _ => {
+                    self.scan.too_large = true;
+                    self.scan.skipping_line = true;
+                    continue;
+                }
+            };
+            let line = &rest[..len];
+            if !line.ends_with(b"\r\n") {
+                return Err(Error::InvalidFormat(b'\n'));
+            }
+            self.scan.pending -= 1;
+            match line[0] {
+                b'*' => {
+                    let len = parse_len(line)?.unwrap_or(0);
+                    self.scan.pending = self
+                        .scan
+                        .pending
+                        .checked_add(len)
+                        .ok_or(Error::InvalidLen)?;
+                }
+                // the contents are followed by another "\r\n"
+                b'$' => self.scan.body = parse_len(line)?.map_or(0, |len| len + 2),
+                _ => (),
+            }
+            self.advance(len);
+        }
+    }
+
+    /// Counts `len` more bytes of the value being decoded as scanned,
+    /// dropping them if it is too large.
+    fn advance(&mut self, len: usize) {
+        self.scanned += len;
+        self.scan.size += len;
+        self.scan.too_large |= self.scan.size.saturating_add(self.scan.body) > self.max_frame_size;
+        if self.scan.too_large {
+            self.buffer.drain(self.start..self.scanned);
+            self.scanned = self.start;
+        }
+    }
+
+    /// Drops the decoded values from the buffer.
+    fn discard_decoded(&mut self) {
+        self.buffer.drain(..self.start);
+        self.scanned -= self.start;
+        self.start = 0;
+    }
+}
+
+/// Parses the length in the header line of an array or bulk string, which
+/// is absent for a null value.
+fn parse_len(line: &[u8]) -> Result<Option<usize>> {
+    let len: isize = str::from_utf8(&line[1..line.len() - 2])?.parse()?;
+    Ok(usize::try_from(len).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_by_byte() {
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n-ERR\r\n";
+        let mut decoder = FrameDecoder::new();
+        let mut values = Vec::new();
+        for &byte in &input[..] {
+            decoder.extend(&[byte]);
+            while let Decoded::Value(value) = decoder.decode().unwrap() {
+                values.push(value.to_owned());
+            }
+        }
+        assert_eq!(
+            values,
+            vec![
+                RedisValue::Array(vec![RedisValue::Str(b"GET"), RedisValue::Str(b"key")])
+                    .to_owned(),
+                RedisValue::Err(b"ERR").to_owned(),
+            ]
+        );
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn nested_lengths_overflow() {
+        let mut decoder = FrameDecoder::new().with_max_frame_size(1024);
+        decoder.extend(&b"*9223372036854775807\r\n".repeat(3));
+        assert!(matches!(decoder.decode(), Err(Error::InvalidLen)));
+    }
+
+    #[test]
+    fn read_from() {
+        struct Pieces(Vec<&'static [u8]>);
+
+        impl Read for Pieces {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                let piece = self.0.remove(0);
+                buf[..piece.len()].copy_from_slice(piece);
+                Ok(piece.len())
+            }
+        }
+
+        let mut reader = Pieces(vec![b"$5\r\nhel", b"lo\r\n:1\r\n:"]);
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.read_from(&mut reader).unwrap(), 7);
+        assert_eq!(decoder.decode().unwrap(), Decoded::NeedMore);
+        assert_eq!(decoder.read_from(&mut reader).unwrap(), 9);
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Decoded::Value(RedisValue::Str(b"hello"))
+        );
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Decoded::Value(RedisValue::Int(1))
+        );
+        assert_eq!(decoder.decode().unwrap(), Decoded::NeedMore);
+        let e = decoder.read_from(&mut reader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(decoder.buffered(), 1);
+    }
+
+    #[test]
+    fn too_large() {
+        let mut input = b"*2\r\n$3\r\nSET\r\n$100\r\n".to_vec();
+        input.extend([b'x'; 100]);
+        input.extend(b"\r\n+");
+        input.extend([b'y'; 100]);
+        input.extend(b"\r\n:1\r\n");
+        let mut decoder = FrameDecoder::new().with_max_frame_size(64);
+        let mut decoded = Vec::new();
+        for chunk in input.chunks(7) {
+            decoder.extend(chunk);
+            loop {
+                match decoder.decode().unwrap() {
+                    Decoded::Value(value) => decoded.push(Some(value.to_owned())),
+                    Decoded::TooLarge => decoded.push(None),
+                    Decoded::NeedMore => break,
+                }
+                assert!(decoder.buffered() <= 64 + 7);
+            }
+        }
+        assert_eq!(
+            decoded,
+            vec![None, None, Some(RedisValue::Int(1).to_owned())]
+        );
+        assert!(!decoder.is_within_value());
+
+        decoder.extend(b"$100\r\nxx");
+        assert_eq!(decoder.decode().unwrap(), Decoded::NeedMore);
+        assert_eq!(decoder.buffered(), 0);
+        assert!(decoder.is_within_value());
+    }
+
+    #[test]
+    fn frames() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(b"?what\r\n*1\r\n$4\r\nPING\r\n");
+        assert_eq!(
+            decoder.decode_frame().unwrap(),
+            Decoded::Value(&b"?what\r\n"[..])
+        );
+        assert_eq!(
+            decoder.decode_frame().unwrap(),
+            Decoded::Value(&b"*1\r\n$4\r\nPING\r\n"[..])
+        );
+        decoder.extend(b"$x\r\n");
+        assert!(matches!(decoder.decode_frame(), Err(Error::ParseInt(_))));
+    }
+
+    #[test]
+    fn invalid() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(b"?\r\n");
+        assert!(matches!(decoder.decode(), Err(Error::InvalidFormat(b'?'))));
+    }
+}
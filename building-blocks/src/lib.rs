@@ -1,14 +1,19 @@
 mod de;
 mod error;
+mod frame;
 mod parse;
 mod ping;
 mod ser;
 
 pub use de::{from_reader, Deserializer};
 pub use error::{Error, Result};
-pub use ping::{Ping, PingResponse};
-pub use ser::{to_writer, Serializer};
+pub use frame::{Decoded, FrameDecoder};
+pub use parse::parse;
+pub use ping::{serve_pings, Ping, PingResponse};
+pub use ser::{to_writer, write_error, Serializer};
 
+/// A RESP value whose strings borrow from the buffer it was parsed from,
+/// see [`parse`].
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum RedisValue<'a> {
     Null,
@@ -17,3 +22,51 @@ pub enum RedisValue<'a> {
     Array(Vec<RedisValue<'a>>),
     Int(i64),
 }
+
+impl RedisValue<'_> {
+    /// Copies the value out of the buffer it borrows from, so that it can
+    /// be kept while the buffer is reused for more input.
+    pub fn to_owned(&self) -> RedisValueOwned {
+        match *self {
+            RedisValue::Null => RedisValueOwned::Null,
+            RedisValue::Str(s) => RedisValueOwned::Str(s.to_vec()),
+            RedisValue::Err(s) => RedisValueOwned::Err(s.to_vec()),
+            RedisValue::Array(ref vals) => {
+                RedisValueOwned::Array(vals.iter().map(RedisValue::to_owned).collect())
+            }
+            RedisValue::Int(i) => RedisValueOwned::Int(i),
+        }
+    }
+}
+
+/// A RESP value that owns its strings, like [`RedisValue`] copied out of
+/// its buffer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RedisValueOwned {
+    Null,
+    Str(Vec<u8>),
+    Err(Vec<u8>),
+    Array(Vec<RedisValueOwned>),
+    Int(i64),
+}
+
+impl RedisValueOwned {
+    /// Borrows the value as a [`RedisValue`].
+    pub fn as_value(&self) -> RedisValue<'_> {
+        match self {
+            RedisValueOwned::Null => RedisValue::Null,
+            RedisValueOwned::Str(s) => RedisValue::Str(s),
+            RedisValueOwned::Err(s) => RedisValue::Err(s),
+            RedisValueOwned::Array(vals) => {
+                RedisValue::Array(vals.iter().map(RedisValueOwned::as_value).collect())
+            }
+            RedisValueOwned::Int(i) => RedisValue::Int(*i),
+        }
+    }
+}
+
+impl From<RedisValue<'_>> for RedisValueOwned {
+    fn from(value: RedisValue<'_>) -> Self {
+        value.to_owned()
+    }
+}
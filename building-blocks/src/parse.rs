@@ -1,13 +1,13 @@
-use crate::RedisValue;
+use crate::{Error, RedisValue, Result};
 use nom::{
     bytes::streaming::{self as bytes, tag},
     character::streaming::char,
-    combinator::{map, map_res},
-    sequence::{self, delimited, terminated},
+    combinator::map_res,
+    sequence::{self, terminated},
     IResult, Parser,
 };
 use sequence::preceded;
-use std::str::{self, FromStr};
+use std::str;
 
 fn until_end(i: &[u8]) -> IResult<&[u8], &[u8]> {
     let (i, data) = bytes::take_until("\r\n")(i)?;
@@ -15,19 +15,19 @@ fn until_end(i: &[u8]) -> IResult<&[u8], &[u8]> {
     Ok((i, data))
 }
 
-fn error(i: &[u8]) -> IResult<&[u8], RedisValue> {
+fn error(i: &[u8]) -> IResult<&[u8], RedisValue<'_>> {
     let (i, _) = char('-')(i)?;
     let (i, data) = until_end(i)?;
     Ok((i, RedisValue::Err(data)))
 }
 
-fn simple_string(i: &[u8]) -> IResult<&[u8], RedisValue> {
+fn simple_string(i: &[u8]) -> IResult<&[u8], RedisValue<'_>> {
     let (i, _) = char('+')(i)?;
     let (i, data) = until_end(i)?;
     Ok((i, RedisValue::Str(data)))
 }
 
-fn bulk_string(i: &[u8]) -> IResult<&[u8], RedisValue> {
+fn bulk_string(i: &[u8]) -> IResult<&[u8], RedisValue<'_>> {
     let (i, len) = map_res(
         map_res(preceded(char('$'), until_end), str::from_utf8),
         |s| s.parse::<u32>(),
@@ -36,21 +36,21 @@ fn bulk_string(i: &[u8]) -> IResult<&[u8], RedisValue> {
     Ok((i, RedisValue::Str(data)))
 }
 
-fn null(i: &[u8]) -> IResult<&[u8], RedisValue> {
+fn null(i: &[u8]) -> IResult<&[u8], RedisValue<'_>> {
     tag("*-1\r\n")
         .or(tag("$-1\r\n"))
         .parse(i)
         .map(|(i, _)| (i, RedisValue::Null))
 }
 
-fn integer(i: &[u8]) -> IResult<&[u8], RedisValue> {
+fn integer(i: &[u8]) -> IResult<&[u8], RedisValue<'_>> {
     map_res(
         map_res(preceded(char(':'), until_end), str::from_utf8),
         |s| s.parse::<i64>().map(RedisValue::Int),
     )(i)
 }
 
-fn array(i: &[u8]) -> IResult<&[u8], RedisValue> {
+fn array(i: &[u8]) -> IResult<&[u8], RedisValue<'_>> {
     let (mut i, len) = map_res(
         map_res(preceded(char('*'), until_end), str::from_utf8),
         |s| s.parse::<u32>(),
@@ -64,7 +64,7 @@ fn array(i: &[u8]) -> IResult<&[u8], RedisValue> {
     Ok((i, RedisValue::Array(vals)))
 }
 
-pub fn value(i: &[u8]) -> IResult<&[u8], RedisValue> {
+fn value(i: &[u8]) -> IResult<&[u8], RedisValue<'_>> {
     simple_string
         .or(bulk_string)
         .or(error)
@@ -74,9 +74,25 @@ pub fn value(i: &[u8]) -> IResult<&[u8], RedisValue> {
         .parse(i)
 }
 
+/// Parses the RESP value at the start of `input`, borrowing its strings
+/// from `input`. Returns the number of bytes it takes up along with it.
+///
+/// Fails with [`Error::Eof`] if `input` ends before the value does, in
+/// which case it should be parsed again once more input has arrived.
+pub fn parse(input: &[u8]) -> Result<(usize, RedisValue<'_>)> {
+    match value(input) {
+        Ok((rest, val)) => Ok((input.len() - rest.len(), val)),
+        Err(nom::Err::Incomplete(_)) => Err(Error::Eof),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(Error::InvalidFormat(
+            e.input.first().copied().unwrap_or(b'\n'),
+        )),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::RedisValueOwned;
 
     fn test_complete_value(s: &[u8], expected: RedisValue) {
         let (rest, val) = value(s).unwrap();
@@ -143,4 +159,43 @@ mod tests {
             ]),
         );
     }
+
+    #[test]
+    fn parse_streaming() {
+        let input = b"*2\r\n$3\r\nfoo\r\n:42\r\n+OK\r\n";
+        let first = 18;
+        for end in 0..first {
+            assert!(matches!(parse(&input[..end]), Err(Error::Eof)));
+        }
+        let (consumed, val) = parse(input).unwrap();
+        assert_eq!(consumed, first);
+        assert_eq!(
+            val,
+            RedisValue::Array(vec![RedisValue::Str(b"foo"), RedisValue::Int(42)])
+        );
+        assert_eq!(
+            parse(&input[consumed..]).unwrap(),
+            (5, RedisValue::Str(b"OK"))
+        );
+        assert!(matches!(
+            parse(b"!foo\r\n"),
+            Err(Error::InvalidFormat(b'!'))
+        ));
+    }
+
+    #[test]
+    fn owned() {
+        let mut buf = b"*3\r\n$3\r\nfoo\r\n-Bar\r\n*-1\r\n".to_vec();
+        let owned = parse(&buf).unwrap().1.to_owned();
+        buf.clear();
+        assert_eq!(
+            owned,
+            RedisValueOwned::Array(vec![
+                RedisValueOwned::Str(b"foo".to_vec()),
+                RedisValueOwned::Err(b"Bar".to_vec()),
+                RedisValueOwned::Null,
+            ])
+        );
+        assert_eq!(RedisValueOwned::from(owned.as_value()), owned);
+    }
 }
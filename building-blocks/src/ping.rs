@@ -1,4 +1,6 @@
+use crate::{Deserializer, Error, Result};
 use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
 
 #[derive(Serialize, Deserialize)]
 pub struct Ping {
@@ -23,3 +25,56 @@ pub enum PingResponse {
     Pong,
     Echo(String),
 }
+
+/// Answers the pings read from `reader` one after another on `writer`,
+/// e.g. all requests sent on a connection, until the peer closes it.
+///
+/// A request that cannot be decoded is answered with an error reply, after
+/// which its error is returned, as the rest of the input cannot be told
+/// apart from it. So are errors while reading or writing, e.g. when a read
+/// times out.
+pub fn serve_pings<R: BufRead, W: Write>(reader: R, mut writer: W) -> Result<()> {
+    let mut de = Deserializer::new(reader);
+    loop {
+        let response = match Ping::deserialize(&mut de) {
+            Ok(Ping { msg: None }) => PingResponse::Pong,
+            Ok(Ping { msg: Some(msg) }) => PingResponse::Echo(msg),
+            Err(Error::Eof) => return Ok(()),
+            Err(Error::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                crate::write_error(&mut writer, &format!("ERR {}", e))?;
+                return Err(e);
+            }
+        };
+        crate::to_writer(&mut writer, &response)?;
+        writer.flush()?;
+    }
+}
+
+#[test]
+fn test_serve_pings() {
+    let mut requests = Vec::new();
+    crate::to_writer(&mut requests, &Ping::empty()).unwrap();
+    crate::to_writer(&mut requests, &Ping::with_msg("hello")).unwrap();
+    let mut responses = Vec::new();
+    serve_pings(&requests[..], &mut responses).unwrap();
+
+    let mut de = Deserializer::new(&responses[..]);
+    assert!(matches!(
+        PingResponse::deserialize(&mut de),
+        Ok(PingResponse::Pong)
+    ));
+    assert!(matches!(
+        PingResponse::deserialize(&mut de),
+        Ok(PingResponse::Echo(msg)) if msg == "hello"
+    ));
+    assert!(matches!(
+        PingResponse::deserialize(&mut de),
+        Err(Error::Eof)
+    ));
+
+    let mut responses = Vec::new();
+    let result = serve_pings(&b"*1\r\n$4\r\nPONG\r\n"[..], &mut responses);
+    assert!(result.is_err());
+    assert!(responses.starts_with(b"-ERR "));
+}
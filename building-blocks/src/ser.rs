@@ -2,10 +2,17 @@
 //! description of the REdis Serialization Protocol (RESP) as
 //! described in [the Redis Protocol
 //! specification](https://redis.io/topics/protocol).
+//!
+//! Integers, `bool`s as 0 or 1 among them, are RESP integers, and strings,
+//! bytes, chars and floats bulk strings. `None` and `()` are the null bulk
+//! string. Sequences and tuples are arrays of their elements, and maps
+//! arrays of their keys and values in turn. Structs are arrays of their
+//! name in upper case followed by their fields, and so are enum variants
+//! with fields, while unit variants are just their name.
 
 use crate::{Error, Result};
 use serde::{ser, Serialize};
-use std::io::Write;
+use std::{convert::TryFrom, io::Write};
 
 pub struct Serializer<W> {
     writer: W,
@@ -21,6 +28,14 @@ where
     Ok(())
 }
 
+/// Writes `message` as an error reply, e.g. `-ERR unknown command 'FOO'`,
+/// which by convention starts with the kind of error in upper case. Line
+/// breaks, which an error reply cannot hold, are replaced with spaces.
+pub fn write_error<W: Write>(mut writer: W, message: &str) -> Result<()> {
+    write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " "))?;
+    Ok(())
+}
+
 impl<W: Write> ser::Serializer for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
@@ -58,7 +73,7 @@ impl<W: Write> ser::Serializer for &mut Serializer<W> {
     }
 
     fn serialize_i64(self, v: i64) -> Result<Self::Ok> {
-        write!(&mut self.writer, ":{}", v)?;
+        write!(&mut self.writer, ":{}\r\n", v)?;
         Ok(())
     }
 
@@ -78,9 +93,9 @@ impl<W: Write> ser::Serializer for &mut Serializer<W> {
         Ok(())
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
@@ -124,74 +139,81 @@ impl<W: Write> ser::Serializer for &mut Serializer<W> {
         Ok(self)
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(self, _name: &'static str, value: &T) -> Result<Self::Ok>
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Self::Ok>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
         value: &T,
     ) -> Result<Self::Ok>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
+        self.writer.write_all(b"*2\r\n")?;
+        self.serialize_bytes(variant.to_uppercase().as_bytes())?;
         value.serialize(self)
     }
 
-    fn serialize_bool(self, _v: bool) -> Result<Self::Ok> {
-        todo!()
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok> {
+        self.serialize_i64(i64::from(v))
     }
 
-    fn serialize_u64(self, _v: u64) -> Result<Self::Ok> {
-        todo!()
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok> {
+        let v = i64::try_from(v).map_err(|_| Error::IntOutOfRange)?;
+        self.serialize_i64(v)
     }
 
-    fn serialize_f32(self, _v: f32) -> Result<Self::Ok> {
-        todo!()
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok> {
+        self.serialize_str(&v.to_string())
     }
 
-    fn serialize_f64(self, _v: f64) -> Result<Self::Ok> {
-        todo!()
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok> {
+        self.serialize_str(&v.to_string())
     }
 
-    fn serialize_char(self, _v: char) -> Result<Self::Ok> {
-        todo!()
+    fn serialize_char(self, v: char) -> Result<Self::Ok> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
     }
 
     fn serialize_unit(self) -> Result<Self::Ok> {
-        todo!()
+        self.serialize_none()
     }
 
-    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
-        todo!()
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
     }
 
     fn serialize_tuple_struct(
         self,
         _name: &'static str,
-        _len: usize,
+        len: usize,
     ) -> Result<Self::SerializeTupleStruct> {
-        todo!()
+        self.serialize_seq(Some(len))
     }
 
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
-        _len: usize,
+        variant: &'static str,
+        len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
-        todo!()
+        write!(&mut self.writer, "*{}\r\n", len + 1)?;
+        self.serialize_bytes(variant.to_uppercase().as_bytes())?;
+        Ok(self)
     }
 
-    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        todo!()
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap> {
+        let len = len.ok_or(Error::LenNotAvailable)?;
+        write!(&mut self.writer, "*{}\r\n", 2 * len)?;
+        Ok(self)
     }
 }
 
@@ -199,9 +221,9 @@ impl<W: Write> ser::SerializeSeq for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -215,9 +237,9 @@ impl<W: Write> ser::SerializeStruct for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -231,9 +253,9 @@ impl<W: Write> ser::SerializeStructVariant for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -247,64 +269,64 @@ impl<W: Write> ser::SerializeTuple for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        todo!()
+        value.serialize(&mut **self)
     }
 
     fn end(self) -> Result<Self::Ok> {
-        todo!()
+        Ok(())
     }
 }
 
-macro_rules! todo_impl {
+macro_rules! element_impl {
     ($trait_name:path) => {
-        impl<W: Write> $trait_name for & mut Serializer<W> {
+        impl<W: Write> $trait_name for &mut Serializer<W> {
             type Ok = ();
             type Error = Error;
 
-            fn serialize_field<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+            fn serialize_field<T>(&mut self, value: &T) -> Result<()>
             where
-                T: Serialize,
+                T: ?Sized + Serialize,
             {
-                todo!()
+                value.serialize(&mut **self)
             }
 
             fn end(self) -> Result<Self::Ok> {
-                todo!()
+                Ok(())
             }
         }
     };
 
-	($trait_name:path, $($rest:path),+) => {
-		todo_impl!( $trait_name );
-		todo_impl!( $($rest),+ );
-	};
+    ($trait_name:path, $($rest:path),+) => {
+        element_impl!( $trait_name );
+        element_impl!( $($rest),+ );
+    };
 }
-todo_impl!(ser::SerializeTupleStruct, ser::SerializeTupleVariant);
+element_impl!(ser::SerializeTupleStruct, ser::SerializeTupleVariant);
 
 impl<W: Write> ser::SerializeMap for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, _key: &T) -> Result<()>
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        todo!()
+        key.serialize(&mut **self)
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        todo!()
+        value.serialize(&mut **self)
     }
 
     fn end(self) -> Result<Self::Ok> {
-        todo!()
+        Ok(())
     }
 }
 
@@ -323,3 +345,58 @@ fn test_command() {
     dbg!(&std::str::from_utf8(&buffer));
     assert_eq!(&buffer, b"*2\r\n$4\r\nPING\r\n$4\r\ntest\r\n");
 }
+
+#[test]
+fn test_enum() {
+    #[derive(Serialize)]
+    enum Request<'a> {
+        Get { key: &'a str },
+        Echo(&'a str),
+        Quit,
+    }
+
+    let mut buffer = Vec::new();
+    to_writer(&mut buffer, &Request::Get { key: "k" }).unwrap();
+    to_writer(&mut buffer, &Request::Echo("hi")).unwrap();
+    to_writer(&mut buffer, &Request::Quit).unwrap();
+    assert_eq!(
+        &buffer[..],
+        &b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n$4\r\nQUIT\r\n"[..]
+    );
+}
+
+#[test]
+fn test_error() {
+    let mut buffer = Vec::new();
+    write_error(&mut buffer, "ERR unknown\r\ncommand").unwrap();
+    assert_eq!(&buffer[..], &b"-ERR unknown  command\r\n"[..]);
+}
+
+#[test]
+fn test_seq() {
+    let mut buffer = Vec::new();
+    to_writer(&mut buffer, &vec![("a", "1")]).unwrap();
+    assert_eq!(&buffer[..], &b"*1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n"[..]);
+}
+
+#[test]
+fn test_integer() {
+    let mut buffer = Vec::new();
+    to_writer(&mut buffer, &vec![-3i64, 42]).unwrap();
+    assert_eq!(&buffer[..], &b"*2\r\n:-3\r\n:42\r\n"[..]);
+}
+
+#[test]
+fn test_tuple_variant() {
+    #[derive(Serialize)]
+    enum Request<'a> {
+        Move(&'a str, &'a str),
+    }
+
+    let mut buffer = Vec::new();
+    to_writer(&mut buffer, &Request::Move("a", "b")).unwrap();
+    assert_eq!(
+        &buffer[..],
+        &b"*3\r\n$4\r\nMOVE\r\n$1\r\na\r\n$1\r\nb\r\n"[..]
+    );
+}
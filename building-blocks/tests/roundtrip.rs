@@ -0,0 +1,237 @@
+use building_blocks::{from_reader, to_writer, Deserializer, Error};
+use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Debug};
+
+const CASES: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Set {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Request {
+    Get {
+        key: String,
+    },
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    MSet {
+        entries: Vec<(String, String)>,
+    },
+    Ping,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Response {
+    Ok(Option<String>),
+    Err(String),
+    Batch(Vec<Response>),
+    Entry { key: String, value: String },
+    Moved(String, i64),
+    End,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Meters(u32);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Point(i64, i64);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Quit;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Everything {
+    flag: bool,
+    small: i8,
+    large: u64,
+    ratio: f64,
+    letter: char,
+    bytes: Vec<u8>,
+    distance: Meters,
+    point: Point,
+    quit: Quit,
+    unit: (),
+    counts: BTreeMap<String, i64>,
+    nested: Vec<Vec<Option<String>>>,
+}
+
+fn round_trip<T>(value: &T)
+where
+    T: Serialize + DeserializeOwned + Debug + PartialEq,
+{
+    let mut buffer = Vec::new();
+    to_writer(&mut buffer, value).unwrap();
+    let decoded: T = from_reader(&buffer[..]).unwrap_or_else(|e| {
+        panic!(
+            "failed to decode {:?} from {:?}: {}",
+            value,
+            String::from_utf8_lossy(&buffer),
+            e
+        )
+    });
+    assert_eq!(&decoded, value);
+}
+
+fn string(rng: &mut StdRng) -> String {
+    let len = rng.gen_range(0..20);
+    if rng.gen_bool(0.5) {
+        (0..len).map(|_| rng.sample(Alphanumeric) as char).collect()
+    } else {
+        // any characters, including line breaks
+        (0..len).map(|_| rng.gen::<char>()).collect()
+    }
+}
+
+fn option(rng: &mut StdRng) -> Option<String> {
+    if rng.gen_bool(0.3) {
+        None
+    } else {
+        Some(string(rng))
+    }
+}
+
+fn request(rng: &mut StdRng) -> Request {
+    match rng.gen_range(0..5) {
+        0 => Request::Get { key: string(rng) },
+        1 => Request::Cas {
+            key: string(rng),
+            expected: option(rng),
+            new: string(rng),
+        },
+        2 => Request::Incr {
+            key: string(rng),
+            delta: rng.gen(),
+        },
+        3 => Request::MSet {
+            entries: (0..rng.gen_range(0..5))
+                .map(|_| (string(rng), string(rng)))
+                .collect(),
+        },
+        _ => Request::Ping,
+    }
+}
+
+fn response(rng: &mut StdRng, depth: u32) -> Response {
+    match rng.gen_range(0..if depth < 3 { 6 } else { 5 }) {
+        0 => Response::Ok(option(rng)),
+        1 => Response::Err(string(rng)),
+        2 => Response::Entry {
+            key: string(rng),
+            value: string(rng),
+        },
+        3 => Response::Moved(string(rng), rng.gen()),
+        4 => Response::End,
+        _ => Response::Batch(
+            (0..rng.gen_range(0..4))
+                .map(|_| response(rng, depth + 1))
+                .collect(),
+        ),
+    }
+}
+
+fn everything(rng: &mut StdRng) -> Everything {
+    Everything {
+        flag: rng.gen(),
+        small: rng.gen(),
+        large: rng.gen_range(0..=i64::MAX as u64),
+        ratio: rng.gen::<f64>() * 1e6 - 5e5,
+        letter: rng.gen(),
+        bytes: (0..rng.gen_range(0..10)).map(|_| rng.gen()).collect(),
+        distance: Meters(rng.gen()),
+        point: Point(rng.gen(), rng.gen()),
+        quit: Quit,
+        unit: (),
+        counts: (0..rng.gen_range(0..4))
+            .map(|_| (string(rng), rng.gen()))
+            .collect(),
+        nested: (0..rng.gen_range(0..3))
+            .map(|_| (0..rng.gen_range(0..3)).map(|_| option(rng)).collect())
+            .collect(),
+    }
+}
+
+// Struct commands should decode to what was encoded.
+#[test]
+fn structs() {
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..CASES {
+        round_trip(&Set {
+            key: string(&mut rng),
+            value: string(&mut rng),
+        });
+    }
+}
+
+// Enums should decode to the same variant with the same fields, whether
+// they are unit, newtype, tuple or struct variants.
+#[test]
+fn enums() {
+    let mut rng = StdRng::seed_from_u64(2);
+    for _ in 0..CASES {
+        round_trip(&request(&mut rng));
+        round_trip(&response(&mut rng, 0));
+    }
+}
+
+// Every other supported type should decode to what was encoded, also
+// when nested in arrays.
+#[test]
+fn all_types() {
+    let mut rng = StdRng::seed_from_u64(3);
+    for _ in 0..CASES {
+        round_trip(&everything(&mut rng));
+    }
+    round_trip(&vec![i64::MIN, -1, 0, 1, i64::MAX]);
+    round_trip(&vec![f64::INFINITY, f64::MIN_POSITIVE, -0.5]);
+}
+
+// Several values written in a row should be read back one after another.
+#[test]
+fn streams() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let responses: Vec<_> = (0..CASES).map(|_| response(&mut rng, 0)).collect();
+    let mut buffer = Vec::new();
+    for response in &responses {
+        to_writer(&mut buffer, response).unwrap();
+    }
+    let mut de = Deserializer::new(&buffer[..]);
+    for response in &responses {
+        assert_eq!(&Response::deserialize(&mut de).unwrap(), response);
+    }
+    assert!(matches!(Response::deserialize(&mut de), Err(Error::Eof)));
+}
+
+// Values of unknown type should decode to what they look like.
+#[test]
+fn self_describing() {
+    let input: &[u8] = b"*5\r\n$1\r\na\r\n:-7\r\n$-1\r\n*1\r\n+OK\r\n-ERR oops\r\n";
+    let value: serde_json::Value = from_reader(input).unwrap();
+    assert_eq!(
+        value,
+        serde_json::json!(["a", -7, null, ["OK"], "ERR oops"])
+    );
+}
+
+// Values that do not fit the type should be rejected.
+#[test]
+fn mismatches() {
+    assert!(from_reader::<_, bool>(&b":2\r\n"[..]).is_err());
+    assert!(from_reader::<_, u8>(&b":256\r\n"[..]).is_err());
+    assert!(from_reader::<_, Quit>(&b"$4\r\nEXIT\r\n"[..]).is_err());
+    assert!(from_reader::<_, BTreeMap<String, i64>>(&b"*1\r\n$1\r\na\r\n"[..]).is_err());
+    assert!(matches!(
+        to_writer(Vec::new(), &u64::MAX),
+        Err(Error::IntOutOfRange)
+    ));
+}
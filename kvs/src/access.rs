@@ -0,0 +1,65 @@
+//! Approximate times of the last access to each key, like the LRU clock
+//! of Redis, to tell how long keys have been idle.
+//!
+//! Times are kept by the hash of the key, in seconds since the store was
+//! opened. A key is only written to once per second however often it is
+//! accessed, so that hot keys do not keep writing the same memory from
+//! every thread. Keys not accessed since the store was opened count as
+//! idle since then.
+
+use crate::index;
+use crossbeam_skiplist::SkipMap;
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+pub(crate) struct AccessTimes {
+    opened: Instant,
+    // the second each key was last accessed in, by its hash
+    last: SkipMap<u64, AtomicU32>,
+}
+
+impl AccessTimes {
+    pub fn new() -> AccessTimes {
+        AccessTimes {
+            opened: Instant::now(),
+            last: SkipMap::new(),
+        }
+    }
+
+    /// Notes that `key` was read or written now.
+    pub fn touch(&self, key: &str) {
+        let now = self.now();
+        let hash = index::hash_key(key);
+        match self.last.get(&hash) {
+            Some(entry) => {
+                let last = entry.value();
+                if last.load(Ordering::Relaxed) != now {
+                    last.store(now, Ordering::Relaxed);
+                }
+            }
+            None => {
+                self.last.insert(hash, AtomicU32::new(now));
+            }
+        }
+    }
+
+    /// Forgets `key`, which was removed.
+    pub fn forget(&self, key: &str) {
+        self.last.remove(&index::hash_key(key));
+    }
+
+    /// Returns how long ago `key` was last accessed, to the second.
+    pub fn idle_time(&self, key: &str) -> Duration {
+        let last = self
+            .last
+            .get(&index::hash_key(key))
+            .map_or(0, |entry| entry.value().load(Ordering::Relaxed));
+        Duration::from_secs(u64::from(self.now().saturating_sub(last)))
+    }
+
+    fn now(&self) -> u32 {
+        self.opened.elapsed().as_secs().min(u64::from(u32::MAX)) as u32
+    }
+}
@@ -0,0 +1,447 @@
+use crate::{
+    clients::{Clients, Connection},
+    metrics::Metrics,
+    protocol::Request,
+    server::{
+        end_of_input, log_served, next_frame, protocol_error, timed_out, Frame, Handler,
+        DEFAULT_MAX_FRAME_SIZE,
+    },
+    AuthProvider, ErrorFormat, KeyPolicy, KvsEngine, KvsError, MissingKey, Password, Result,
+};
+use building_blocks::{Deserializer, FrameDecoder};
+use serde::Deserialize;
+use std::{
+    future::Future,
+    io,
+    net::ToSocketAddrs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task, time,
+};
+
+/// How many responses of a scan may wait to be written to the client.
+const SCAN_BUFFER: usize = 64;
+
+/// How many bytes are read from a connection at most at once.
+const READ_SIZE: usize = 4096;
+
+/// A server like [`KvsServer`](crate::KvsServer) that runs on tokio.
+///
+/// Connections are tasks rather than threads, so idle clients cost little
+/// more than their socket. Requests are read and responses written
+/// asynchronously, while the engine calls, which block, run on tokio's
+/// blocking threads. [`AsyncKvsServer::run`] and
+/// [`AsyncKvsServer::serve`] have to be called from within a tokio
+/// runtime.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: E,
+    error_format: ErrorFormat,
+    key_policy: KeyPolicy,
+    missing_key: MissingKey,
+    max_frame_size: usize,
+    redis_compat: bool,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    slow_request_threshold: Option<Duration>,
+    auth: Option<Box<dyn AuthProvider>>,
+    metrics_listener: Option<std::net::TcpListener>,
+}
+
+impl<E: KvsEngine + 'static> AsyncKvsServer<E> {
+    /// Creates a server for the given engine.
+    pub fn new(engine: E) -> AsyncKvsServer<E> {
+        AsyncKvsServer {
+            engine,
+            error_format: ErrorFormat::default(),
+            key_policy: KeyPolicy::default(),
+            missing_key: MissingKey::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            redis_compat: false,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            slow_request_threshold: None,
+            auth: None,
+            metrics_listener: None,
+        }
+    }
+
+    /// Sets the rules that the keys of requests have to follow. Requests
+    /// with a rejected key fail before they reach the engine. Defaults to
+    /// accepting every key.
+    pub fn with_key_policy(mut self, policy: KeyPolicy) -> AsyncKvsServer<E> {
+        self.key_policy = policy;
+        self
+    }
+
+    /// Sets how errors while serving a client are logged. Defaults to
+    /// `ErrorFormat::Text`.
+    pub fn with_error_format(mut self, error_format: ErrorFormat) -> AsyncKvsServer<E> {
+        self.error_format = error_format;
+        self
+    }
+
+    /// Sets how a `GET` for a missing key is answered. Defaults to
+    /// `MissingKey::Nil`. Keys missing from an `MGET` are always nil.
+    pub fn with_missing_key(mut self, missing_key: MissingKey) -> AsyncKvsServer<E> {
+        self.missing_key = missing_key;
+        self
+    }
+
+    /// Sets the most bytes a request may take up, like
+    /// [`KvsServer::with_max_frame_size`](crate::KvsServer::with_max_frame_size).
+    /// Defaults to 512 MiB.
+    pub fn with_max_frame_size(mut self, bytes: usize) -> AsyncKvsServer<E> {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Speaks the Redis protocol instead of that of
+    /// [`KvsClient`](crate::KvsClient), like
+    /// [`KvsServer::with_redis_compat`](crate::KvsServer::with_redis_compat).
+    /// Off by default.
+    pub fn with_redis_compat(mut self) -> AsyncKvsServer<E> {
+        self.redis_compat = true;
+        self
+    }
+
+    /// Closes connections on which no request arrives for `timeout`, like
+    /// [`KvsServer::with_idle_timeout`](crate::KvsServer::with_idle_timeout).
+    /// Off by default.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> AsyncKvsServer<E> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections on which the rest of a request that has begun
+    /// to arrive does not come in for `timeout`, like
+    /// [`KvsServer::with_read_timeout`](crate::KvsServer::with_read_timeout).
+    /// The timeout covers reading the rest of the request as a whole
+    /// rather than each wait for more of it. Off by default.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> AsyncKvsServer<E> {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections on which a response cannot be written for
+    /// `timeout`, like
+    /// [`KvsServer::with_write_timeout`](crate::KvsServer::with_write_timeout).
+    /// Off by default.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> AsyncKvsServer<E> {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Logs a warning for every request that takes the engine at least
+    /// `threshold`, like
+    /// [`KvsServer::with_slow_request_threshold`](crate::KvsServer::with_slow_request_threshold).
+    /// Off by default.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> AsyncKvsServer<E> {
+        self.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Requires connections to authenticate with `password`, like
+    /// [`KvsServer::with_password`](crate::KvsServer::with_password).
+    /// Off by default.
+    pub fn with_password(self, password: impl Into<String>) -> AsyncKvsServer<E> {
+        self.with_auth(Password::new(password))
+    }
+
+    /// Requires connections to authenticate with a password that
+    /// `provider` accepts, like
+    /// [`KvsServer::with_auth`](crate::KvsServer::with_auth).
+    pub fn with_auth(mut self, provider: impl AuthProvider + 'static) -> AsyncKvsServer<E> {
+        self.auth = Some(Box::new(provider));
+        self
+    }
+
+    /// Serves the metrics of the server at `/metrics` over HTTP on
+    /// `listener`, like
+    /// [`KvsServer::with_metrics_listener`](crate::KvsServer::with_metrics_listener).
+    /// Scrapes are answered on a thread of their own rather than a task.
+    pub fn with_metrics_listener(mut self, listener: std::net::TcpListener) -> AsyncKvsServer<E> {
+        self.metrics_listener = Some(listener);
+        self
+    }
+
+    /// Binds to `addr` and serves connections on it.
+    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(std::net::TcpListener::bind(addr)?).await
+    }
+
+    /// Serves connections accepted on `listener`, each in a task of its
+    /// own.
+    pub async fn serve(self, listener: std::net::TcpListener) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let handler = Arc::new(Handler {
+            engine: self.engine,
+            error_format: self.error_format,
+            key_policy: self.key_policy,
+            missing_key: self.missing_key,
+            max_frame_size: self.max_frame_size,
+            redis_compat: self.redis_compat,
+            idle_timeout: self.idle_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            slow_request_threshold: self.slow_request_threshold,
+            auth: self.auth,
+            metrics: Metrics::default(),
+            keyspace: Arc::default(),
+            clients: Clients::default(),
+        });
+        handler.engine.add_event_listener(handler.keyspace.clone());
+        if let Some(metrics_listener) = self.metrics_listener {
+            handler.serve_metrics(metrics_listener)?;
+        }
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let handler = Arc::clone(&handler);
+                    let accepted = Instant::now();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(&handler, stream, accepted.elapsed()).await {
+                            handler.log_error(&e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Connection failed: {}", e),
+            }
+        }
+    }
+}
+
+async fn handle<E: KvsEngine + 'static>(
+    handler: &Arc<Handler<E>>,
+    stream: TcpStream,
+    queued: Duration,
+) -> Result<()> {
+    let _active = handler.metrics.connection(queued);
+    let peer = stream.peer_addr()?;
+    // a handle to close the socket with, shared with the tokio stream
+    let stream = stream.into_std()?;
+    let registration = handler.clients.register(peer, stream.try_clone()?);
+    let stream = TcpStream::from_std(stream)?;
+    let connection = registration.connection();
+    let (mut reader, mut writer) = stream.into_split();
+    let mut decoder = FrameDecoder::new().with_max_frame_size(handler.max_frame_size);
+    let mut chunk = vec![0; READ_SIZE];
+    let mut frame = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        if decoder.buffered() == 0 {
+            // wait for the next request to begin
+            let read = within(handler.idle_timeout, reader.read(&mut chunk)).await;
+            match read {
+                Some(Ok(read)) if read > 0 => decoder.extend(&chunk[..read]),
+                _ => {
+                    log::debug!("Closing idle connection from {}", peer);
+                    return Ok(());
+                }
+            }
+        }
+        let read = within(
+            handler.read_timeout,
+            read_frame(&mut reader, &mut decoder, &mut chunk, &mut frame),
+        );
+        match read.await.ok_or_else(|| timed_out("reading a request"))? {
+            Ok(Frame::Complete) => (),
+            Ok(Frame::TooLarge) => {
+                write(
+                    &mut writer,
+                    &handler.reject_frame(peer),
+                    handler.write_timeout,
+                )
+                .await?;
+                continue;
+            }
+            Ok(Frame::Closed) => return Ok(()),
+            // the rest of the input cannot be told apart from the request
+            Err(KvsError::Protocol(e)) => {
+                write(&mut writer, &protocol_error(&e), handler.write_timeout).await?;
+                return Err(e.into());
+            }
+            Err(e) => return Err(e),
+        }
+        if handler.redis_compat {
+            let reply = {
+                let handler = Arc::clone(handler);
+                let connection = Arc::clone(connection);
+                let frame = frame.clone();
+                task::spawn_blocking(move || handler.respond_redis(&frame, &connection))
+                    .await
+                    .map_err(io::Error::other)?
+            };
+            write(&mut writer, &reply, handler.write_timeout).await?;
+            connection.answered();
+            continue;
+        }
+        let request = match Request::deserialize(&mut Deserializer::new(&frame[..])) {
+            Ok(request) => request,
+            Err(e) => {
+                log::debug!("Invalid request from {}: {}", peer, e);
+                write(&mut writer, &protocol_error(&e), handler.write_timeout).await?;
+                continue;
+            }
+        };
+        log::trace!("Request from {}: {:?}", peer, request);
+        let command = request.command();
+        connection.received(command);
+        if let Some(reply) = handler.authorize(&request, connection) {
+            write(&mut writer, &reply, handler.write_timeout).await?;
+            connection.answered();
+            continue;
+        }
+        if let Request::Subscribe { channels } = request {
+            log::debug!("Subscribed {} to {:?}", peer, channels);
+            connection.answered();
+            return serve_subscription(handler, channels, reader, writer).await;
+        }
+        let start = Instant::now();
+        if let Request::Scan { start: from, end } = request {
+            stream_scan(handler, from, end, connection, &mut writer).await?;
+            connection.answered();
+            log_served(command, peer, start.elapsed());
+            continue;
+        }
+        let response = {
+            let handler = Arc::clone(handler);
+            let connection = Arc::clone(connection);
+            task::spawn_blocking(move || handler.respond(request, &connection))
+                .await
+                .map_err(io::Error::other)?
+        };
+        log::trace!("Response to {}: {:?}", peer, response);
+        buf.clear();
+        building_blocks::to_writer(&mut buf, &response)?;
+        write(&mut writer, &buf, handler.write_timeout).await?;
+        connection.answered();
+        log_served(command, peer, start.elapsed());
+    }
+}
+
+/// Streams the responses of a scan to `writer`.
+///
+/// The scan runs on a blocking thread and hands the encoded responses
+/// over through a bounded channel, so it waits for a slow client instead
+/// of buffering the whole range. It stops once the connection is gone.
+async fn stream_scan<E: KvsEngine + 'static, W: AsyncWrite + Unpin>(
+    handler: &Arc<Handler<E>>,
+    start: Option<String>,
+    end: Option<String>,
+    connection: &Arc<Connection>,
+    writer: &mut W,
+) -> Result<()> {
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(SCAN_BUFFER);
+    let scan = {
+        let handler = Arc::clone(handler);
+        let connection = Arc::clone(connection);
+        task::spawn_blocking(move || {
+            handler.stream_scan(start, end, &connection, |response| {
+                let mut buf = Vec::new();
+                building_blocks::to_writer(&mut buf, &response)?;
+                sender
+                    .blocking_send(buf)
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+            })
+        })
+    };
+    while let Some(mut buf) = receiver.recv().await {
+        // write whatever is ready at once
+        while let Ok(next) = receiver.try_recv() {
+            buf.extend(next);
+        }
+        write(writer, &buf, handler.write_timeout).await?;
+    }
+    scan.await.map_err(io::Error::other)?
+}
+
+/// Sends the notifications on `channels` over a connection until the
+/// client closes it. Anything else the client sends is discarded.
+async fn serve_subscription<E, R, W>(
+    handler: &Arc<Handler<E>>,
+    channels: Vec<String>,
+    mut reader: R,
+    mut writer: W,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let confirmations = handler.subscribe(channels, move |message| sender.send(message).is_ok());
+    let mut buf = Vec::new();
+    for response in confirmations {
+        building_blocks::to_writer(&mut buf, &response)?;
+    }
+    write(&mut writer, &buf, handler.write_timeout).await?;
+    let mut discarded = [0; 512];
+    loop {
+        tokio::select! {
+            message = receiver.recv() => {
+                let message = match message {
+                    Some(message) => message,
+                    None => return Ok(()),
+                };
+                buf.clear();
+                building_blocks::to_writer(&mut buf, &message)?;
+                write(&mut writer, &buf, handler.write_timeout).await?;
+            }
+            read = reader.read(&mut discarded) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Runs `future` to its end, or returns `None` if that takes longer than
+/// `timeout`.
+async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Writes all of `buf` to `writer`, failing if that takes longer than
+/// `timeout`.
+async fn write<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buf: &[u8],
+    timeout: Option<Duration>,
+) -> Result<()> {
+    within(timeout, writer.write_all(buf))
+        .await
+        .ok_or_else(|| timed_out("writing a response"))??;
+    Ok(())
+}
+
+/// Reads the next request from `reader` into `frame`, feeding `decoder`
+/// through `chunk` until it has all of it, see [`next_frame`].
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    decoder: &mut FrameDecoder,
+    chunk: &mut [u8],
+    frame: &mut Vec<u8>,
+) -> Result<Frame> {
+    loop {
+        if let Some(read) = next_frame(decoder, frame)? {
+            return Ok(read);
+        }
+        let read = reader.read(chunk).await?;
+        if read == 0 {
+            return end_of_input(decoder);
+        }
+        decoder.extend(&chunk[..read]);
+    }
+}
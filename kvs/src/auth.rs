@@ -0,0 +1,72 @@
+//! Password protection for servers, see
+//! [`KvsServer::with_password`](crate::KvsServer::with_password).
+//!
+//! A connection to a protected server has to authenticate with `AUTH`
+//! before anything but `AUTH` and `PING` is carried out on it. Other
+//! requests are answered with the error `NOAUTH Authentication
+//! required.`, and an `AUTH` with the wrong password with `WRONGPASS
+//! invalid password`, the same as Redis does. [`KvsClient`] returns both
+//! as [`KvsError::Auth`].
+//!
+//! [`KvsClient`]: crate::KvsClient
+//! [`KvsError::Auth`]: crate::KvsError::Auth
+
+/// The error for a request on a connection that has not authenticated.
+pub(crate) const NOAUTH: &str = "NOAUTH Authentication required.";
+
+/// The error for an `AUTH` with a password that is not accepted.
+pub(crate) const WRONGPASS: &str = "WRONGPASS invalid password";
+
+/// Decides which passwords a server accepts.
+///
+/// It is called on the connection's own thread or task for every `AUTH`,
+/// so it should answer quickly.
+pub trait AuthProvider: Send + Sync {
+    /// Returns whether `password` authenticates a connection.
+    fn authenticate(&self, password: &str) -> bool;
+}
+
+/// Accepts a single password, like Redis' `requirepass`.
+pub struct Password(String);
+
+impl Password {
+    /// Creates a provider that accepts `password`.
+    pub fn new(password: impl Into<String>) -> Password {
+        Password(password.into())
+    }
+}
+
+impl AuthProvider for Password {
+    fn authenticate(&self, password: &str) -> bool {
+        // compares every byte so that the time taken does not tell how
+        // much of a guess was right
+        let (expected, given) = (self.0.as_bytes(), password.as_bytes());
+        expected.len() == given.len()
+            && expected
+                .iter()
+                .zip(given)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Returns whether the error message of a server is one of the errors
+/// of authentication.
+pub(crate) fn is_auth_error(message: &str) -> bool {
+    message.starts_with("NOAUTH") || message.starts_with("WRONGPASS")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password() {
+        let password = Password::new("secret");
+        assert!(password.authenticate("secret"));
+        assert!(!password.authenticate("secre"));
+        assert!(!password.authenticate("secrets"));
+        assert!(!password.authenticate("Secret"));
+        assert!(!password.authenticate(""));
+    }
+}
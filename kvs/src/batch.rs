@@ -0,0 +1,69 @@
+/// Writes collected in memory and applied by [`KvStore::commit`] all at
+/// once.
+///
+/// Either all or none of the writes of a committed batch survive a
+/// crash.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, WriteBatch};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("from".to_owned(), "100".to_owned())?;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.remove("from".to_owned());
+/// batch.set("to".to_owned(), "100".to_owned());
+/// store.commit(batch)?;
+/// assert_eq!(store.get("to".to_owned())?, Some("100".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`KvStore::commit`]: crate::KvStore::commit
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of `key` to `value`.
+    pub fn set(&mut self, key: String, value: String) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Adds removing `key`, which has to exist by the time the batch is
+    /// committed, or be set earlier in the batch.
+    pub fn remove(&mut self, key: String) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl BatchOp {
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::{net::SocketAddr, process};
+
+use clap::Clap;
+use kvs::{ErrorFormat, KvsClient, KvsError};
+
+const KEY_NOT_FOUND: &str = "Key not found";
+
+#[derive(Clap)]
+#[clap(name = "kvs-client",
+       version = env!("CARGO_PKG_VERSION"),
+       author = env!("CARGO_PKG_AUTHORS"),
+       about = "A client for kvs-server.")]
+struct Cli {
+    /// The address of the server.
+    #[clap(long, default_value = "127.0.0.1:4000", global = true)]
+    addr: SocketAddr,
+    /// How to report errors on stderr.
+    #[clap(long, default_value = "text", possible_values = &["text", "json"], global = true)]
+    errors: ErrorFormat,
+    /// Names the connection to the server, as listed by `clients`.
+    #[clap(long, global = true)]
+    name: Option<String>,
+    /// Authenticates with the server, for one started with
+    /// `--requirepass`.
+    #[clap(long, global = true)]
+    password: Option<String>,
+    #[clap(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Gets the value corresponding to <key> from the server.
+    Get { key: String },
+    /// Removes the entry corresponding to <key> on the server.
+    Rm { key: String },
+    /// Set the value corresponding to <key> on the server to <value>.
+    Set { key: String, value: String },
+    /// Pings the server and prints the round-trip times.
+    Ping {
+        /// The number of pings to send.
+        #[clap(long, default_value = "1")]
+        count: usize,
+    },
+    /// Prints the metrics of the server in the Prometheus text format.
+    Metrics,
+    /// Lists the connections of the server, one per line.
+    Clients,
+    /// Closes the connection with the id or address <target>, as listed
+    /// by `clients`.
+    Kill { target: String },
+    /// Prints the seconds since <key> was last read or written, if the
+    /// server keeps track of that.
+    Idletime { key: String },
+    /// Prints the notifications on the given channels as they arrive, one
+    /// per line with the channel and the message separated by a tab, e.g.
+    /// the keys that expire on `__keyevent@0__:expired`.
+    Subscribe {
+        #[clap(required = true)]
+        channels: Vec<String>,
+    },
+}
+
+fn main() {
+    let cli: Cli = Cli::parse();
+    if let Err(e) = run(&cli) {
+        match cli.errors {
+            ErrorFormat::Text => eprintln!("Error: {:?}", e),
+            ErrorFormat::Json => eprintln!("{}", e.to_json()),
+        }
+        process::exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> kvs::Result<()> {
+    let mut client = match &cli.password {
+        Some(password) => KvsClient::connect_with_auth(cli.addr, password)?,
+        None => KvsClient::connect(cli.addr)?,
+    };
+    if let Some(name) = &cli.name {
+        client.set_name(name.clone())?;
+    }
+
+    use Command::*;
+    match &cli.cmd {
+        Get { key } => {
+            let msg = match client.get(key.clone()) {
+                Ok(value) => value.unwrap_or_else(|| KEY_NOT_FOUND.to_owned()),
+                Err(KvsError::NonExistentKey(_)) => KEY_NOT_FOUND.to_owned(),
+                Err(e) => return Err(e),
+            };
+            println!("{}", msg);
+        }
+        Rm { key } => match client.remove(key.clone()) {
+            Ok(()) => (),
+            Err(KvsError::NonExistentKey(_)) if cli.errors == ErrorFormat::Text => {
+                eprintln!("{}", KEY_NOT_FOUND);
+                process::exit(1);
+            }
+            Err(e) => return Err(e),
+        },
+        Set { key, value } => {
+            client.set(key.clone(), value.clone())?;
+        }
+        Ping { count } => {
+            let latency = client.measure_latency(*count)?;
+            println!(
+                "{} pings: min {:?}, mean {:?}, median {:?}, p99 {:?}, max {:?}",
+                latency.samples,
+                latency.min,
+                latency.mean,
+                latency.median,
+                latency.p99,
+                latency.max
+            );
+        }
+        Metrics => print!("{}", client.metrics()?),
+        Clients => print!("{}", client.list_clients()?),
+        Kill { target } => client.kill_client(target.clone())?,
+        Idletime { key } => match client.idle_time(key.clone())? {
+            Some(idle) => println!("{}", idle.as_secs()),
+            None => println!("{}", KEY_NOT_FOUND),
+        },
+        Subscribe { channels } => {
+            for notification in client.subscribe(channels.clone())? {
+                let (channel, message) = notification?;
+                println!("{}\t{}", channel, message);
+            }
+        }
+    };
+    Ok(())
+}
@@ -0,0 +1,351 @@
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpListener},
+    process,
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Clap;
+use kvs::{
+    thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool},
+    ErrorFormat, KvStore, KvsEngine, KvsServer, MemoryEngine, MissingKey,
+};
+use log::{LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+
+#[derive(Clap)]
+#[clap(name = "kvs-server",
+       version = env!("CARGO_PKG_VERSION"),
+       author = env!("CARGO_PKG_AUTHORS"),
+       about = "Serves a key-value store over TCP.")]
+struct Cli {
+    /// The path where the key-value store should store its data.
+    #[clap(parse(from_os_str), default_value = ".")]
+    path: std::path::PathBuf,
+    /// The address to listen on.
+    #[clap(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    /// The storage engine to serve: the log-structured `kvs`, or `memory`,
+    /// which keeps all entries in memory and takes snapshots in the path.
+    #[clap(long, default_value = "kvs", possible_values = &["kvs", "memory"])]
+    engine: Engine,
+    /// How many seconds the memory engine waits between snapshots.
+    #[clap(long, default_value = "60")]
+    snapshot_interval: u64,
+    /// The number of threads serving connections. Defaults to the number
+    /// of CPUs.
+    #[clap(long)]
+    threads: Option<u32>,
+    /// The kind of thread pool serving connections.
+    #[clap(long, default_value = "shared-queue", possible_values = &["shared-queue", "rayon", "naive"])]
+    pool: Pool,
+    /// Serve connections asynchronously on tokio instead of a thread pool,
+    /// with `--threads` worker threads.
+    #[cfg(feature = "async")]
+    #[clap(long = "async")]
+    async_io: bool,
+    /// The most verbose messages to log. Requests are logged at `debug`,
+    /// with their contents at `trace`.
+    #[clap(long, default_value = "info", possible_values = &["off", "error", "warn", "info", "debug", "trace"])]
+    log_level: LevelFilter,
+    /// How to write the log to stderr: as text, or as one JSON object per
+    /// message for log aggregation.
+    #[clap(long, default_value = "text", possible_values = &["text", "json"])]
+    log_format: LogFormat,
+    /// How to report errors in the log and on stderr.
+    #[clap(long, default_value = "text", possible_values = &["text", "json"])]
+    errors: ErrorFormat,
+    /// How to answer a GET for a key that does not exist: with nil, or
+    /// with a "no such key" error.
+    #[clap(long, default_value = "nil", possible_values = &["nil", "error"])]
+    missing_key: MissingKey,
+    /// The most bytes a request may take up. Larger requests are
+    /// answered with an error.
+    #[clap(long, default_value = "536870912")]
+    max_frame_size: usize,
+    /// Speak the Redis protocol for GET, SET, DEL, EXISTS and PING
+    /// instead of that of kvs-client, for redis-cli and Redis clients.
+    #[clap(long)]
+    redis_compat: bool,
+    /// Close connections on which no request arrives for this many
+    /// seconds, at least 1.
+    #[clap(long, parse(try_from_str = parse_timeout))]
+    idle_timeout: Option<Duration>,
+    /// Close connections on which the rest of a request does not arrive
+    /// within this many seconds, at least 1.
+    #[clap(long, parse(try_from_str = parse_timeout))]
+    read_timeout: Option<Duration>,
+    /// Close connections on which a response cannot be written within
+    /// this many seconds, at least 1.
+    #[clap(long, parse(try_from_str = parse_timeout))]
+    write_timeout: Option<Duration>,
+    /// Log a warning for every request that takes at least this many
+    /// milliseconds.
+    #[clap(long)]
+    slow_request_threshold: Option<u64>,
+    /// Require clients to authenticate with this password before
+    /// anything but AUTH and PING.
+    #[clap(long)]
+    requirepass: Option<String>,
+    /// Keep up to this many bytes of recently read values in memory.
+    #[clap(long)]
+    cache_size: Option<u64>,
+    /// Keep track of how long keys have been idle, for `idletime`.
+    #[clap(long)]
+    track_idle_time: bool,
+    /// Push the expiry of values set with a TTL back by up to this
+    /// fraction of the TTL, between 0 and 1, so that keys set together
+    /// do not all expire at once.
+    #[clap(long)]
+    ttl_jitter: Option<f64>,
+    /// Serve metrics for Prometheus over HTTP at `/metrics` on this
+    /// address.
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+}
+
+enum Engine {
+    Kvs,
+    Memory,
+}
+
+impl Engine {
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Memory => "memory",
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            "memory" => Ok(Engine::Memory),
+            _ => Err(format!("unknown engine: {}", s)),
+        }
+    }
+}
+
+enum Pool {
+    SharedQueue,
+    Rayon,
+    Naive,
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "rayon" => Ok(Pool::Rayon),
+            "naive" => Ok(Pool::Naive),
+            _ => Err(format!("unknown thread pool: {}", s)),
+        }
+    }
+}
+
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Logs one JSON object per message to stderr.
+struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let line = serde_json::json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "thread": thread::current().name(),
+            "message": record.args().to_string(),
+        });
+        // a log that cannot be written has nowhere to report that
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+fn init_logger(cli: &Cli) {
+    let result = match cli.log_format {
+        LogFormat::Text => SimpleLogger::new().with_level(cli.log_level).init(),
+        LogFormat::Json => log::set_boxed_logger(Box::new(JsonLogger {
+            level: cli.log_level,
+        }))
+        .map(|()| log::set_max_level(cli.log_level)),
+    };
+    result.expect("failed to initialize logger");
+}
+
+fn main() {
+    let cli: Cli = Cli::parse();
+    init_logger(&cli);
+
+    if let Err(e) = run(&cli) {
+        match cli.errors {
+            ErrorFormat::Text => eprintln!("Error: {:?}", e),
+            ErrorFormat::Json => eprintln!("{}", e.to_json()),
+        }
+        process::exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> kvs::Result<()> {
+    match cli.engine {
+        Engine::Kvs => {
+            let mut builder = KvStore::builder();
+            if let Some(capacity) = cli.cache_size {
+                builder = builder.value_cache(capacity);
+            }
+            if cli.track_idle_time {
+                builder = builder.track_idle_time();
+            }
+            if let Some(fraction) = cli.ttl_jitter {
+                builder = builder.ttl_jitter(fraction);
+            }
+            run_engine(cli, builder.open(&cli.path)?)
+        }
+        Engine::Memory => {
+            let interval = Duration::from_secs(cli.snapshot_interval);
+            run_engine(
+                cli,
+                MemoryEngine::open_with_snapshot_interval(&cli.path, interval)?,
+            )
+        }
+    }
+}
+
+fn run_engine<E: KvsEngine + 'static>(cli: &Cli, store: E) -> kvs::Result<()> {
+    let threads = match cli.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism()?.get() as u32,
+    };
+    log::info!(
+        "kvs-server {} serving the {} engine on {} with {} threads",
+        env!("CARGO_PKG_VERSION"),
+        cli.engine.name(),
+        cli.addr,
+        threads
+    );
+    #[cfg(feature = "async")]
+    {
+        if cli.async_io {
+            return serve_async(cli, store, threads);
+        }
+    }
+    match cli.pool {
+        Pool::SharedQueue => serve(cli, store, SharedQueueThreadPool::new(threads)?),
+        Pool::Rayon => serve(cli, store, RayonThreadPool::new(threads)?),
+        Pool::Naive => serve(cli, store, NaiveThreadPool::new(threads)?),
+    }
+}
+
+/// Applies the options of `cli` to `server`, a `KvsServer` or an
+/// `AsyncKvsServer`, which have the same builder methods. Returns early
+/// if the metrics listener cannot be bound.
+macro_rules! configure {
+    ($server:expr, $cli:expr) => {{
+        let cli: &Cli = $cli;
+        let mut server = $server
+            .with_error_format(cli.errors)
+            .with_missing_key(cli.missing_key)
+            .with_max_frame_size(cli.max_frame_size);
+        if cli.redis_compat {
+            server = server.with_redis_compat();
+        }
+        if let Some(timeout) = cli.idle_timeout {
+            server = server.with_idle_timeout(timeout);
+        }
+        if let Some(timeout) = cli.read_timeout {
+            server = server.with_read_timeout(timeout);
+        }
+        if let Some(timeout) = cli.write_timeout {
+            server = server.with_write_timeout(timeout);
+        }
+        if let Some(millis) = cli.slow_request_threshold {
+            server = server.with_slow_request_threshold(Duration::from_millis(millis));
+        }
+        if let Some(password) = &cli.requirepass {
+            server = server.with_password(password.as_str());
+        }
+        if let Some(listener) = metrics_listener(cli)? {
+            server = server.with_metrics_listener(listener);
+        }
+        server
+    }};
+}
+
+fn serve<E: KvsEngine + 'static, P: ThreadPool>(cli: &Cli, store: E, pool: P) -> kvs::Result<()> {
+    let server = configure!(KvsServer::new(store).with_pool(pool), cli);
+    server.run(cli.addr)
+}
+
+/// Parses a timeout in seconds. A timeout of 0 would make every read or
+/// write on a connection fail, so it is rejected.
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("must be at least 1 second".to_owned()),
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn metrics_listener(cli: &Cli) -> kvs::Result<Option<TcpListener>> {
+    let addr = match cli.metrics_addr {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    Ok(Some(TcpListener::bind(addr)?))
+}
+
+#[cfg(feature = "async")]
+fn serve_async<E: KvsEngine + 'static>(cli: &Cli, store: E, threads: u32) -> kvs::Result<()> {
+    if threads == 0 {
+        let e = std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the runtime needs at least one thread",
+        );
+        return Err(e.into());
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads as usize)
+        .enable_io()
+        .enable_time()
+        .build()?;
+    let server = configure!(kvs::AsyncKvsServer::new(store), cli);
+    runtime.block_on(server.run(cli.addr))
+}
@@ -1,8 +1,52 @@
-use std::process;
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+    process,
+    str::FromStr,
+};
 
 use clap::Clap;
+use kvs::{
+    export::{self, DiffFormat, ExportFormat},
+    ErrorFormat, KvStore, KvsError,
+};
 
 const KEY_NOT_FOUND: &str = "Key not found";
+#[cfg(not(feature = "parquet"))]
+const FORMATS: &[&str] = &["kvsdump", "json", "csv", "msgpack", "resp-commands"];
+#[cfg(feature = "parquet")]
+const FORMATS: &[&str] = &[
+    "kvsdump",
+    "json",
+    "csv",
+    "msgpack",
+    "parquet",
+    "resp-commands",
+];
+#[cfg(not(feature = "parquet"))]
+const IMPORT_FORMATS: &[&str] = &[
+    "kvsdump",
+    "json",
+    "csv",
+    "msgpack",
+    "resp-commands",
+    "redis-rdb",
+];
+#[cfg(feature = "parquet")]
+const IMPORT_FORMATS: &[&str] = &[
+    "kvsdump",
+    "json",
+    "csv",
+    "msgpack",
+    "parquet",
+    "resp-commands",
+    "redis-rdb",
+];
+const DIFF_FORMATS: &[&str] = &["kvsdiff", "json"];
+/// Values of at least this many bytes are compressed in stores created
+/// with `--compression lz4`.
+const COMPRESS_MIN_SIZE: usize = 64;
 
 #[derive(Clap)]
 #[clap(name = env!("CARGO_PKG_NAME"),
@@ -12,11 +56,81 @@ const KEY_NOT_FOUND: &str = "Key not found";
 struct Cli {
     /// The path where the key-value store should store its data.
     #[clap(parse(from_os_str), default_value = ".")]
-    path: std::path::PathBuf,
+    path: PathBuf,
+    /// The storage engine to use.
+    #[clap(long, default_value = "kvs", possible_values = &["kvs"])]
+    engine: Engine,
+    /// How to report errors on stderr.
+    #[clap(long, default_value = "text", possible_values = &["text", "json"], global = true)]
+    errors: ErrorFormat,
     #[clap(subcommand)]
     cmd: Command,
 }
 
+enum Engine {
+    Kvs,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            _ => Err(format!("unknown engine: {}", s)),
+        }
+    }
+}
+
+enum RecordCodec {
+    MsgPack,
+}
+
+impl FromStr for RecordCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "msgpack" => Ok(RecordCodec::MsgPack),
+            _ => Err(format!("unknown codec: {}", s)),
+        }
+    }
+}
+
+/// The format of an import: that of an export, or a Redis dump.
+enum ImportFormat {
+    Export(ExportFormat),
+    RedisRdb,
+}
+
+impl FromStr for ImportFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> kvs::Result<Self> {
+        match s {
+            "redis-rdb" => Ok(ImportFormat::RedisRdb),
+            s => s.parse().map(ImportFormat::Export),
+        }
+    }
+}
+
+enum Compression {
+    None,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+}
+
 #[derive(Clap)]
 enum Command {
     /// Gets the value corresponding to <key> in the key-value store.
@@ -25,14 +139,164 @@ enum Command {
     Rm { key: String },
     /// Set the value corresponding to <key> in the key-value store to <value>.
     Set { key: String, value: String },
+    /// Writes all key/value pairs in the store to <file>, or to stdout.
+    Export {
+        /// The format of the export.
+        #[clap(long, default_value = "kvsdump", possible_values = FORMATS)]
+        format: ExportFormat,
+        /// The file to write the export to.
+        #[clap(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// Reads key/value pairs from <file>, or from stdin, and sets them in
+    /// the store.
+    ///
+    /// With `--format redis-rdb`, the string keys of a Redis dump are
+    /// imported, and the keys skipped are reported on stderr.
+    Import {
+        /// The format of the export being imported.
+        #[clap(long, default_value = "kvsdump", possible_values = IMPORT_FORMATS)]
+        format: ImportFormat,
+        /// The file to read the export from.
+        #[clap(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// Prints every record of the log with its segment, offset, length,
+    /// type and key, and whether it is live or stale.
+    LogDump,
+    /// Compacts the whole log now.
+    Compact {
+        /// The most bytes per second to copy.
+        #[clap(long)]
+        rate_limit: Option<u64>,
+        /// Print what the compaction did.
+        #[clap(long)]
+        report: bool,
+    },
+    /// Creates a copy of the store in <dest> that shares the files of its
+    /// sealed segments.
+    Checkpoint {
+        /// The directory to create the copy in.
+        #[clap(parse(from_os_str))]
+        dest: PathBuf,
+    },
+    /// Writes the changes that turn the store in <old> into the one in
+    /// <new> to <file>, or to stdout.
+    Diff {
+        /// The format of the diff.
+        #[clap(long, default_value = "kvsdiff", possible_values = DIFF_FORMATS)]
+        format: DiffFormat,
+        /// The older version of the store, e.g. a checkpoint.
+        #[clap(parse(from_os_str))]
+        old: PathBuf,
+        /// The newer version of the store.
+        #[clap(parse(from_os_str))]
+        new: PathBuf,
+        /// The file to write the diff to.
+        #[clap(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// Reads a diff from <file>, or from stdin, and applies it to the
+    /// store.
+    ApplyDiff {
+        /// The format of the diff.
+        #[clap(long, default_value = "kvsdiff", possible_values = DIFF_FORMATS)]
+        format: DiffFormat,
+        /// The file to read the diff from.
+        #[clap(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+    /// Creates an empty store in <dir>, or in the path of the store, with
+    /// its options fixed in a manifest.
+    Init {
+        /// The storage engine of the store.
+        #[clap(long, default_value = "kvs", possible_values = &["kvs"])]
+        engine: Engine,
+        /// How records are encoded in the log.
+        #[clap(long, default_value = "msgpack", possible_values = &["msgpack"])]
+        codec: RecordCodec,
+        /// How values are compressed in the log.
+        #[clap(long, default_value = "none", possible_values = &["none", "lz4"])]
+        compression: Compression,
+        /// The directory to create the store in.
+        #[clap(parse(from_os_str))]
+        dir: Option<PathBuf>,
+    },
+}
+
+fn main() {
+    let Cli {
+        path,
+        engine,
+        errors,
+        cmd,
+    } = Cli::parse();
+    let result = match cmd {
+        Command::Init {
+            engine,
+            codec,
+            compression,
+            dir,
+        } => init(dir.unwrap_or(path), engine, codec, compression),
+        Command::Diff {
+            format,
+            old,
+            new,
+            file,
+        } => diff(old, new, format, file),
+        cmd => match engine {
+            Engine::Kvs => {
+                // dumping the log must not repair or seal it, and neither
+                // need copying it, which also works while a server has
+                // the store open
+                let builder = match cmd {
+                    Command::LogDump | Command::Checkpoint { .. } => KvStore::builder().read_only(),
+                    _ => KvStore::builder(),
+                };
+                builder.open(path).and_then(|store| run(store, cmd, errors))
+            }
+        },
+    };
+    if let Err(e) = result {
+        match errors {
+            ErrorFormat::Text => eprintln!("Error: {:?}", e),
+            ErrorFormat::Json => eprintln!("{}", e.to_json()),
+        }
+        process::exit(1);
+    }
 }
 
-fn main() -> kvs::Result<()> {
-    let cli: Cli = Cli::parse();
-    let mut store = kvs::KvStore::open(cli.path)?;
+fn init(
+    dir: PathBuf,
+    engine: Engine,
+    codec: RecordCodec,
+    compression: Compression,
+) -> kvs::Result<()> {
+    let builder = match (engine, codec) {
+        (Engine::Kvs, RecordCodec::MsgPack) => KvStore::builder(),
+    };
+    let builder = match compression {
+        Compression::None => builder,
+        Compression::Lz4 => builder.compress_values(COMPRESS_MIN_SIZE),
+    };
+    builder.init(dir)
+}
 
+fn diff(old: PathBuf, new: PathBuf, format: DiffFormat, file: Option<PathBuf>) -> kvs::Result<()> {
+    let old = KvStore::builder().read_only().open(old)?;
+    let new = KvStore::builder().read_only().open(new)?;
+    if let Some(file) = file {
+        export::diff(&old, &new, format, BufWriter::new(File::create(file)?))?;
+    } else {
+        let stdout = io::stdout();
+        export::diff(&old, &new, format, BufWriter::new(stdout.lock()))?;
+    }
+    Ok(())
+}
+
+fn run(store: KvStore, cmd: Command, errors: ErrorFormat) -> kvs::Result<()> {
     use Command::*;
-    match cli.cmd {
+    match cmd {
         Get { key } => {
             let msg = store.get(key)?.unwrap_or_else(|| KEY_NOT_FOUND.to_owned());
             println!("{}", msg);
@@ -41,7 +305,7 @@ fn main() -> kvs::Result<()> {
             let result = store.remove(key);
             match result {
                 Ok(()) => (),
-                Err(kvs::KvsError::NonExistentKey(_)) => {
+                Err(KvsError::NonExistentKey(_)) if errors == ErrorFormat::Text => {
                     println!("{}", KEY_NOT_FOUND);
                     process::exit(1);
                 }
@@ -51,6 +315,95 @@ fn main() -> kvs::Result<()> {
         Set { key, value } => {
             store.set(key, value)?;
         }
+        Export { format, file } => {
+            if let Some(file) = file {
+                export::export(&store, format, BufWriter::new(File::create(file)?))?;
+            } else {
+                let stdout = io::stdout();
+                export::export(&store, format, BufWriter::new(stdout.lock()))?;
+            }
+        }
+        Import {
+            format: ImportFormat::Export(format),
+            file,
+        } => {
+            if let Some(file) = file {
+                export::import(&store, format, BufReader::new(File::open(file)?))?;
+            } else {
+                let stdin = io::stdin();
+                export::import(&store, format, BufReader::new(stdin.lock()))?;
+            }
+        }
+        Import {
+            format: ImportFormat::RedisRdb,
+            file,
+        } => {
+            let report = if let Some(file) = file {
+                export::import_redis_rdb(&store, BufReader::new(File::open(file)?))?
+            } else {
+                let stdin = io::stdin();
+                export::import_redis_rdb(&store, BufReader::new(stdin.lock()))?
+            };
+            for (value_type, count) in &report.skipped_types {
+                eprintln!("warning: skipped {} keys of type {}", count, value_type);
+            }
+            if report.skipped_keys > 0 {
+                eprintln!(
+                    "warning: skipped {} keys that are not valid UTF-8",
+                    report.skipped_keys
+                );
+            }
+            if report.skipped_databases > 0 {
+                eprintln!(
+                    "warning: skipped {} keys of databases other than 0",
+                    report.skipped_databases
+                );
+            }
+        }
+        ApplyDiff { format, file } => {
+            if let Some(file) = file {
+                export::apply_diff(&store, format, BufReader::new(File::open(file)?))?;
+            } else {
+                let stdin = io::stdin();
+                export::apply_diff(&store, format, BufReader::new(stdin.lock()))?;
+            }
+        }
+        LogDump => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            for record in store.log_records()? {
+                let key = record
+                    .key
+                    .map_or_else(|| "-".to_owned(), |key| format!("{:?}", key));
+                let state = if record.live { "live" } else { "stale" };
+                writeln!(
+                    out,
+                    "{}.log\t{}\t{}\t{}\t{}\t{}",
+                    record.gen, record.offset, record.len, record.kind, key, state
+                )?;
+            }
+            out.flush()?;
+        }
+        Compact { rate_limit, report } => {
+            store.set_compaction_rate_limit(rate_limit);
+            store.compact_now()?;
+            if report {
+                match store.compaction_history().last() {
+                    Some(report) => println!(
+                        "compacted {} bytes in {:?}: read {} bytes, wrote {} bytes, dropped {} records, live ratio {:.2}",
+                        report.sealed_bytes,
+                        report.duration,
+                        report.bytes_read,
+                        report.bytes_written,
+                        report.records_dropped,
+                        report.live_ratio()
+                    ),
+                    None => println!("nothing to compact"),
+                }
+            }
+        }
+        Checkpoint { dest } => store.checkpoint(dest)?,
+        Init { .. } | Diff { .. } => unreachable!("handled without opening the store"),
     };
     Ok(())
 }
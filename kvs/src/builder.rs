@@ -0,0 +1,282 @@
+use crate::{IndexMode, KeyPolicy, KvStore, Result};
+use std::{path::PathBuf, time::Duration};
+
+/// Options for opening a [`KvStore`], created by [`KvStore::builder`].
+///
+/// ```rust
+/// # use kvs::{IndexMode, KvStore, Result, SyncPolicy};
+/// # fn try_main() -> Result<()> {
+/// use std::{env::current_dir, time::Duration};
+/// let store = KvStore::builder()
+///     .index_mode(IndexMode::Hashed)
+///     .dedup_values(4096)
+///     .compaction_threshold(16 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Interval(Duration::from_secs(1)))
+///     .open(current_dir()?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreBuilder {
+    pub(crate) index_mode: IndexMode,
+    pub(crate) dedup_min_size: Option<usize>,
+    pub(crate) compress_min_size: Option<usize>,
+    pub(crate) value_cache_capacity: Option<u64>,
+    pub(crate) track_idle_time: bool,
+    pub(crate) segment_size: Option<u64>,
+    pub(crate) segment_max_age: Option<Duration>,
+    pub(crate) recovery_mode: RecoveryMode,
+    pub(crate) key_policy: KeyPolicy,
+    pub(crate) compaction_threshold: Option<u64>,
+    pub(crate) max_space_amplification: Option<f64>,
+    pub(crate) compaction_rate_limit: Option<u64>,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) group_commit_latency: Duration,
+    pub(crate) ttl_jitter: f64,
+    pub(crate) read_only: bool,
+    pub(crate) file_prefix: String,
+}
+
+/// When writes are synced to disk.
+///
+/// Every write is handed to the operating system before it returns, so
+/// it survives the process crashing. Syncing it also makes it survive the
+/// machine crashing.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// Leave it to the operating system.
+    #[default]
+    Never,
+    /// Sync after every write.
+    Always,
+    /// Sync after a write if the last sync is at least this long ago.
+    Interval(Duration),
+}
+
+/// What to do about a record at the end of the log that was only partly
+/// written, e.g. because the process died while appending it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum RecoveryMode {
+    /// Truncate the log to the end of the last complete record and log a
+    /// warning. The write that was cut short is lost.
+    #[default]
+    TruncateTail,
+    /// Fail to open the store with `KvsError::Corruption`.
+    Strict,
+}
+
+impl KvStoreBuilder {
+    /// Creates a builder with the default options.
+    pub fn new() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    /// Sets how keys are stored in the in-memory index. Defaults to
+    /// `IndexMode::Ordered`.
+    pub fn index_mode(mut self, mode: IndexMode) -> KvStoreBuilder {
+        self.index_mode = mode;
+        self
+    }
+
+    /// Stores values of at least `min_size` bytes only once, no matter
+    /// how many keys they are set for. Off by default.
+    ///
+    /// Such values are kept in a separate content-addressed file that
+    /// keys refer to. Values that are no longer referenced are dropped
+    /// from it during compaction.
+    pub fn dedup_values(mut self, min_size: usize) -> KvStoreBuilder {
+        self.dedup_min_size = Some(min_size);
+        self
+    }
+
+    /// Compresses values of at least `min_size` bytes with LZ4 before
+    /// writing them to the log. Off by default.
+    ///
+    /// A value is only stored compressed if that makes it smaller. Every
+    /// record tells whether its value is compressed, so this can be
+    /// turned on and off for an existing store. Values deduplicated by
+    /// [`dedup_values`](KvStoreBuilder::dedup_values) are stored as they
+    /// are.
+    pub fn compress_values(mut self, min_size: usize) -> KvStoreBuilder {
+        self.compress_min_size = Some(min_size);
+        self
+    }
+
+    /// Keeps recently read values in memory, up to `capacity` bytes of
+    /// keys and values, so that repeated gets of the same keys do not read
+    /// the log. Off by default.
+    ///
+    /// The least recently used values are evicted first. See
+    /// [`KvStore::cache_stats`] for how well the cache works.
+    pub fn value_cache(mut self, capacity: u64) -> KvStoreBuilder {
+        self.value_cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Keeps the approximate time of the last read or write of every key,
+    /// to tell how long it has been idle with [`KvStore::idle_time`]. Off
+    /// by default.
+    ///
+    /// Times are kept to the second in memory, at about 100 bytes per key,
+    /// and are not persisted: keys not accessed since the store was opened
+    /// count as idle since then.
+    pub fn track_idle_time(mut self) -> KvStoreBuilder {
+        self.track_idle_time = true;
+        self
+    }
+
+    /// Starts a new segment file once the active one has grown to at
+    /// least `max_bytes`. Defaults to 4 MiB.
+    ///
+    /// Only segments that are no longer written to are compacted, so
+    /// smaller segments make stale entries reclaimable sooner.
+    pub fn segment_size(mut self, max_bytes: u64) -> KvStoreBuilder {
+        self.segment_size = Some(max_bytes);
+        self
+    }
+
+    /// Also starts a new segment file before writing to an active one
+    /// whose first write is at least `max_age` ago, so every segment only
+    /// holds writes from a limited time span. Off by default.
+    ///
+    /// After reopening a store, the age of the active segment is taken
+    /// from the creation time of its file.
+    pub fn segment_max_age(mut self, max_age: Duration) -> KvStoreBuilder {
+        self.segment_max_age = Some(max_age);
+        self
+    }
+
+    /// Compacts the sealed segments in the background once they hold
+    /// more than `bytes` of stale entries. Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreBuilder {
+        self.compaction_threshold = Some(bytes);
+        self
+    }
+
+    /// Adapts the compaction threshold so that the log stays within
+    /// `max_space_amplification` times the size of its live entries,
+    /// e.g. 2 for at most as many stale bytes as live ones. Off by
+    /// default, and at least 1.
+    ///
+    /// The threshold is recomputed whenever a segment is sealed or a
+    /// compaction finishes. It is lowered by what is written while a
+    /// compaction runs, going by the recent write rate and how long the
+    /// last compaction took, by at most half, and never drops below the
+    /// [compaction threshold](KvStoreBuilder::compaction_threshold). The
+    /// current threshold is part of the [`Stats`](crate::Stats).
+    pub fn adaptive_compaction(mut self, max_space_amplification: f64) -> KvStoreBuilder {
+        self.max_space_amplification = Some(max_space_amplification.max(1.0));
+        self
+    }
+
+    /// Slows compactions down to copy at most `bytes_per_sec` on average,
+    /// so they leave disk bandwidth to reads and writes. Unlimited by
+    /// default. See also [`KvStore::set_compaction_rate_limit`].
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> KvStoreBuilder {
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Sets when writes are synced to disk. Defaults to
+    /// `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> KvStoreBuilder {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Lets a write that has to be synced wait up to `max_latency` for
+    /// concurrent writes, so that all of them are synced at once. Defaults
+    /// to zero.
+    ///
+    /// Writes share a sync in any case if they come while another one is
+    /// running. A latency of about as long as a sync, e.g. 1 ms, lets
+    /// more of them share it, which raises the throughput of many
+    /// concurrent writers with `SyncPolicy::Always` at the cost of the
+    /// latency of each write.
+    pub fn group_commit_latency(mut self, max_latency: Duration) -> KvStoreBuilder {
+        self.group_commit_latency = max_latency;
+        self
+    }
+
+    /// Pushes the expiry of every value set with a TTL back by up to
+    /// `fraction` of the TTL, so that keys set with the same TTL at the
+    /// same time, e.g. by an import, do not all expire at once. The
+    /// fraction is clamped to between 0 and 1, and defaults to 0.
+    ///
+    /// How far a value is pushed back depends on its key, so the keys are
+    /// spread evenly over the allowed span. Values never expire earlier
+    /// than their TTL.
+    pub fn ttl_jitter(mut self, fraction: f64) -> KvStoreBuilder {
+        self.ttl_jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Opens the store without ever modifying its directory. Off by
+    /// default.
+    ///
+    /// Writes fail with `KvsError::ReadOnly`. A partly written record at
+    /// the end of the log is skipped instead of truncated.
+    pub fn read_only(mut self) -> KvStoreBuilder {
+        self.read_only = true;
+        self
+    }
+
+    /// Starts the names of all files of the store with `prefix`, so
+    /// several stores can share a directory. Defaults to no prefix.
+    ///
+    /// Segment file names continue with a number, so the prefix must not
+    /// end with a digit, e.g. `"users-"`.
+    pub fn file_prefix(mut self, prefix: impl Into<String>) -> KvStoreBuilder {
+        self.file_prefix = prefix.into();
+        self
+    }
+
+    /// Sets how a partly written record at the end of the log is handled
+    /// on open. Defaults to `RecoveryMode::TruncateTail`.
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> KvStoreBuilder {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// Sets the rules that keys passed to the store have to follow.
+    /// Defaults to accepting every key.
+    ///
+    /// Keys that are already in the store are not checked.
+    pub fn key_policy(mut self, policy: KeyPolicy) -> KvStoreBuilder {
+        self.key_policy = policy;
+        self
+    }
+
+    /// Creates a new, empty store at the given path with these options,
+    /// and fixes its compression in a manifest.
+    ///
+    /// Later opens compress values like the manifest says, unless the
+    /// builder sets [`compress_values`](KvStoreBuilder::compress_values)
+    /// itself. The directory is created if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Fails with an `io::ErrorKind::AlreadyExists` error if the directory
+    /// already holds a store, and with `KvsError::ReadOnly` for a
+    /// read-only builder.
+    pub fn init(&self, path: impl Into<PathBuf>) -> Result<()> {
+        KvStore::init_with(path.into(), self)
+    }
+
+    /// Opens a `KvStore` with the given path and these options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    ///
+    /// Returns `KvsError::ReadOnly` for a read-only store that still has to
+    /// be migrated from the single log file of older versions.
+    ///
+    /// Returns `KvsError::Locked` if the store is already open for writing,
+    /// unless it is opened read-only.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path.into(), self)
+    }
+}
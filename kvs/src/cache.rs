@@ -0,0 +1,130 @@
+//! A cache of recently read values, so that gets of hot keys skip reading
+//! and decoding their record.
+//!
+//! Values are cached by the position of their record rather than by key.
+//! A record never changes once written, so writes need not invalidate
+//! anything: the index points a key that is set again at the new record,
+//! and the value cached for the old one is evicted once it is the least
+//! recently used. Records moved by a compaction are cached anew under
+//! their new position.
+
+use crate::index::CommandPos;
+use std::collections::{BTreeMap, HashMap};
+
+/// How a value cache of a `KvStore` performed, as returned by
+/// [`KvStore::cache_stats`](crate::KvStore::cache_stats).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    /// Gets that found their value in the cache.
+    pub hits: u64,
+    /// Gets that had to read their value from the log.
+    pub misses: u64,
+    /// Number of cached values.
+    pub entries: u64,
+    /// Bytes of the cached keys and values.
+    pub bytes: u64,
+}
+
+struct Slot {
+    key: String,
+    value: Vec<u8>,
+    expires: Option<u64>,
+    // when the value was last used
+    tick: u64,
+}
+
+/// Values by the position of their record, evicting the least recently
+/// used ones beyond its capacity.
+pub(crate) struct ValueCache {
+    capacity: u64,
+    slots: HashMap<CommandPos, Slot>,
+    // positions by when they were last used
+    order: BTreeMap<u64, CommandPos>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl ValueCache {
+    /// Creates a cache that holds up to `capacity` bytes of keys and
+    /// values.
+    pub fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            capacity,
+            slots: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns the key, value and expiry of the record at `pos` if they
+    /// are cached, and counts a hit or a miss.
+    pub fn get(&mut self, pos: CommandPos) -> Option<(String, Vec<u8>, Option<u64>)> {
+        self.tick += 1;
+        let slot = match self.slots.get_mut(&pos) {
+            Some(slot) => slot,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        self.stats.hits += 1;
+        self.order.remove(&slot.tick);
+        slot.tick = self.tick;
+        self.order.insert(self.tick, pos);
+        Some((slot.key.clone(), slot.value.clone(), slot.expires))
+    }
+
+    /// Caches the key, value and expiry read from the record at `pos`,
+    /// evicting the least recently used values to make room.
+    pub fn insert(&mut self, pos: CommandPos, key: &str, value: &[u8], expires: Option<u64>) {
+        let size = (key.len() + value.len()) as u64;
+        if size > self.capacity || self.slots.contains_key(&pos) {
+            return;
+        }
+        while self.stats.bytes + size > self.capacity {
+            match self.order.keys().next().copied() {
+                Some(tick) => self.evict(tick),
+                None => break,
+            }
+        }
+        self.tick += 1;
+        let slot = Slot {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            expires,
+            tick: self.tick,
+        };
+        self.slots.insert(pos, slot);
+        self.order.insert(self.tick, pos);
+        self.stats.entries += 1;
+        self.stats.bytes += size;
+    }
+
+    /// Drops the values of records in segments older than `gen`, which
+    /// have been removed.
+    pub fn forget_before(&mut self, gen: u64) {
+        let ticks: Vec<u64> = self
+            .slots
+            .iter()
+            .filter(|(pos, _)| pos.gen < gen)
+            .map(|(_, slot)| slot.tick)
+            .collect();
+        for tick in ticks {
+            self.evict(tick);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.clone()
+    }
+
+    fn evict(&mut self, tick: u64) {
+        if let Some(pos) = self.order.remove(&tick) {
+            if let Some(slot) = self.slots.remove(&pos) {
+                self.stats.entries -= 1;
+                self.stats.bytes -= (slot.key.len() + slot.value.len()) as u64;
+            }
+        }
+    }
+}
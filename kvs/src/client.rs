@@ -0,0 +1,669 @@
+use crate::{
+    protocol::{self, Request, Response},
+    transform::Transforms,
+    Codec, Cursor, Page, Result, ValueTransform,
+};
+use building_blocks::Deserializer;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    io::{self, BufReader, BufWriter, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+/// How many requests of a pipeline are sent before their responses are
+/// read, so that unread responses cannot fill up the connection and leave
+/// the server unable to read more requests.
+const PIPELINE_WINDOW: usize = 1024;
+
+/// A client of a [`KvsServer`](crate::KvsServer), keeping a single
+/// connection open for all its requests.
+///
+/// ```no_run
+/// # use kvs::{KvsClient, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Besides strings, any serde type can be stored with
+/// [`set_typed`](KvsClient::set_typed), which encodes it with the
+/// client's [`Codec`] before sending it:
+///
+/// ```no_run
+/// # use kvs::{Codec, KvsClient, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?.with_codec(Codec::Ron);
+/// client.set_typed("point".to_owned(), &(1, 2))?;
+/// assert_eq!(client.get_typed::<(i32, i32)>("point".to_owned())?, Some((1, 2)));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Every request waits for its response before the next is sent, unless
+/// they are sent together in a [`pipeline`](KvsClient::pipeline). Values
+/// can also be compressed or encrypted before they leave the client, see
+/// [`with_transform`](KvsClient::with_transform).
+pub struct KvsClient {
+    reader: Deserializer<BufReader<TcpStream>>,
+    writer: BufWriter<TcpStream>,
+    codec: Codec,
+    transforms: Transforms,
+    // whether an exchange with the server failed, which leaves the
+    // connection out of step with it
+    broken: bool,
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: Deserializer::new(BufReader::new(stream.try_clone()?)),
+            writer: BufWriter::new(stream),
+            codec: Codec::default(),
+            transforms: Transforms::default(),
+            broken: false,
+        })
+    }
+
+    /// Connects to the server at `addr` and authenticates with
+    /// `password`, see [`KvsClient::auth`].
+    pub fn connect_with_auth(addr: impl ToSocketAddrs, password: &str) -> Result<KvsClient> {
+        let mut client = KvsClient::connect(addr)?;
+        client.auth(password.to_owned())?;
+        Ok(client)
+    }
+
+    /// Sets the codec used by `get_typed` and `set_typed`. Defaults to
+    /// `Codec::Json`.
+    pub fn with_codec(mut self, codec: Codec) -> KvsClient {
+        self.codec = codec;
+        self
+    }
+
+    /// Adds a transformation that values go through before they are sent
+    /// to the server, and in reverse after they are received from it.
+    /// Several transformations are applied in the order they were added,
+    /// e.g. compression before encryption. Transformed values are stored
+    /// base64 encoded.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Lz4Compression, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?.with_transform(Lz4Compression);
+    /// client.set("key".to_owned(), "value ".repeat(100))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Every client of the keys needs the same transformations, and the
+    /// server only sees the transformed values: `compare_and_swap` only
+    /// works if the transformations are deterministic, and `increment`
+    /// does not work on transformed values at all.
+    pub fn with_transform(mut self, transform: impl ValueTransform + 'static) -> KvsClient {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    /// Gets the value of `key` from the server. Returns `None` if the key
+    /// does not exist, or `KvsError::NonExistentKey` if the server answers
+    /// `MissingKey::Error`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.request(&Request::Get { key })?;
+        self.reverse(value)
+    }
+
+    /// Sets the value of `key` on the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let value = self.transforms.apply(value)?;
+        self.request(&Request::Set { key, value }).map(drop)
+    }
+
+    /// Gets the values of several keys from the server with a single
+    /// request. Every key gets a result of its own, in the order of
+    /// `keys`.
+    ///
+    /// # Errors
+    ///
+    /// Fails as a whole only if the request could not be carried out,
+    /// e.g. because the connection broke.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        let results = self.request_batch(&Request::MGet { keys })?;
+        Ok(results
+            .into_iter()
+            .map(|result| result.and_then(|value| self.reverse(value)))
+            .collect())
+    }
+
+    /// Sets several key/value pairs on the server with a single request.
+    /// Every pair gets a result of its own, in the order of `entries`.
+    ///
+    /// # Errors
+    ///
+    /// Fails as a whole only if the request could not be carried out,
+    /// e.g. because the connection broke.
+    pub fn set_many(&mut self, entries: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| Ok((key, self.transforms.apply(value)?)))
+            .collect::<Result<_>>()?;
+        let results = self.request_batch(&Request::MSet { entries })?;
+        Ok(results.into_iter().map(|result| result.map(drop)).collect())
+    }
+
+    /// Gets the value of `key` from the server and decodes it as a `T`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Codec` if the value cannot be decoded.
+    pub fn get_typed<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>> {
+        self.get(key)?
+            .map(|value| self.codec.decode(&value))
+            .transpose()
+    }
+
+    /// Encodes `value` and sets it as the value of `key` on the server.
+    pub fn set_typed<T: Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+        let value = self.codec.encode(value)?;
+        self.set(key, value)
+    }
+
+    /// Removes `key` on the server.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::NonExistentKey` if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(&Request::Rm { key }).map(drop)
+    }
+
+    /// Gets the value of `key` from the server, or sets it to `value` if
+    /// there is none. Returns the value the key ends up with.
+    ///
+    /// No other client can write to the key in between.
+    pub fn get_or_insert(&mut self, key: String, value: String) -> Result<String> {
+        let value = self.transforms.apply(value)?;
+        let value = self
+            .request(&Request::GetOrSet { key, value })?
+            .ok_or_else(protocol::unexpected_response)?;
+        self.transforms.reverse(value)
+    }
+
+    /// Sets `key` to `new` on the server if its value is `expected`,
+    /// where `None` stands for a missing key. Returns whether it was set.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        let expected = expected
+            .map(|value| self.transforms.apply(value))
+            .transpose()?;
+        let new = self.transforms.apply(new)?;
+        match self
+            .request(&Request::Cas { key, expected, new })?
+            .as_deref()
+        {
+            Some("1") => Ok(true),
+            Some("0") => Ok(false),
+            _ => Err(protocol::unexpected_response()),
+        }
+    }
+
+    /// Adds `delta` to the integer value of `key` on the server, which
+    /// counts as 0 if the key does not exist, and returns the result.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.request(&Request::Incr { key, delta })?
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(protocol::unexpected_response)
+    }
+
+    /// Starts a pipeline of requests that are sent together when it is
+    /// executed, instead of waiting for the response to each before
+    /// sending the next, which saves a round trip per request.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let results = client
+    ///     .pipeline()
+    ///     .set("key".to_owned(), "value".to_owned())
+    ///     .get("key".to_owned())
+    ///     .remove("key".to_owned())
+    ///     .execute()?;
+    /// assert_eq!(results[1].as_ref().ok(), Some(&Some("value".to_owned())));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Scans the keys on the server from `start` up to but excluding
+    /// `end`, where `None` leaves that side of the range open.
+    ///
+    /// The server sends the entries as it reads them and the returned
+    /// iterator receives them one at a time, so neither side holds the
+    /// whole range in memory. Dropping the iterator early still receives
+    /// and discards the remaining entries, which keeps the connection
+    /// usable.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// for entry in client.scan(Some("a".to_owned()), Some("n".to_owned()))? {
+    ///     let (key, value) = entry?;
+    ///     println!("{} = {}", key, value);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan(&mut self, start: Option<String>, end: Option<String>) -> Result<ScanStream<'_>> {
+        let sent = self.write(&Request::Scan { start, end });
+        self.broken |= sent.is_err();
+        sent?;
+        Ok(ScanStream {
+            client: self,
+            done: false,
+        })
+    }
+
+    /// Gets a page of up to `count` key/value pairs, at least 1, with keys
+    /// after `cursor`, or from the first key without one, see
+    /// [`KvsEngine::scan_page`](crate::KvsEngine::scan_page).
+    ///
+    /// Unlike [`KvsClient::scan`], every page is a request of its own, so
+    /// a scan can be resumed on another connection, also after the server
+    /// restarted.
+    pub fn scan_page(&mut self, cursor: Option<&Cursor>, count: usize) -> Result<Page> {
+        let request = Request::ScanPage {
+            cursor: cursor.map(Cursor::to_string),
+            count: count as u64,
+        };
+        match self.send(&request)? {
+            Response::Page { entries, cursor } => Ok(Page {
+                entries: entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, self.transforms.reverse(value)?)))
+                    .collect::<Result<_>>()?,
+                next: cursor.map(|token| token.parse()).transpose()?,
+            }),
+            response => Err(response
+                .into_result()
+                .err()
+                .unwrap_or_else(protocol::unexpected_response)),
+        }
+    }
+
+    /// Authenticates the connection with `password`, for a server that
+    /// requires one before it carries out anything but a ping.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Auth` if the password is wrong, and
+    /// `KvsError::Server` if the server does not require one.
+    pub fn auth(&mut self, password: String) -> Result<()> {
+        self.request(&Request::Auth { password }).map(drop)
+    }
+
+    /// Pings the server and returns how long it took to answer.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        match self.send(&Request::Ping)? {
+            Response::Pong => Ok(start.elapsed()),
+            response => Err(response
+                .into_result()
+                .err()
+                .unwrap_or_else(protocol::unexpected_response)),
+        }
+    }
+
+    /// Gets the metrics of the server in the Prometheus text format,
+    /// including the stats of its engine if it keeps any.
+    pub fn metrics(&mut self) -> Result<String> {
+        self.request(&Request::Metrics)?
+            .ok_or_else(protocol::unexpected_response)
+    }
+
+    /// Names the connection, so that it can be told apart in
+    /// [`list_clients`](KvsClient::list_clients). Names cannot contain
+    /// spaces.
+    pub fn set_name(&mut self, name: String) -> Result<()> {
+        self.request(&Request::ClientSetName { name }).map(drop)
+    }
+
+    /// Lists the connections of the server, one per line with their id,
+    /// address, name, age, idle time, last command and number of pending
+    /// requests, e.g.
+    ///
+    /// ```text
+    /// id=3 addr=127.0.0.1:51234 name=worker-1 age=120 idle=2 cmd=get pending=0
+    /// ```
+    pub fn list_clients(&mut self) -> Result<String> {
+        self.request(&Request::ClientList)?
+            .ok_or_else(protocol::unexpected_response)
+    }
+
+    /// Closes the connection with the given id or address on the server,
+    /// as listed by [`list_clients`](KvsClient::list_clients).
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Server` if there is no such connection.
+    pub fn kill_client(&mut self, target: String) -> Result<()> {
+        self.request(&Request::ClientKill { target }).map(drop)
+    }
+
+    /// Returns about how long ago `key` was last read or written on the
+    /// server, to the second, or `None` if it does not exist or the
+    /// server does not keep track, see
+    /// [`KvStoreBuilder::track_idle_time`](crate::KvStoreBuilder::track_idle_time).
+    pub fn idle_time(&mut self, key: String) -> Result<Option<Duration>> {
+        match self.request(&Request::ObjectIdleTime { key })? {
+            Some(secs) => secs
+                .parse()
+                .map(|secs| Some(Duration::from_secs(secs)))
+                .map_err(|_| protocol::unexpected_response()),
+            None => Ok(None),
+        }
+    }
+
+    /// Subscribes the connection to the notifications on `channels`,
+    /// e.g. [`EXPIRED_CHANNEL`](crate::EXPIRED_CHANNEL), and returns them
+    /// as they arrive. The connection carries nothing else from then on.
+    pub fn subscribe(mut self, channels: Vec<String>) -> Result<Subscription> {
+        let expected = channels.clone();
+        building_blocks::to_writer(&mut self.writer, &Request::Subscribe { channels })?;
+        self.writer.flush()?;
+        for channel in expected {
+            match Response::read(&mut self.reader)? {
+                Response::Subscribed { channel: confirmed } if confirmed == channel => (),
+                response => {
+                    return Err(response
+                        .into_result()
+                        .err()
+                        .unwrap_or_else(protocol::unexpected_response))
+                }
+            }
+        }
+        Ok(Subscription { client: self })
+    }
+
+    /// Pings the server `n` times, one after another, and returns
+    /// statistics of the round-trip times.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let latency = client.measure_latency(100)?;
+    /// println!("median {:?}, p99 {:?}", latency.median, latency.p99);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails with an I/O error of kind `InvalidInput` if `n` is 0, and
+    /// with the first error of a ping otherwise.
+    pub fn measure_latency(&mut self, n: usize) -> Result<Latency> {
+        if n == 0 {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "no pings to measure");
+            return Err(e.into());
+        }
+        let mut samples = (0..n).map(|_| self.ping()).collect::<Result<Vec<_>>>()?;
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(n * p).div_ceil(100) - 1];
+        Ok(Latency {
+            samples: n,
+            min: samples[0],
+            mean: samples.iter().sum::<Duration>() / n as u32,
+            median: percentile(50),
+            p99: percentile(99),
+            max: samples[n - 1],
+        })
+    }
+
+    fn reverse(&self, value: Option<String>) -> Result<Option<String>> {
+        value
+            .map(|value| self.transforms.reverse(value))
+            .transpose()
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Option<String>> {
+        self.send(request)?.into_result()
+    }
+
+    fn request_batch(&mut self, request: &Request) -> Result<Vec<Result<Option<String>>>> {
+        match self.send(request)? {
+            Response::Batch(responses) => {
+                Ok(responses.into_iter().map(Response::into_result).collect())
+            }
+            // the whole request failed
+            response => Err(response
+                .into_result()
+                .err()
+                .unwrap_or_else(protocol::unexpected_response)),
+        }
+    }
+
+    /// Returns whether the connection broke, e.g. because the server
+    /// closed it.
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        let response = self
+            .write(request)
+            .and_then(|()| Ok(Response::read(&mut self.reader)?));
+        self.broken |= response.is_err();
+        response
+    }
+
+    fn write(&mut self, request: &Request) -> Result<()> {
+        building_blocks::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Sends a window of a pipeline and adds the results of its requests
+    /// to `results`.
+    fn send_window(
+        &mut self,
+        window: &[Request],
+        results: &mut Vec<Result<Option<String>>>,
+    ) -> Result<()> {
+        for request in window {
+            building_blocks::to_writer(&mut self.writer, request)?;
+        }
+        self.writer.flush()?;
+        for request in window {
+            let get = matches!(request, Request::Get { .. });
+            let result = Response::read(&mut self.reader)?.into_result();
+            results.push(match result {
+                Ok(value) if get => self.reverse(value),
+                Ok(_) => Ok(None),
+                Err(e) => Err(e),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Requests queued to be sent at once, as started by
+/// [`KvsClient::pipeline`].
+///
+/// The server still carries out the requests one after another, in
+/// order, but other clients' requests may come in between them.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Queues getting the value of `key`.
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Queues setting the value of `key`.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    /// Queues removing `key`.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Rm { key });
+        self
+    }
+
+    /// Returns the number of queued requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns whether no requests are queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends the queued requests and returns their results, in the order
+    /// they were queued: the value for a get, and `None` for a set or a
+    /// remove. The pipeline is empty afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Fails as a whole only if the requests could not be carried out,
+    /// e.g. because the connection broke, in which case it is unknown
+    /// which of them were.
+    pub fn execute(&mut self) -> Result<Vec<Result<Option<String>>>> {
+        let client = &mut *self.client;
+        // transform every value before anything is sent
+        let requests = self
+            .requests
+            .drain(..)
+            .map(|request| match request {
+                Request::Set { key, value } => Ok(Request::Set {
+                    key,
+                    value: client.transforms.apply(value)?,
+                }),
+                request => Ok(request),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut results = Vec::with_capacity(requests.len());
+        for window in requests.chunks(PIPELINE_WINDOW) {
+            let sent = client.send_window(window, &mut results);
+            client.broken |= sent.is_err();
+            sent?;
+        }
+        Ok(results)
+    }
+}
+
+/// Round-trip times of pings to a server, as measured by
+/// [`KvsClient::measure_latency`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Latency {
+    /// The number of pings.
+    pub samples: usize,
+    /// The fastest round trip.
+    pub min: Duration,
+    /// The average round trip.
+    pub mean: Duration,
+    /// The round trip that half of all were at most as slow as.
+    pub median: Duration,
+    /// The round trip that 99% of all were at most as slow as.
+    pub p99: Duration,
+    /// The slowest round trip.
+    pub max: Duration,
+}
+
+/// An iterator over the entries of a scan on the server, as returned by
+/// [`KvsClient::scan`].
+///
+/// It ends after the last entry, or after the first error, be it one of
+/// the server or of the connection. A value that the transformations of
+/// the client cannot restore is an error of its own.
+pub struct ScanStream<'a> {
+    client: &'a mut KvsClient,
+    done: bool,
+}
+
+impl Iterator for ScanStream<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let response = Response::read(&mut self.client.reader);
+        match response {
+            Ok(Response::Entry { key, value }) => Some(
+                self.client
+                    .transforms
+                    .reverse(value)
+                    .map(|value| (key, value)),
+            ),
+            Ok(Response::End) => {
+                self.done = true;
+                None
+            }
+            Ok(response) => {
+                self.done = true;
+                Some(Err(response
+                    .into_result()
+                    .err()
+                    .unwrap_or_else(protocol::unexpected_response)))
+            }
+            Err(e) => {
+                self.done = true;
+                self.client.broken = true;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+impl Drop for ScanStream<'_> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+/// The notifications on the channels a connection is subscribed to, see
+/// [`KvsClient::subscribe`], as pairs of the channel and the message.
+///
+/// The iterator blocks until the next notification arrives, and ends when
+/// the server closes the connection.
+pub struct Subscription {
+    client: KvsClient,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match Response::read(&mut self.client.reader) {
+            Ok(Response::Message { channel, message }) => Some(Ok((channel, message))),
+            Ok(response) => Some(Err(response
+                .into_result()
+                .err()
+                .unwrap_or_else(protocol::unexpected_response))),
+            Err(building_blocks::Error::Eof) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{KvsClient, Result};
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// The most connections a pool opens by default.
+const DEFAULT_MAX_CONNECTIONS: usize = 8;
+/// How long a connection may be idle by default before it is pinged
+/// again on checkout.
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A pool of connections to a [`KvsServer`](crate::KvsServer), which
+/// threads check a [`KvsClient`] out of and return when they are done, so
+/// that they can send requests at the same time without connecting anew
+/// for each.
+///
+/// ```no_run
+/// # use kvs::{KvsClientPool, Result};
+/// # use std::thread;
+/// # fn try_main() -> Result<()> {
+/// let pool = KvsClientPool::new("127.0.0.1:4000")?.with_max_connections(4);
+/// thread::scope(|scope| {
+///     for i in 0..16 {
+///         let pool = &pool;
+///         scope.spawn(move || pool.get()?.set(format!("key{}", i), "value".to_owned()));
+///     }
+/// });
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Connections are opened as needed, up to the maximum, after which
+/// checkouts wait for one to be returned. A connection that broke while
+/// it was checked out is closed instead of returned, and one that was
+/// idle for a while is pinged before it is checked out again, so that
+/// connections the server closed are replaced with new ones.
+pub struct KvsClientPool {
+    addr: SocketAddr,
+    max_connections: usize,
+    health_check_interval: Duration,
+    password: Option<String>,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+struct PoolState {
+    // the connections not checked out, with when they were returned
+    idle: Vec<(KvsClient, Instant)>,
+    // the number of connections, whether idle or checked out
+    open: usize,
+}
+
+impl KvsClientPool {
+    /// Creates a pool of connections to the server at `addr`. No
+    /// connection is opened until one is checked out.
+    pub fn new(addr: impl ToSocketAddrs) -> Result<KvsClientPool> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        })?;
+        Ok(KvsClientPool {
+            addr,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            password: None,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// Sets the most connections the pool opens at once, at least 1.
+    /// Defaults to 8.
+    pub fn with_max_connections(mut self, max_connections: usize) -> KvsClientPool {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Sets how long a connection may be idle before it is pinged on
+    /// checkout to make sure it still works. Defaults to 30 seconds.
+    pub fn with_health_check_interval(mut self, interval: Duration) -> KvsClientPool {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Authenticates every connection the pool opens with `password`, for
+    /// a server that requires one.
+    pub fn with_password(mut self, password: impl Into<String>) -> KvsClientPool {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Checks a connection out of the pool, waiting for one to be
+    /// returned if the most connections are open already.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a new connection cannot be opened or authenticated.
+    pub fn get(&self) -> Result<PooledClient<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((mut client, returned)) = state.idle.pop() {
+                if returned.elapsed() < self.health_check_interval {
+                    return Ok(self.checked_out(client));
+                }
+                drop(state);
+                if client.ping().is_ok() {
+                    return Ok(self.checked_out(client));
+                }
+                log::debug!("Replacing a broken connection to {}", self.addr);
+                state = self.closed();
+            } else if state.open < self.max_connections {
+                state.open += 1;
+                drop(state);
+                let connected = match &self.password {
+                    Some(password) => KvsClient::connect_with_auth(self.addr, password),
+                    None => KvsClient::connect(self.addr),
+                };
+                return match connected {
+                    Ok(client) => Ok(self.checked_out(client)),
+                    Err(e) => {
+                        drop(self.closed());
+                        Err(e)
+                    }
+                };
+            } else {
+                state = self.returned.wait(state).unwrap();
+            }
+        }
+    }
+
+    /// Returns the number of open connections, whether checked out or
+    /// not.
+    pub fn open_connections(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    /// Returns the number of open connections that are not checked out.
+    pub fn idle_connections(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    fn checked_out(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    /// Counts a connection as closed, which makes room for a new one.
+    fn closed(&self) -> MutexGuard<'_, PoolState> {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        self.returned.notify_one();
+        state
+    }
+}
+
+/// A connection checked out of a [`KvsClientPool`], which is returned to
+/// the pool when dropped.
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    // only taken on drop
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        let client = self.client.take().unwrap();
+        if client.is_broken() {
+            drop(self.pool.closed());
+        } else {
+            let mut state = self.pool.state.lock().unwrap();
+            state.idle.push((client, Instant::now()));
+            self.pool.returned.notify_one();
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! The connections of a server, which clients can name, list and close
+//! like with `CLIENT SETNAME`, `CLIENT LIST` and `CLIENT KILL` of Redis.
+//!
+//! A `CLIENTLIST` is answered with a line per connection, in the order
+//! they were accepted:
+//!
+//! ```text
+//! id=3 addr=127.0.0.1:51234 name=worker-1 age=120 idle=2 cmd=get pending=0
+//! ```
+//!
+//! `age` and `idle` are the seconds since the connection was accepted
+//! and since its last request, `cmd` is that request, and `pending` the
+//! number of requests received but not answered yet.
+//!
+//! A `CLIENTKILL` closes the connection with the given id or address,
+//! which also ends a scan or subscription on it, while a request that
+//! already reached the engine is still carried out.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// The open connections of a server.
+#[derive(Default)]
+pub(crate) struct Clients {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+impl Clients {
+    /// Adds a connection to `addr`, which is listed until the returned
+    /// registration is dropped. `stream` is a handle to its socket, to
+    /// close it with.
+    pub fn register(&self, addr: SocketAddr, stream: TcpStream) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+        let connection = Arc::new(Connection {
+            id,
+            addr,
+            stream,
+            connected: now,
+            state: Mutex::new(State {
+                name: String::new(),
+                last_command: "none",
+                last_active: now,
+                pending: 0,
+                authenticated: false,
+            }),
+        });
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&connection));
+        Registration {
+            clients: self,
+            connection,
+        }
+    }
+
+    /// Lists the connections as the answer to a `CLIENTLIST`.
+    pub fn list(&self) -> String {
+        let connections = self.connections.lock().unwrap();
+        let mut out = String::new();
+        for connection in connections.values() {
+            let state = connection.state.lock().unwrap();
+            writeln!(
+                out,
+                "id={} addr={} name={} age={} idle={} cmd={} pending={}",
+                connection.id,
+                connection.addr,
+                state.name,
+                connection.connected.elapsed().as_secs(),
+                state.last_active.elapsed().as_secs(),
+                state.last_command,
+                state.pending
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Closes the connections whose id or address is `target`, which are
+    /// no longer listed from then on. Returns whether there were any.
+    pub fn kill(&self, target: &str) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let before = connections.len();
+        connections.retain(|_, connection| {
+            if connection.id.to_string() != target && connection.addr.to_string() != target {
+                return true;
+            }
+            log::info!(
+                "Closing connection {} to {}",
+                connection.id,
+                connection.addr
+            );
+            // the connection may have closed already
+            let _ = connection.stream.shutdown(Shutdown::Both);
+            false
+        });
+        connections.len() < before
+    }
+}
+
+/// A connection in the [`Clients`] of a server, removed when dropped.
+pub(crate) struct Registration<'a> {
+    clients: &'a Clients,
+    connection: Arc<Connection>,
+}
+
+impl Registration<'_> {
+    /// Returns the connection.
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.clients.connections.lock().unwrap();
+        connections.remove(&self.connection.id);
+    }
+}
+
+/// A connection to a client.
+pub(crate) struct Connection {
+    id: u64,
+    addr: SocketAddr,
+    stream: TcpStream,
+    connected: Instant,
+    state: Mutex<State>,
+}
+
+struct State {
+    name: String,
+    last_command: &'static str,
+    last_active: Instant,
+    // requests received but not answered yet
+    pending: usize,
+    authenticated: bool,
+}
+
+impl Connection {
+    /// Returns the address of the client.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Names the connection.
+    pub fn set_name(&self, name: String) {
+        self.state.lock().unwrap().name = name;
+    }
+
+    /// Notes that the client authenticated, see [`crate::auth`].
+    pub fn authenticate(&self) {
+        self.state.lock().unwrap().authenticated = true;
+    }
+
+    /// Returns whether the client authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        self.state.lock().unwrap().authenticated
+    }
+
+    /// Notes that a request for `command` was received.
+    pub fn received(&self, command: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        state.last_command = command;
+        state.last_active = Instant::now();
+        state.pending += 1;
+    }
+
+    /// Notes that the oldest pending request was answered.
+    pub fn answered(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = state.pending.saturating_sub(1);
+    }
+}
@@ -0,0 +1,33 @@
+use crate::{KvsError, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// How [`KvsClient`](crate::KvsClient) encodes typed values into the
+/// string values of the store.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Codec {
+    /// JSON, via `serde_json`. This is the default.
+    #[default]
+    Json,
+    /// [RON](https://github.com/ron-rs/ron), via `ron`.
+    Ron,
+}
+
+impl Codec {
+    pub(crate) fn encode<T: Serialize>(self, value: &T) -> Result<String> {
+        match self {
+            Codec::Json => serde_json::to_string(value).map_err(codec_error),
+            Codec::Ron => ron::to_string(value).map_err(codec_error),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, value: &str) -> Result<T> {
+        match self {
+            Codec::Json => serde_json::from_str(value).map_err(codec_error),
+            Codec::Ron => ron::from_str(value).map_err(codec_error),
+        }
+    }
+}
+
+fn codec_error(e: impl std::fmt::Display) -> KvsError {
+    KvsError::Codec(e.to_string())
+}
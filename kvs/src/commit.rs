@@ -0,0 +1,118 @@
+//! Group commit: writes that have to be synced to disk share syncs.
+//!
+//! Every such write is handed to the [`CommitQueue`] while the writer
+//! lock is still held, so writes are numbered in log order. After giving
+//! up the lock, the first write to wait becomes the leader: it waits for
+//! the configured latency so that more writes can join, then syncs the
+//! files of all writes queued so far at once. The other writes wait for a
+//! sync that covers them, which takes at most one sync after the current
+//! one.
+
+use crate::{
+    contention::{Histogram, Recorder},
+    Result,
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io, mem,
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Default)]
+struct State {
+    // the number of the last queued write
+    queued: u64,
+    // all writes up to this number are synced
+    synced: u64,
+    // the files to sync for the queued writes, by segment generation
+    pending: BTreeMap<u64, File>,
+    // whether a leader is syncing
+    syncing: bool,
+    // why a sync failed, after which no write counts as synced anymore,
+    // as its data may have been lost
+    failed: Option<(io::ErrorKind, String)>,
+}
+
+/// Writes waiting to be synced, see the [module documentation](self).
+pub(crate) struct CommitQueue {
+    state: Mutex<State>,
+    synced: Condvar,
+    max_latency: Duration,
+    waits: Recorder,
+}
+
+impl CommitQueue {
+    pub fn new(max_latency: Duration) -> CommitQueue {
+        CommitQueue {
+            state: Mutex::new(State::default()),
+            synced: Condvar::new(),
+            max_latency,
+            waits: Recorder::default(),
+        }
+    }
+
+    /// Queues a write that was flushed to `file`, the active segment of
+    /// generation `gen`. Has to be called under the writer lock. Returns
+    /// the number of the write to wait for.
+    pub fn enqueue(&self, gen: u64, file: File) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.queued += 1;
+        state.pending.insert(gen, file);
+        state.queued
+    }
+
+    /// Waits until the write numbered `ticket`, if any, is synced, syncing
+    /// it and the writes queued with it if no other write is doing so.
+    pub fn wait(&self, ticket: Option<u64>) -> Result<()> {
+        let ticket = match ticket {
+            Some(ticket) => ticket,
+            None => return Ok(()),
+        };
+        let start = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let result = loop {
+            if let Some((kind, message)) = &state.failed {
+                break Err(io::Error::new(*kind, message.clone()).into());
+            }
+            if state.synced >= ticket {
+                break Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            drop(state);
+            if !self.max_latency.is_zero() {
+                thread::sleep(self.max_latency);
+            }
+            let (files, last) = {
+                let mut state = self.state.lock().unwrap();
+                (mem::take(&mut state.pending), state.queued)
+            };
+            let result = files.values().try_for_each(File::sync_data);
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            match result {
+                Ok(()) => state.synced = last,
+                Err(e) => {
+                    log::error!("Sync failed: {}", e);
+                    state.failed = Some((e.kind(), format!("sync failed: {}", e)));
+                }
+            }
+            self.synced.notify_all();
+        };
+        drop(state);
+        self.waits.record(start.elapsed());
+        result
+    }
+
+    /// Returns how long writes waited to be synced so far.
+    pub fn waits(&self) -> Histogram {
+        self.waits.snapshot()
+    }
+}
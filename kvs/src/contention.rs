@@ -0,0 +1,107 @@
+//! Histograms of how long threads wait for locks and queues, to tell which
+//! of them holds up throughput.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// The number of buckets of a histogram. Bucket `i` counts waits of up to
+/// 2^i microseconds, except for the last one, which counts all longer
+/// waits.
+const BUCKETS: usize = 22;
+
+/// How long waits took, in buckets bounded by powers of two
+/// microseconds.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    sum: Duration,
+}
+
+impl Histogram {
+    /// Returns the number of waits.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the total time spent waiting.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Returns the upper bound and number of waits of every bucket, by
+    /// ascending bound. The bound of the last bucket is `Duration::MAX`.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(i, &count)| {
+            let bound = if i + 1 < BUCKETS {
+                Duration::from_micros(1 << i)
+            } else {
+                Duration::MAX
+            };
+            (bound, count)
+        })
+    }
+}
+
+/// How long writes of a `KvStore` waited for its locks, as returned by
+/// [`KvStore::lock_waits`](crate::KvStore::lock_waits).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LockWaits {
+    /// Waits for the lock on the active segment, one per write or batch.
+    pub writer: Histogram,
+    /// Waits for the locks on the keys written, one per write or batch.
+    pub stripes: Histogram,
+    /// Waits for writes to be synced to disk, one per write or batch that
+    /// the `SyncPolicy` has synced.
+    pub commits: Histogram,
+}
+
+/// Records waits into a histogram from many threads at once.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    counts: [AtomicU64; BUCKETS],
+    nanos: AtomicU64,
+}
+
+impl Recorder {
+    pub fn record(&self, wait: Duration) {
+        let micros = wait.as_micros();
+        let bucket = if micros <= 1 {
+            0
+        } else {
+            // the exponent of the next power of two
+            (128 - (micros - 1).leading_zeros() as usize).min(BUCKETS - 1)
+        };
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Locks `mutex`, recording how long that took. An uncontended lock is
+    /// recorded without reading the clock.
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        if let Ok(guard) = mutex.try_lock() {
+            self.record(Duration::ZERO);
+            return guard;
+        }
+        let start = Instant::now();
+        let guard = mutex.lock().unwrap();
+        self.record(start.elapsed());
+        guard
+    }
+
+    pub fn snapshot(&self) -> Histogram {
+        let mut counts = [0; BUCKETS];
+        for (count, recorded) in counts.iter_mut().zip(&self.counts) {
+            *count = recorded.load(Ordering::Relaxed);
+        }
+        Histogram {
+            counts,
+            sum: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
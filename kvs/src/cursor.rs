@@ -0,0 +1,109 @@
+//! Paging through a scan with cursors, see [`KvsEngine::scan_page`].
+//!
+//! [`KvsEngine::scan_page`]: crate::KvsEngine::scan_page
+
+use crate::{KvsError, Result};
+use std::{fmt, ops::Bound, str::FromStr};
+
+/// The version of the token format, its first character.
+const VERSION: char = '1';
+
+/// Where the next page of a scan starts.
+///
+/// A cursor holds the last key of the page it follows rather than a
+/// position in the log, so it stays valid across compactions and
+/// restarts, and can be handed to another connection or process. The next
+/// page starts at the first live key after it at the time it is read:
+/// keys set after the cursor was handed out show up if they sort after
+/// it, and keys removed in the meantime do not.
+///
+/// Cursors are passed around as opaque tokens, see the `Display` and
+/// `FromStr` impls.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cursor {
+    last_key: String,
+}
+
+impl Cursor {
+    /// Creates the cursor for the page after `key`.
+    pub(crate) fn after(key: String) -> Cursor {
+        Cursor { last_key: key }
+    }
+
+    /// Returns the range of the keys from the cursor on.
+    pub(crate) fn range(cursor: Option<&Cursor>) -> (Bound<String>, Bound<String>) {
+        match cursor {
+            Some(cursor) => (Bound::Excluded(cursor.last_key.clone()), Bound::Unbounded),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    /// Writes the token of the cursor, the version of the format
+    /// followed by the bytes of the key in hex.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", VERSION)?;
+        for byte in self.last_key.bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = KvsError;
+
+    /// Parses the token of a cursor.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidCursor` if `token` was not written by
+    /// the `Display` impl.
+    fn from_str(token: &str) -> Result<Cursor> {
+        let invalid = || KvsError::InvalidCursor(token.to_owned());
+        let hex = token.strip_prefix(VERSION).ok_or_else(invalid)?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        let last_key = String::from_utf8(bytes).map_err(|_| invalid())?;
+        Ok(Cursor { last_key })
+    }
+}
+
+/// A page of a scan, see [`KvsEngine::scan_page`].
+///
+/// [`KvsEngine::scan_page`]: crate::KvsEngine::scan_page
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Page {
+    /// The key/value pairs of the page, in ascending key order.
+    pub entries: Vec<(String, String)>,
+    /// Where the next page starts, or `None` if this is the last one.
+    pub next: Option<Cursor>,
+}
+
+impl Page {
+    /// Takes up to `count` pairs, at least 1, off the front of `entries`
+    /// as a page. It is the last one if `entries` has no more after them.
+    pub(crate) fn take(
+        mut entries: impl Iterator<Item = Result<(String, String)>>,
+        count: usize,
+    ) -> Result<Page> {
+        let page = entries
+            .by_ref()
+            .take(count.max(1))
+            .collect::<Result<Vec<_>>>()?;
+        let next = match (page.last(), entries.next().transpose()?) {
+            (Some((key, _)), Some(_)) => Some(Cursor::after(key.clone())),
+            _ => None,
+        };
+        Ok(Page {
+            entries: page,
+            next,
+        })
+    }
+}
@@ -0,0 +1,160 @@
+//! A content-addressed store for values shared by multiple keys.
+//!
+//! Every distinct value is stored once in `blobs.log` under a numeric
+//! id, which the main log refers to instead of the value. A hash of the
+//! content is kept in memory to find existing copies of a value; since
+//! hashes may collide, candidates are compared byte for byte before
+//! being reused.
+
+use crate::{
+    io::{BufReaderWithPos, BufWriterWithPos},
+    KvsError, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::PathBuf,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Blob {
+    id: u64,
+    value: String,
+}
+
+pub(crate) struct BlobStore {
+    path: PathBuf,
+    reader: BufReaderWithPos<File>,
+    // absent if the store is read-only
+    writer: Option<BufWriterWithPos<File>>,
+    // id -> location of the blob in the file
+    blobs: HashMap<u64, Range<u64>>,
+    // content hash -> ids of the blobs with that hash
+    by_hash: HashMap<u64, Vec<u64>>,
+    next_id: u64,
+}
+
+impl BlobStore {
+    /// Opens the blob file at `path`, creating it if it does not exist
+    /// unless the blob store is `read_only`. Compactions write to the same
+    /// path with a `tmp` extension.
+    pub fn open(path: PathBuf, read_only: bool) -> Result<BlobStore> {
+        let writer = if read_only {
+            None
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let mut writer = BufWriterWithPos::new(file)?;
+            writer.seek(SeekFrom::End(0))?;
+            Some(writer)
+        };
+
+        let mut store = BlobStore {
+            reader: BufReaderWithPos::new(File::open(&path)?)?,
+            path,
+            writer,
+            blobs: HashMap::new(),
+            by_hash: HashMap::new(),
+            next_id: 0,
+        };
+        store.load()?;
+        Ok(store)
+    }
+
+    fn load(&mut self) -> Result<()> {
+        let end = self.reader.seek(SeekFrom::End(0))?;
+        let mut pos = self.reader.seek(SeekFrom::Start(0))?;
+        while pos < end {
+            let blob: Blob = rmp_serde::from_read(&mut self.reader)?;
+            let new_pos = self.reader.pos();
+            self.insert(blob.id, hash_value(&blob.value), pos..new_pos);
+            self.next_id = self.next_id.max(blob.id + 1);
+            pos = new_pos;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, id: u64, hash: u64, blob_pos: Range<u64>) {
+        self.blobs.insert(id, blob_pos);
+        self.by_hash.entry(hash).or_default().push(id);
+    }
+
+    /// Stores `value` unless an identical value is already stored, and
+    /// returns the id of the blob holding it.
+    pub fn put(&mut self, value: String) -> Result<u64> {
+        let hash = hash_value(&value);
+        let candidates = self.by_hash.get(&hash).cloned().unwrap_or_default();
+        for id in candidates {
+            if self.get(id)? == value {
+                return Ok(id);
+            }
+        }
+
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let pos = writer.pos();
+        rmp_serde::encode::write(writer, &Blob { id, value })?;
+        writer.flush()?;
+        let end = writer.pos();
+        self.insert(id, hash, pos..end);
+        Ok(id)
+    }
+
+    /// Reads the value of the blob with the given id.
+    pub fn get(&mut self, id: u64) -> Result<String> {
+        let blob_pos = self.blobs.get(&id).ok_or(KvsError::UnexpectedCommandType)?;
+        let len = blob_pos.end - blob_pos.start;
+        self.reader.seek(SeekFrom::Start(blob_pos.start))?;
+        let blob: Blob = rmp_serde::from_read((&mut self.reader).take(len))?;
+        Ok(blob.value)
+    }
+
+    /// Drops all blobs whose id is not in `live`, rewriting the blob
+    /// file if any were dropped.
+    pub fn retain(&mut self, live: &HashSet<u64>) -> Result<()> {
+        if self.blobs.keys().all(|id| live.contains(id)) {
+            return Ok(());
+        }
+        log::trace!(
+            "Dropping {} unreferenced blobs",
+            self.blobs.len() - live.len()
+        );
+
+        let tmp_path = self.path.with_extension("tmp");
+        let tmp = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&tmp_path)?;
+        let mut writer = BufWriterWithPos::new(tmp)?;
+        let mut blobs = HashMap::new();
+        for &id in live {
+            let value = self.get(id)?;
+            let pos = writer.pos();
+            rmp_serde::encode::write(&mut writer, &Blob { id, value })?;
+            blobs.insert(id, pos..writer.pos());
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+
+        fs::rename(&tmp_path, &self.path)?;
+        self.reader = BufReaderWithPos::new(File::open(&self.path)?)?;
+        self.writer = Some(writer);
+        self.blobs = blobs;
+        for ids in self.by_hash.values_mut() {
+            ids.retain(|id| live.contains(id));
+        }
+        self.by_hash.retain(|_, ids| !ids.is_empty());
+        Ok(())
+    }
+}
+
+fn hash_value(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,50 @@
+use crate::{KvStore, Result};
+
+/// A key of a `KvStore`, whose value may or may not exist, as returned by
+/// [`KvStore::entry`].
+///
+/// Its methods look up the value and write one if there is none as a
+/// single step, so that concurrent writers of the key cannot race with
+/// each other, e.g. to initialize it.
+pub struct Entry<'a> {
+    store: &'a KvStore,
+    key: String,
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn new(store: &'a KvStore, key: String) -> Entry<'a> {
+        Entry { store, key }
+    }
+
+    /// Returns the key of the entry, normalized by the store's
+    /// `KeyPolicy`.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the value of the entry, setting it to `default` first if
+    /// there is none.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::get`] and [`KvStore::set`].
+    pub fn or_insert(self, default: String) -> Result<String> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value of the entry, setting it to the result of
+    /// `default` first if there is none.
+    ///
+    /// Writes to keys of the store are held up while `default` runs, so
+    /// it should return quickly and must not write to the store itself.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::get`] and [`KvStore::set`].
+    pub fn or_insert_with<F>(self, default: F) -> Result<String>
+    where
+        F: FnOnce() -> String,
+    {
+        self.store.get_or_insert_with(self.key, default)
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, path::PathBuf, str::FromStr};
 use thiserror::Error;
 
 /// Convenience alias for `Result<T, KvsError>`.
@@ -19,8 +19,167 @@ pub enum KvsError {
     /// Error on remove with a non-existent key
     #[error("No such key: `{0}`")]
     NonExistentKey(String),
+    /// Error on writing to a store that was opened read-only.
+    #[error("Store is opened read-only")]
+    ReadOnly,
+    /// Error on passing a key that is rejected by the `KeyPolicy`.
+    #[error("Invalid key `{key}`: {reason}")]
+    InvalidKey {
+        /// The key, after normalization.
+        key: String,
+        /// Which rule the key breaks.
+        reason: String,
+    },
+    /// Error on incrementing a value that is not an integer, or would
+    /// overflow.
+    #[error("Cannot increment `{key}`: {reason}")]
+    InvalidCounter {
+        /// The key of the value.
+        key: String,
+        /// Why the value cannot be incremented.
+        reason: String,
+    },
+    /// Error on reading a value as a string that was set as bytes which
+    /// are not valid UTF-8.
+    #[error("Value of `{0}` is not valid UTF-8")]
+    NotUtf8(String),
+    /// Error on reading a record of a segment that is truncated or does
+    /// not match its checksum.
+    #[error("Corrupted record at offset {offset} of {}: {reason}", .path.display())]
+    Corruption {
+        /// Path of the segment.
+        path: PathBuf,
+        /// Offset of the record in the segment.
+        offset: u64,
+        /// What is wrong with the record.
+        reason: String,
+    },
+    /// Error on opening a directory that holds data this version of kvs
+    /// cannot read, e.g. that of another storage engine.
+    #[error("{} holds data of {engine}: {advice}", .path.display())]
+    ForeignData {
+        /// Path of the file that gave the data away.
+        path: PathBuf,
+        /// What wrote the data.
+        engine: String,
+        /// What to do about it.
+        advice: String,
+    },
+    /// Error on opening a store for writing whose directory is locked by
+    /// another process, or another open store in this one.
+    #[error("{} is locked: the store is already open for writing", .0.display())]
+    Locked(PathBuf),
     /// Error on finding an unexpected command when retrieving a
     /// value. This indicates a corrupted log or a program error.
     #[error("Unexpected command type")]
     UnexpectedCommandType,
+    /// Error on reading an export that is malformed or uses an unknown
+    /// format.
+    #[error("Invalid dump: {0}")]
+    InvalidDump(String),
+    /// Error on encoding or decoding a message sent between client and
+    /// server.
+    #[error("{0}")]
+    Protocol(#[from] building_blocks::Error),
+    /// Error reported by the server in response to a request.
+    #[error("Server error: {0}")]
+    Server(String),
+    /// Error on encoding or decoding a typed value with a `Codec`, or on
+    /// reversing a `ValueTransform`.
+    #[error("Codec error: {0}")]
+    Codec(String),
+    /// Error of a server on a request from a connection that has not
+    /// authenticated, or on an `AUTH` with the wrong password.
+    #[error("Authentication error: {0}")]
+    Auth(String),
+    /// Error on parsing the token of a scan cursor that is not one.
+    #[error("Invalid cursor: {0:?}")]
+    InvalidCursor(String),
+}
+
+impl KvsError {
+    /// Returns a stable identifier of the kind of error, e.g.
+    /// `"corruption"` for `KvsError::Corruption`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            KvsError::Io(_) => "io",
+            KvsError::Ser(_) => "serialization",
+            KvsError::Des(_) => "deserialization",
+            KvsError::NonExistentKey(_) => "non_existent_key",
+            KvsError::ReadOnly => "read_only",
+            KvsError::InvalidKey { .. } => "invalid_key",
+            KvsError::InvalidCounter { .. } => "invalid_counter",
+            KvsError::NotUtf8(_) => "not_utf8",
+            KvsError::Corruption { .. } => "corruption",
+            KvsError::ForeignData { .. } => "foreign_data",
+            KvsError::Locked(_) => "locked",
+            KvsError::UnexpectedCommandType => "unexpected_command_type",
+            KvsError::InvalidDump(_) => "invalid_dump",
+            KvsError::Protocol(_) => "protocol",
+            KvsError::Server(_) => "server",
+            KvsError::Codec(_) => "codec",
+            KvsError::Auth(_) => "auth",
+            KvsError::InvalidCursor(_) => "invalid_cursor",
+        }
+    }
+
+    /// Describes the error as a single-line JSON object with its `code`,
+    /// its `message` and any context it carries, e.g. the `key` of a
+    /// `KvsError::NonExistentKey` or the `path` and `offset` of a
+    /// `KvsError::Corruption`.
+    pub fn to_json(&self) -> String {
+        let mut json = serde_json::json!({
+            "code": self.code(),
+            "message": self.to_string(),
+        });
+        match self {
+            KvsError::Io(e) => json["kind"] = format!("{:?}", e.kind()).into(),
+            KvsError::NonExistentKey(key) | KvsError::NotUtf8(key) => {
+                json["key"] = key.as_str().into()
+            }
+            KvsError::InvalidKey { key, reason } | KvsError::InvalidCounter { key, reason } => {
+                json["key"] = key.as_str().into();
+                json["reason"] = reason.as_str().into();
+            }
+            KvsError::Corruption {
+                path,
+                offset,
+                reason,
+            } => {
+                json["path"] = path.display().to_string().into();
+                json["offset"] = (*offset).into();
+                json["reason"] = reason.as_str().into();
+            }
+            KvsError::ForeignData { path, engine, .. } => {
+                json["path"] = path.display().to_string().into();
+                json["engine"] = engine.as_str().into();
+            }
+            KvsError::Locked(path) => json["path"] = path.display().to_string().into(),
+            KvsError::InvalidCursor(cursor) => json["cursor"] = cursor.as_str().into(),
+            _ => (),
+        }
+        json.to_string()
+    }
+}
+
+/// How the command line tools and the server report errors.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ErrorFormat {
+    /// Human-readable messages.
+    #[default]
+    Text,
+    /// One JSON object per error, see [`KvsError::to_json`].
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format: {}", s)),
+        }
+    }
 }
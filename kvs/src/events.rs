@@ -0,0 +1,144 @@
+//! Hooks for applications embedding a `KvStore`.
+//!
+//! Implement [`EventListener`] and register it with
+//! [`KvStore::add_listener`](crate::KvStore::add_listener) to be notified
+//! of internal events, e.g. to feed them into your own metrics or
+//! alerting.
+//!
+//! Implement [`CompactionFilter`] and register it with
+//! [`KvStore::add_compaction_filter`](crate::KvStore::add_compaction_filter)
+//! to garbage collect or rewrite entries whenever they are compacted.
+
+use std::path::PathBuf;
+
+/// Receives notifications about events inside a `KvStore`.
+///
+/// All methods have empty default implementations, so implementors only
+/// need to override the events they are interested in. Listeners are
+/// called synchronously on the thread that triggered the event and should
+/// return quickly. Events of compactions are triggered on the compaction
+/// thread of the store.
+pub trait EventListener: Send + Sync {
+    /// Called before a compaction starts.
+    fn on_compaction_start(&self, _event: &CompactionStarted) {}
+
+    /// Called after a compaction finished successfully.
+    fn on_compaction_finish(&self, _event: &CompactionFinished) {}
+
+    /// Called after the active segment was sealed and writes moved on to
+    /// a new segment.
+    fn on_segment_sealed(&self, _event: &SegmentSealed) {}
+
+    /// Called after a sealed segment holding only stale entries was
+    /// deleted without a compaction.
+    fn on_segment_dropped(&self, _event: &SegmentDropped) {}
+
+    /// Called after the entry of a key whose value had expired was
+    /// dropped, either by a compaction or by a lookup of the key. Called
+    /// once for every expired value.
+    fn on_eviction(&self, _event: &Evicted) {}
+
+    /// Called when reading a value found a corrupted record. The read
+    /// fails with `KvsError::Corruption`.
+    fn on_corruption_detected(&self, _event: &CorruptionDetected) {}
+}
+
+/// Decides what happens to live entries when they are compacted.
+///
+/// A compaction only copies the entries of sealed segments, so an entry
+/// is filtered some time after it was written, and possibly more than
+/// once. Filters are called on the compaction thread of the store, so
+/// they do not hold up writes, but they do hold up the next compaction.
+///
+/// ```rust
+/// # use kvs::{CompactionFilter, FilterDecision};
+/// /// Drops all keys of a retired feature.
+/// struct DropRetired;
+///
+/// impl CompactionFilter for DropRetired {
+///     fn filter(&self, key: &str, _value: &str) -> FilterDecision {
+///         if key.starts_with("retired:") {
+///             FilterDecision::Remove
+///         } else {
+///             FilterDecision::Keep
+///         }
+///     }
+/// }
+/// ```
+pub trait CompactionFilter: Send + Sync {
+    /// Called with every live entry a compaction copies.
+    fn filter(&self, key: &str, value: &str) -> FilterDecision;
+}
+
+/// What a [`CompactionFilter`] wants done with an entry.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FilterDecision {
+    /// Keep the entry as it is.
+    Keep,
+    /// Remove the key from the store.
+    ///
+    /// If the process crashes before the compaction has deleted the old
+    /// segments, the key may reappear until it is filtered again.
+    Remove,
+    /// Replace the value of the entry.
+    Transform(String),
+}
+
+/// Details about a compaction that is about to start.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CompactionStarted {
+    /// Number of live keys that will be copied.
+    pub live_keys: usize,
+    /// Number of stale bytes in the log that triggered the compaction.
+    pub stale_bytes: u64,
+}
+
+/// Details about a finished compaction.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CompactionFinished {
+    /// Number of live keys that were copied.
+    pub live_keys: usize,
+    /// Size of the segment written by the compaction in bytes.
+    pub log_bytes: u64,
+}
+
+/// Details about a sealed segment.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SegmentSealed {
+    /// Generation of the sealed segment.
+    pub gen: u64,
+    /// Size of the sealed segment in bytes.
+    pub bytes: u64,
+}
+
+/// Details about a sealed segment that was deleted because none of its
+/// entries were live anymore.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SegmentDropped {
+    /// Generation of the dropped segment.
+    pub gen: u64,
+    /// Size of the dropped segment in bytes.
+    pub bytes: u64,
+}
+
+/// Details about an expired entry that was dropped.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Evicted {
+    /// The key of the entry.
+    pub key: String,
+}
+
+/// Details about a corrupted record.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CorruptionDetected {
+    /// Path of the segment holding the record.
+    pub path: PathBuf,
+    /// Offset of the record in the segment.
+    pub offset: u64,
+}
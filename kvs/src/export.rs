@@ -0,0 +1,1085 @@
+//! Exporting the live contents of a store and importing them again.
+//!
+//! Exports only depend on a `Write` and imports on a `Read`, so both can
+//! be streamed through pipes, e.g. `kvs export | ssh host kvs import`.
+//!
+//! # The `kvsdump` format
+//!
+//! A dump starts with the magic bytes `KVSDUMP` followed by a version
+//! byte. It is followed by any number of entry records and is terminated
+//! by an end record. All integers are little endian.
+//!
+//! ```text
+//! entry: 0x01 | key len (u32) | value len (u32) | key | value | crc32 (u32)
+//! end:   0x00 | number of entries (u64)
+//! ```
+//!
+//! The checksum of an entry covers both lengths, the key and the value.
+//! The end record makes it possible to tell a complete dump apart from
+//! one that was cut off.
+//!
+//! # Portable formats
+//!
+//! For use with other tools, stores can also be exported as
+//!
+//! - `json`: a single object mapping each key to its value.
+//! - `csv`: one `key,value` record per line without a header. Fields are
+//!   quoted as described in RFC 4180 where necessary.
+//! - `msgpack`: a sequence of `[key, value]` arrays terminated by `nil`.
+//! - `parquet`, with the `parquet` feature: a Parquet file with the
+//!   columns `key` and `value`, both UTF-8 strings, and `expires_at`, a
+//!   timestamp in milliseconds that is null for values that never expire.
+//!   It is Snappy compressed, and written in row groups of up to 65536
+//!   entries, so that only one of them is buffered at a time. Imports
+//!   read the whole file into memory first, as its metadata comes last,
+//!   and ignore `expires_at`.
+//! - `resp-commands`: a `SET key value` command for each entry, encoded
+//!   in RESP like a client sends it to Redis, so that an export can be
+//!   piped into `redis-cli --pipe` to move the store to Redis. Values that
+//!   expire are set with `PXAT` and the time they expire at, which needs
+//!   Redis 6.2 or later. Imports ignore it.
+//!
+//! # Redis dumps
+//!
+//! [`import_redis_rdb`] loads the string keys of an RDB dump of Redis,
+//! e.g. its `dump.rdb`, for a move from Redis to a store. There is no
+//! export to that format.
+//!
+//! # Diffs
+//!
+//! [`diff`] writes the changes between two versions of a store, e.g. two
+//! checkpoints taken with [`KvStore::checkpoint`], and [`apply_diff`]
+//! applies them to another store, so it can be kept in sync without full
+//! exports. In the `kvsdiff` format, a diff starts with the magic bytes
+//! `KVSDIFF` and a version byte, followed by change records laid out like
+//! the entries of a `kvsdump` and terminated by an end record like that of
+//! a `kvsdump`:
+//!
+//! ```text
+//! added:   0x01 | key len (u32) | value len (u32) | key | value | crc32 (u32)
+//! changed: 0x02 | key len (u32) | value len (u32) | key | value | crc32 (u32)
+//! removed: 0x03 | key len (u32) | 0 (u32)         | key | crc32 (u32)
+//! ```
+//!
+//! In the `json` format, every change is a JSON object on a line of its
+//! own, e.g. `{"op":"changed","key":"a","value":"2"}` or
+//! `{"op":"removed","key":"b"}`.
+
+use crate::{
+    kv,
+    rdb::{RdbReader, RdbValue},
+    KvStore, KvsEngine, KvsError, Result, SnapshotIter,
+};
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    io::{self, BufRead, Read, Write},
+    str::{self, FromStr},
+};
+
+const MAGIC: &[u8; 7] = b"KVSDUMP";
+const VERSION: u8 = 1;
+const TAG_END: u8 = 0;
+const TAG_ENTRY: u8 = 1;
+
+const DIFF_MAGIC: &[u8; 7] = b"KVSDIFF";
+const DIFF_VERSION: u8 = 1;
+const TAG_ADDED: u8 = 1;
+const TAG_CHANGED: u8 = 2;
+const TAG_REMOVED: u8 = 3;
+
+/// The format of an export.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// The native binary dump format, see the [module
+    /// documentation](self).
+    KvsDump,
+    /// A JSON object of all entries.
+    Json,
+    /// Comma separated `key,value` records.
+    Csv,
+    /// MessagePack `[key, value]` arrays terminated by `nil`.
+    MsgPack,
+    /// A Parquet file with `key`, `value` and `expires_at` columns.
+    #[cfg(feature = "parquet")]
+    Parquet,
+    /// Redis `SET` commands in RESP.
+    RespCommands,
+}
+
+impl FromStr for ExportFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvsdump" => Ok(ExportFormat::KvsDump),
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            "msgpack" => Ok(ExportFormat::MsgPack),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(ExportFormat::Parquet),
+            "resp-commands" => Ok(ExportFormat::RespCommands),
+            _ => Err(KvsError::InvalidDump(format!("unknown format `{}`", s))),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportFormat::KvsDump => f.write_str("kvsdump"),
+            ExportFormat::Json => f.write_str("json"),
+            ExportFormat::Csv => f.write_str("csv"),
+            ExportFormat::MsgPack => f.write_str("msgpack"),
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => f.write_str("parquet"),
+            ExportFormat::RespCommands => f.write_str("resp-commands"),
+        }
+    }
+}
+
+/// Writes all live key/value pairs of `store` to `writer`.
+///
+/// Returns the number of exported entries.
+///
+/// # Errors
+///
+/// Errors encountered while reading the store or writing the export are
+/// propagated.
+pub fn export<W: Write>(store: &KvStore, format: ExportFormat, mut writer: W) -> Result<u64> {
+    match format {
+        ExportFormat::KvsDump => {
+            let mut dump = DumpWriter::new(writer)?;
+            store.visit_entries(|key, value| dump.entry(&key, &value))?;
+            dump.finish()
+        }
+        ExportFormat::Json => {
+            writer.write_all(b"{")?;
+            let mut count = 0u64;
+            store.visit_entries(|key, value| {
+                if count > 0 {
+                    writer.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut writer, &key).map_err(json_error)?;
+                writer.write_all(b":")?;
+                serde_json::to_writer(&mut writer, &value).map_err(json_error)?;
+                count += 1;
+                Ok(())
+            })?;
+            writer.write_all(b"}\n")?;
+            writer.flush()?;
+            Ok(count)
+        }
+        ExportFormat::Csv => {
+            let mut count = 0u64;
+            store.visit_entries(|key, value| {
+                write_csv_field(&mut writer, &key)?;
+                writer.write_all(b",")?;
+                write_csv_field(&mut writer, &value)?;
+                writer.write_all(b"\r\n")?;
+                count += 1;
+                Ok(())
+            })?;
+            writer.flush()?;
+            Ok(count)
+        }
+        ExportFormat::MsgPack => {
+            let mut count = 0u64;
+            store.visit_entries(|key, value| {
+                rmp_serde::encode::write(&mut writer, &(key, value))?;
+                count += 1;
+                Ok(())
+            })?;
+            rmp_serde::encode::write(&mut writer, &())?;
+            writer.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => columnar::export(store, writer),
+        ExportFormat::RespCommands => {
+            let mut count = 0u64;
+            store.visit_expiring_entries(|key, value, expires| {
+                match expires {
+                    Some(expires) => {
+                        let expires = expires.to_string();
+                        write_resp_command(&mut writer, &["SET", &key, &value, "PXAT", &expires])?
+                    }
+                    None => write_resp_command(&mut writer, &["SET", &key, &value])?,
+                }
+                count += 1;
+                Ok(())
+            })?;
+            writer.flush()?;
+            Ok(count)
+        }
+    }
+}
+
+/// Reads an export from `reader` and sets all of its key/value pairs in
+/// `store`, overwriting existing values.
+///
+/// Returns the number of imported entries.
+///
+/// # Errors
+///
+/// Returns `KvsError::InvalidDump` if the input is not a valid export.
+/// Entries read before such an error are already set in `store`.
+pub fn import<E, R>(store: &E, format: ExportFormat, mut reader: R) -> Result<u64>
+where
+    E: KvsEngine + ?Sized,
+    R: Read,
+{
+    match format {
+        ExportFormat::KvsDump => {
+            let header: [u8; 8] = read_array(&mut reader)?;
+            if &header[..7] != MAGIC {
+                return Err(KvsError::InvalidDump("missing kvsdump header".to_owned()));
+            }
+            if header[7] != VERSION {
+                return Err(KvsError::InvalidDump(format!(
+                    "unsupported kvsdump version {}",
+                    header[7]
+                )));
+            }
+
+            let mut count = 0u64;
+            loop {
+                match read_array::<_, 1>(&mut reader)?[0] {
+                    TAG_ENTRY => {
+                        let (key, value) = read_entry(&mut reader)?;
+                        store.set(key, value)?;
+                        count += 1;
+                    }
+                    TAG_END => {
+                        let expected = u64::from_le_bytes(read_array(&mut reader)?);
+                        if expected != count {
+                            return Err(KvsError::InvalidDump(format!(
+                                "expected {} entries, found {}",
+                                expected, count
+                            )));
+                        }
+                        return Ok(count);
+                    }
+                    tag => {
+                        return Err(KvsError::InvalidDump(format!(
+                            "unexpected record tag {}",
+                            tag
+                        )))
+                    }
+                }
+            }
+        }
+        ExportFormat::Json => {
+            let entries: BTreeMap<String, String> =
+                serde_json::from_reader(reader).map_err(json_error)?;
+            let count = entries.len() as u64;
+            for (key, value) in entries {
+                store.set(key, value)?;
+            }
+            Ok(count)
+        }
+        ExportFormat::Csv => {
+            let mut input = String::new();
+            reader
+                .read_to_string(&mut input)
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::InvalidData => {
+                        KvsError::InvalidDump("export is not valid UTF-8".to_owned())
+                    }
+                    _ => e.into(),
+                })?;
+            let mut count = 0u64;
+            for record in parse_csv(&input)? {
+                let (key, value) = match <[String; 2]>::try_from(record) {
+                    Ok([key, value]) => (key, value),
+                    Err(record) => {
+                        return Err(KvsError::InvalidDump(format!(
+                            "record {} has {} fields instead of 2",
+                            count + 1,
+                            record.len()
+                        )))
+                    }
+                };
+                store.set(key, value)?;
+                count += 1;
+            }
+            Ok(count)
+        }
+        ExportFormat::MsgPack => {
+            let mut count = 0u64;
+            loop {
+                let entry: Option<(String, String)> = rmp_serde::from_read(&mut reader)
+                    .map_err(|e| KvsError::InvalidDump(e.to_string()))?;
+                match entry {
+                    Some((key, value)) => {
+                        store.set(key, value)?;
+                        count += 1;
+                    }
+                    None => return Ok(count),
+                }
+            }
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => columnar::import(store, reader),
+        ExportFormat::RespCommands => {
+            let mut reader = io::BufReader::new(reader);
+            let mut count = 0u64;
+            while let Some(command) = read_resp_command(&mut reader)? {
+                let is_set = match command.as_slice() {
+                    [name, _, _] => name.eq_ignore_ascii_case("set"),
+                    [name, _, _, option, _] => {
+                        name.eq_ignore_ascii_case("set") && option.eq_ignore_ascii_case("pxat")
+                    }
+                    _ => false,
+                };
+                if !is_set {
+                    return Err(KvsError::InvalidDump(format!(
+                        "command {} is not a SET",
+                        count + 1
+                    )));
+                }
+                let mut args = command.into_iter().skip(1);
+                store.set(args.next().unwrap(), args.next().unwrap())?;
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+}
+
+/// The keys of a Redis dump that [`import_redis_rdb`] set in a store, and
+/// those it skipped.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RdbImport {
+    /// The number of keys set.
+    pub imported: u64,
+    /// The number of keys skipped because their values are not strings,
+    /// by the name of their Redis type, e.g. `"hash"`.
+    pub skipped_types: BTreeMap<&'static str, u64>,
+    /// The number of keys skipped because they are not valid UTF-8.
+    pub skipped_keys: u64,
+    /// The number of keys skipped because they are in a database other
+    /// than database 0.
+    pub skipped_databases: u64,
+}
+
+/// Reads a Redis RDB dump from `reader` and sets its string keys in
+/// `store`, overwriting existing values.
+///
+/// Keys keep their expiry, and those that have already expired are left
+/// out, like Redis does when it loads a dump. Values need not be UTF-8,
+/// see [`KvStore::set_bytes`], but keys have to be. Only the keys of
+/// database 0 are imported. The returned report counts the keys skipped
+/// for each of these reasons.
+///
+/// Dumps of every version up to that of Redis 7.4 can be read. Values of
+/// other types than strings are skipped, except for streams and values of
+/// modules, which cannot be.
+///
+/// # Errors
+///
+/// Returns `KvsError::InvalidDump` if the input is not a valid dump, its
+/// checksum does not match, or it holds a stream or module value. Keys
+/// read before such an error are already set in `store`.
+pub fn import_redis_rdb<R: Read>(store: &KvStore, reader: R) -> Result<RdbImport> {
+    let mut report = RdbImport::default();
+    let mut dump = RdbReader::new(reader)?;
+    while let Some(entry) = dump.next_entry()? {
+        let value = match entry.value {
+            RdbValue::String(value) => value,
+            RdbValue::Other(value_type) => {
+                *report.skipped_types.entry(value_type).or_default() += 1;
+                continue;
+            }
+        };
+        if entry.db != 0 {
+            report.skipped_databases += 1;
+            continue;
+        }
+        let key = match String::from_utf8(entry.key) {
+            Ok(key) => key,
+            Err(_) => {
+                report.skipped_keys += 1;
+                continue;
+            }
+        };
+        if matches!(entry.expires, Some(expires) if expires <= kv::now_millis()) {
+            continue;
+        }
+        store.set_bytes_until(key, value, entry.expires)?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+/// The format of a diff.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DiffFormat {
+    /// The native binary diff format, see the [module
+    /// documentation](self#diffs).
+    KvsDiff,
+    /// One JSON object per change and line.
+    Json,
+}
+
+impl FromStr for DiffFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvsdiff" => Ok(DiffFormat::KvsDiff),
+            "json" => Ok(DiffFormat::Json),
+            _ => Err(KvsError::InvalidDump(format!(
+                "unknown diff format `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DiffFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffFormat::KvsDiff => f.write_str("kvsdiff"),
+            DiffFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// A difference between two versions of a store, see [`changes`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Change {
+    /// A key that only the newer version has.
+    Added {
+        /// The key.
+        key: String,
+        /// Its value in the newer version.
+        value: String,
+    },
+    /// A key with different values in the two versions.
+    Changed {
+        /// The key.
+        key: String,
+        /// Its value in the newer version.
+        value: String,
+    },
+    /// A key that only the older version has.
+    Removed {
+        /// The key.
+        key: String,
+    },
+}
+
+/// An iterator over the changes from one version of a store to another,
+/// in ascending key order, returned by [`changes`].
+pub struct Changes {
+    old: SnapshotIter,
+    new: SnapshotIter,
+    old_next: Option<(String, String)>,
+    new_next: Option<(String, String)>,
+}
+
+/// Returns the changes that turn the live entries of `old` into those of
+/// `new`.
+///
+/// Both stores are scanned side by side in key order, as they are when
+/// this is called.
+///
+/// # Errors
+///
+/// It propagates errors while scanning either store, also from the
+/// iterator.
+pub fn changes(old: &KvStore, new: &KvStore) -> Result<Changes> {
+    let mut old = old.scan(..)?;
+    let mut new = new.scan(..)?;
+    Ok(Changes {
+        old_next: old.next().transpose()?,
+        new_next: new.next().transpose()?,
+        old,
+        new,
+    })
+}
+
+impl Changes {
+    fn advance_old(&mut self) -> Result<()> {
+        self.old_next = self.old.next().transpose()?;
+        Ok(())
+    }
+
+    fn advance_new(&mut self) -> Result<()> {
+        self.new_next = self.new.next().transpose()?;
+        Ok(())
+    }
+
+    fn next_change(&mut self) -> Result<Option<Change>> {
+        loop {
+            let change = match (self.old_next.take(), self.new_next.take()) {
+                (None, None) => return Ok(None),
+                (Some((key, _)), None) => {
+                    self.advance_old()?;
+                    Change::Removed { key }
+                }
+                (None, Some((key, value))) => {
+                    self.advance_new()?;
+                    Change::Added { key, value }
+                }
+                (Some(old), Some(new)) => match old.0.cmp(&new.0) {
+                    std::cmp::Ordering::Less => {
+                        self.new_next = Some(new);
+                        self.advance_old()?;
+                        Change::Removed { key: old.0 }
+                    }
+                    std::cmp::Ordering::Greater => {
+                        self.old_next = Some(old);
+                        self.advance_new()?;
+                        Change::Added {
+                            key: new.0,
+                            value: new.1,
+                        }
+                    }
+                    std::cmp::Ordering::Equal => {
+                        self.advance_old()?;
+                        self.advance_new()?;
+                        if old.1 == new.1 {
+                            continue;
+                        }
+                        Change::Changed {
+                            key: new.0,
+                            value: new.1,
+                        }
+                    }
+                },
+            };
+            return Ok(Some(change));
+        }
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_change().transpose()
+    }
+}
+
+/// Writes the changes that turn `old` into `new` to `writer`, see
+/// [`changes`].
+///
+/// Returns the number of changes.
+///
+/// # Errors
+///
+/// Errors encountered while reading the stores or writing the diff are
+/// propagated.
+pub fn diff<W: Write>(
+    old: &KvStore,
+    new: &KvStore,
+    format: DiffFormat,
+    mut writer: W,
+) -> Result<u64> {
+    let mut count = 0u64;
+    if format == DiffFormat::KvsDiff {
+        writer.write_all(DIFF_MAGIC)?;
+        writer.write_all(&[DIFF_VERSION])?;
+    }
+    for change in changes(old, new)? {
+        let change = change?;
+        match format {
+            DiffFormat::KvsDiff => match &change {
+                Change::Added { key, value } => write_entry(&mut writer, TAG_ADDED, key, value)?,
+                Change::Changed { key, value } => {
+                    write_entry(&mut writer, TAG_CHANGED, key, value)?
+                }
+                Change::Removed { key } => write_entry(&mut writer, TAG_REMOVED, key, "")?,
+            },
+            DiffFormat::Json => {
+                serde_json::to_writer(&mut writer, &change).map_err(json_error)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        count += 1;
+    }
+    if format == DiffFormat::KvsDiff {
+        writer.write_all(&[TAG_END])?;
+        writer.write_all(&count.to_le_bytes())?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Reads a diff from `reader` and applies its changes to `store`. Keys
+/// that are removed by the diff but missing from `store` are left alone.
+///
+/// Returns the number of applied changes.
+///
+/// # Errors
+///
+/// Returns `KvsError::InvalidDump` if the input is not a valid diff.
+/// Changes read before such an error are already applied to `store`.
+pub fn apply_diff<E, R>(store: &E, format: DiffFormat, mut reader: R) -> Result<u64>
+where
+    E: KvsEngine + ?Sized,
+    R: Read,
+{
+    let apply = |change: Change| match change {
+        Change::Added { key, value } | Change::Changed { key, value } => store.set(key, value),
+        Change::Removed { key } => match store.remove(key) {
+            Err(KvsError::NonExistentKey(_)) => Ok(()),
+            result => result,
+        },
+    };
+    match format {
+        DiffFormat::KvsDiff => {
+            let header: [u8; 8] = read_array(&mut reader)?;
+            if &header[..7] != DIFF_MAGIC {
+                return Err(KvsError::InvalidDump("missing kvsdiff header".to_owned()));
+            }
+            if header[7] != DIFF_VERSION {
+                return Err(KvsError::InvalidDump(format!(
+                    "unsupported kvsdiff version {}",
+                    header[7]
+                )));
+            }
+            let mut count = 0u64;
+            loop {
+                let tag = read_array::<_, 1>(&mut reader)?[0];
+                let change = match tag {
+                    TAG_END => {
+                        let expected = u64::from_le_bytes(read_array(&mut reader)?);
+                        if expected != count {
+                            return Err(KvsError::InvalidDump(format!(
+                                "expected {} changes, found {}",
+                                expected, count
+                            )));
+                        }
+                        return Ok(count);
+                    }
+                    TAG_ADDED | TAG_CHANGED | TAG_REMOVED => {
+                        let (key, value) = read_entry(&mut reader)?;
+                        match tag {
+                            TAG_ADDED => Change::Added { key, value },
+                            TAG_CHANGED => Change::Changed { key, value },
+                            _ => Change::Removed { key },
+                        }
+                    }
+                    tag => {
+                        return Err(KvsError::InvalidDump(format!(
+                            "unexpected record tag {}",
+                            tag
+                        )))
+                    }
+                };
+                apply(change)?;
+                count += 1;
+            }
+        }
+        DiffFormat::Json => {
+            let mut count = 0u64;
+            for change in serde_json::Deserializer::from_reader(reader).into_iter() {
+                apply(change.map_err(json_error)?)?;
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+}
+
+/// The `parquet` format, see the [module documentation](self).
+#[cfg(feature = "parquet")]
+mod columnar {
+    use crate::{KvStore, KvsEngine, KvsError, Result};
+    use parquet::{
+        basic::Compression,
+        data_type::{ByteArray, ByteArrayType, Int64Type},
+        errors::ParquetError,
+        file::{
+            properties::WriterProperties, reader::FileReader,
+            serialized_reader::SerializedFileReader, writer::SerializedFileWriter,
+        },
+        record::Field,
+        schema::parser::parse_message_type,
+    };
+    use std::{
+        io::{self, Read, Write},
+        mem,
+        sync::{Arc, Mutex},
+    };
+
+    const SCHEMA: &str = "
+        message kvs {
+            required binary key (STRING);
+            required binary value (STRING);
+            optional int64 expires_at (TIMESTAMP(MILLIS, true));
+        }
+    ";
+
+    /// The most entries per row group.
+    const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+    /// The entries of a row group, column by column.
+    #[derive(Default)]
+    struct RowGroup {
+        keys: Vec<ByteArray>,
+        values: Vec<ByteArray>,
+        // the expiry times present, and whether each entry has one
+        expires: Vec<i64>,
+        expires_levels: Vec<i16>,
+    }
+
+    /// A buffer that the Parquet writer, which has to be `Send`, writes
+    /// to, and that is drained into the actual writer after every row
+    /// group.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn drain_into<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            let bytes = mem::take(&mut *self.0.lock().unwrap());
+            writer.write_all(&bytes)
+        }
+    }
+
+    pub fn export<W: Write>(store: &KvStore, mut writer: W) -> Result<u64> {
+        let schema = Arc::new(parse_message_type(SCHEMA).map_err(parquet_error)?);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let buffer = Buffer::default();
+        let mut file = SerializedFileWriter::new(buffer.clone(), schema, Arc::new(properties))
+            .map_err(parquet_error)?;
+        let mut group = RowGroup::default();
+        let mut count = 0u64;
+        store.visit_expiring_entries(|key, value, expires| {
+            group.keys.push(key.into_bytes().into());
+            group.values.push(value.into_bytes().into());
+            match expires {
+                Some(expires) => {
+                    group.expires.push(expires as i64);
+                    group.expires_levels.push(1);
+                }
+                None => group.expires_levels.push(0),
+            }
+            count += 1;
+            if group.keys.len() == ROW_GROUP_SIZE {
+                write_row_group(&mut file, mem::take(&mut group))?;
+                buffer.drain_into(&mut writer)?;
+            }
+            Ok(())
+        })?;
+        if !group.keys.is_empty() {
+            write_row_group(&mut file, group)?;
+        }
+        file.close().map_err(parquet_error)?;
+        buffer.drain_into(&mut writer)?;
+        writer.flush()?;
+        Ok(count)
+    }
+
+    fn write_row_group<W: Write + Send>(
+        file: &mut SerializedFileWriter<W>,
+        group: RowGroup,
+    ) -> Result<()> {
+        let mut writer = file.next_row_group().map_err(parquet_error)?;
+        for column in 0..3 {
+            let mut column_writer = writer
+                .next_column()
+                .map_err(parquet_error)?
+                .expect("the schema has three columns");
+            match column {
+                0 => column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&group.keys, None, None),
+                1 => column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&group.values, None, None),
+                _ => column_writer.typed::<Int64Type>().write_batch(
+                    &group.expires,
+                    Some(&group.expires_levels),
+                    None,
+                ),
+            }
+            .map_err(parquet_error)?;
+            column_writer.close().map_err(parquet_error)?;
+        }
+        writer.close().map_err(parquet_error)?;
+        Ok(())
+    }
+
+    pub fn import<E, R>(store: &E, mut reader: R) -> Result<u64>
+    where
+        E: KvsEngine + ?Sized,
+        R: Read,
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let file = SerializedFileReader::new(bytes::Bytes::from(bytes)).map_err(parquet_error)?;
+        let mut count = 0u64;
+        for row in file.get_row_iter(None).map_err(parquet_error)? {
+            let row = row.map_err(parquet_error)?;
+            let mut key = None;
+            let mut value = None;
+            for (name, field) in row.get_column_iter() {
+                let column = match name.as_str() {
+                    "key" => &mut key,
+                    "value" => &mut value,
+                    _ => continue,
+                };
+                match field {
+                    Field::Str(string) => *column = Some(string.clone()),
+                    field => {
+                        return Err(KvsError::InvalidDump(format!(
+                            "row {} has a {} of {} instead of a string",
+                            count + 1,
+                            name,
+                            field
+                        )))
+                    }
+                }
+            }
+            match (key, value) {
+                (Some(key), Some(value)) => store.set(key, value)?,
+                _ => {
+                    return Err(KvsError::InvalidDump(
+                        "the file lacks a key or value column".to_owned(),
+                    ))
+                }
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn parquet_error(e: ParquetError) -> KvsError {
+        match e {
+            ParquetError::External(e) => match e.downcast::<io::Error>() {
+                Ok(e) => (*e).into(),
+                Err(e) => KvsError::InvalidDump(e.to_string()),
+            },
+            e => KvsError::InvalidDump(e.to_string()),
+        }
+    }
+}
+
+fn json_error(e: serde_json::Error) -> KvsError {
+    match e.classify() {
+        serde_json::error::Category::Io => io::Error::from(e).into(),
+        _ => KvsError::InvalidDump(e.to_string()),
+    }
+}
+
+/// Writes `field`, quoted if it contains a separator, a quote or a line
+/// break.
+fn write_csv_field<W: Write>(writer: &mut W, field: &str) -> Result<()> {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        writer.write_all(b"\"")?;
+        writer.write_all(field.replace('"', "\"\"").as_bytes())?;
+        writer.write_all(b"\"")?;
+    } else {
+        writer.write_all(field.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Splits `input` into records of fields. Records end with `\n` or
+/// `\r\n`, and the line break after the last record is optional.
+fn parse_csv(input: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => {
+                        return Err(KvsError::InvalidDump(
+                            "unterminated quoted field".to_owned(),
+                        ))
+                    }
+                }
+            },
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Writes a command as a RESP array of bulk strings.
+fn write_resp_command<W: Write>(writer: &mut W, args: &[&str]) -> Result<()> {
+    write!(writer, "*{}\r\n", args.len())?;
+    for arg in args {
+        write!(writer, "${}\r\n", arg.len())?;
+        writer.write_all(arg.as_bytes())?;
+        writer.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+/// Reads a command written by [`write_resp_command`], or returns `None`
+/// at the end of the input.
+fn read_resp_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    fn read_header<R: BufRead>(reader: &mut R, prefix: u8) -> Result<Option<usize>> {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        let len = line
+            .strip_prefix(&[prefix])
+            .and_then(|line| line.strip_suffix(b"\r\n"))
+            .and_then(|len| str::from_utf8(len).ok())
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| {
+                KvsError::InvalidDump(format!("expected `{}` and a length", prefix as char))
+            })?;
+        Ok(Some(len))
+    }
+
+    let len = match read_header(reader, b'*')? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let mut args = Vec::with_capacity(len.min(16));
+    for _ in 0..len {
+        let arg_len = read_header(reader, b'$')?
+            .ok_or_else(|| KvsError::InvalidDump("dump is truncated".to_owned()))?;
+        let arg = read_vec(reader, arg_len as u64)?;
+        if read_array(reader)? != *b"\r\n" {
+            return Err(KvsError::InvalidDump(
+                "bulk string is not terminated".to_owned(),
+            ));
+        }
+        let arg = String::from_utf8(arg)
+            .map_err(|_| KvsError::InvalidDump("command is not valid UTF-8".to_owned()))?;
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Writes a `kvsdump` entry by entry.
+pub(crate) struct DumpWriter<W: Write> {
+    writer: W,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Starts a dump by writing its header.
+    pub fn new(mut writer: W) -> Result<DumpWriter<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(DumpWriter { writer, count: 0 })
+    }
+
+    pub fn entry(&mut self, key: &str, value: &str) -> Result<()> {
+        write_entry(&mut self.writer, TAG_ENTRY, key, value)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Ends the dump, flushes the writer and returns the number of
+    /// entries.
+    pub fn finish(mut self) -> Result<u64> {
+        self.writer.write_all(&[TAG_END])?;
+        self.writer.write_all(&self.count.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+fn write_entry<W: Write>(writer: &mut W, tag: u8, key: &str, value: &str) -> Result<()> {
+    let too_long = |_| KvsError::InvalidDump("entry too long".to_owned());
+    let key_len = u32::try_from(key.len()).map_err(too_long)?.to_le_bytes();
+    let value_len = u32::try_from(value.len()).map_err(too_long)?.to_le_bytes();
+
+    let mut hasher = Hasher::new();
+    hasher.update(&key_len);
+    hasher.update(&value_len);
+    hasher.update(key.as_bytes());
+    hasher.update(value.as_bytes());
+
+    writer.write_all(&[tag])?;
+    writer.write_all(&key_len)?;
+    writer.write_all(&value_len)?;
+    writer.write_all(key.as_bytes())?;
+    writer.write_all(value.as_bytes())?;
+    writer.write_all(&hasher.finalize().to_le_bytes())?;
+    Ok(())
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<(String, String)> {
+    let key_len = read_array(reader)?;
+    let value_len = read_array(reader)?;
+    let key = read_vec(reader, u32::from_le_bytes(key_len).into())?;
+    let value = read_vec(reader, u32::from_le_bytes(value_len).into())?;
+    let crc = u32::from_le_bytes(read_array(reader)?);
+
+    let mut hasher = Hasher::new();
+    hasher.update(&key_len);
+    hasher.update(&value_len);
+    hasher.update(&key);
+    hasher.update(&value);
+    if hasher.finalize() != crc {
+        return Err(KvsError::InvalidDump("checksum mismatch".to_owned()));
+    }
+
+    let utf8 = |_| KvsError::InvalidDump("entry is not valid UTF-8".to_owned());
+    Ok((
+        String::from_utf8(key).map_err(utf8)?,
+        String::from_utf8(value).map_err(utf8)?,
+    ))
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    read_exact(reader, &mut buf)?;
+    Ok(buf)
+}
+
+/// Reads `len` bytes. The length comes from the input, so the buffer
+/// only grows as the bytes arrive.
+fn read_vec<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(KvsError::InvalidDump("dump is truncated".to_owned()));
+    }
+    Ok(buf)
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => KvsError::InvalidDump("dump is truncated".to_owned()),
+        _ => e.into(),
+    })
+}
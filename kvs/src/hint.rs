@@ -0,0 +1,312 @@
+//! Hint files, which let a store load the index entries of a compacted
+//! segment without replaying it.
+//!
+//! A compaction writes a hint file `{gen}.hint` next to the segment it
+//! produces, listing the key and location of every command in it. The
+//! segment never changes afterwards, so the hint stays valid for as long
+//! as the segment exists.
+//!
+//! Keys are front-coded: every entry only stores the part of its key that
+//! differs from the previous entry's key. Compactions copy entries in key
+//! order, so neighbouring keys tend to share long prefixes. All integers
+//! are LEB128 varints, and a checksum over the whole file is appended.
+//! The byte after the entries tells whether any of the values expire.
+//!
+//! ```text
+//! hint:  "KVSHINT" | version (u8) | entry* | 0x00 | expiring (u8) | crc32 (u32, little endian)
+//! entry: 0x01 | shared prefix len | suffix len | suffix | pos | len
+//! ```
+//!
+//! Version 1 files lack the expiring byte, and are read as if some values
+//! expire.
+
+use crate::Result;
+use crc32fast::Hasher;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 7] = b"KVSHINT";
+const VERSION: u8 = 2;
+const TAG_END: u8 = 0;
+const TAG_ENTRY: u8 = 1;
+
+/// The key and location of a command in a segment.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Hint {
+    pub key: String,
+    pub pos: u64,
+    pub len: u64,
+}
+
+/// The entries of a hint file.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Hints {
+    pub entries: Vec<Hint>,
+    /// Whether any of the values may expire.
+    pub expiring: bool,
+}
+
+/// Writes a hint file entry by entry.
+pub(crate) struct HintWriter<W: Write> {
+    writer: W,
+    hasher: Hasher,
+    prev_key: String,
+    expiring: bool,
+}
+
+impl<W: Write> HintWriter<W> {
+    pub fn new(writer: W) -> Result<HintWriter<W>> {
+        let mut hints = HintWriter {
+            writer,
+            hasher: Hasher::new(),
+            prev_key: String::new(),
+            expiring: false,
+        };
+        hints.write(MAGIC)?;
+        hints.write(&[VERSION])?;
+        Ok(hints)
+    }
+
+    /// Adds the entry of `key`, whose value expires if `expiring`.
+    pub fn add(&mut self, key: &str, pos: u64, len: u64, expiring: bool) -> Result<()> {
+        self.expiring |= expiring;
+        let shared = shared_prefix(&self.prev_key, key);
+        let suffix = &key.as_bytes()[shared..];
+        self.write(&[TAG_ENTRY])?;
+        self.write_varint(shared as u64)?;
+        self.write_varint(suffix.len() as u64)?;
+        self.write(suffix)?;
+        self.write_varint(pos)?;
+        self.write_varint(len)?;
+
+        self.prev_key.truncate(shared);
+        self.prev_key.push_str(&key[shared..]);
+        Ok(())
+    }
+
+    /// Terminates the hint file and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write(&[TAG_END, u8::from(self.expiring)])?;
+        let crc = self.hasher.clone().finalize();
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.update(buf);
+        self.writer.write_all(buf)?;
+        Ok(())
+    }
+
+    fn write_varint(&mut self, mut n: u64) -> Result<()> {
+        let mut buf = [0; 10];
+        let mut i = 0;
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                buf[i] = byte;
+                i += 1;
+                break;
+            }
+            buf[i] = byte | 0x80;
+            i += 1;
+        }
+        self.write(&buf[..i])
+    }
+}
+
+/// Reads the hint file at `path`. Returns `None` if there is none.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` I/O error if the file is truncated or
+/// corrupt. No hints are returned in that case.
+pub(crate) fn read_hints(path: &Path) -> Result<Option<Hints>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = HintReader {
+        reader: BufReader::new(file),
+        hasher: Hasher::new(),
+    };
+
+    let mut header = [0; 8];
+    reader.read(&mut header)?;
+    if &header[..7] != MAGIC || !(1..=VERSION).contains(&header[7]) {
+        return Err(invalid("unknown hint file header"));
+    }
+
+    let mut hints = Vec::new();
+    let mut key = Vec::new();
+    loop {
+        let mut tag = [0];
+        reader.read(&mut tag)?;
+        match tag[0] {
+            TAG_ENTRY => {
+                let shared = reader.read_varint()? as usize;
+                let suffix_len = reader.read_varint()? as usize;
+                if shared > key.len() {
+                    return Err(invalid("shared prefix longer than previous key"));
+                }
+                key.truncate(shared);
+                key.resize(shared + suffix_len, 0);
+                reader.read(&mut key[shared..])?;
+                let pos = reader.read_varint()?;
+                let len = reader.read_varint()?;
+                let key =
+                    String::from_utf8(key.clone()).map_err(|_| invalid("key is not UTF-8"))?;
+                hints.push(Hint { key, pos, len });
+            }
+            TAG_END => break,
+            _ => return Err(invalid("unexpected record tag")),
+        }
+    }
+    let expiring = match header[7] {
+        1 => true,
+        _ => {
+            let mut expiring = [0];
+            reader.read(&mut expiring)?;
+            expiring[0] != 0
+        }
+    };
+
+    let expected = reader.hasher.clone().finalize();
+    let mut crc = [0; 4];
+    reader.reader.read_exact(&mut crc).map_err(truncated)?;
+    if u32::from_le_bytes(crc) != expected {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(Some(Hints {
+        entries: hints,
+        expiring,
+    }))
+}
+
+struct HintReader<R: Read> {
+    reader: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HintReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buf).map_err(truncated)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0];
+            self.read(&mut byte)?;
+            n |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+}
+
+fn shared_prefix(a: &str, b: &str) -> usize {
+    let mut len = a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count();
+    // keep the suffix valid UTF-8
+    while !b.is_char_boundary(len) {
+        len -= 1;
+    }
+    len
+}
+
+fn truncated(e: io::Error) -> crate::KvsError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("hint file is truncated"),
+        _ => e.into(),
+    }
+}
+
+fn invalid(msg: &str) -> crate::KvsError {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn front_coded_round_trip() -> Result<()> {
+        let keys = [
+            "",
+            "key",
+            "key1",
+            "key10",
+            "key2",
+            "other",
+            "ünï",
+            "ünïcode",
+            // share a byte, but no character
+            "é",
+            "è",
+        ];
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("1.hint");
+
+        let mut writer = HintWriter::new(Vec::new())?;
+        for (i, key) in keys.iter().enumerate() {
+            writer.add(key, i as u64 * 300, 150, false)?;
+        }
+        let bytes = writer.finish()?;
+        std::fs::write(&path, &bytes)?;
+
+        let hints = read_hints(&path)?.unwrap();
+        assert!(!hints.expiring);
+        let expected: Vec<Hint> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| Hint {
+                key: key.to_string(),
+                pos: i as u64 * 300,
+                len: 150,
+            })
+            .collect();
+        assert_eq!(hints.entries, expected);
+
+        // Any truncation is detected.
+        for len in 0..bytes.len() {
+            std::fs::write(&path, &bytes[..len])?;
+            assert!(read_hints(&path).is_err());
+        }
+        assert!(read_hints(&temp_dir.path().join("2.hint"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn expiring() -> Result<()> {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("1.hint");
+
+        let mut writer = HintWriter::new(Vec::new())?;
+        writer.add("key1", 0, 150, false)?;
+        writer.add("key2", 150, 150, true)?;
+        std::fs::write(&path, writer.finish()?)?;
+        assert!(read_hints(&path)?.unwrap().expiring);
+
+        // Version 1 files do not say, so they may hold expiring values.
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1, TAG_END]);
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend(crc.to_le_bytes());
+        std::fs::write(&path, &bytes)?;
+        let hints = read_hints(&path)?.unwrap();
+        assert!(hints.entries.is_empty());
+        assert!(hints.expiring);
+        Ok(())
+    }
+}
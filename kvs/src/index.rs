@@ -0,0 +1,471 @@
+//! The in-memory index mapping keys to the location of their latest
+//! `Set` command in the log.
+//!
+//! The index is a concurrent map, so it can be read while it is written
+//! to. The position of an existing key is updated in place, so it never
+//! disappears in between. Writes must not race with each other: they read
+//! an entry before replacing it, so callers have to serialize them.
+
+use crate::Result;
+use crossbeam_skiplist::SkipMap;
+use crossbeam_utils::atomic::AtomicCell;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    ops::{Bound, Range, RangeBounds},
+};
+
+/// How keys are stored in the in-memory index.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum IndexMode {
+    /// Keep every key in full in an ordered map. This is the default.
+    #[default]
+    Ordered,
+    /// Keep only a fixed-size hash of every key. This bounds the memory
+    /// used per key regardless of key length, at the cost of an extra
+    /// log read when a write hits an already indexed hash, which is
+    /// needed to tell an overwrite apart from a hash collision.
+    Hashed,
+}
+
+/// The segment, position and length of a serialized command in the log.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct CommandPos {
+    pub gen: u64,
+    pub pos: u64,
+    pub len: u64,
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
+
+// there is only one index per store, so its size does not matter
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Index {
+    Ordered(SkipMap<String, AtomicCell<CommandPos>>),
+    Hashed(HashedIndex),
+}
+
+impl Index {
+    pub fn new(mode: IndexMode) -> Index {
+        match mode {
+            IndexMode::Ordered => Index::Ordered(SkipMap::new()),
+            IndexMode::Hashed => Index::Hashed(HashedIndex::new(hash_key)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Ordered(map) => map.len(),
+            Index::Hashed(index) => index.entries.len() + index.collisions.len(),
+        }
+    }
+
+    /// Returns the position of the command that may hold `key`.
+    ///
+    /// For a hashed index this is only a candidate: the caller has to
+    /// check that the command found at that position is for `key`.
+    pub fn get(&self, key: &str) -> Option<CommandPos> {
+        match self {
+            Index::Ordered(map) => map.get(key).map(|entry| entry.value().load()),
+            Index::Hashed(index) => index
+                .collisions
+                .get(key)
+                .map(|entry| entry.value().load())
+                .or_else(|| {
+                    let entry = index.entries.get(&(index.hash)(key))?;
+                    Some(entry.value().load())
+                }),
+        }
+    }
+
+    /// Returns whether `key` is present. See [`Index::insert`] for the
+    /// meaning of `resolve`.
+    pub fn contains<F>(&self, key: &str, mut resolve: F) -> Result<bool>
+    where
+        F: FnMut(CommandPos) -> Result<String>,
+    {
+        match self {
+            Index::Ordered(map) => Ok(map.contains_key(key)),
+            Index::Hashed(index) => {
+                if index.collisions.contains_key(key) {
+                    return Ok(true);
+                }
+                match index.entries.get(&(index.hash)(key)) {
+                    Some(entry) => Ok(resolve(entry.value().load())? == key),
+                    None => Ok(false),
+                }
+            }
+        }
+    }
+
+    /// Points `key` at `pos`, returning its previous position if any.
+    ///
+    /// `resolve` reads the key stored at a position in the log. It is
+    /// only called by a hashed index, to verify the key of an existing
+    /// entry with the same hash.
+    pub fn insert<F>(&self, key: String, pos: CommandPos, resolve: F) -> Result<Option<CommandPos>>
+    where
+        F: FnMut(CommandPos) -> Result<String>,
+    {
+        match self {
+            Index::Ordered(map) => Ok(insert(map, key, pos)),
+            Index::Hashed(index) => index.insert(key, pos, resolve),
+        }
+    }
+
+    /// Removes `key` from the index, returning its position if it was
+    /// present. See [`Index::insert`] for the meaning of `resolve`.
+    pub fn remove<F>(&self, key: &str, resolve: F) -> Result<Option<CommandPos>>
+    where
+        F: FnMut(CommandPos) -> Result<String>,
+    {
+        match self {
+            Index::Ordered(map) => Ok(map.remove(key).map(|entry| entry.value().load())),
+            Index::Hashed(index) => index.remove(key, resolve),
+        }
+    }
+
+    /// Returns all keys in ascending order. See [`Index::insert`] for the
+    /// meaning of `resolve`, which a hashed index calls for every key.
+    pub fn keys<F>(&self, mut resolve: F) -> Result<Vec<String>>
+    where
+        F: FnMut(CommandPos) -> Result<String>,
+    {
+        match self {
+            Index::Ordered(map) => Ok(map.iter().map(|entry| entry.key().clone()).collect()),
+            Index::Hashed(index) => {
+                let mut keys = Vec::with_capacity(self.len());
+                for entry in index.entries.iter() {
+                    keys.push(resolve(entry.value().load())?);
+                }
+                keys.extend(index.collisions.iter().map(|entry| entry.key().clone()));
+                keys.sort_unstable();
+                Ok(keys)
+            }
+        }
+    }
+
+    /// Calls `visit` with the position of every key starting with
+    /// `prefix`. See [`Index::insert`] for the meaning of `resolve`,
+    /// which a hashed index calls for every key unless `prefix` is empty.
+    pub fn visit_prefix<R, F>(&self, prefix: &str, mut resolve: R, mut visit: F) -> Result<()>
+    where
+        R: FnMut(CommandPos) -> Result<String>,
+        F: FnMut(CommandPos),
+    {
+        match self {
+            Index::Ordered(map) => map
+                .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|entry| entry.key().starts_with(prefix))
+                .for_each(|entry| visit(entry.value().load())),
+            Index::Hashed(index) => {
+                for entry in index.entries.iter() {
+                    let pos = entry.value().load();
+                    if prefix.is_empty() || resolve(pos)?.starts_with(prefix) {
+                        visit(pos);
+                    }
+                }
+                index
+                    .collisions
+                    .iter()
+                    .filter(|entry| entry.key().starts_with(prefix))
+                    .for_each(|entry| visit(entry.value().load()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `visit` with the position of every key in `range` starting
+    /// with `prefix`, in ascending key order. See [`Index::insert`] for
+    /// the meaning of `resolve`, which a hashed index calls for every
+    /// key, to sort the keys.
+    pub fn visit_range<R, F>(
+        &self,
+        range: (Bound<&str>, Bound<&str>),
+        prefix: &str,
+        mut resolve: R,
+        mut visit: F,
+    ) -> Result<()>
+    where
+        R: FnMut(CommandPos) -> Result<String>,
+        F: FnMut(CommandPos),
+    {
+        match self {
+            // keys starting with `prefix` are next to each other
+            Index::Ordered(map) => map
+                .range::<str, _>(range)
+                .skip_while(|entry| !entry.key().starts_with(prefix))
+                .take_while(|entry| entry.key().starts_with(prefix))
+                .for_each(|entry| visit(entry.value().load())),
+            Index::Hashed(index) => {
+                let mut found = Vec::new();
+                for entry in index.entries.iter() {
+                    let pos = entry.value().load();
+                    found.push((resolve(pos)?, pos));
+                }
+                for entry in index.collisions.iter() {
+                    found.push((entry.key().clone(), entry.value().load()));
+                }
+                found.retain(|(key, _)| {
+                    RangeBounds::<str>::contains(&range, key.as_str()) && key.starts_with(prefix)
+                });
+                found.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+                found.into_iter().for_each(|(_, pos)| visit(pos));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the smallest key in `range`, or `None` if there is none.
+    /// Only an ordered index knows the order of its keys; a hashed index
+    /// returns `None` without looking.
+    pub fn first_key(&self, range: (Bound<&str>, Bound<&str>)) -> Option<String> {
+        match self {
+            Index::Ordered(map) => map
+                .range::<str, _>(range)
+                .next()
+                .map(|entry| entry.key().clone()),
+            Index::Hashed(_) => None,
+        }
+    }
+
+    /// Returns the largest key in `range`, like [`Index::first_key`].
+    pub fn last_key(&self, range: (Bound<&str>, Bound<&str>)) -> Option<String> {
+        match self {
+            Index::Ordered(map) => map
+                .range::<str, _>(range)
+                .next_back()
+                .map(|entry| entry.key().clone()),
+            Index::Hashed(_) => None,
+        }
+    }
+
+    /// Returns the mode the index was created with.
+    pub fn mode(&self) -> IndexMode {
+        match self {
+            Index::Ordered(_) => IndexMode::Ordered,
+            Index::Hashed(_) => IndexMode::Hashed,
+        }
+    }
+
+    /// Points every entry at a position in `moves` to the position it is
+    /// mapped to, and returns the positions entries were moved to.
+    pub fn relocate(&self, moves: &HashMap<CommandPos, CommandPos>) -> Vec<CommandPos> {
+        let mut moved = Vec::new();
+        match self {
+            Index::Ordered(map) => relocate(map, moves, &mut moved),
+            Index::Hashed(index) => {
+                relocate(&index.entries, moves, &mut moved);
+                relocate(&index.collisions, moves, &mut moved);
+            }
+        }
+        moved
+    }
+}
+
+/// Points `key` at `pos` in `map`, returning its previous position if any.
+fn insert<K>(
+    map: &SkipMap<K, AtomicCell<CommandPos>>,
+    key: K,
+    pos: CommandPos,
+) -> Option<CommandPos>
+where
+    K: Ord + Send + 'static,
+{
+    match map.get(&key) {
+        Some(entry) => Some(entry.value().swap(pos)),
+        None => {
+            map.insert(key, AtomicCell::new(pos));
+            None
+        }
+    }
+}
+
+fn relocate<K>(
+    map: &SkipMap<K, AtomicCell<CommandPos>>,
+    moves: &HashMap<CommandPos, CommandPos>,
+    moved: &mut Vec<CommandPos>,
+) where
+    K: Ord + Send + 'static,
+{
+    for entry in map.iter() {
+        if let Some(&pos) = moves.get(&entry.value().load()) {
+            entry.value().store(pos);
+            moved.push(pos);
+        }
+    }
+}
+
+pub(crate) struct HashedIndex {
+    hash: fn(&str) -> u64,
+    entries: SkipMap<u64, AtomicCell<CommandPos>>,
+    // keys whose hash was already taken by another key when inserted
+    collisions: SkipMap<String, AtomicCell<CommandPos>>,
+}
+
+impl HashedIndex {
+    fn new(hash: fn(&str) -> u64) -> HashedIndex {
+        HashedIndex {
+            hash,
+            entries: SkipMap::new(),
+            collisions: SkipMap::new(),
+        }
+    }
+
+    fn insert<F>(&self, key: String, pos: CommandPos, mut resolve: F) -> Result<Option<CommandPos>>
+    where
+        F: FnMut(CommandPos) -> Result<String>,
+    {
+        if let Some(entry) = self.collisions.get(&key) {
+            return Ok(Some(entry.value().swap(pos)));
+        }
+
+        let hash = (self.hash)(&key);
+        match self.entries.get(&hash) {
+            Some(entry) if resolve(entry.value().load())? == key => {
+                Ok(Some(entry.value().swap(pos)))
+            }
+            Some(_) => {
+                self.collisions.insert(key, AtomicCell::new(pos));
+                Ok(None)
+            }
+            None => {
+                self.entries.insert(hash, AtomicCell::new(pos));
+                Ok(None)
+            }
+        }
+    }
+
+    fn remove<F>(&self, key: &str, mut resolve: F) -> Result<Option<CommandPos>>
+    where
+        F: FnMut(CommandPos) -> Result<String>,
+    {
+        if let Some(old) = self.collisions.remove(key) {
+            return Ok(Some(old.value().load()));
+        }
+
+        let hash = (self.hash)(key);
+        match self.entries.get(&hash).map(|entry| entry.value().load()) {
+            Some(old) if resolve(old)? == key => {
+                self.entries.remove(&hash);
+                Ok(Some(old))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+pub(crate) fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(pos: u64) -> CommandPos {
+        CommandPos {
+            gen: 1,
+            pos,
+            len: 1,
+        }
+    }
+
+    // Every key collides, so all but the first key end up in the
+    // collision map and must be told apart through `resolve`.
+    #[test]
+    fn hashed_collisions() -> Result<()> {
+        const KEYS: [&str; 4] = ["a", "b", "a", "b"];
+        let resolve = |p: CommandPos| Ok(KEYS[p.pos as usize].to_owned());
+        let index = Index::Hashed(HashedIndex::new(|_| 0));
+
+        assert_eq!(index.insert("a".to_owned(), pos(0), resolve)?, None);
+        assert_eq!(index.insert("b".to_owned(), pos(1), resolve)?, None);
+        assert_eq!(index.insert("a".to_owned(), pos(2), resolve)?, Some(pos(0)));
+        assert_eq!(index.insert("b".to_owned(), pos(3), resolve)?, Some(pos(1)));
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get("a"), Some(pos(2)));
+        assert_eq!(index.get("b"), Some(pos(3)));
+
+        assert_eq!(index.remove("c", resolve)?, None);
+        assert_eq!(index.remove("a", resolve)?, Some(pos(2)));
+        assert_eq!(index.remove("a", resolve)?, None);
+        assert_eq!(index.remove("b", resolve)?, Some(pos(3)));
+        assert_eq!(index.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn visit_range() -> Result<()> {
+        const KEYS: [&str; 5] = ["b", "ab", "a", "abc", "c"];
+        let resolve = |p: CommandPos| Ok(KEYS[p.pos as usize].to_owned());
+        for index in [
+            Index::new(IndexMode::Ordered),
+            Index::Hashed(HashedIndex::new(|key| key.len() as u64)),
+        ] {
+            for (i, key) in KEYS.iter().enumerate() {
+                index.insert(key.to_string(), pos(i as u64), resolve)?;
+            }
+            let visited = |range, prefix| -> Result<Vec<&str>> {
+                let mut keys = Vec::new();
+                index.visit_range(range, prefix, resolve, |p| keys.push(KEYS[p.pos as usize]))?;
+                Ok(keys)
+            };
+            let all = (Bound::Unbounded, Bound::Unbounded);
+            assert_eq!(visited(all, "")?, ["a", "ab", "abc", "b", "c"]);
+            assert_eq!(visited(all, "ab")?, ["ab", "abc"]);
+            let range = (Bound::Excluded("a"), Bound::Included("b"));
+            assert_eq!(visited(range, "")?, ["ab", "abc", "b"]);
+            assert_eq!(visited(range, "a")?, ["ab", "abc"]);
+            assert_eq!(visited(range, "c")?, Vec::<&str>::new());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keys() -> Result<()> {
+        const KEYS: [&str; 4] = ["b", "ab", "a", "c"];
+        let resolve = |p: CommandPos| Ok(KEYS[p.pos as usize].to_owned());
+        for index in [
+            Index::new(IndexMode::Ordered),
+            Index::Hashed(HashedIndex::new(|key| key.len() as u64)),
+        ] {
+            for (i, key) in KEYS.iter().enumerate() {
+                index.insert(key.to_string(), pos(i as u64), resolve)?;
+            }
+            assert_eq!(index.keys(resolve)?, ["a", "ab", "b", "c"]);
+            assert!(index.contains("ab", resolve)?);
+            assert!(!index.contains("abc", resolve)?);
+            assert!(!index.contains("d", resolve)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn relocate() -> Result<()> {
+        let resolve = |_| unreachable!();
+        for index in [
+            Index::new(IndexMode::Ordered),
+            Index::new(IndexMode::Hashed),
+        ] {
+            index.insert("a".to_owned(), pos(0), resolve)?;
+            index.insert("b".to_owned(), pos(1), resolve)?;
+            let moves = [(pos(0), pos(5))].iter().copied().collect();
+            assert_eq!(index.relocate(&moves), vec![pos(5)]);
+            assert_eq!(index.get("a"), Some(pos(5)));
+            assert_eq!(index.get("b"), Some(pos(1)));
+            assert_eq!(index.len(), 2);
+        }
+        Ok(())
+    }
+}
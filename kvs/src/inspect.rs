@@ -0,0 +1,64 @@
+//! The raw records of a store's log, for debugging.
+//!
+//! See [`KvStore::log_records`](crate::KvStore::log_records) and the
+//! `kvs log-dump` command.
+
+use std::fmt;
+
+/// A record found in a segment of the log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogRecord {
+    /// The generation of the segment, i.e. the number in its file name.
+    pub gen: u64,
+    /// Where the record starts in the segment, in bytes.
+    pub offset: u64,
+    /// The length of the record in bytes, including its header.
+    pub len: u64,
+    /// What the record holds.
+    pub kind: RecordKind,
+    /// The key the record is for. Batch markers have none.
+    pub key: Option<String>,
+    /// Whether the index points at this record. Only sets can be live,
+    /// all other records are stale.
+    pub live: bool,
+}
+
+/// What a [`LogRecord`] holds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RecordKind {
+    /// Sets a value stored in the record.
+    Set,
+    /// Sets a deduplicated value stored in the blob file.
+    SetBlob,
+    /// Sets a value stored compressed in the record.
+    SetCompressed,
+    /// Sets a value that is not valid UTF-8.
+    SetBytes,
+    /// Removes a key.
+    Rm,
+    /// Starts a batch.
+    Begin,
+    /// Commits a batch.
+    Commit,
+    /// The rest of the segment could not be read, for `reason`. The
+    /// record spans all remaining bytes.
+    Unreadable {
+        /// The error reading the record.
+        reason: String,
+    },
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordKind::Set => f.write_str("set"),
+            RecordKind::SetBlob => f.write_str("set-blob"),
+            RecordKind::SetCompressed => f.write_str("set-compressed"),
+            RecordKind::SetBytes => f.write_str("set-bytes"),
+            RecordKind::Rm => f.write_str("rm"),
+            RecordKind::Begin => f.write_str("begin"),
+            RecordKind::Commit => f.write_str("commit"),
+            RecordKind::Unreadable { reason } => write!(f, "unreadable ({})", reason),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{KvsError, Result};
+
+/// Rules that keys have to follow, checked whenever a key is passed to a
+/// store or server.
+///
+/// By default every key is accepted as it is.
+///
+/// ```rust
+/// # use kvs::{KeyPolicy, KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let policy = KeyPolicy::new()
+///     .max_len(64)
+///     .allowed_chars(|c| c.is_ascii_alphanumeric() || c == ':')
+///     .fold_case();
+/// let store = KvStore::builder().key_policy(policy).open(current_dir()?)?;
+/// store.set("User:42".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("user:42".to_owned())?, Some("value".to_owned()));
+/// assert!(store.set("user 42".to_owned(), "value".to_owned()).is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KeyPolicy {
+    max_len: Option<usize>,
+    allowed_chars: Option<fn(char) -> bool>,
+    fold_case: bool,
+}
+
+impl KeyPolicy {
+    /// Creates a policy that accepts every key.
+    pub fn new() -> KeyPolicy {
+        KeyPolicy::default()
+    }
+
+    /// Rejects keys longer than `max_len` bytes.
+    pub fn max_len(mut self, max_len: usize) -> KeyPolicy {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Rejects keys containing a character for which `allowed` returns
+    /// `false`.
+    pub fn allowed_chars(mut self, allowed: fn(char) -> bool) -> KeyPolicy {
+        self.allowed_chars = Some(allowed);
+        self
+    }
+
+    /// Converts keys to lowercase, so keys differing only in case refer
+    /// to the same entry. Keys are converted before they are checked.
+    pub fn fold_case(mut self) -> KeyPolicy {
+        self.fold_case = true;
+        self
+    }
+
+    /// Returns the normalized form of `key`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidKey` if the key breaks one of the rules.
+    pub fn apply(&self, key: String) -> Result<String> {
+        let key = if self.fold_case {
+            key.to_lowercase()
+        } else {
+            key
+        };
+        if let Some(max_len) = self.max_len {
+            if key.len() > max_len {
+                let reason = format!("longer than {} bytes", max_len);
+                return Err(KvsError::InvalidKey { key, reason });
+            }
+        }
+        if let Some(allowed) = self.allowed_chars {
+            if let Some(c) = key.chars().find(|&c| !allowed(c)) {
+                let reason = format!("character {:?} is not allowed", c);
+                return Err(KvsError::InvalidKey { key, reason });
+            }
+        }
+        Ok(key)
+    }
+}
@@ -1,67 +1,251 @@
 //! A simple key-value store backed by a Write Ahead Log. The commands
 //! are serialized to the log using the
-//! [MsgPack](https://github.com/3Hren/msgpack-rust) format.
+//! [MsgPack](https://github.com/3Hren/msgpack-rust) format, each in a
+//! checksummed record, see [`crate::segment`].
 
 use crate::{
-    io::{BufReaderWithPos, BufWriterWithPos},
-    KvsError, Result,
+    access::AccessTimes,
+    batch::BatchOp,
+    cache::{CacheStats, ValueCache},
+    commit::CommitQueue,
+    contention::{LockWaits, Recorder},
+    cursor::{Cursor, Page},
+    dedup::BlobStore,
+    entry::Entry,
+    events::{
+        CompactionFilter, CompactionFinished, CompactionStarted, CorruptionDetected, EventListener,
+        Evicted, FilterDecision, SegmentDropped, SegmentSealed,
+    },
+    hint::{self, HintWriter},
+    index::{self, CommandPos, Index, IndexMode},
+    inspect::{LogRecord, RecordKind},
+    io::BufWriterWithPos,
+    key::KeyPolicy,
+    manifest::{self, Manifest},
+    segment::{self, Foreign, Format, Layout, SegmentReader, WeakSegmentHandle},
+    stats::{CompactionReport, Stats},
+    KvStoreBuilder, KvsEngine, KvsError, RecoveryMode, Result, SyncPolicy, WriteBatch,
 };
+use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
-    fs::{self, File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    ops::Range,
-    path::PathBuf,
+    collections::{btree_map, BTreeMap, HashMap, HashSet, VecDeque},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    iter,
+    ops::{Bound, Range, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    vec,
 };
 
-/// Amount of "wasted" bytes before a compaction is triggered after an operation.
+/// Amount of "wasted" bytes in sealed segments before a compaction is
+/// triggered after an operation, unless configured otherwise.
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+/// Size at which the active segment is sealed, unless configured
+/// otherwise.
+const DEFAULT_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Number of locks that writes to keys are spread over.
+const LOCK_STRIPES: usize = 64;
+
+/// The most expired keys a compaction drops from the index while holding
+/// the writer lock once.
+const EVICTION_BATCH: usize = 1024;
+
+/// Number of compactions [`KvStore::compaction_history`] remembers.
+const COMPACTION_HISTORY: usize = 32;
+
+/// How much the write rate measured over the last segment counts towards
+/// that of an adaptive compaction threshold.
+const WRITE_RATE_WEIGHT: f64 = 0.3;
+
+/// Name of the log file used by versions of the store before segments
+/// were numbered.
+const LEGACY_LOG: &str = "kvs.log";
+
+/// Name of the file a compaction writes to before it becomes a segment.
+const COMPACTION_FILE: &str = "compaction.tmp";
+
+/// Name of the file a compaction writes the segment's hints to.
+const COMPACTION_HINT_FILE: &str = "compaction.hint.tmp";
+
+/// Name of the file of the `BlobStore`.
+const BLOB_FILE: &str = "blobs.log";
+
+/// The file locked while a store is open for writing.
+const LOCK_FILE: &str = "LOCK";
+
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+        // when the value expires, in milliseconds since the Unix epoch.
+        // Left out if the value does not expire, which is what records
+        // written before expiry existed are read as.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    Rm {
+        key: String,
+    },
+    /// Sets a value that is stored in the `BlobStore`.
+    SetBlob {
+        key: String,
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    /// Starts a batch of commands that only apply once it is committed.
+    Begin,
+    /// Commits the batch started by the last `Begin`.
+    Commit,
+    /// Sets a value that is stored compressed, see `compress_value`. It
+    /// may be any bytes, like the value of `SetBytes`.
+    SetCompressed {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    /// Sets a value that is not valid UTF-8.
+    SetBytes {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(key: String, value: String, expires: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires,
+        }
     }
 
     fn remove(key: String) -> Command {
         Command::Rm { key }
     }
+
+    /// Returns the key of the command. Batch markers have none, but they
+    /// are never found through the index either.
+    fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. }
+            | Command::Rm { key }
+            | Command::SetBlob { key, .. }
+            | Command::SetCompressed { key, .. }
+            | Command::SetBytes { key, .. } => key,
+            Command::Begin | Command::Commit => "",
+        }
+    }
+
+    /// Returns when the value set by the command expires, if ever.
+    fn expires(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires, .. }
+            | Command::SetBlob { expires, .. }
+            | Command::SetCompressed { expires, .. }
+            | Command::SetBytes { expires, .. } => *expires,
+            Command::Rm { .. } | Command::Begin | Command::Commit => None,
+        }
+    }
+}
+
+/// Compresses `value` with LZ4, prefixed with its uncompressed length.
+fn compress_value(value: &[u8]) -> Vec<u8> {
+    lz4_flex::compress_prepend_size(value)
+}
+
+/// Restores a value compressed by `compress_value`.
+fn decompress_value(value: &[u8]) -> Result<Vec<u8>> {
+    lz4_flex::decompress_size_prepended(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()).into())
 }
 
-/// The position and length of a serialized command in the log.
-#[derive(Copy, Clone, Debug)]
-struct CommandPos {
-    pos: u64,
-    len: u64,
+/// Turns the stored `value` of `key` into a string.
+fn into_string(key: &str, value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value).map_err(|_| KvsError::NotUtf8(key.to_owned()))
 }
 
-impl From<Range<u64>> for CommandPos {
-    fn from(range: Range<u64>) -> Self {
-        CommandPos {
-            pos: range.start,
-            len: range.end - range.start,
+/// Returns the bound above all keys starting with `prefix`: the smallest
+/// string after them, if there is one.
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        // UTF-8 orders strings like their chars, so the next char does
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return Bound::Excluded(end);
         }
     }
+    Bound::Unbounded
+}
+
+/// Returns the current time in milliseconds since the Unix epoch, which
+/// is how expiry times are stored.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns whether a value expiring at `expires` has expired.
+fn is_expired(expires: Option<u64>) -> bool {
+    expires.is_some_and(|expires| expires <= now_millis())
 }
 
 /// The `KvStore` stores string key/value pairs.
 ///
-/// Key/value pairs are persisted to disk in log file(s). The log file
-/// is named 'kvs.log' or 'kvs.log.new' if compaction is in progress.
-/// A `BTreeMap` in memory stores the keys and the value locations for
-/// fast query.
+/// Key/value pairs are persisted to disk in segment files named after
+/// their generation, e.g. '1.log', '3.log'. Commands are appended to the
+/// active segment, the one with the highest generation, until it reaches
+/// the maximum segment size; it is then sealed and a new one is started.
+/// A compaction merges the live entries of all sealed segments into a
+/// single new segment, leaving the active one as it is. Compactions run
+/// on a background thread of the store, which writes trigger once enough
+/// stale entries have piled up. A compaction in progress writes to
+/// 'compaction.tmp'.
+/// Every segment written by a compaction comes with a hint file, e.g.
+/// '2.hint', from which its index entries are loaded on open instead of
+/// replaying it.
+/// Every command carries a checksum that is verified whenever it is read;
+/// a corrupted record fails the read with `KvsError::Corruption`.
+/// All file names may start with a common prefix, see
+/// [`KvStoreBuilder::file_prefix`].
+/// A concurrent skip list in memory stores the keys and the value
+/// locations for fast query. Alternatively, the index can store only
+/// hashes of the keys, see [`IndexMode`].
+///
+/// A `KvStore` is a handle that can be cloned cheaply and shared between
+/// threads. Writes to the same key are carried out one at a time. Writes
+/// to different keys encode their records and sync the log in parallel,
+/// and only take turns appending to it. Writes only wait for a compaction
+/// while it starts and while it moves index entries over to its new
+/// segment. Reads never wait for writes or compactions: index entries
+/// are moved one by one, and the segments a compaction replaces stay
+/// readable until nobody reads from them anymore. Only reads of deduplicated values may wait
+/// for their blob file. Every clone reads through its own file handles,
+/// so threads that read a lot should each use their own clone.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
 /// # fn try_main() -> Result<()> {
 /// use std::env::current_dir;
-/// let mut store = KvStore::open(current_dir()?)?;
+/// let store = KvStore::open(current_dir()?)?;
 /// store.set("key".to_owned(), "value".to_owned())?;
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
@@ -69,16 +253,109 @@ impl From<Range<u64>> for CommandPos {
 /// # }
 /// ```
 pub struct KvStore {
-    // directory for the log data
-    path: PathBuf,
-    // log file reader
-    reader: BufReaderWithPos<File>,
-    // log file writer
-    writer: BufWriterWithPos<File>,
-    index: BTreeMap<String, CommandPos>,
-    // number of bytes occupied by "stale" commands that could be
-    // deleted during a compaction.
-    uncompacted: u64,
+    shared: Arc<Shared>,
+    // readers of this handle
+    readers: Mutex<ReaderCache>,
+}
+
+/// The state of a store shared by all its handles.
+struct Shared {
+    // names of the files in the directory for the log data
+    layout: Layout,
+    index: Index,
+    // all live segments, by generation. A segment is added before any
+    // index entry points into it, and removed after none does anymore.
+    // Removed entries are only dropped some time later, so they must not
+    // keep their segment alive.
+    segments: SkipMap<u64, WeakSegmentHandle>,
+    // incremented whenever segments are removed
+    epoch: AtomicU64,
+    // Writes to keys hashing to the same stripe serialize on its lock
+    // from start to end. Writes to other keys only wait for each other
+    // while appending their readily encoded records under `writer`.
+    stripes: Vec<Mutex<()>>,
+    writer: Mutex<Writer>,
+    // writes waiting to be synced
+    commits: CommitQueue,
+    // asks the compaction thread for a compaction, absent if the store is
+    // read-only
+    compactions: Option<Sender<()>>,
+    // notified whenever a compaction ends
+    compaction_done: Condvar,
+    // the most bytes per second a compaction copies, 0 if unlimited
+    compaction_rate_limit: AtomicU64,
+    // number of `KvStore` handles
+    handles: AtomicUsize,
+    // how long writes waited for `stripes` and `writer`
+    stripe_waits: Recorder,
+    writer_waits: Recorder,
+    // keys looked up and written, and compactions finished since opening
+    reads: AtomicU64,
+    writes: AtomicU64,
+    compactions_done: AtomicU64,
+    // the most recent compactions, oldest first
+    compaction_history: Mutex<VecDeque<CompactionReport>>,
+    read_only: bool,
+    // the locked lock file, absent if the store is read-only. The last
+    // handle unlocks it, as the compaction thread may keep the store alive
+    // a little longer.
+    dir_lock: Mutex<Option<File>>,
+    // present if values have ever been deduplicated in this store
+    blobs: Option<Mutex<BlobStore>>,
+    // values of at least this size are deduplicated
+    dedup_min_size: Option<usize>,
+    // values of at least this size are compressed
+    compress_min_size: Option<usize>,
+    // expiries are pushed back by up to this fraction of their TTL
+    ttl_jitter: f64,
+    // whether any value set since the store was opened, or loaded when it
+    // was, expires. Scans only know how many pairs they yield if none do.
+    expiring: AtomicBool,
+    // recently read values, if enabled
+    value_cache: Option<Mutex<ValueCache>>,
+    // when keys were last accessed, if tracked
+    access: Option<AccessTimes>,
+    listeners: RwLock<Vec<Arc<dyn EventListener>>>,
+    filters: RwLock<Vec<Arc<dyn CompactionFilter>>>,
+    key_policy: KeyPolicy,
+}
+
+/// The state only needed for writing, behind a single lock.
+struct Writer {
+    // readers on all segments, by generation
+    readers: BTreeMap<u64, SegmentReader>,
+    // generation of the active segment
+    gen: u64,
+    // writer on the active segment, absent if the store is read-only
+    writer: Option<BufWriterWithPos<File>>,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    // number of bytes occupied by "stale" commands in each segment that
+    // could be deleted during a compaction.
+    stale: BTreeMap<u64, u64>,
+    // the active segment is sealed once it reaches this size
+    segment_size: u64,
+    // the active segment is sealed before writes once its first write is
+    // this old
+    segment_max_age: Option<Duration>,
+    // when the first write to the active segment happened, if any
+    active_since: Option<SystemTime>,
+    // the sealed segments are compacted once they hold more stale bytes
+    compaction_threshold: u64,
+    // adapts the compaction threshold, if enabled
+    adaptive: Option<AdaptiveThreshold>,
+    // whether a compaction was asked for and has not ended yet
+    compacting: bool,
+    // the error of the last compaction, if it failed
+    compaction_error: Option<KvsError>,
+}
+
+/// Readers opened by a handle, by generation.
+#[derive(Default)]
+struct ReaderCache {
+    // the `Shared::epoch` the readers were last checked against
+    epoch: u64,
+    readers: BTreeMap<u64, SegmentReader>,
 }
 
 impl KvStore {
@@ -89,47 +366,538 @@ impl KvStore {
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during the log replay.
+    /// Returns `KvsError::Corruption` if a record fails its checksum.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let dir = path.into();
+        KvStore::builder().open(path)
+    }
+
+    /// Returns a builder to configure how a `KvStore` is opened.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Opens a `KvStore` with the given path, storing keys in the
+    /// in-memory index as specified by `mode`.
+    ///
+    /// The index is rebuilt on every open, so the same store may be
+    /// opened with a different mode later on.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_index_mode(path: impl Into<PathBuf>, mode: IndexMode) -> Result<KvStore> {
+        KvStore::builder().index_mode(mode).open(path)
+    }
+
+    pub(crate) fn init_with(dir: PathBuf, options: &KvStoreBuilder) -> Result<()> {
+        if options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
         fs::create_dir_all(&dir)?;
+        let layout = Layout::new(dir, options.file_prefix.clone());
+        detect_foreign_data(&layout)?;
+        ensure_no_store(&layout)?;
+        Manifest {
+            engine: manifest::ENGINE.to_owned(),
+            codec: manifest::CODEC.to_owned(),
+            compress_min_size: options.compress_min_size,
+        }
+        .write(&layout)?;
+        // opening creates the lock file and the first segment
+        KvStore::open_with(layout.dir().to_owned(), options).map(drop)
+    }
+
+    pub(crate) fn open_with(dir: PathBuf, options: &KvStoreBuilder) -> Result<KvStore> {
+        if options.file_prefix.ends_with(|c: char| c.is_ascii_digit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file prefix must not end with a digit",
+            )
+            .into());
+        }
+        let read_only = options.read_only;
+        if !read_only {
+            fs::create_dir_all(&dir)?;
+        }
+        let layout = Layout::new(dir, options.file_prefix.clone());
 
-        let path = dir.join("kvs.log");
-        let log = OpenOptions::new().create(true).write(true).open(&path)?;
-        let mut reader = BufReaderWithPos::new(File::open(&path)?)?;
-        let mut index = BTreeMap::new();
-        let uncompacted = load(&mut reader, &mut index)?;
+        let manifest = Manifest::read(&layout)?;
+        if let Some(manifest) = &manifest {
+            manifest.check(&layout)?;
+        }
+        detect_foreign_data(&layout)?;
+        let dir_lock = if read_only {
+            None
+        } else {
+            Some(lock_dir(&layout)?)
+        };
+        let gens = prepare_segments(&layout, read_only)?;
+        // a read-only store may not have any segment yet
+        let gen = gens.last().copied().unwrap_or(1);
+        let mut writer = if read_only {
+            None
+        } else {
+            Some(layout.segment(gen).open_writer()?)
+        };
+        let mut readers = BTreeMap::new();
+        let index = Index::new(options.index_mode);
+        let mut stale = BTreeMap::new();
+        let mut unfinished_batch = false;
+        let mut expiring = false;
+        for seg_gen in gens {
+            let segment = layout.segment(seg_gen);
+            readers.insert(seg_gen, segment.open_reader()?);
+            // only the active segment is ever appended to
+            let torn_tail = match options.recovery_mode {
+                RecoveryMode::TruncateTail if seg_gen == gen && read_only => TornTail::Skip,
+                RecoveryMode::TruncateTail if seg_gen == gen => TornTail::Truncate,
+                _ => TornTail::Fail,
+            };
+            let hints = hint::read_hints(&segment.hint_path()).unwrap_or_else(|e| {
+                log::warn!("ignoring hint file of segment {}: {}", seg_gen, e);
+                None
+            });
+            let open_batch = match hints {
+                Some(hints) => {
+                    expiring |= hints.expiring;
+                    load_hints(seg_gen, hints.entries, &mut readers, &index, &mut stale)?;
+                    false
+                }
+                None => load(
+                    seg_gen,
+                    torn_tail,
+                    &mut readers,
+                    &index,
+                    &mut stale,
+                    &mut expiring,
+                )?,
+            };
+            unfinished_batch = open_batch && seg_gen == gen;
+        }
+        let mut active_since = None;
+        if let Some(writer) = &mut writer {
+            // the active segment may have been truncated
+            let len = writer.seek(SeekFrom::End(0))?;
+            if len > readers[&gen].data_start() {
+                let metadata = writer.get_ref().metadata()?;
+                active_since = Some(metadata.created().unwrap_or_else(|_| SystemTime::now()));
+            }
+        }
 
-        let mut writer = BufWriterWithPos::new(log)?;
-        writer.seek(SeekFrom::End(0))?;
+        let blob_path = layout.file(BLOB_FILE);
+        let blobs = if blob_path.exists() || (options.dedup_min_size.is_some() && !read_only) {
+            Some(Mutex::new(BlobStore::open(blob_path, read_only)?))
+        } else {
+            None
+        };
 
-        Ok(KvStore {
-            path: dir,
-            reader,
-            writer,
+        let segments = SkipMap::new();
+        for (&gen, reader) in &readers {
+            segments.insert(gen, reader.segment().downgrade());
+        }
+        let legacy = readers
+            .get(&gen)
+            .is_some_and(|reader| reader.format() == Format::Legacy);
+        let (compactions, jobs) = if read_only {
+            (None, None)
+        } else {
+            let (compactions, jobs) = mpsc::channel();
+            (Some(compactions), Some(jobs))
+        };
+        let compaction_threshold = options.compaction_threshold.unwrap_or(COMPACTION_THRESHOLD);
+        let shared = Shared {
+            layout,
             index,
-            uncompacted,
+            segments,
+            epoch: AtomicU64::new(0),
+            stripes: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            writer: Mutex::new(Writer {
+                readers,
+                gen,
+                writer,
+                sync_policy: options.sync_policy,
+                last_sync: Instant::now(),
+                stale,
+                segment_size: options.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE),
+                segment_max_age: options.segment_max_age,
+                active_since,
+                compaction_threshold,
+                adaptive: options.max_space_amplification.map(|max_amplification| {
+                    AdaptiveThreshold {
+                        max_amplification,
+                        floor: compaction_threshold,
+                        write_rate: 0.0,
+                        segment_started: Instant::now(),
+                        compaction_time: Duration::ZERO,
+                    }
+                }),
+                compacting: false,
+                compaction_error: None,
+            }),
+            commits: CommitQueue::new(options.group_commit_latency),
+            compactions,
+            compaction_done: Condvar::new(),
+            compaction_rate_limit: AtomicU64::new(options.compaction_rate_limit.unwrap_or(0)),
+            handles: AtomicUsize::new(1),
+            stripe_waits: Recorder::default(),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            compactions_done: AtomicU64::new(0),
+            compaction_history: Mutex::new(VecDeque::with_capacity(COMPACTION_HISTORY)),
+            writer_waits: Recorder::default(),
+            read_only,
+            dir_lock: Mutex::new(dir_lock),
+            blobs,
+            dedup_min_size: options.dedup_min_size,
+            ttl_jitter: options.ttl_jitter,
+            expiring: AtomicBool::new(expiring),
+            // the builder overrides the manifest
+            compress_min_size: options
+                .compress_min_size
+                .or_else(|| manifest.and_then(|manifest| manifest.compress_min_size)),
+            value_cache: options
+                .value_cache_capacity
+                .map(|capacity| Mutex::new(ValueCache::new(capacity))),
+            access: options.track_idle_time.then(AccessTimes::new),
+            listeners: RwLock::new(Vec::new()),
+            filters: RwLock::new(Vec::new()),
+            key_policy: options.key_policy.clone(),
+        };
+        // records without checksums are never appended to, and neither are
+        // unfinished batches
+        if !read_only && (legacy || unfinished_batch) {
+            shared.seal(&mut shared.writer.lock().unwrap())?;
+        }
+        shared.writer.lock().unwrap().adapt_compaction_threshold()?;
+        let shared = Arc::new(shared);
+        if let Some(jobs) = jobs {
+            spawn_compactor(Arc::downgrade(&shared), jobs)?;
+        }
+        Ok(KvStore {
+            shared,
+            readers: Mutex::new(ReaderCache::default()),
+        })
+    }
+
+    /// Waits until the compaction running in the background, if any, has
+    /// ended.
+    ///
+    /// Writes start compactions without waiting for them. Dropping the
+    /// last handle to a store also waits for its compaction, so the store
+    /// can be reopened right away.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last compaction if it failed and the
+    /// error has not been returned before. A failed compaction is retried
+    /// by a later write.
+    pub fn wait_for_compaction(&self) -> Result<()> {
+        let mut w = self.shared.wait_for_compaction();
+        w.compaction_error.take().map_or(Ok(()), Err)
+    }
+
+    /// Compacts the whole log now, no matter how many stale entries it
+    /// holds, and waits for the compaction to finish.
+    ///
+    /// The active segment is sealed first, so that its entries are
+    /// compacted too. A compaction already running is waited for before.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::ReadOnly` if the store is read-only, or the
+    /// error of the compaction.
+    pub fn compact_now(&self) -> Result<()> {
+        let shared = &self.shared;
+        let compactions = shared.compactions.as_ref().ok_or(KvsError::ReadOnly)?;
+        let mut w = shared.wait_for_compaction();
+        // it has been logged already
+        w.compaction_error = None;
+        if w.active_len() > w.readers[&w.gen].data_start() {
+            shared.seal(&mut w)?;
+        }
+        if w.readers.len() == 1 {
+            return Ok(());
+        }
+        w.compacting = compactions.send(()).is_ok();
+        drop(w);
+        self.wait_for_compaction()
+    }
+
+    /// Changes the most bytes per second compactions copy, see
+    /// [`KvStoreBuilder::compaction_rate_limit`]. `None` lifts the limit.
+    /// Also applies to a compaction already running.
+    pub fn set_compaction_rate_limit(&self, bytes_per_sec: Option<u64>) {
+        self.shared
+            .compaction_rate_limit
+            .store(bytes_per_sec.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    /// Registers a listener that is notified of events in this store,
+    /// no matter through which handle they are triggered.
+    pub fn add_listener(&self, listener: Arc<dyn EventListener>) {
+        self.shared.listeners.write().unwrap().push(listener);
+    }
+
+    /// Registers a filter that every live entry is passed through when it
+    /// is compacted, after the filters registered before it. An entry
+    /// removed by one filter is not passed to the next.
+    pub fn add_compaction_filter(&self, filter: Arc<dyn CompactionFilter>) {
+        self.shared.filters.write().unwrap().push(filter);
+    }
+
+    /// Returns how long writes through any handle of this store waited for
+    /// its locks so far.
+    ///
+    /// Long waits for the stripes mean that writes to the same keys hold
+    /// each other up, while long waits for the writer mean that writes
+    /// are limited by appending to the log. Long waits for commits mean
+    /// that they are limited by syncing it, see
+    /// [`KvStoreBuilder::group_commit_latency`].
+    pub fn lock_waits(&self) -> LockWaits {
+        LockWaits {
+            writer: self.shared.writer_waits.snapshot(),
+            stripes: self.shared.stripe_waits.snapshot(),
+            commits: self.shared.commits.waits(),
+        }
+    }
+
+    /// Returns the hits and misses and the size of the value cache so far,
+    /// if the store was opened with one, see
+    /// [`KvStoreBuilder::value_cache`].
+    ///
+    /// Many misses despite a cache that is not full mean that gets are
+    /// spread over more keys than it can hold.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        let cache = self.shared.value_cache.as_ref()?;
+        Some(cache.lock().unwrap().stats())
+    }
+
+    /// Returns about how long ago `key` was last read or written, to the
+    /// second, like `OBJECT IDLETIME` of Redis. Returns `None` if the key
+    /// does not exist or the store was not opened with
+    /// [`KvStoreBuilder::track_idle_time`].
+    ///
+    /// This neither reads the value nor counts as an access itself.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::contains_key`].
+    pub fn idle_time(&self, key: String) -> Result<Option<Duration>> {
+        let access = match &self.shared.access {
+            Some(access) => access,
+            None => return Ok(None),
+        };
+        let key = self.shared.key_policy.apply(key)?;
+        let idle = access.idle_time(&key);
+        if !self.contains_key(key)? {
+            return Ok(None);
+        }
+        Ok(Some(idle))
+    }
+
+    /// Returns the size of the store and what it did since it was opened,
+    /// along with its [`lock_waits`](KvStore::lock_waits) and
+    /// [`cache_stats`](KvStore::cache_stats).
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while reading the sizes of the segments.
+    pub fn stats(&self) -> Result<Stats> {
+        let shared = &*self.shared;
+        let (sealed, active_len, stale_bytes, compaction_threshold) = {
+            let w = shared.writer.lock().unwrap();
+            let sealed: Vec<_> = w
+                .readers
+                .iter()
+                .filter(|(&gen, _)| gen != w.gen)
+                .map(|(_, reader)| reader.segment().clone())
+                .collect();
+            let stale_bytes = w.stale.values().sum();
+            (sealed, w.active_len(), stale_bytes, w.compaction_threshold)
+        };
+        // sealed segments never change, and the handles keep them from
+        // being deleted meanwhile
+        let mut log_bytes = active_len;
+        for segment in &sealed {
+            log_bytes += fs::metadata(segment.path())?.len();
+        }
+        Ok(Stats {
+            live_keys: shared.index.len() as u64,
+            log_bytes,
+            stale_bytes,
+            segments: sealed.len() as u64 + 1,
+            compaction_threshold,
+            compactions: shared.compactions_done.load(Ordering::Relaxed),
+            reads: shared.reads.load(Ordering::Relaxed),
+            writes: shared.writes.load(Ordering::Relaxed),
+            lock_waits: self.lock_waits(),
+            cache: self.cache_stats(),
+            compaction_history: self.compaction_history(),
         })
     }
 
+    /// Returns what the most recent compactions since the store was opened
+    /// did, oldest first. Up to 32 are kept.
+    pub fn compaction_history(&self) -> Vec<CompactionReport> {
+        let history = self.shared.compaction_history.lock().unwrap();
+        history.iter().cloned().collect()
+    }
+
+    /// Creates a copy of the store as it is now in the directory `dest`,
+    /// which can be opened and written to independently of this store.
+    ///
+    /// Sealed segments never change, so they are hard-linked rather than
+    /// copied where the file system allows it, and take no extra space
+    /// until one of the stores compacts them away. Only the active
+    /// segment, the manifest and the deduplicated values are copied.
+    /// Writes wait until the checkpoint is done.
+    ///
+    /// # Errors
+    ///
+    /// Fails with an `io::ErrorKind::AlreadyExists` error if `dest`
+    /// already holds a store, and propagates I/O errors otherwise.
+    pub fn checkpoint(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        let shared = &*self.shared;
+        let dest = shared.layout.with_dir(dest.into());
+        fs::create_dir_all(dest.dir())?;
+        ensure_no_store(&dest)?;
+
+        let w = shared.writer.lock().unwrap();
+        for (&gen, reader) in &w.readers {
+            let segment = reader.segment();
+            let target = dest.segment(gen);
+            if gen == w.gen && !shared.read_only {
+                // only what has been written so far
+                copy_file(segment.path(), target.path(), Some(w.active_len()))?;
+                continue;
+            }
+            link_or_copy(segment.path(), target.path())?;
+            let hints = segment.hint_path();
+            if hints.exists() {
+                link_or_copy(&hints, &target.hint_path())?;
+            }
+        }
+        manifest::copy(&shared.layout, &dest)?;
+        if let Some(blobs) = &shared.blobs {
+            // values are added and dropped under this lock
+            let _blobs = blobs.lock().unwrap();
+            let path = shared.layout.file(BLOB_FILE);
+            copy_file(&path, &dest.file(BLOB_FILE), None)?;
+        }
+        Ok(())
+    }
+
+    /// Returns every record in the log, by segment and offset, along with
+    /// whether the index points at it.
+    ///
+    /// This is meant for debugging the store, e.g. through `kvs
+    /// log-dump`. Writes and compactions wait until all segments have
+    /// been read. A segment is read up to the first record that cannot
+    /// be read, which is returned as `RecordKind::Unreadable`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors other than those reading a record.
+    pub fn log_records(&self) -> Result<Vec<LogRecord>> {
+        let mut writer = self.shared.writer_waits.lock(&self.shared.writer);
+        let mut records = Vec::new();
+        for (&gen, reader) in writer.readers.iter_mut() {
+            let end = reader.seek(SeekFrom::End(0))?;
+            let mut pos = reader.seek(SeekFrom::Start(reader.data_start()))?;
+            while pos < end {
+                let (kind, key) = match reader.read_record::<Command>(end - pos) {
+                    Ok(cmd) => match cmd {
+                        Command::Set { key, .. } => (RecordKind::Set, Some(key)),
+                        Command::SetBlob { key, .. } => (RecordKind::SetBlob, Some(key)),
+                        Command::SetCompressed { key, .. } => {
+                            (RecordKind::SetCompressed, Some(key))
+                        }
+                        Command::SetBytes { key, .. } => (RecordKind::SetBytes, Some(key)),
+                        Command::Rm { key } => (RecordKind::Rm, Some(key)),
+                        Command::Begin => (RecordKind::Begin, None),
+                        Command::Commit => (RecordKind::Commit, None),
+                    },
+                    Err(KvsError::Io(e)) => return Err(KvsError::Io(e)),
+                    Err(e) => {
+                        let reason = e.to_string();
+                        (RecordKind::Unreadable { reason }, None)
+                    }
+                };
+                let new_pos = match kind {
+                    RecordKind::Unreadable { .. } => end,
+                    _ => reader.pos(),
+                };
+                let cmd_pos = CommandPos::from((gen, pos..new_pos));
+                let live = match (&kind, &key) {
+                    (RecordKind::Set, Some(key))
+                    | (RecordKind::SetBlob, Some(key))
+                    | (RecordKind::SetCompressed, Some(key))
+                    | (RecordKind::SetBytes, Some(key)) => {
+                        self.shared.index.get(key) == Some(cmd_pos)
+                    }
+                    _ => false,
+                };
+                records.push(LogRecord {
+                    gen,
+                    offset: pos,
+                    len: new_pos - pos,
+                    kind,
+                    key,
+                    live,
+                });
+                pos = new_pos;
+            }
+        }
+        Ok(records)
+    }
+
     /// Gets the string value of a string key. Returns `None` if the
     /// given key does not exist.
     ///
     /// # Errors
     ///
     /// Returns `KvsError::UnexpectedCommandType` if an
-    /// unexpected command is found.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.index.get(&key) {
-            Some(cmd_pos) => {
-                let reader = &mut self.reader;
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-                let mut cmd_reader = reader.take(cmd_pos.len);
-                if let Command::Set { value, .. } = rmp_serde::from_read(&mut cmd_reader)? {
-                    Ok(Some(value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
-                }
-            }
+    /// unexpected command is found, and `KvsError::Corruption` if the
+    /// value's record is corrupted.
+    ///
+    /// Returns `KvsError::InvalidKey` if the key is rejected by the
+    /// store's `KeyPolicy`. The same applies to `set` and `remove`.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let key = self.shared.key_policy.apply(key)?;
+        let mut cache = self.readers.lock().unwrap();
+        self.lookup(&mut cache, &key)
+    }
+
+    /// Gets the values of several keys, in the order of `keys`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::get`] as soon as one of the keys fails.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let keys = keys
+            .iter()
+            .map(|key| self.shared.key_policy.apply(key.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let mut cache = self.readers.lock().unwrap();
+        keys.iter()
+            .map(|key| self.lookup(&mut cache, key))
+            .collect()
+    }
+
+    /// Looks up the value of `key`, which has passed the key policy.
+    fn lookup(&self, cache: &mut ReaderCache, key: &str) -> Result<Option<String>> {
+        Ok(self.lookup_expiring(cache, key)?.map(|(value, _)| value))
+    }
+
+    /// Looks up the value of `key`, which has passed the key policy,
+    /// along with when it expires.
+    fn lookup_expiring(
+        &self,
+        cache: &mut ReaderCache,
+        key: &str,
+    ) -> Result<Option<(String, Option<u64>)>> {
+        match self.shared.lookup_bytes(cache, key)? {
+            Some((value, expires)) => Ok(Some((into_string(key, value)?, expires))),
             None => Ok(None),
         }
     }
@@ -139,135 +907,2372 @@ impl KvStore {
     ///
     /// # Errors
     ///
+    /// Returns `KvsError::ReadOnly` if the store was opened read-only.
+    ///
     /// Errors encountered during I/O and serialization are
     /// propagated.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let pos = self.writer.pos();
-        // let old = self.index.insert(key.clone(), pos);
-        let cmd = Command::set(key, value);
-        rmp_serde::encode::write(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        let key = self.shared.key_policy.apply(key)?;
+        self.shared.set_many(vec![(key, value)], None)
+    }
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.insert(key, (pos..self.writer.pos()).into()) {
-                self.uncompacted += old_cmd.len;
-            }
-        } else {
-            unreachable!()
-        }
+    /// Gets the value of a string key as bytes. Returns `None` if the
+    /// given key does not exist.
+    ///
+    /// Values set as strings are returned as their UTF-8 encoding.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::get`].
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let key = self.shared.key_policy.apply(key)?;
+        let mut cache = self.readers.lock().unwrap();
+        Ok(self
+            .shared
+            .lookup_bytes(&mut cache, &key)?
+            .map(|(value, _)| value))
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
+    /// Sets the value of a string key to arbitrary bytes. If the key
+    /// already exists, the previous value will be overwritten.
+    ///
+    /// A value that is valid UTF-8 is stored like a string set with
+    /// [`KvStore::set`]. Any other value can only be read back with
+    /// [`KvStore::get_bytes`]; everything reading it as a string, e.g.
+    /// [`KvStore::get`] or a scan, fails with `KvsError::NotUtf8`. Such
+    /// values are never deduplicated and are not passed to compaction
+    /// filters.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::set`].
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let key = self.shared.key_policy.apply(key)?;
+        let shared = &*self.shared;
+        let _stripes = shared.lock_keys(iter::once(key.as_str()));
+        shared.write_bytes(key, value, None)
+    }
 
-        Ok(())
+    /// Sets the value of a string key to arbitrary bytes like
+    /// [`KvStore::set_bytes`], expiring at `expires`, in milliseconds since
+    /// the Unix epoch, if given. Used by imports, which keep the expiry of
+    /// each key as it is.
+    pub(crate) fn set_bytes_until(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires: Option<u64>,
+    ) -> Result<()> {
+        let key = self.shared.key_policy.apply(key)?;
+        let shared = &*self.shared;
+        let _stripes = shared.lock_keys(iter::once(key.as_str()));
+        shared.write_bytes(key, value, expires)
     }
 
-    /// Removes a given key.
+    /// Sets the value of a string key to a string that expires after
+    /// `ttl`. Once it has expired, the key is treated as absent when
+    /// read, and its entry is dropped by the first lookup of the key or
+    /// the next compaction of its segment, whichever comes first, which
+    /// is reported to the listeners as an [`Evicted`] event. Expiring
+    /// writes no tombstones. The expiry is pushed back
+    /// if the store has a [TTL jitter](KvStoreBuilder::ttl_jitter).
+    ///
+    /// The index is not aware of expiry, so [`KvStore::len`],
+    /// [`KvStore::keys`], [`KvStore::count`] and
+    /// [`KvStore::contains_key`] still include expired keys until
+    /// then.
     ///
     /// # Errors
     ///
-    /// Returns `KvsError::NonExistentKey` if the given key is not
-    /// found.
+    /// Fails like [`KvStore::set`].
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let key = self.shared.key_policy.apply(key)?;
+        let expires = self.shared.expiry(&key, ttl);
+        self.shared.set_many(vec![(key, value)], Some(expires))
+    }
+
+    /// Returns how long the value of `key` has left until it expires, or
+    /// `None` if it never does.
     ///
-    /// Errors encountered during I/O or serialization are propagated.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let pos = self.writer.pos();
-        match self.index.remove(&key) {
-            Some(old_cmd) => {
-                let cmd = Command::remove(key);
-                rmp_serde::encode::write(&mut self.writer, &cmd)?;
-                self.writer.flush()?;
-
-                let new_pos = self.writer.pos();
-                self.uncompacted += new_pos - pos;
-                self.uncompacted += old_cmd.len;
-                if self.uncompacted > COMPACTION_THRESHOLD {
-                    self.compact()?;
-                }
+    /// # Errors
+    ///
+    /// Returns `KvsError::NonExistentKey` if the key does not exist or
+    /// has expired. Fails like [`KvStore::get`] otherwise.
+    pub fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let key = self.shared.key_policy.apply(key)?;
+        let mut cache = self.readers.lock().unwrap();
+        match self.lookup_expiring(&mut cache, &key)? {
+            Some((_, expires)) => {
+                Ok(expires
+                    .map(|expires| Duration::from_millis(expires.saturating_sub(now_millis()))))
+            }
+            None => Err(KvsError::NonExistentKey(key)),
+        }
+    }
 
-                Ok(())
+    /// Makes the value of `key` never expire. Returns whether it was set
+    /// to expire before.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::NonExistentKey` if the key does not exist or
+    /// has expired. Fails like [`KvStore::set`] otherwise.
+    pub fn persist(&self, key: String) -> Result<bool> {
+        let key = self.shared.key_policy.apply(key)?;
+        let shared = &*self.shared;
+        let _stripes = shared.lock_keys(iter::once(key.as_str()));
+        let found = {
+            let mut cache = self.readers.lock().unwrap();
+            self.shared.lookup_bytes(&mut cache, &key)?
+        };
+        match found {
+            Some((_, None)) => Ok(false),
+            Some((value, Some(_))) => {
+                shared.write_bytes(key, value, None)?;
+                Ok(true)
             }
             None => Err(KvsError::NonExistentKey(key)),
         }
     }
 
-    /// Clears stale entries in the log.
+    /// Sets several key/value pairs, in order.
     ///
-    /// Compaction is carried out by creating a new log file, copying
-    /// all the live commands as found in the index over to the new
-    /// log, and replacing the old log with the new one.
-    fn compact(&mut self) -> Result<()> {
-        log::trace!("Starting compaction...");
-        log::trace!("Index size: {}", self.index.len());
-        log::trace!("Uncompacted: {}", self.uncompacted);
+    /// All of them are appended to the log at once, with a single flush
+    /// and at most a single sync, which is much faster than setting them
+    /// one by one. The segment they go to may grow beyond the segment
+    /// size.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::set`]. No pair is set if a key is rejected
+    /// by the key policy, but if writing to the log fails, some of the
+    /// pairs may turn up once the store is opened again.
+    pub fn set_many(&self, entries: Vec<(String, String)>) -> Result<()> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| Ok((self.shared.key_policy.apply(key)?, value)))
+            .collect::<Result<Vec<_>>>()?;
+        self.shared.set_many(entries, None)
+    }
 
-        let new_path = self.path.join("new.log");
-        dbg!(&new_path);
-        let new_log = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&new_path)?;
+    /// Returns an entry for `key`, to get its value or insert one if there
+    /// is none in a single step.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let store = KvStore::open(current_dir()?)?;
+    /// let config = store.entry("config".to_owned())?.or_insert_with(|| "{}".to_owned())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidKey` if the key is rejected by the
+    /// store's `KeyPolicy`.
+    pub fn entry(&self, key: String) -> Result<Entry<'_>> {
+        let key = self.shared.key_policy.apply(key)?;
+        Ok(Entry::new(self, key))
+    }
 
-        let mut compaction_writer = BufWriterWithPos::new(new_log)?;
-        for cmd_pos in self.index.values_mut() {
-            let pos = cmd_pos.pos;
-            if self.reader.pos() != pos {
-                self.reader.seek(SeekFrom::Start(pos))?;
+    /// Gets the value of `key`, which has passed the key policy, or sets
+    /// it to `default()` if there is none. No other write to the key can
+    /// come in between.
+    pub(crate) fn get_or_insert_with<F>(&self, key: String, default: F) -> Result<String>
+    where
+        F: FnOnce() -> String,
+    {
+        self.update(key, |found| match found {
+            Some(value) => Ok((None, value)),
+            None => {
+                let value = default();
+                Ok((Some(value.clone()), value))
             }
-            let start = compaction_writer.pos();
-            let reader = &mut self.reader;
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = std::io::copy(&mut entry_reader, &mut compaction_writer)?;
+        })
+    }
+
+    /// Sets the value of `key` to `new` if it is `expected`, where `None`
+    /// stands for a missing key. Returns whether the value was set.
+    ///
+    /// No other write to the key can come in between the comparison and
+    /// the write, so concurrent clients can update a value optimistically
+    /// by reading it, and swapping it for the updated value until that
+    /// succeeds.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::get`] and [`KvStore::set`].
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        let key = self.shared.key_policy.apply(key)?;
+        self.update(key, |found| {
+            if found == expected {
+                Ok((Some(new), true))
+            } else {
+                Ok((None, false))
+            }
+        })
+    }
+
+    /// Adds `delta` to the value of `key`, which has to be a decimal
+    /// integer, and returns the result. A missing key counts as 0.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidCounter` if the value is not an integer
+    /// or the result overflows an `i64`. Fails like [`KvStore::get`] and
+    /// [`KvStore::set`] otherwise.
+    pub fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let key = self.shared.key_policy.apply(key)?;
+        let invalid = |key: &str, reason: &str| KvsError::InvalidCounter {
+            key: key.to_owned(),
+            reason: reason.to_owned(),
+        };
+        self.update(key.clone(), |found| {
+            let value: i64 = match found {
+                Some(value) => value.parse().map_err(|_| invalid(&key, "not an integer"))?,
+                None => 0,
+            };
+            let value = value
+                .checked_add(delta)
+                .ok_or_else(|| invalid(&key, "overflow"))?;
+            Ok((Some(value.to_string()), value))
+        })
+    }
 
-            *cmd_pos = (start..start + len).into();
+    /// Looks up the value of `key`, which has passed the key policy, and
+    /// passes it to `f`. If `f` returns a new value, the key is set to it,
+    /// expiring when the old value would have. No other write to the key
+    /// can come in between.
+    fn update<T, F>(&self, key: String, f: F) -> Result<T>
+    where
+        F: FnOnce(Option<String>) -> Result<(Option<String>, T)>,
+    {
+        let shared = &*self.shared;
+        let _stripes = shared.lock_keys(iter::once(key.as_str()));
+        let found = {
+            let mut cache = self.readers.lock().unwrap();
+            self.lookup_expiring(&mut cache, &key)?
+        };
+        let (found, expires) =
+            found.map_or((None, None), |(value, expires)| (Some(value), expires));
+        let (value, result) = f(found)?;
+        if let Some(value) = value {
+            shared.write_sets(vec![(key, value)], expires)?;
         }
-        compaction_writer.flush()?;
+        Ok(result)
+    }
 
-        let from = new_path;
-        let to = self.path.join("kvs.log");
-        fs::rename(from, &to)?;
-        self.writer = compaction_writer;
-        self.reader = BufReaderWithPos::new(File::open(to)?)?;
-        log::trace!("Compaction finished");
-        Ok(())
+    /// Applies all writes of `batch`, in order.
+    ///
+    /// The writes are appended to the log between markers that make them
+    /// apply all or not at all when the log is replayed after a crash.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::NonExistentKey` if the batch removes a key that
+    /// neither exists nor is set earlier in the batch. Fails like
+    /// [`KvStore::set`] otherwise. No write of the batch applies if it
+    /// fails.
+    pub fn commit(&self, batch: WriteBatch) -> Result<()> {
+        let policy = &self.shared.key_policy;
+        let ops = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Ok(BatchOp::Set {
+                    key: policy.apply(key)?,
+                    value,
+                }),
+                BatchOp::Remove { key } => Ok(BatchOp::Remove {
+                    key: policy.apply(key)?,
+                }),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.shared.commit(ops)
     }
-}
 
-/// Load the whole log file and store value locations in the index map.
-///
-/// Returns how many bytes can be saved after a compaction.
-fn load(
-    mut reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    let mut uncompacted = 0;
-    let end = reader.seek(SeekFrom::End(0))?;
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not
+    /// found.
+    ///
+    /// Returns `KvsError::ReadOnly` if the store was opened read-only.
+    ///
+    /// Errors encountered during I/O or serialization are propagated.
+    pub fn remove(&self, key: String) -> Result<()> {
+        let key = self.shared.key_policy.apply(key)?;
+        self.shared.remove(key)
+    }
 
-    loop {
-        if pos >= end {
-            return Ok(uncompacted);
-        }
+    /// Returns the number of live keys.
+    pub fn len(&self) -> usize {
+        self.shared.index.len()
+    }
 
-        let cmd: Command = rmp_serde::from_read(&mut reader)?;
-        let new_pos = reader.pos();
+    /// Returns whether the store has no live keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        use Command::*;
-        match cmd {
-            Set { key, .. } => {
-                index.insert(key, (pos..new_pos).into());
-            }
-            Rm { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
-                } else {
-                    log::warn!("log out of sync: missing key in index for remove command.");
-                }
-                uncompacted += new_pos - pos;
-            }
-        };
-        pos = new_pos;
+    /// Returns whether `key` is live.
+    ///
+    /// This only consults the index, except if the index stores hashed
+    /// keys and holds the hash of `key`. Then the key found under that
+    /// hash is read back from the log.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidKey` if the key is rejected by the
+    /// store's `KeyPolicy`.
+    pub fn contains_key(&self, key: String) -> Result<bool> {
+        let key = self.shared.key_policy.apply(key)?;
+        let shared = &*self.shared;
+        let mut cache = self.readers.lock().unwrap();
+        cache.retry(shared, |cache| {
+            let mut moved = false;
+            let found = shared
+                .index
+                .contains(&key, |p| cache.resolve_key(shared, p, &mut moved))?;
+            Ok(Some(found).filter(|_| !moved))
+        })
+    }
+
+    /// Returns an iterator over all live keys, in ascending order.
+    ///
+    /// The keys are those live when this is called. They are taken from
+    /// the index, unless it stores hashed keys, in which case they are
+    /// read back from the log.
+    pub fn keys(&self) -> Result<Keys> {
+        let shared = &*self.shared;
+        let mut cache = self.readers.lock().unwrap();
+        let keys = cache.retry(shared, |cache| {
+            let mut moved = false;
+            let keys = shared
+                .index
+                .keys(|p| cache.resolve_key(shared, p, &mut moved))?;
+            Ok(Some(keys).filter(|_| !moved))
+        })?;
+        Ok(Keys(keys.into_iter()))
+    }
+
+    /// Returns the number of live keys starting with `prefix`.
+    ///
+    /// This only consults the index. If the index stores hashed keys,
+    /// the keys have to be read back from the log unless `prefix` is
+    /// empty.
+    pub fn count(&self, prefix: &str) -> Result<usize> {
+        let mut cache = self.readers.lock().unwrap();
+        Ok(self.positions(&mut cache, prefix)?.len())
     }
+
+    /// Returns the number of bytes occupied in the log by the live
+    /// entries of keys starting with `prefix`, including the encoding
+    /// overhead of their records.
+    ///
+    /// Like [`KvStore::count`], values are never read to compute this.
+    pub fn usage(&self, prefix: &str) -> Result<u64> {
+        let mut cache = self.readers.lock().unwrap();
+        let positions = self.positions(&mut cache, prefix)?;
+        Ok(positions.iter().map(|cmd_pos| cmd_pos.len).sum())
+    }
+
+    /// Returns an iterator over the live key/value pairs with keys in
+    /// `range`, in ascending key order.
+    ///
+    /// Keys are ordered by their bytes, like `Ord` for `str`, whatever the
+    /// [`IndexMode`]. The iterator is double-ended, so `.rev()` yields
+    /// the pairs in descending key order instead.
+    ///
+    /// Only the keys are looked up when this is called. Values are read
+    /// from the log as the iterator advances, see [`SnapshotIter`].
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let store = KvStore::open(current_dir()?)?;
+    /// for entry in store.scan("a".to_owned().."n".to_owned())? {
+    ///     let (key, value) = entry?;
+    ///     println!("{} = {}", key, value);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while looking up the keys. If the index
+    /// stores hashed keys, every key has to be read back from the log.
+    pub fn scan(&self, range: impl RangeBounds<String>) -> Result<SnapshotIter> {
+        let range = (
+            range.start_bound().map(String::as_str),
+            range.end_bound().map(String::as_str),
+        );
+        self.scan_index(range, "")
+    }
+
+    /// Returns an iterator over the live key/value pairs with keys
+    /// starting with `prefix`, in ascending key order, like
+    /// [`KvStore::scan`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::scan`].
+    pub fn scan_prefix(&self, prefix: &str) -> Result<SnapshotIter> {
+        self.scan_index((Bound::Included(prefix), Bound::Unbounded), prefix)
+    }
+
+    /// Returns an iterator over the live key/value pairs with keys from
+    /// `key` on, in ascending key order, like [`KvStore::scan`].
+    ///
+    /// This resumes a scan at a key, e.g. to page through a store: a page
+    /// of `n` pairs takes `n + 1` of them, and the key of the last one is
+    /// where the next page starts. [`KvStore::scan_page`] does the same
+    /// with cursors that can be handed out.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// # let store = KvStore::open(std::env::current_dir()?)?;
+    /// let mut cursor = String::new();
+    /// loop {
+    ///     let mut page = store.scan_from(&cursor)?.take(101).collect::<Result<Vec<_>>>()?;
+    ///     let next = if page.len() == 101 { page.pop() } else { None };
+    ///     for (key, value) in page {
+    ///         println!("{} = {}", key, value);
+    ///     }
+    ///     match next {
+    ///         Some((key, _)) => cursor = key,
+    ///         None => break,
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::scan`].
+    pub fn scan_from(&self, key: &str) -> Result<SnapshotIter> {
+        self.scan_index((Bound::Included(key), Bound::Unbounded), "")
+    }
+
+    /// Returns a page of up to `count` live key/value pairs, at least 1,
+    /// with keys after `cursor`, or from the first key without one, in
+    /// ascending key order, like [`KvStore::scan`]. The page ends with the
+    /// cursor of the next one, which stays valid across compactions and
+    /// restarts, see [`Cursor`].
+    ///
+    /// ```rust
+    /// # use kvs::{Cursor, KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// # let store = KvStore::open(std::env::current_dir()?)?;
+    /// let mut cursor: Option<Cursor> = None;
+    /// loop {
+    ///     let page = store.scan_page(cursor.as_ref(), 100)?;
+    ///     for (key, value) in page.entries {
+    ///         println!("{} = {}", key, value);
+    ///     }
+    ///     match page.next {
+    ///         Some(next) => cursor = Some(next),
+    ///         None => break,
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::scan`].
+    pub fn scan_page(&self, cursor: Option<&Cursor>, count: usize) -> Result<Page> {
+        Page::take(self.scan(Cursor::range(cursor))?, count)
+    }
+
+    /// Returns an iterator over the key/value pairs with keys in `range`,
+    /// in ascending key order, as they are when it reaches them.
+    ///
+    /// Unlike [`KvStore::scan`], nothing is looked up when this is called
+    /// and no segment is kept around. Every key is looked up once the
+    /// iterator reaches it, see [`LiveIter`].
+    ///
+    /// # Errors
+    ///
+    /// If the index stores hashed keys, their order is only known by
+    /// reading all of them back from the log when this is called, which
+    /// propagates I/O errors.
+    pub fn scan_live(&self, range: impl RangeBounds<String>) -> Result<LiveIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.live_iter(range, String::new())
+    }
+
+    /// Returns an iterator over the key/value pairs with keys starting
+    /// with `prefix`, in ascending key order, like
+    /// [`KvStore::scan_live`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`KvStore::scan_live`].
+    pub fn scan_prefix_live(&self, prefix: &str) -> Result<LiveIter> {
+        let range = (Bound::Included(prefix.to_owned()), prefix_end(prefix));
+        self.live_iter(range, prefix.to_owned())
+    }
+
+    fn live_iter(&self, range: (Bound<String>, Bound<String>), prefix: String) -> Result<LiveIter> {
+        let shared = &*self.shared;
+        let keys = match shared.index.mode() {
+            IndexMode::Ordered => LiveKeys::Ordered {
+                after: range.0,
+                end: range.1,
+            },
+            IndexMode::Hashed => {
+                let mut cache = self.readers.lock().unwrap();
+                let keys = cache.retry(shared, |cache| {
+                    let mut moved = false;
+                    let keys = shared
+                        .index
+                        .keys(|p| cache.resolve_key(shared, p, &mut moved))?;
+                    Ok(Some(keys).filter(|_| !moved))
+                })?;
+                let keys: Vec<_> = keys
+                    .into_iter()
+                    .filter(|key| RangeBounds::<String>::contains(&range, key))
+                    .collect();
+                LiveKeys::Listed(keys.into_iter())
+            }
+        };
+        Ok(LiveIter {
+            shared: Arc::clone(&self.shared),
+            cache: ReaderCache::default(),
+            keys,
+            prefix,
+        })
+    }
+
+    fn scan_index(&self, range: (Bound<&str>, Bound<&str>), prefix: &str) -> Result<SnapshotIter> {
+        // the scan has readers of its own, so that the segments it reads
+        // stay around and the handle can be used in the meantime
+        let mut cache = ReaderCache::default();
+        let positions = self.collect_positions(&mut cache, |index, resolve, visit| {
+            index.visit_range(range, prefix, resolve, visit)
+        })?;
+        Ok(SnapshotIter {
+            shared: Arc::clone(&self.shared),
+            cache,
+            positions: positions.into_iter(),
+        })
+    }
+
+    /// Calls `f` with every live key/value pair, in index order.
+    ///
+    /// The pairs are those live when this is called; writes in the
+    /// meantime are not visited.
+    pub(crate) fn visit_entries<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.visit_expiring_entries(|key, value, _| f(key, value))
+    }
+
+    /// Calls `f` with every live key/value pair and when it expires, in
+    /// milliseconds since the Unix epoch, like [`KvStore::visit_entries`].
+    pub(crate) fn visit_expiring_entries<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String, Option<u64>) -> Result<()>,
+    {
+        let mut cache = self.readers.lock().unwrap();
+        for cmd_pos in self.positions(&mut cache, "")? {
+            let (key, value, expires) = self.shared.read_expiring_entry(&mut cache, cmd_pos)?;
+            if !is_expired(expires) {
+                f(key, value, expires)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the positions of the live commands of all keys starting
+    /// with `prefix`, in index order. `cache` has readers on all of their
+    /// segments until it is refreshed.
+    fn positions(&self, cache: &mut ReaderCache, prefix: &str) -> Result<Vec<CommandPos>> {
+        self.collect_positions(cache, |index, resolve, visit| {
+            index.visit_prefix(prefix, resolve, visit)
+        })
+    }
+
+    /// Returns the positions visited by `visit_index`, like
+    /// [`KvStore::positions`].
+    fn collect_positions<V>(
+        &self,
+        cache: &mut ReaderCache,
+        mut visit_index: V,
+    ) -> Result<Vec<CommandPos>>
+    where
+        V: FnMut(
+            &Index,
+            &mut dyn FnMut(CommandPos) -> Result<String>,
+            &mut dyn FnMut(CommandPos),
+        ) -> Result<()>,
+    {
+        let shared = &*self.shared;
+        cache.retry(shared, |cache| {
+            cache.pin(shared)?;
+            let mut positions = Vec::new();
+            let mut moved = false;
+            visit_index(
+                &shared.index,
+                &mut |p| cache.resolve_key(shared, p, &mut moved),
+                &mut |p| positions.push(p),
+            )?;
+            for cmd_pos in &positions {
+                moved |= !cache.prepare(shared, cmd_pos.gen)?;
+            }
+            Ok(Some(positions).filter(|_| !moved))
+        })
+    }
+}
+
+/// An iterator over a snapshot of the key/value pairs of a `KvStore`,
+/// returned by [`KvStore::scan`] and [`KvStore::scan_prefix`].
+///
+/// It yields the pairs that were live when it was created, except for
+/// those that have expired by the time they are reached. Writes after
+/// that are not seen, and the segments holding the pairs are kept on disk
+/// until the iterator is dropped, even if they are compacted. A value
+/// that cannot be read is yielded as an error, and the iteration may go
+/// on after it.
+///
+/// The positions of all pairs are held in memory from the start. See
+/// [`LiveIter`] for an iterator that holds on to nothing. Its size hint is
+/// exact unless values with a TTL have been set in the store, as any of
+/// them may expire before they are reached.
+pub struct SnapshotIter {
+    shared: Arc<Shared>,
+    cache: ReaderCache,
+    positions: vec::IntoIter<CommandPos>,
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for cmd_pos in &mut self.positions {
+            match self.shared.read_entry(&mut self.cache, cmd_pos) {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                // expired
+                Ok(None) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.shared.expiring.load(Ordering::SeqCst) {
+            (0, self.positions.size_hint().1)
+        } else {
+            self.positions.size_hint()
+        }
+    }
+}
+
+impl DoubleEndedIterator for SnapshotIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(cmd_pos) = self.positions.next_back() {
+            match self.shared.read_entry(&mut self.cache, cmd_pos) {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                // expired
+                Ok(None) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the key/value pairs of a `KvStore` as they are when
+/// it reaches them, returned by [`KvStore::scan_live`] and
+/// [`KvStore::scan_prefix_live`].
+///
+/// Every key is looked up in the index only when the previous one has
+/// been yielded, and its value is read right away. Keys are yielded in
+/// ascending order, each at most once, but there is no point in time at
+/// which the pairs it yields were all live together: a key written ahead
+/// of the iterator is seen with its new value, one removed ahead of it is
+/// skipped, and one written behind it is missed. Compaction goes on
+/// undisturbed, since the iterator holds on to no segment.
+///
+/// If the index stores hashed keys, the keys are the ones live when the
+/// iterator was created, but their values are still read as they are
+/// reached.
+///
+/// It is double-ended: keys taken from the back are yielded in descending
+/// order, and both ends stop where they meet.
+///
+/// Use a [`SnapshotIter`] to see a consistent set of pairs instead.
+pub struct LiveIter {
+    shared: Arc<Shared>,
+    cache: ReaderCache,
+    keys: LiveKeys,
+    // every key yielded starts with it
+    prefix: String,
+}
+
+/// Where a `LiveIter` takes its next key from.
+enum LiveKeys {
+    /// The keys of an ordered index after `after` and before `end`,
+    /// looked up one at a time from either end.
+    Ordered {
+        after: Bound<String>,
+        end: Bound<String>,
+    },
+    /// The keys of a hashed index, listed in order upfront.
+    Listed(vec::IntoIter<String>),
+}
+
+impl LiveKeys {
+    fn next(&mut self, index: &Index) -> Option<String> {
+        match self {
+            LiveKeys::Ordered { after, end } => {
+                let key = index.first_key((
+                    after.as_ref().map(String::as_str),
+                    end.as_ref().map(String::as_str),
+                ))?;
+                *after = Bound::Excluded(key.clone());
+                Some(key)
+            }
+            LiveKeys::Listed(keys) => keys.next(),
+        }
+    }
+
+    fn next_back(&mut self, index: &Index) -> Option<String> {
+        match self {
+            LiveKeys::Ordered { after, end } => {
+                let key = index.last_key((
+                    after.as_ref().map(String::as_str),
+                    end.as_ref().map(String::as_str),
+                ))?;
+                *end = Bound::Excluded(key.clone());
+                Some(key)
+            }
+            LiveKeys::Listed(keys) => keys.next_back(),
+        }
+    }
+}
+
+impl LiveIter {
+    /// Looks up the value of `key`, or returns `None` if it has been
+    /// removed or has expired since it was found.
+    fn lookup(&mut self, key: String) -> Option<Result<(String, String)>> {
+        self.shared
+            .lookup_bytes(&mut self.cache, &key)
+            .and_then(|found| found.map(|(value, _)| into_string(&key, value)).transpose())
+            .transpose()
+            .map(|value| value.map(|value| (key, value)))
+    }
+}
+
+impl Iterator for LiveIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next(&self.shared.index)?;
+            if !key.starts_with(&self.prefix) {
+                // keys starting with the prefix are next to each other
+                if key.as_str() > self.prefix.as_str() {
+                    return None;
+                }
+                continue;
+            }
+            if let Some(entry) = self.lookup(key) {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for LiveIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next_back(&self.shared.index)?;
+            if !key.starts_with(&self.prefix) {
+                if key.as_str() < self.prefix.as_str() {
+                    return None;
+                }
+                continue;
+            }
+            if let Some(entry) = self.lookup(key) {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+/// An iterator over the live keys of a `KvStore`, returned by
+/// [`KvStore::keys`].
+#[derive(Debug)]
+pub struct Keys(vec::IntoIter<String>);
+
+impl Iterator for Keys {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Keys {
+    fn next_back(&mut self) -> Option<String> {
+        self.0.next_back()
+    }
+}
+
+impl ExactSizeIterator for Keys {}
+
+impl Clone for KvStore {
+    /// Returns another handle to the same store, with its own readers.
+    fn clone(&self) -> KvStore {
+        self.shared.handles.fetch_add(1, Ordering::SeqCst);
+        KvStore {
+            shared: Arc::clone(&self.shared),
+            readers: Mutex::new(ReaderCache::default()),
+        }
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if self.shared.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            drop(self.shared.wait_for_compaction());
+            self.shared.dir_lock.lock().unwrap().take();
+        }
+    }
+}
+
+impl ReaderCache {
+    /// Calls `attempt` until it returns a result, which it does not if a
+    /// command it looked up in the index was moved by a compaction before
+    /// it could be read. Readers on removed segments are dropped before
+    /// every attempt.
+    fn retry<T, F>(&mut self, shared: &Shared, mut attempt: F) -> Result<T>
+    where
+        F: FnMut(&mut ReaderCache) -> Result<Option<T>>,
+    {
+        loop {
+            let epoch = shared.epoch.load(Ordering::SeqCst);
+            if self.epoch != epoch {
+                self.readers
+                    .retain(|gen, _| shared.segments.contains_key(gen));
+                self.epoch = epoch;
+            }
+            if let Some(result) = attempt(self)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Makes sure there is a reader on the segment of generation `gen`.
+    /// Returns `false` if there is no such segment anymore.
+    fn prepare(&mut self, shared: &Shared, gen: u64) -> Result<bool> {
+        if let btree_map::Entry::Vacant(entry) = self.readers.entry(gen) {
+            match shared.segments.get(&gen).and_then(|e| e.value().upgrade()) {
+                Some(segment) => {
+                    entry.insert(segment.open_reader()?);
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Makes sure there are readers on all segments, so the commands
+    /// currently in the index stay readable.
+    fn pin(&mut self, shared: &Shared) -> Result<()> {
+        for segment in shared.segments.iter() {
+            if let btree_map::Entry::Vacant(entry) = self.readers.entry(*segment.key()) {
+                if let Some(handle) = segment.value().upgrade() {
+                    entry.insert(handle.open_reader()?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the key of the command at the given position, or returns
+    /// `None` if its segment is gone.
+    fn read_key(&mut self, shared: &Shared, cmd_pos: CommandPos) -> Result<Option<String>> {
+        if !self.prepare(shared, cmd_pos.gen)? {
+            return Ok(None);
+        }
+        read_key(&mut self.readers, cmd_pos).map(Some)
+    }
+
+    /// Reads the key of the command at the given position for an index
+    /// lookup. If its segment is gone, an empty key is returned and
+    /// `moved` is set, so that the attempt can be retried.
+    fn resolve_key(
+        &mut self,
+        shared: &Shared,
+        cmd_pos: CommandPos,
+        moved: &mut bool,
+    ) -> Result<String> {
+        match self.read_key(shared, cmd_pos)? {
+            Some(key) => Ok(key),
+            None => {
+                *moved = true;
+                Ok(String::new())
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// Looks up the value of `key`, which has passed the key policy,
+    /// along with when it expires, without requiring it to be UTF-8.
+    ///
+    /// An expired value is dropped from the index on the way, see
+    /// [`Shared::expire`].
+    fn lookup_bytes(
+        &self,
+        cache: &mut ReaderCache,
+        key: &str,
+    ) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let mut expired = None;
+        let found = cache.retry(self, |cache| {
+            let cmd_pos = match self.index.get(key) {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(Some(None)),
+            };
+            let cached = self
+                .value_cache
+                .as_ref()
+                .and_then(|values| values.lock().unwrap().get(cmd_pos));
+            let (found, value, expires) = match cached {
+                Some(entry) => entry,
+                None => {
+                    if !cache.prepare(self, cmd_pos.gen)? {
+                        return Ok(None);
+                    }
+                    let (found, value, expires) = self.read_stored_entry(cache, cmd_pos)?;
+                    if let Some(values) = &self.value_cache {
+                        values
+                            .lock()
+                            .unwrap()
+                            .insert(cmd_pos, &found, &value, expires);
+                    }
+                    (found, value, expires)
+                }
+            };
+            // a hashed index may point at a colliding key
+            if found != key {
+                return Ok(Some(None));
+            }
+            if is_expired(expires) {
+                expired = Some(cmd_pos);
+                return Ok(Some(None));
+            }
+            if let Some(access) = &self.access {
+                access.touch(key);
+            }
+            Ok(Some(Some((value, expires))))
+        })?;
+        if let Some(cmd_pos) = expired {
+            self.expire(key, cmd_pos)?;
+        }
+        Ok(found)
+    }
+
+    /// Returns whether `key`, which has passed the key policy, is set and
+    /// has not expired, dropping its entry if it has. Unlike
+    /// [`Shared::lookup_bytes`], it is neither a read nor an access.
+    fn is_live(&self, cache: &mut ReaderCache, key: &str) -> Result<bool> {
+        let mut expired = None;
+        let live = cache.retry(self, |cache| {
+            let cmd_pos = match self.index.get(key) {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(Some(false)),
+            };
+            if !cache.prepare(self, cmd_pos.gen)? {
+                return Ok(None);
+            }
+            let cmd = read_command(&mut cache.readers, cmd_pos)?;
+            // a hashed index may point at a colliding key
+            if cmd.key() != key {
+                return Ok(Some(false));
+            }
+            if is_expired(cmd.expires()) {
+                expired = Some(cmd_pos);
+                return Ok(Some(false));
+            }
+            Ok(Some(true))
+        })?;
+        if let Some(cmd_pos) = expired {
+            self.expire(key, cmd_pos)?;
+        }
+        Ok(live)
+    }
+
+    /// Drops the expired entry of `key` at `cmd_pos` from the index,
+    /// unless it has been written since, and reports it as evicted. Its
+    /// record stays in the log until a compaction, which skips it like
+    /// any other expired entry.
+    fn expire(&self, key: &str, cmd_pos: CommandPos) -> Result<()> {
+        {
+            let mut w = self.writer.lock().unwrap();
+            if self.index.get(key) != Some(cmd_pos) {
+                return Ok(());
+            }
+            let readers = &mut w.readers;
+            self.index.remove(key, |p| read_key(readers, p))?;
+            *w.stale.entry(cmd_pos.gen).or_default() += cmd_pos.len;
+        }
+        self.report_evictions(vec![key.to_owned()]);
+        Ok(())
+    }
+
+    /// Tells the listeners that the expired entries of `keys` were
+    /// dropped.
+    fn report_evictions(&self, keys: Vec<String>) {
+        let listeners = self.listeners.read().unwrap();
+        for key in keys {
+            if let Some(access) = &self.access {
+                access.forget(&key);
+            }
+            let event = Evicted { key };
+            for listener in listeners.iter() {
+                listener.on_eviction(&event);
+            }
+        }
+    }
+
+    /// Notes the access of a key by `cmd` if idle times are tracked, or
+    /// forgets the key if it removes it.
+    fn note_access(&self, cmd: &Command) {
+        if let Some(access) = &self.access {
+            match cmd {
+                Command::Rm { key } => access.forget(key),
+                Command::Begin | Command::Commit => (),
+                _ => access.touch(cmd.key()),
+            }
+        }
+    }
+
+    /// Returns when a value of `key` set now with `ttl` expires, pushed
+    /// back by up to the TTL jitter of the store.
+    fn expiry(&self, key: &str, ttl: Duration) -> u64 {
+        let ttl = ttl.as_millis().min(u64::MAX as u128) as u64;
+        let max_jitter = (ttl as f64 * self.ttl_jitter) as u64;
+        let jitter = match max_jitter {
+            0 => 0,
+            max_jitter => index::hash_key(key) % (max_jitter + 1),
+        };
+        now_millis().saturating_add(ttl).saturating_add(jitter)
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>, expires: Option<u64>) -> Result<()> {
+        let _stripes = self.lock_keys(entries.iter().map(|(key, _)| key.as_str()));
+        self.write_sets(entries, expires)
+    }
+
+    /// Sets the given pairs, whose keys have their stripes locked by the
+    /// caller, to expire at `expires`.
+    fn write_sets(&self, entries: Vec<(String, String)>, expires: Option<u64>) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let cmds = entries
+            .into_iter()
+            .map(|(key, value)| self.encode_set(key, value, expires))
+            .collect::<Result<Vec<_>>>()?;
+        self.write_commands(cmds)
+    }
+
+    /// Sets `key`, whose stripe is locked by the caller, to the bytes
+    /// `value` until `expires`.
+    fn write_bytes(&self, key: String, value: Vec<u8>, expires: Option<u64>) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let cmd = self.encode_set_bytes(key, value, expires)?;
+        self.write_commands(vec![cmd])
+    }
+
+    /// Appends the given commands, whose keys have their stripes locked by
+    /// the caller, and applies them to the index.
+    fn write_commands(&self, cmds: Vec<Command>) -> Result<()> {
+        if cmds.iter().any(|cmd| cmd.expires().is_some()) {
+            // before any scan can see them
+            self.expiring.store(true, Ordering::SeqCst);
+        }
+        let count = cmds.len() as u64;
+        let mut records = Vec::new();
+        let mut ranges = Vec::with_capacity(cmds.len());
+        for cmd in &cmds {
+            let start = records.len() as u64;
+            segment::write_record(&mut records, cmd)?;
+            ranges.push(start..records.len() as u64);
+        }
+
+        let sync = {
+            let mut writer = self.writer_waits.lock(&self.writer);
+            let w = &mut *writer;
+            if w.expired() {
+                self.seal(w)?;
+            }
+            let start = w.write(&records)?;
+            let sync = w.commit(&self.commits)?;
+            for (cmd, range) in cmds.into_iter().zip(ranges) {
+                let gen = w.gen;
+                let range = start + range.start..start + range.end;
+                self.note_access(&cmd);
+                apply(gen, cmd, range, &mut w.readers, &self.index, &mut w.stale)?;
+            }
+            self.maintain(w)?;
+            sync
+        };
+        self.writes.fetch_add(count, Ordering::Relaxed);
+        self.commits.wait(sync)
+    }
+
+    fn commit(&self, ops: Vec<BatchOp>) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let _stripes = self.lock_keys(ops.iter().map(BatchOp::key));
+        let count = ops.len() as u64;
+        let mut cmds = Vec::with_capacity(ops.len() + 2);
+        cmds.push(Command::Begin);
+        for op in ops {
+            cmds.push(match op {
+                BatchOp::Set { key, value } => self.encode_set(key, value, None)?,
+                BatchOp::Remove { key } => Command::remove(key),
+            });
+        }
+        cmds.push(Command::Commit);
+        let mut records = Vec::new();
+        let mut ranges = Vec::with_capacity(cmds.len());
+        for cmd in &cmds {
+            let start = records.len() as u64;
+            segment::write_record(&mut records, cmd)?;
+            ranges.push(start..records.len() as u64);
+        }
+
+        let sync = {
+            let mut writer = self.writer_waits.lock(&self.writer);
+            let w = &mut *writer;
+            // removed keys have to exist, unless set earlier in the batch
+            let mut live = HashMap::new();
+            for cmd in &cmds {
+                match cmd {
+                    Command::Rm { key } => {
+                        let readers = &mut w.readers;
+                        let exists = match live.get(key.as_str()) {
+                            Some(&exists) => exists,
+                            None => self.index.contains(key, |p| read_key(readers, p))?,
+                        };
+                        if !exists {
+                            return Err(KvsError::NonExistentKey(key.clone()));
+                        }
+                        live.insert(key.as_str(), false);
+                    }
+                    Command::Set { key, .. }
+                    | Command::SetBlob { key, .. }
+                    | Command::SetCompressed { key, .. }
+                    | Command::SetBytes { key, .. } => {
+                        live.insert(key.as_str(), true);
+                    }
+                    Command::Begin | Command::Commit => (),
+                }
+            }
+
+            // the batch has to end up in a single segment
+            if w.expired() {
+                self.seal(w)?;
+            }
+            let start = w.write(&records)?;
+            let sync = w.commit(&self.commits)?;
+            for (cmd, range) in cmds.into_iter().zip(ranges) {
+                let gen = w.gen;
+                let range = start + range.start..start + range.end;
+                self.note_access(&cmd);
+                apply(gen, cmd, range, &mut w.readers, &self.index, &mut w.stale)?;
+            }
+            self.maintain(w)?;
+            sync
+        };
+        self.writes.fetch_add(count, Ordering::Relaxed);
+        self.commits.wait(sync)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let _stripes = self.lock_keys(iter::once(key.as_str()));
+        let mut record = Vec::new();
+        segment::write_record(&mut record, &Command::remove(key.clone()))?;
+
+        let sync = {
+            let mut writer = self.writer_waits.lock(&self.writer);
+            let w = &mut *writer;
+            if w.expired() {
+                self.seal(w)?;
+            }
+            let readers = &mut w.readers;
+            let old_cmd = match self.index.remove(&key, |p| read_key(readers, p))? {
+                Some(old_cmd) => old_cmd,
+                None => return Err(KvsError::NonExistentKey(key)),
+            };
+            w.write(&record)?;
+            let sync = w.commit(&self.commits)?;
+            *w.stale.entry(w.gen).or_default() += record.len() as u64;
+            *w.stale.entry(old_cmd.gen).or_default() += old_cmd.len;
+            if let Some(access) = &self.access {
+                access.forget(&key);
+            }
+            self.maintain(w)?;
+            sync
+        };
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.commits.wait(sync)
+    }
+
+    /// Locks the stripes of `keys`, in ascending order so that writers
+    /// locking several of them cannot deadlock.
+    fn lock_keys<'a>(&self, keys: impl Iterator<Item = &'a str>) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys
+            .map(|key| (index::hash_key(key) % self.stripes.len() as u64) as usize)
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let mut start = None;
+        let guards = stripes
+            .into_iter()
+            .map(|stripe| {
+                let stripe = &self.stripes[stripe];
+                stripe.try_lock().unwrap_or_else(|_| {
+                    start.get_or_insert_with(Instant::now);
+                    stripe.lock().unwrap()
+                })
+            })
+            .collect();
+        let wait = start.map_or(Duration::ZERO, |start| start.elapsed());
+        self.stripe_waits.record(wait);
+        guards
+    }
+
+    /// Reads the key and value set by the command at the given position,
+    /// whose segment has to be prepared in `cache`. Returns `None` if the
+    /// value has expired.
+    fn read_entry(
+        &self,
+        cache: &mut ReaderCache,
+        cmd_pos: CommandPos,
+    ) -> Result<Option<(String, String)>> {
+        let (key, value, expires) = self.read_expiring_entry(cache, cmd_pos)?;
+        Ok(Some((key, value)).filter(|_| !is_expired(expires)))
+    }
+
+    /// Reads the key and value set by the command at the given position,
+    /// like [`Shared::read_entry`], along with when the value expires.
+    fn read_expiring_entry(
+        &self,
+        cache: &mut ReaderCache,
+        cmd_pos: CommandPos,
+    ) -> Result<(String, String, Option<u64>)> {
+        let (key, value, expires) = self.read_stored_entry(cache, cmd_pos)?;
+        let value = into_string(&key, value)?;
+        Ok((key, value, expires))
+    }
+
+    /// Reads the key and value set by the command at the given position
+    /// like [`Shared::read_expiring_entry`], with the value as it is
+    /// stored, which need not be UTF-8.
+    fn read_stored_entry(
+        &self,
+        cache: &mut ReaderCache,
+        cmd_pos: CommandPos,
+    ) -> Result<(String, Vec<u8>, Option<u64>)> {
+        let cmd = read_command(&mut cache.readers, cmd_pos);
+        if let Err(KvsError::Corruption { path, offset, .. }) = &cmd {
+            log::error!(
+                "Corrupted record at offset {} of {}",
+                offset,
+                path.display()
+            );
+            let event = CorruptionDetected {
+                path: path.clone(),
+                offset: *offset,
+            };
+            for listener in self.listeners.read().unwrap().iter() {
+                listener.on_corruption_detected(&event);
+            }
+        }
+        match cmd? {
+            Command::Set {
+                key,
+                value,
+                expires,
+            } => Ok((key, value.into_bytes(), expires)),
+            Command::SetBlob { key, id, expires } => {
+                Ok((key, self.read_blob(id)?.into_bytes(), expires))
+            }
+            Command::SetCompressed {
+                key,
+                value,
+                expires,
+            } => Ok((key, decompress_value(&value)?, expires)),
+            Command::SetBytes {
+                key,
+                value,
+                expires,
+            } => Ok((key, value, expires)),
+            Command::Rm { .. } | Command::Begin | Command::Commit => {
+                Err(KvsError::UnexpectedCommandType)
+            }
+        }
+    }
+
+    /// Drops the cached values of segments that have been removed, which
+    /// are all older than the oldest one left.
+    fn forget_removed_values(&self, w: &Writer) {
+        if let (Some(values), Some(&gen)) = (&self.value_cache, w.readers.keys().next()) {
+            values.lock().unwrap().forget_before(gen);
+        }
+    }
+
+    /// Reads the value stored in the blob with the given id.
+    fn read_blob(&self, id: u64) -> Result<String> {
+        let blobs = self.blobs.as_ref().ok_or(KvsError::UnexpectedCommandType)?;
+        blobs.lock().unwrap().get(id)
+    }
+
+    /// Returns the command setting `key` to `value` until `expires`,
+    /// storing the value in the `BlobStore` if it is large enough to be
+    /// deduplicated, or else compressing it if it is large enough and
+    /// that makes it smaller.
+    fn encode_set(&self, key: String, value: String, expires: Option<u64>) -> Result<Command> {
+        if let (Some(blobs), Some(min_size)) = (&self.blobs, self.dedup_min_size) {
+            if value.len() >= min_size {
+                let id = blobs.lock().unwrap().put(value)?;
+                return Ok(Command::SetBlob { key, id, expires });
+            }
+        }
+        match self.compress(value.as_bytes()) {
+            Some(value) => Ok(Command::SetCompressed {
+                key,
+                value,
+                expires,
+            }),
+            None => Ok(Command::set(key, value, expires)),
+        }
+    }
+
+    /// Returns the command setting `key` to the bytes `value` until
+    /// `expires`. Values that are valid UTF-8 are encoded like strings.
+    fn encode_set_bytes(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires: Option<u64>,
+    ) -> Result<Command> {
+        let value = match String::from_utf8(value) {
+            Ok(value) => return self.encode_set(key, value, expires),
+            Err(e) => e.into_bytes(),
+        };
+        match self.compress(&value) {
+            Some(value) => Ok(Command::SetCompressed {
+                key,
+                value,
+                expires,
+            }),
+            None => Ok(Command::SetBytes {
+                key,
+                value,
+                expires,
+            }),
+        }
+    }
+
+    /// Compresses `value` if it is large enough and that makes it smaller.
+    fn compress(&self, value: &[u8]) -> Option<Vec<u8>> {
+        let min_size = self.compress_min_size?;
+        if value.len() < min_size {
+            return None;
+        }
+        let compressed = compress_value(value);
+        Some(compressed).filter(|compressed| compressed.len() < value.len())
+    }
+
+    /// Passes the live command `cmd` through `filters`, and returns the
+    /// command to keep in its place, if any.
+    fn filter(
+        &self,
+        filters: &[Arc<dyn CompactionFilter>],
+        cmd: Command,
+    ) -> Result<Option<Command>> {
+        if filters.is_empty() {
+            return Ok(Some(cmd));
+        }
+        let stored;
+        let value = match &cmd {
+            Command::Set { value, .. } => value,
+            Command::SetBlob { id, .. } => {
+                stored = self.read_blob(*id)?;
+                &stored
+            }
+            Command::SetCompressed { value, .. } => {
+                match String::from_utf8(decompress_value(value)?) {
+                    Ok(value) => stored = value,
+                    // bytes are kept as they are
+                    Err(_) => return Ok(Some(cmd)),
+                }
+                &stored
+            }
+            Command::SetBytes { .. } => return Ok(Some(cmd)),
+            Command::Rm { .. } | Command::Begin | Command::Commit => {
+                return Err(KvsError::UnexpectedCommandType)
+            }
+        };
+
+        let mut transformed = None;
+        for filter in filters {
+            let current = transformed.as_ref().unwrap_or(value);
+            match filter.filter(cmd.key(), current) {
+                FilterDecision::Keep => (),
+                FilterDecision::Remove => return Ok(None),
+                FilterDecision::Transform(value) => transformed = Some(value),
+            }
+        }
+        match transformed {
+            Some(value) => self
+                .encode_set(cmd.key().to_owned(), value, cmd.expires())
+                .map(Some),
+            None => Ok(Some(cmd)),
+        }
+    }
+
+    /// Seals the active segment if it is full, drops sealed segments that
+    /// hold only stale entries, and asks the compaction thread to compact
+    /// the sealed segments if they still hold enough stale entries.
+    ///
+    /// Sealed segments are left alone while a compaction is running.
+    fn maintain(&self, w: &mut Writer) -> Result<()> {
+        if w.active_len() >= w.segment_size {
+            self.seal(w)?;
+        }
+        if w.compacting {
+            return Ok(());
+        }
+        self.drop_stale_segments(w)?;
+        if w.sealed_stale() > w.compaction_threshold {
+            if let Some(compactions) = &self.compactions {
+                w.compacting = compactions.send(()).is_ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until no compaction is running, and returns the writer
+    /// state.
+    fn wait_for_compaction(&self) -> MutexGuard<'_, Writer> {
+        let mut w = self.writer.lock().unwrap();
+        while w.compacting {
+            w = self.compaction_done.wait(w).unwrap();
+        }
+        w
+    }
+
+    /// Seals the active segment and starts a new one.
+    ///
+    /// The generations of consecutive active segments are two apart, so
+    /// there is always a free generation right below the active segment
+    /// for a compaction to write to.
+    fn seal(&self, w: &mut Writer) -> Result<()> {
+        let event = SegmentSealed {
+            gen: w.gen,
+            bytes: w.active_len(),
+        };
+        w.gen += 2;
+        let segment = self.layout.segment(w.gen);
+        w.writer = Some(segment.open_writer()?);
+        w.active_since = None;
+        w.readers.insert(w.gen, segment.open_reader()?);
+        self.segments.insert(w.gen, segment.downgrade());
+        if let Some(adaptive) = &mut w.adaptive {
+            adaptive.sealed(event.bytes);
+        }
+        w.adapt_compaction_threshold()?;
+        log::trace!("Sealed segment {}", event.gen);
+
+        for listener in self.listeners.read().unwrap().iter() {
+            listener.on_segment_sealed(&event);
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest sealed segments for as long as all of their
+    /// entries are stale, which is much cheaper than a compaction.
+    ///
+    /// Younger segments are left alone even if they are entirely stale:
+    /// a removal in them may still shadow an entry of an older segment
+    /// when the log is replayed.
+    fn drop_stale_segments(&self, w: &mut Writer) -> Result<()> {
+        let mut dropped = Vec::new();
+        while let Some((&gen, reader)) = w.readers.iter().next() {
+            if gen == w.gen {
+                break;
+            }
+            let bytes = fs::metadata(reader.segment().path())?.len();
+            let stale = w.stale.get(&gen).copied().unwrap_or(0);
+            if stale < bytes.saturating_sub(reader.data_start()) {
+                break;
+            }
+            if let Some(reader) = w.readers.remove(&gen) {
+                reader.segment().retire();
+            }
+            self.segments.remove(&gen);
+            w.stale.remove(&gen);
+            log::trace!("Dropped stale segment {}", gen);
+            dropped.push(SegmentDropped { gen, bytes });
+        }
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.forget_removed_values(w);
+
+        let listeners = self.listeners.read().unwrap();
+        for event in &dropped {
+            for listener in listeners.iter() {
+                listener.on_segment_dropped(event);
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears stale entries in the sealed segments. Runs on the
+    /// compaction thread, see [`spawn_compactor`].
+    ///
+    /// Compaction is carried out by creating a new segment, copying all
+    /// the live commands of the sealed segments as found in the index
+    /// over to it, and deleting the sealed segments. Its generation lies
+    /// between theirs and that of the active segment, which is left as it
+    /// is. Commands are re-encoded on the way, so records of legacy
+    /// segments gain checksums, and passed through the compaction filters,
+    /// which may remove or rewrite them.
+    ///
+    /// Reads and writes go on in the meantime, with writes going to newer
+    /// segments. Once the new segment is complete, the writer lock is
+    /// taken and the index entries that still point into the sealed
+    /// segments are moved over to it, and only then are the sealed
+    /// segments removed.
+    fn compact(&self) -> Result<()> {
+        let started = Instant::now();
+        let (compaction_gen, stale_bytes, sealed) = {
+            let mut w = self.writer.lock().unwrap();
+            // a store written by an older version may use that generation
+            if w.readers.contains_key(&(w.gen - 1)) {
+                self.seal(&mut w)?;
+            }
+            let sealed: Vec<_> = w
+                .readers
+                .range(..w.gen - 1)
+                .map(|(_, reader)| reader.segment().clone())
+                .collect();
+            (w.gen - 1, w.sealed_stale(), sealed)
+        };
+        let mut sealed_bytes = 0;
+        for segment in sealed {
+            sealed_bytes += fs::metadata(segment.path())?.len();
+        }
+        let index = &self.index;
+
+        log::trace!("Starting compaction...");
+        log::trace!("Index size: {}", index.len());
+        log::trace!("Uncompacted: {}", stale_bytes);
+        let event = CompactionStarted {
+            live_keys: index.len(),
+            stale_bytes,
+        };
+        for listener in self.listeners.read().unwrap().iter() {
+            listener.on_compaction_start(&event);
+        }
+
+        let compaction_path = self.layout.file(COMPACTION_FILE);
+        let new_log = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&compaction_path)?;
+
+        let mut compaction_writer = BufWriterWithPos::new(new_log)?;
+        segment::write_header(&mut compaction_writer)?;
+        let hint_path = self.layout.file(COMPACTION_HINT_FILE);
+        let mut hints = HintWriter::new(BufWriter::new(File::create(&hint_path)?))?;
+        // Only this compaction removes the sealed segments, so they stay
+        // readable through the readers opened here.
+        let mut cache = ReaderCache::default();
+        let mut positions = Vec::with_capacity(index.len());
+        index.visit_prefix(
+            "",
+            |p| {
+                cache.prepare(self, p.gen)?;
+                read_key(&mut cache.readers, p)
+            },
+            |p| {
+                if p.gen < compaction_gen {
+                    positions.push(p);
+                }
+            },
+        )?;
+        let filters = self.filters.read().unwrap().clone();
+        let mut throttle = Throttle::new();
+        let mut live_blobs = HashSet::new();
+        let mut moves = HashMap::new();
+        let mut removed = Vec::new();
+        let mut evicted = Vec::new();
+        let mut bytes_read = 0;
+        for cmd_pos in positions {
+            cache.prepare(self, cmd_pos.gen)?;
+            let reader = segment_reader(&mut cache.readers, cmd_pos.gen);
+            if reader.pos() != cmd_pos.pos {
+                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            }
+            let cmd: Command = reader.read_record(cmd_pos.len)?;
+            bytes_read += cmd_pos.len;
+            if is_expired(cmd.expires()) {
+                evicted.push((cmd.key().to_owned(), cmd_pos));
+                continue;
+            }
+            let key = cmd.key().to_owned();
+            let cmd = match self.filter(&filters, cmd)? {
+                Some(cmd) => cmd,
+                None => {
+                    removed.push((key, cmd_pos));
+                    continue;
+                }
+            };
+            if let Command::SetBlob { id, .. } = cmd {
+                live_blobs.insert(id);
+            }
+
+            let start = compaction_writer.pos();
+            segment::write_record(&mut compaction_writer, &cmd)?;
+            let new_pos: CommandPos = (compaction_gen, start..compaction_writer.pos()).into();
+            hints.add(cmd.key(), new_pos.pos, new_pos.len, cmd.expires().is_some())?;
+            moves.insert(cmd_pos, new_pos);
+            throttle.copied(
+                new_pos.len,
+                self.compaction_rate_limit.load(Ordering::SeqCst),
+            );
+        }
+        compaction_writer.flush()?;
+        compaction_writer.get_ref().sync_data()?;
+        let hint_file = hints.finish()?.into_inner().map_err(|e| e.into_error())?;
+        hint_file.sync_data()?;
+        drop(cache);
+
+        // Expired entries leave the index ahead of the install, a batch
+        // at a time, so that many keys expiring at once hold up writes
+        // for no longer than a batch each.
+        let records_dropped = (evicted.len() + removed.len()) as u64;
+        let mut evicted_keys = Vec::new();
+        for batch in evicted.chunks(EVICTION_BATCH) {
+            let mut w = self.writer.lock().unwrap();
+            let readers = &mut w.readers;
+            for (key, cmd_pos) in batch {
+                if index.get(key) == Some(*cmd_pos) {
+                    index.remove(key, |p| read_key(readers, p))?;
+                    evicted_keys.push(key.clone());
+                }
+            }
+        }
+
+        // The rename atomically publishes the compacted segment. The
+        // sealed segments are deleted oldest first, as soon as nobody
+        // reads from them anymore; the log replays correctly after a
+        // crash at any point in between.
+        // The hint only counts once its segment is in place.
+        let mut writer = self.writer.lock().unwrap();
+        let w = &mut *writer;
+        let segment = self.layout.segment(compaction_gen);
+        fs::rename(&compaction_path, segment.path())?;
+        fs::rename(&hint_path, segment.hint_path())?;
+        w.readers.insert(compaction_gen, segment.open_reader()?);
+        self.segments.insert(compaction_gen, segment.downgrade());
+        // the copies of entries written in the meantime are stale already
+        let copied: u64 = moves.values().map(|p| p.len).sum();
+        let moved: u64 = index.relocate(&moves).iter().map(|p| p.len).sum();
+        if copied > moved {
+            *w.stale.entry(compaction_gen).or_default() += copied - moved;
+        }
+        let readers = &mut w.readers;
+        for (key, cmd_pos) in removed {
+            if index.get(&key) == Some(cmd_pos) {
+                index.remove(&key, |p| read_key(readers, p))?;
+            }
+        }
+        if self.blobs.is_some() {
+            // values written in the meantime may share the same blobs
+            let mut newer = Vec::new();
+            index.visit_prefix(
+                "",
+                |p| read_key(readers, p),
+                |p| {
+                    if p.gen > compaction_gen {
+                        newer.push(p);
+                    }
+                },
+            )?;
+            for cmd_pos in newer {
+                if let Command::SetBlob { id, .. } = read_command(readers, cmd_pos)? {
+                    live_blobs.insert(id);
+                }
+            }
+        }
+        let sealed: Vec<u64> = w
+            .readers
+            .range(..compaction_gen)
+            .map(|(&gen, _)| gen)
+            .collect();
+        for gen in sealed {
+            if let Some(reader) = w.readers.remove(&gen) {
+                reader.segment().retire();
+            }
+            self.segments.remove(&gen);
+            w.stale.remove(&gen);
+        }
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.forget_removed_values(w);
+        if let Some(blobs) = &self.blobs {
+            blobs.lock().unwrap().retain(&live_blobs)?;
+        }
+        if let Some(adaptive) = &mut w.adaptive {
+            adaptive.compaction_time = started.elapsed();
+        }
+        w.adapt_compaction_threshold()?;
+        let live_keys = index.len();
+        drop(writer);
+        self.compactions_done.fetch_add(1, Ordering::Relaxed);
+        let report = CompactionReport {
+            finished_at: SystemTime::now(),
+            duration: started.elapsed(),
+            sealed_bytes,
+            bytes_read,
+            bytes_written: compaction_writer.pos(),
+            records_dropped,
+        };
+        log::debug!("Compaction finished: {:?}", report);
+        {
+            let mut history = self.compaction_history.lock().unwrap();
+            if history.len() == COMPACTION_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(report);
+        }
+
+        self.report_evictions(evicted_keys);
+        let listeners = self.listeners.read().unwrap();
+        let event = CompactionFinished {
+            live_keys,
+            log_bytes: compaction_writer.pos(),
+        };
+        for listener in listeners.iter() {
+            listener.on_compaction_finish(&event);
+        }
+        Ok(())
+    }
+}
+
+/// Paces the bytes a compaction copies.
+struct Throttle {
+    // the limit in bytes per second, 0 if unlimited
+    limit: u64,
+    start: Instant,
+    // bytes copied since `start`
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new() -> Throttle {
+        Throttle {
+            limit: 0,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` more as copied, and sleeps for as long as copying
+    /// is ahead of `limit` bytes per second, unless `limit` is 0. A new
+    /// limit only applies to the bytes copied from then on.
+    fn copied(&mut self, bytes: u64, limit: u64) {
+        if limit != self.limit {
+            *self = Throttle {
+                limit,
+                ..Throttle::new()
+            };
+        }
+        self.bytes += bytes;
+        if limit == 0 {
+            return;
+        }
+        let due = Duration::from_secs_f64(self.bytes as f64 / limit as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+impl Writer {
+    /// Writes encoded `records` to the buffer of the active segment, and
+    /// returns the position they start at.
+    fn write(&mut self, records: &[u8]) -> Result<u64> {
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let pos = writer.pos();
+        writer.write_all(records)?;
+        Ok(pos)
+    }
+
+    /// Flushes what was written to the active segment. If the sync policy
+    /// requires it to be synced, queues it in `commits` and returns the
+    /// ticket to wait for, which is left to the caller so that other
+    /// writers need not wait for the sync.
+    fn commit(&mut self, commits: &CommitQueue) -> Result<Option<u64>> {
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        writer.flush()?;
+        self.active_since.get_or_insert_with(SystemTime::now);
+
+        let sync = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if !sync {
+            return Ok(None);
+        }
+        self.last_sync = Instant::now();
+        let file = writer.get_ref().try_clone()?;
+        Ok(Some(commits.enqueue(self.gen, file)))
+    }
+
+    /// Returns the size of the active segment.
+    fn active_len(&self) -> u64 {
+        self.writer.as_ref().map_or(0, |writer| writer.pos())
+    }
+
+    /// Returns whether the first write to the active segment is older
+    /// than the maximum segment age.
+    fn expired(&self) -> bool {
+        match (self.segment_max_age, self.active_since) {
+            (Some(max_age), Some(since)) => since.elapsed().is_ok_and(|age| age >= max_age),
+            _ => false,
+        }
+    }
+
+    /// Returns the number of stale bytes in all sealed segments.
+    fn sealed_stale(&self) -> u64 {
+        self.stale
+            .iter()
+            .filter(|&(&gen, _)| gen != self.gen)
+            .map(|(_, bytes)| bytes)
+            .sum()
+    }
+
+    /// Recomputes the compaction threshold from the size of the log, if
+    /// it adapts.
+    fn adapt_compaction_threshold(&mut self) -> Result<()> {
+        let adaptive = match &self.adaptive {
+            Some(adaptive) => adaptive,
+            None => return Ok(()),
+        };
+        let mut log_bytes = self.active_len();
+        for (&gen, reader) in &self.readers {
+            if gen != self.gen {
+                log_bytes += fs::metadata(reader.segment().path())?.len();
+            }
+        }
+        let stale_bytes = self.stale.values().sum();
+        let active_stale = self.stale.get(&self.gen).copied().unwrap_or(0);
+        let threshold = adaptive.threshold(log_bytes, stale_bytes, active_stale);
+        if threshold != self.compaction_threshold {
+            log::trace!("Compaction threshold adapted to {}", threshold);
+            self.compaction_threshold = threshold;
+        }
+        Ok(())
+    }
+}
+
+/// Adapts the compaction threshold to bound the space amplification of
+/// the log, see [`KvStoreBuilder::adaptive_compaction`].
+struct AdaptiveThreshold {
+    // the most bytes the log may take per live byte
+    max_amplification: f64,
+    // the least threshold, as set by the builder
+    floor: u64,
+    // bytes per second written to the log, smoothed over segments
+    write_rate: f64,
+    // when the active segment was started
+    segment_started: Instant,
+    // how long the last compaction took
+    compaction_time: Duration,
+}
+
+impl AdaptiveThreshold {
+    /// Accounts for the active segment being sealed with `bytes`.
+    fn sealed(&mut self, bytes: u64) {
+        let elapsed = self.segment_started.elapsed().as_secs_f64();
+        self.segment_started = Instant::now();
+        if elapsed == 0.0 {
+            return;
+        }
+        let rate = bytes as f64 / elapsed;
+        self.write_rate = if self.write_rate == 0.0 {
+            rate
+        } else {
+            WRITE_RATE_WEIGHT * rate + (1.0 - WRITE_RATE_WEIGHT) * self.write_rate
+        };
+    }
+
+    /// Returns the stale bytes in the sealed segments that should trigger
+    /// a compaction of a log of `log_bytes` with `stale_bytes`, of which
+    /// `active_stale` are in the active segment and out of its reach.
+    fn threshold(&self, log_bytes: u64, stale_bytes: u64, active_stale: u64) -> u64 {
+        let live = log_bytes.saturating_sub(stale_bytes) as f64;
+        let allowance = live * (self.max_amplification - 1.0) - active_stale as f64;
+        // writes go on while a compaction runs, and should fit as well,
+        // but not at the cost of compacting all the time
+        let overshoot = self.write_rate * self.compaction_time.as_secs_f64();
+        let threshold = allowance - overshoot.min(allowance / 2.0);
+        (threshold.max(0.0) as u64).max(self.floor)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    /// Unlike [`KvStore::contains_key`], leaves out expired keys. It
+    /// neither counts as an access nor decodes the value, and only reads
+    /// the record if values with a TTL have been set in the store.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        if !self.shared.expiring.load(Ordering::SeqCst) {
+            return KvStore::contains_key(self, key);
+        }
+        let key = self.shared.key_policy.apply(key)?;
+        let mut cache = self.readers.lock().unwrap();
+        self.shared.is_live(&mut cache, &key)
+    }
+
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        KvStore::get_many(self, keys)
+    }
+
+    fn set_many(&self, entries: Vec<(String, String)>) -> Result<()> {
+        KvStore::set_many(self, entries)
+    }
+
+    fn get_or_insert(&self, key: String, value: String) -> Result<String> {
+        self.entry(key)?.or_insert(value)
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        KvStore::compare_and_swap(self, key, expected, new)
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        KvStore::increment(self, key, delta)
+    }
+
+    fn scan(
+        &self,
+        range: (Bound<String>, Bound<String>),
+    ) -> Result<Box<dyn Iterator<Item = Result<(String, String)>> + '_>> {
+        Ok(Box::new(KvStore::scan(self, range)?))
+    }
+
+    fn stats(&self) -> Result<Option<Stats>> {
+        KvStore::stats(self).map(Some)
+    }
+
+    fn add_event_listener(&self, listener: Arc<dyn EventListener>) -> bool {
+        self.add_listener(listener);
+        true
+    }
+
+    fn idle_time(&self, key: String) -> Result<Option<Duration>> {
+        KvStore::idle_time(self, key)
+    }
+}
+
+/// Starts the compaction thread of a store, which carries out the
+/// compactions asked for on `jobs` one after another, until the store is
+/// dropped.
+fn spawn_compactor(shared: Weak<Shared>, jobs: Receiver<()>) -> Result<()> {
+    thread::Builder::new()
+        .name("kvs-compaction".to_owned())
+        .spawn(move || {
+            for () in jobs {
+                let shared = match shared.upgrade() {
+                    Some(shared) => shared,
+                    None => return,
+                };
+                let result = shared.compact();
+                let mut w = shared.writer.lock().unwrap();
+                w.compacting = false;
+                if let Err(e) = result {
+                    log::error!("Compaction failed: {}", e);
+                    w.compaction_error = Some(e);
+                }
+                shared.compaction_done.notify_all();
+            }
+        })?;
+    Ok(())
+}
+
+/// Fails with an `io::ErrorKind::AlreadyExists` error if the directory of
+/// `layout` holds a store already.
+fn ensure_no_store(layout: &Layout) -> Result<()> {
+    if manifest::exists(layout)
+        || !layout.list_generations()?.is_empty()
+        || layout.file(LEGACY_LOG).exists()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already holds a store", layout.dir().display()),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Hard-links `target` to the file `source`, or copies it if the file
+/// system does not allow that.
+fn link_or_copy(source: &Path, target: &Path) -> Result<()> {
+    if fs::hard_link(source, target).is_err() {
+        copy_file(source, target, None)?;
+    }
+    Ok(())
+}
+
+/// Copies the first `len` bytes of the file `source`, or all of them, to
+/// a new file `target` and syncs it.
+fn copy_file(source: &Path, target: &Path, len: Option<u64>) -> Result<()> {
+    let mut source = File::open(source)?;
+    let mut target = File::create(target)?;
+    match len {
+        Some(len) => io::copy(&mut io::Read::take(&mut source, len), &mut target)?,
+        None => io::copy(&mut source, &mut target)?,
+    };
+    target.sync_all()?;
+    Ok(())
+}
+
+/// Locks the lock file of the store, which is created if needed, so the
+/// store is open for writing only once at a time. The lock is released
+/// when the returned file is closed.
+fn lock_dir(layout: &Layout) -> Result<File> {
+    let path = layout.file(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked(path)),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Fails with `KvsError::ForeignData` if the directory holds data this
+/// version cannot read, instead of failing somewhere during the replay:
+/// the files of sled if there is no log, a log encoded with bincode by
+/// early versions, or segments of a newer version.
+fn detect_foreign_data(layout: &Layout) -> Result<()> {
+    let mut logs: Vec<PathBuf> = layout
+        .list_generations()?
+        .into_iter()
+        .map(|gen| layout.segment(gen).path().to_owned())
+        .collect();
+    logs.extend(Some(layout.file(LEGACY_LOG)).filter(|path| path.exists()));
+    if logs.is_empty() {
+        let conf = layout.dir().join("conf");
+        if conf.is_file() && layout.dir().join("db").is_file() {
+            return Err(KvsError::ForeignData {
+                path: conf,
+                engine: "sled".to_owned(),
+                advice: "open it with sled, or export its entries and import them \
+                         into an empty directory with `kvs import`"
+                    .to_owned(),
+            });
+        }
+    }
+    for path in logs {
+        let (engine, advice) = match segment::detect_foreign(&path)? {
+            None => continue,
+            Some(Foreign::NewerVersion(version)) => (
+                format!("a newer version of kvs (segment format {})", version),
+                "upgrade kvs to open it",
+            ),
+            Some(Foreign::Bincode) => (
+                "an early version of kvs that encoded its log with bincode".to_owned(),
+                "export its entries with that version and import them into an empty \
+                 directory with `kvs import`",
+            ),
+        };
+        return Err(KvsError::ForeignData {
+            path,
+            engine,
+            advice: advice.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the generations of the segments of the store, in ascending
+/// order. The last one is the active segment; its file is created on
+/// first use.
+///
+/// The output of an unfinished compaction is removed. A log written
+/// before segments were numbered becomes the first segment. A read-only
+/// store is left as it is, so it may have no segments at all.
+fn prepare_segments(layout: &Layout, read_only: bool) -> Result<Vec<u64>> {
+    if !read_only {
+        for name in &[COMPACTION_FILE, COMPACTION_HINT_FILE] {
+            let path = layout.file(name);
+            if path.exists() {
+                log::warn!("removing output of an unfinished compaction");
+                fs::remove_file(path)?;
+            }
+        }
+    }
+
+    let gens = layout.list_generations()?;
+    if !gens.is_empty() {
+        return Ok(gens);
+    }
+    let segment = layout.segment(1);
+    let legacy = layout.file(LEGACY_LOG);
+    if read_only {
+        if legacy.exists() {
+            log::error!(
+                "{} has to be renamed to {}",
+                LEGACY_LOG,
+                segment.path().display()
+            );
+            return Err(KvsError::ReadOnly);
+        }
+        return Ok(Vec::new());
+    }
+    if legacy.exists() {
+        log::info!("renaming {} to {}", LEGACY_LOG, segment.path().display());
+        fs::rename(legacy, segment.path())?;
+    }
+    Ok(vec![1])
+}
+
+fn segment_reader(readers: &mut BTreeMap<u64, SegmentReader>, gen: u64) -> &mut SegmentReader {
+    readers
+        .get_mut(&gen)
+        .expect("indexed commands are in open segments")
+}
+
+/// Reads the command at the given position in the log.
+fn read_command(
+    readers: &mut BTreeMap<u64, SegmentReader>,
+    cmd_pos: CommandPos,
+) -> Result<Command> {
+    let reader = segment_reader(readers, cmd_pos.gen);
+    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    reader.read_record(cmd_pos.len)
+}
+
+/// Reads the key of the command at the given position in the log.
+fn read_key(readers: &mut BTreeMap<u64, SegmentReader>, cmd_pos: CommandPos) -> Result<String> {
+    Ok(read_command(readers, cmd_pos)?.key().to_owned())
+}
+
+/// Stores the value locations listed in the hint file of the segment of
+/// generation `gen` in the index map, like `load` would.
+fn load_hints(
+    gen: u64,
+    hints: Vec<hint::Hint>,
+    readers: &mut BTreeMap<u64, SegmentReader>,
+    index: &Index,
+    stale: &mut BTreeMap<u64, u64>,
+) -> Result<()> {
+    for hint in hints {
+        let cmd_pos = (gen, hint.pos..hint.pos + hint.len).into();
+        if let Some(old_cmd) = index.insert(hint.key, cmd_pos, |p| read_key(readers, p))? {
+            *stale.entry(old_cmd.gen).or_default() += old_cmd.len;
+        }
+    }
+    Ok(())
+}
+
+/// What `load` does about a partly written record at the end of a
+/// segment.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TornTail {
+    /// Fail with the error reading it.
+    Fail,
+    /// Stop loading before it, leaving the segment as it is.
+    Skip,
+    /// Cut it off the segment.
+    Truncate,
+}
+
+/// Loads the segment of generation `gen` and stores value locations in
+/// the index map.
+///
+/// Adds how many bytes can be saved by a compaction to `stale`, for each
+/// segment, and sets `expiring` if any value set expires. The commands of
+/// a batch are only applied once its commit is found; returns whether the
+/// segment ends with a batch left unfinished.
+fn load(
+    gen: u64,
+    torn_tail: TornTail,
+    readers: &mut BTreeMap<u64, SegmentReader>,
+    index: &Index,
+    stale: &mut BTreeMap<u64, u64>,
+    expiring: &mut bool,
+) -> Result<bool> {
+    // the commands of the current batch and where they are
+    let mut batch: Option<Vec<(Command, Range<u64>)>> = None;
+    let reader = segment_reader(readers, gen);
+    let end = reader.seek(SeekFrom::End(0))?;
+    let mut pos = reader.seek(SeekFrom::Start(reader.data_start()))?;
+
+    while pos < end {
+        let reader = segment_reader(readers, gen);
+        // resolving keys in a hashed index moves the reader
+        if reader.pos() != pos {
+            reader.seek(SeekFrom::Start(pos))?;
+        }
+        let cmd: Command = match reader.read_record(end - pos) {
+            Ok(cmd) => cmd,
+            Err(e) if torn_tail == TornTail::Skip && segment::is_truncation(&e) => {
+                log::warn!(
+                    "skipping {} bytes of a partly written record at offset {} of {}",
+                    end - pos,
+                    pos,
+                    reader.segment().path().display()
+                );
+                break;
+            }
+            Err(e) if torn_tail == TornTail::Truncate && segment::is_truncation(&e) => {
+                let path = reader.segment().path();
+                log::warn!(
+                    "truncating {} bytes of a partly written record at offset {} of {}",
+                    end - pos,
+                    pos,
+                    path.display()
+                );
+                OpenOptions::new().write(true).open(path)?.set_len(pos)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let new_pos = reader.pos();
+        *expiring |= cmd.expires().is_some();
+
+        match cmd {
+            Command::Begin => {
+                if let Some(cmds) = batch.replace(Vec::new()) {
+                    discard(gen, cmds, stale);
+                }
+                *stale.entry(gen).or_default() += new_pos - pos;
+            }
+            Command::Commit => {
+                for (cmd, range) in batch.take().unwrap_or_default() {
+                    apply(gen, cmd, range, readers, index, stale)?;
+                }
+                *stale.entry(gen).or_default() += new_pos - pos;
+            }
+            cmd => match &mut batch {
+                Some(cmds) => cmds.push((cmd, pos..new_pos)),
+                None => apply(gen, cmd, pos..new_pos, readers, index, stale)?,
+            },
+        }
+        pos = new_pos;
+    }
+    match batch {
+        Some(cmds) => {
+            log::warn!(
+                "discarding an unfinished batch of {} commands in segment {}",
+                cmds.len(),
+                gen
+            );
+            discard(gen, cmds, stale);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Applies the command at `range` in the segment of generation `gen` to
+/// the index map, adding the bytes it makes stale to `stale`.
+fn apply(
+    gen: u64,
+    cmd: Command,
+    range: Range<u64>,
+    readers: &mut BTreeMap<u64, SegmentReader>,
+    index: &Index,
+    stale: &mut BTreeMap<u64, u64>,
+) -> Result<()> {
+    match cmd {
+        Command::Set { key, .. }
+        | Command::SetBlob { key, .. }
+        | Command::SetCompressed { key, .. }
+        | Command::SetBytes { key, .. } => {
+            let cmd_pos = (gen, range).into();
+            if let Some(old_cmd) = index.insert(key, cmd_pos, |p| read_key(readers, p))? {
+                *stale.entry(old_cmd.gen).or_default() += old_cmd.len;
+            }
+        }
+        Command::Rm { key } => {
+            // the removed entry is gone if its segment was compacted
+            if let Some(old_cmd) = index.remove(&key, |p| read_key(readers, p))? {
+                *stale.entry(old_cmd.gen).or_default() += old_cmd.len;
+            }
+            *stale.entry(gen).or_default() += range.end - range.start;
+        }
+        Command::Begin | Command::Commit => {
+            *stale.entry(gen).or_default() += range.end - range.start;
+        }
+    }
+    Ok(())
+}
+
+/// Counts the commands of a batch that never got committed as stale.
+fn discard(gen: u64, cmds: Vec<(Command, Range<u64>)>, stale: &mut BTreeMap<u64, u64>) {
+    let bytes: u64 = cmds.iter().map(|(_, range)| range.end - range.start).sum();
+    *stale.entry(gen).or_default() += bytes;
 }
@@ -1,9 +1,193 @@
 #![deny(missing_docs)]
 //! A simple key-value store.
 
-pub use error::{KvsError, Result};
-pub use kv::KvStore;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvsServer;
+pub use auth::{AuthProvider, Password};
+pub use batch::WriteBatch;
+pub use builder::{KvStoreBuilder, RecoveryMode, SyncPolicy};
+pub use cache::CacheStats;
+pub use client::{KvsClient, Latency, Pipeline, ScanStream, Subscription};
+pub use client_pool::{KvsClientPool, PooledClient};
+pub use codec::Codec;
+pub use contention::{Histogram, LockWaits};
+pub use cursor::{Cursor, Page};
+pub use entry::Entry;
+pub use error::{ErrorFormat, KvsError, Result};
+pub use events::{
+    CompactionFilter, CompactionFinished, CompactionStarted, CorruptionDetected, EventListener,
+    Evicted, FilterDecision, SegmentDropped, SegmentSealed,
+};
+pub use index::IndexMode;
+pub use inspect::{LogRecord, RecordKind};
+pub use key::KeyPolicy;
+pub use kv::{Keys, KvStore, LiveIter, SnapshotIter};
+pub use memory::MemoryEngine;
+pub use notify::EXPIRED_CHANNEL;
+pub use server::{KvsServer, MissingKey};
+pub use stats::{CompactionReport, Stats};
+pub use transform::{Lz4Compression, ValueTransform};
 
+mod access;
+#[cfg(feature = "async")]
+mod async_server;
+mod auth;
+mod batch;
+mod builder;
+mod cache;
+mod client;
+mod client_pool;
+mod clients;
+mod codec;
+mod commit;
+mod contention;
+mod cursor;
+mod dedup;
+mod entry;
 mod error;
+mod events;
+pub mod export;
+mod hint;
+mod index;
+mod inspect;
 mod io;
+mod key;
 mod kv;
+mod manifest;
+mod memory;
+mod metrics;
+mod notify;
+mod protocol;
+#[cfg(feature = "python")]
+mod python;
+mod rdb;
+mod redis;
+mod segment;
+mod server;
+mod stats;
+pub mod thread_pool;
+mod transform;
+
+use std::{ops::Bound, sync::Arc, time::Duration};
+
+/// A storage engine for string key/value pairs.
+///
+/// This allows alternative backends to be used behind the same API.
+/// Engines can be shared between threads and used by all of them at
+/// once.
+pub trait KvsEngine: Send + Sync {
+    /// Sets the value of a string key to a string. If the key already
+    /// exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a string key. Returns `None` if the
+    /// given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::NonExistentKey` if the given key is not
+    /// found.
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns whether a string key exists, without reading its value.
+    ///
+    /// The default implementation gets the value.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Gets the values of several keys, in the order of `keys`.
+    ///
+    /// The default implementation gets one key after another.
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    /// Sets several key/value pairs, in order.
+    ///
+    /// The default implementation sets one pair after another, so an
+    /// error may leave the pairs before it set.
+    fn set_many(&self, entries: Vec<(String, String)>) -> Result<()> {
+        entries
+            .into_iter()
+            .try_for_each(|(key, value)| self.set(key, value))
+    }
+
+    /// Gets the string value of a string key, or sets it to `value` if
+    /// the key does not exist. Returns the value the key ends up with.
+    ///
+    /// Concurrent writes to the key cannot come in between the lookup
+    /// and the write.
+    fn get_or_insert(&self, key: String, value: String) -> Result<String>;
+
+    /// Sets the value of a string key to `new` if it is `expected`, where
+    /// `None` stands for a missing key. Returns whether the value was set.
+    ///
+    /// Concurrent writes to the key cannot come in between the comparison
+    /// and the write.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool>;
+
+    /// Adds `delta` to the integer value of a string key, which counts as
+    /// 0 if the key does not exist, and returns the result.
+    ///
+    /// Concurrent writes to the key cannot come in between the lookup
+    /// and the write.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidCounter` if the value is not a decimal
+    /// integer or the result overflows.
+    fn increment(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Returns an iterator over the key/value pairs with keys in `range`,
+    /// in ascending key order, that is by the bytes of the keys like `Ord`
+    /// for `str`. Engines have to keep to this order.
+    ///
+    /// Entries are read as the iterator advances, so the whole range never
+    /// has to be held in memory.
+    #[allow(clippy::type_complexity)]
+    fn scan(
+        &self,
+        range: (Bound<String>, Bound<String>),
+    ) -> Result<Box<dyn Iterator<Item = Result<(String, String)>> + '_>>;
+
+    /// Returns a page of up to `count` key/value pairs, at least 1, with
+    /// keys after `cursor`, or from the first key without one, in
+    /// ascending key order. The page ends with the cursor of the next
+    /// one, which stays valid across compactions and restarts, see
+    /// [`Cursor`].
+    ///
+    /// The default implementation takes the page from [`KvsEngine::scan`].
+    fn scan_page(&self, cursor: Option<&Cursor>, count: usize) -> Result<Page> {
+        Page::take(self.scan(Cursor::range(cursor))?, count)
+    }
+
+    /// Returns the statistics of the engine, if it keeps any. Servers
+    /// include them in their metrics.
+    ///
+    /// The default implementation returns `None`.
+    fn stats(&self) -> Result<Option<Stats>> {
+        Ok(None)
+    }
+
+    /// Registers a listener that is notified of events in the engine, if
+    /// it reports any. Returns whether it does. Servers publish some of
+    /// them to their subscribers, see [`EXPIRED_CHANNEL`].
+    ///
+    /// The default implementation reports no events and returns false.
+    fn add_event_listener(&self, _listener: Arc<dyn EventListener>) -> bool {
+        false
+    }
+
+    /// Returns about how long ago a string key was last read or written,
+    /// if the engine keeps track of that. Returns `None` if the key does
+    /// not exist.
+    ///
+    /// The default implementation keeps no track and returns `None`.
+    fn idle_time(&self, _key: String) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,144 @@
+//! The manifest fixes the options of a store that was created with
+//! [`KvStoreBuilder::init`](crate::KvStoreBuilder::init).
+//!
+//! It is a text file of `name = value` lines:
+//!
+//! ```text
+//! engine = kvs
+//! codec = msgpack
+//! compression = lz4
+//! compress_min_size = 64
+//! ```
+//!
+//! Stores created by opening a directory have no manifest, and take all
+//! their options from the builder.
+
+use crate::{segment::Layout, KvsError, Result};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The only engine that writes stores.
+pub(crate) const ENGINE: &str = "kvs";
+
+/// The encoding of the records in the log.
+pub(crate) const CODEC: &str = "msgpack";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Manifest {
+    pub engine: String,
+    pub codec: String,
+    // values of at least this size are compressed with LZ4, if present
+    pub compress_min_size: Option<usize>,
+}
+
+impl Manifest {
+    /// Reads the manifest of the store, if it has one.
+    pub fn read(layout: &Layout) -> Result<Option<Manifest>> {
+        let path = layout.file(MANIFEST_FILE);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid manifest {}: {}", path.display(), reason),
+            )
+        };
+        let mut engine = None;
+        let mut codec = None;
+        let mut compression = None;
+        let mut compress_min_size = None;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected `name = value`, got `{}`", line)))?;
+            let value = value.trim().to_owned();
+            match name.trim() {
+                "engine" => engine = Some(value),
+                "codec" => codec = Some(value),
+                "compression" => compression = Some(value),
+                "compress_min_size" => {
+                    let size = value
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid compress_min_size `{}`", value)))?;
+                    compress_min_size = Some(size);
+                }
+                // options of newer versions, which have a say only if
+                // they change the engine or codec
+                _ => (),
+            }
+        }
+        let compress_min_size = match compression.as_deref() {
+            None | Some("none") => None,
+            Some("lz4") => Some(compress_min_size.unwrap_or(0)),
+            Some(other) => return Err(invalid(format!("unknown compression `{}`", other)).into()),
+        };
+        Ok(Some(Manifest {
+            engine: engine.ok_or_else(|| invalid("engine is missing".to_owned()))?,
+            codec: codec.ok_or_else(|| invalid("codec is missing".to_owned()))?,
+            compress_min_size,
+        }))
+    }
+
+    /// Writes the manifest of a new store and syncs it.
+    pub fn write(&self, layout: &Layout) -> Result<()> {
+        let mut file = File::create(layout.file(MANIFEST_FILE))?;
+        writeln!(file, "engine = {}", self.engine)?;
+        writeln!(file, "codec = {}", self.codec)?;
+        match self.compress_min_size {
+            None => writeln!(file, "compression = none")?,
+            Some(min_size) => {
+                writeln!(file, "compression = lz4")?;
+                writeln!(file, "compress_min_size = {}", min_size)?;
+            }
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Fails with `KvsError::ForeignData` if the store was created by an
+    /// engine or with a codec that this version cannot read.
+    pub fn check(&self, layout: &Layout) -> Result<()> {
+        let foreign = |engine: String, advice: String| KvsError::ForeignData {
+            path: layout.file(MANIFEST_FILE),
+            engine,
+            advice,
+        };
+        if self.engine != ENGINE {
+            return Err(foreign(
+                self.engine.clone(),
+                format!("open it with {}", self.engine),
+            ));
+        }
+        if self.codec != CODEC {
+            return Err(foreign(
+                format!("kvs with the {} codec", self.codec),
+                "upgrade kvs to open it".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Copies the manifest of the store in `from`, if it has one, to the
+/// store in `to`.
+pub(crate) fn copy(from: &Layout, to: &Layout) -> Result<()> {
+    if exists(from) {
+        fs::copy(from.file(MANIFEST_FILE), to.file(MANIFEST_FILE))?;
+    }
+    Ok(())
+}
+
+/// Returns whether the store has a manifest.
+pub(crate) fn exists(layout: &Layout) -> bool {
+    layout.file(MANIFEST_FILE).exists()
+}
@@ -0,0 +1,320 @@
+//! An engine that keeps all entries in memory, see [`MemoryEngine`].
+
+use crate::{
+    export::{self, DumpWriter, ExportFormat},
+    KvsEngine, KvsError, Result,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+/// The file a snapshot is written to, in the `kvsdump` format.
+const SNAPSHOT_FILE: &str = "snapshot.kvsdump";
+
+/// The file a snapshot is written to before it replaces the last one.
+const SNAPSHOT_TMP_FILE: &str = "snapshot.kvsdump.tmp";
+
+/// A storage engine that keeps all entries in memory, for data that can
+/// afford to lose its latest writes, such as caches.
+///
+/// Reads and writes never touch the disk. An engine opened on a directory
+/// takes snapshots of its entries there: on [`MemoryEngine::snapshot`],
+/// periodically if it is opened with a snapshot interval, and when the
+/// last handle to it is dropped. Opening it again loads the last
+/// snapshot, so everything written after it is lost if the process dies.
+///
+/// Handles are cheap to clone and share the same entries.
+///
+/// ```rust
+/// # use kvs::{KvsEngine, MemoryEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let engine = MemoryEngine::new();
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MemoryEngine {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    entries: RwLock<BTreeMap<String, String>>,
+    // the directory snapshots are written to, if any
+    dir: Option<PathBuf>,
+    // whether the entries changed since the last snapshot
+    dirty: AtomicBool,
+    // held while a snapshot is written
+    snapshots: Mutex<()>,
+    // stops the snapshot thread, if any, once dropped
+    _stop: Option<Sender<()>>,
+}
+
+impl MemoryEngine {
+    /// Creates an empty engine that never writes to disk.
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::with_inner(None, None)
+    }
+
+    /// Opens an engine that takes snapshots in the given directory,
+    /// loading the last snapshot if there is one.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, and returns `KvsError::InvalidDump` if
+    /// the snapshot is damaged.
+    pub fn open(path: impl Into<PathBuf>) -> Result<MemoryEngine> {
+        MemoryEngine::load(path.into(), None)
+    }
+
+    /// Opens an engine like [`MemoryEngine::open`] that also takes a
+    /// snapshot every `interval` in the background, if anything changed.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`MemoryEngine::open`].
+    pub fn open_with_snapshot_interval(
+        path: impl Into<PathBuf>,
+        interval: Duration,
+    ) -> Result<MemoryEngine> {
+        let (stop, stopped) = mpsc::channel();
+        let engine = MemoryEngine::load(path.into(), Some(stop))?;
+        spawn_snapshotter(Arc::downgrade(&engine.inner), interval, stopped)?;
+        Ok(engine)
+    }
+
+    fn load(dir: PathBuf, stop: Option<Sender<()>>) -> Result<MemoryEngine> {
+        fs::create_dir_all(&dir)?;
+        let snapshot = dir.join(SNAPSHOT_FILE);
+        let engine = MemoryEngine::with_inner(Some(dir), stop);
+        if snapshot.exists() {
+            let reader = BufReader::new(File::open(snapshot)?);
+            let result = export::import(&engine, ExportFormat::KvsDump, reader);
+            // neither a loaded nor a partly loaded snapshot is written back
+            engine.inner.dirty.store(false, Ordering::SeqCst);
+            result?;
+        }
+        Ok(engine)
+    }
+
+    fn with_inner(dir: Option<PathBuf>, stop: Option<Sender<()>>) -> MemoryEngine {
+        MemoryEngine {
+            inner: Arc::new(Inner {
+                entries: RwLock::new(BTreeMap::new()),
+                dir,
+                dirty: AtomicBool::new(false),
+                snapshots: Mutex::new(()),
+                _stop: stop,
+            }),
+        }
+    }
+
+    /// Writes a snapshot of the entries to the directory of the engine,
+    /// unless nothing changed since the last one. Does nothing for an
+    /// engine created with [`MemoryEngine::new`].
+    ///
+    /// The snapshot is written to a temporary file first, so a crash in
+    /// the middle leaves the last snapshot in place.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while writing the snapshot.
+    pub fn snapshot(&self) -> Result<()> {
+        self.inner.snapshot()
+    }
+
+    /// Updates the entries with `f` under the write lock and marks them
+    /// as changed, unless `f` fails.
+    fn update<T>(&self, f: impl FnOnce(&mut BTreeMap<String, String>) -> Result<T>) -> Result<T> {
+        let mut entries = self.inner.entries.write().unwrap();
+        let result = f(&mut entries);
+        if result.is_ok() {
+            self.inner.dirty.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+impl Default for MemoryEngine {
+    fn default() -> Self {
+        MemoryEngine::new()
+    }
+}
+
+impl Inner {
+    fn snapshot(&self) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let _snapshots = self.snapshots.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        // copy the entries rather than block writes while writing them
+        let entries = self.entries.read().unwrap().clone();
+        let result = write_snapshot(dir, &entries);
+        if result.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Err(e) = self.snapshot() {
+            log::error!("Snapshot failed: {}", e);
+        }
+    }
+}
+
+/// Writes `entries` to the snapshot file in `dir`, replacing it at once.
+fn write_snapshot(dir: &Path, entries: &BTreeMap<String, String>) -> Result<()> {
+    let tmp = dir.join(SNAPSHOT_TMP_FILE);
+    let file = File::create(&tmp)?;
+    let mut dump = DumpWriter::new(BufWriter::new(&file))?;
+    for (key, value) in entries {
+        dump.entry(key, value)?;
+    }
+    let count = dump.finish()?;
+    file.sync_all()?;
+    fs::rename(tmp, dir.join(SNAPSHOT_FILE))?;
+    log::trace!("Took a snapshot of {} entries", count);
+    Ok(())
+}
+
+/// Starts the thread that takes a snapshot of an engine every `interval`
+/// until the engine is dropped.
+fn spawn_snapshotter(inner: Weak<Inner>, interval: Duration, stop: Receiver<()>) -> Result<()> {
+    thread::Builder::new()
+        .name("kvs-snapshot".to_owned())
+        .spawn(move || loop {
+            match stop.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => (),
+                _ => return,
+            }
+            let inner = match inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            if let Err(e) = inner.snapshot() {
+                log::error!("Snapshot failed: {}", e);
+            }
+        })?;
+    Ok(())
+}
+
+/// Returns whether `BTreeMap::range` would panic on `range` instead of
+/// returning nothing.
+fn is_inverted(range: &(Bound<String>, Bound<String>)) -> bool {
+    use Bound::*;
+    match range {
+        (Included(start) | Excluded(start), Included(end) | Excluded(end)) if start > end => true,
+        (Excluded(start), Excluded(end)) => start == end,
+        _ => false,
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.update(|entries| {
+            entries.insert(key, value);
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.inner.entries.read().unwrap().get(&key).cloned())
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.inner.entries.read().unwrap().contains_key(&key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.update(|entries| match entries.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::NonExistentKey(key)),
+        })
+    }
+
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let entries = self.inner.entries.read().unwrap();
+        Ok(keys.iter().map(|key| entries.get(key).cloned()).collect())
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.update(|entries| {
+            entries.extend(pairs);
+            Ok(())
+        })
+    }
+
+    fn get_or_insert(&self, key: String, value: String) -> Result<String> {
+        self.update(|entries| Ok(entries.entry(key).or_insert(value).clone()))
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        self.update(|entries| {
+            if entries.get(&key) != expected.as_ref() {
+                return Ok(false);
+            }
+            entries.insert(key, new);
+            Ok(true)
+        })
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        self.update(|entries| {
+            let invalid = |reason: &str| KvsError::InvalidCounter {
+                key: key.clone(),
+                reason: reason.to_owned(),
+            };
+            let value: i64 = match entries.get(&key) {
+                Some(value) => value.parse().map_err(|_| invalid("not an integer"))?,
+                None => 0,
+            };
+            let value = value
+                .checked_add(delta)
+                .ok_or_else(|| invalid("overflow"))?;
+            entries.insert(key.clone(), value.to_string());
+            Ok(value)
+        })
+    }
+
+    /// Returns the entries in `range` as they are when this is called.
+    /// They are copied right away, so the range should fit in memory
+    /// twice.
+    fn scan(
+        &self,
+        range: (Bound<String>, Bound<String>),
+    ) -> Result<Box<dyn Iterator<Item = Result<(String, String)>> + '_>> {
+        if is_inverted(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let entries: Vec<_> = self
+            .inner
+            .entries
+            .read()
+            .unwrap()
+            .range(range)
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Ok(Box::new(entries.into_iter()))
+    }
+}
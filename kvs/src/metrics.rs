@@ -0,0 +1,405 @@
+//! Metrics of a server in the Prometheus text format.
+//!
+//! Every server counts the requests it serves per command, with their
+//! errors and a histogram of how long they took, as well as its
+//! connections and how long they waited to be served. Along with the
+//! [`Stats`] of the engine, if it has any, they are answered to a
+//! `METRICS` request, and served over HTTP at `/metrics` if the server
+//! was given a listener for that, see
+//! [`KvsServer::with_metrics_listener`](crate::KvsServer::with_metrics_listener).
+
+use crate::{
+    contention::{Histogram, Recorder},
+    CompactionReport, Result, Stats,
+};
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// The commands of the protocol, as they are labeled in the metrics.
+pub(crate) const COMMANDS: &[&str] = &[
+    "get",
+    "set",
+    "rm",
+    "getorset",
+    "cas",
+    "incr",
+    "mget",
+    "mset",
+    "scan",
+    "ping",
+    "metrics",
+    "subscribe",
+    "clientsetname",
+    "clientlist",
+    "clientkill",
+    "objectidletime",
+    "del",
+    "exists",
+    "auth",
+    "scanpage",
+];
+
+/// How long the HTTP endpoint waits for a scraper to send its request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct CommandMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency: Recorder,
+}
+
+/// The metrics a server collects while serving, see the [module
+/// documentation](self).
+pub(crate) struct Metrics {
+    // by the index of the command in `COMMANDS`
+    commands: Vec<CommandMetrics>,
+    active_connections: AtomicU64,
+    connections: AtomicU64,
+    // how long accepted connections waited to be served
+    queue_waits: Recorder,
+}
+
+/// Counts a connection as active until it is dropped.
+pub(crate) struct ActiveConnection<'a>(&'a Metrics);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            commands: COMMANDS.iter().map(|_| CommandMetrics::default()).collect(),
+            active_connections: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            queue_waits: Recorder::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts a connection that was accepted `queued` ago and is served
+    /// from now on.
+    pub fn connection(&self, queued: Duration) -> ActiveConnection<'_> {
+        self.queue_waits.record(queued);
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    /// Counts a request for `command`, one of `COMMANDS`, that took
+    /// `elapsed`.
+    pub fn record(&self, command: &str, elapsed: Duration, failed: bool) {
+        let metrics = match COMMANDS.iter().position(|&name| name == command) {
+            Some(i) => &self.commands[i],
+            None => return,
+        };
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.latency.record(elapsed);
+    }
+
+    /// Renders the metrics, followed by the stats of the engine if it has
+    /// any.
+    pub fn render(&self, stats: Option<&Stats>) -> String {
+        let mut out = Exposition::default();
+        out.family(
+            "kvs_requests_total",
+            "counter",
+            "Requests served, by command.",
+        );
+        for (command, metrics) in COMMANDS.iter().zip(&self.commands) {
+            let requests = metrics.requests.load(Ordering::Relaxed);
+            out.sample("kvs_requests_total", &[("command", command)], requests);
+        }
+        out.family(
+            "kvs_request_errors_total",
+            "counter",
+            "Requests answered with an error, by command.",
+        );
+        for (command, metrics) in COMMANDS.iter().zip(&self.commands) {
+            let errors = metrics.errors.load(Ordering::Relaxed);
+            out.sample("kvs_request_errors_total", &[("command", command)], errors);
+        }
+        out.family(
+            "kvs_request_duration_seconds",
+            "histogram",
+            "How long requests took to be carried out, by command.",
+        );
+        for (command, metrics) in COMMANDS.iter().zip(&self.commands) {
+            out.histogram(
+                "kvs_request_duration_seconds",
+                &[("command", command)],
+                &metrics.latency.snapshot(),
+            );
+        }
+        out.family(
+            "kvs_connections_active",
+            "gauge",
+            "Connections being served.",
+        );
+        let active = self.active_connections.load(Ordering::Relaxed);
+        out.sample("kvs_connections_active", &[], active);
+        out.family("kvs_connections_total", "counter", "Connections served.");
+        let connections = self.connections.load(Ordering::Relaxed);
+        out.sample("kvs_connections_total", &[], connections);
+        out.family(
+            "kvs_connection_queue_seconds",
+            "histogram",
+            "How long accepted connections waited to be served.",
+        );
+        out.histogram(
+            "kvs_connection_queue_seconds",
+            &[],
+            &self.queue_waits.snapshot(),
+        );
+        if let Some(stats) = stats {
+            render_stats(&mut out, stats);
+        }
+        out.text
+    }
+}
+
+fn render_stats(out: &mut Exposition, stats: &Stats) {
+    let gauges = [
+        ("kvs_live_keys", "Live keys in the store.", stats.live_keys),
+        (
+            "kvs_log_bytes",
+            "Bytes of all segments of the log.",
+            stats.log_bytes,
+        ),
+        (
+            "kvs_stale_bytes",
+            "Bytes of the log waiting to be compacted away.",
+            stats.stale_bytes,
+        ),
+        ("kvs_segments", "Segments of the log.", stats.segments),
+        (
+            "kvs_compaction_threshold_bytes",
+            "Stale bytes in the sealed segments that trigger a compaction.",
+            stats.compaction_threshold,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        out.family(name, "gauge", help);
+        out.sample(name, &[], value);
+    }
+    let counters = [
+        (
+            "kvs_compactions_total",
+            "Compactions finished since the store was opened.",
+            stats.compactions,
+        ),
+        (
+            "kvs_engine_reads_total",
+            "Keys looked up since the store was opened.",
+            stats.reads,
+        ),
+        (
+            "kvs_engine_writes_total",
+            "Keys set or removed since the store was opened.",
+            stats.writes,
+        ),
+    ];
+    for (name, help, value) in counters {
+        out.family(name, "counter", help);
+        out.sample(name, &[], value);
+    }
+    out.family(
+        "kvs_lock_wait_seconds",
+        "histogram",
+        "How long writes waited for the locks of the store, by lock.",
+    );
+    let waits = &stats.lock_waits;
+    out.histogram(
+        "kvs_lock_wait_seconds",
+        &[("lock", "writer")],
+        &waits.writer,
+    );
+    out.histogram(
+        "kvs_lock_wait_seconds",
+        &[("lock", "stripes")],
+        &waits.stripes,
+    );
+    out.histogram(
+        "kvs_lock_wait_seconds",
+        &[("lock", "commits")],
+        &waits.commits,
+    );
+    if let Some(report) = stats.compaction_history.last() {
+        render_compaction(out, report);
+    }
+    if let Some(cache) = &stats.cache {
+        let metrics = [
+            (
+                "kvs_cache_hits_total",
+                "counter",
+                "Gets answered from the value cache.",
+                cache.hits,
+            ),
+            (
+                "kvs_cache_misses_total",
+                "counter",
+                "Gets that missed the value cache.",
+                cache.misses,
+            ),
+            (
+                "kvs_cache_entries",
+                "gauge",
+                "Values in the value cache.",
+                cache.entries,
+            ),
+            (
+                "kvs_cache_bytes",
+                "gauge",
+                "Bytes of the keys and values in the value cache.",
+                cache.bytes,
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            out.family(name, kind, help);
+            out.sample(name, &[], value);
+        }
+    }
+}
+
+fn render_compaction(out: &mut Exposition, report: &CompactionReport) {
+    let name = "kvs_last_compaction_duration_seconds";
+    out.family(name, "gauge", "How long the last compaction took.");
+    out.sample(name, &[], report.duration.as_secs_f64());
+    let gauges = [
+        (
+            "kvs_last_compaction_sealed_bytes",
+            "Bytes of the segments the last compaction compacted.",
+            report.sealed_bytes,
+        ),
+        (
+            "kvs_last_compaction_read_bytes",
+            "Bytes of live records the last compaction read.",
+            report.bytes_read,
+        ),
+        (
+            "kvs_last_compaction_written_bytes",
+            "Bytes of the segment the last compaction wrote.",
+            report.bytes_written,
+        ),
+        (
+            "kvs_last_compaction_dropped_records",
+            "Expired or filtered records the last compaction dropped.",
+            report.records_dropped,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        out.family(name, "gauge", help);
+        out.sample(name, &[], value);
+    }
+    let name = "kvs_last_compaction_live_ratio";
+    out.family(
+        name,
+        "gauge",
+        "Share of the compacted segments the last compaction copied.",
+    );
+    out.sample(name, &[], report.live_ratio());
+}
+
+/// Metrics in the Prometheus text exposition format.
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.text, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.text, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, value))
+                .collect();
+            write!(self.text, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.text, " {}", value).unwrap();
+    }
+
+    /// Writes the cumulative buckets, sum and count of `histogram`.
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket = format!("{}_bucket", name);
+        let mut count = 0;
+        for (bound, n) in histogram.buckets() {
+            count += n;
+            let le = if bound == Duration::MAX {
+                "+Inf".to_owned()
+            } else {
+                bound.as_secs_f64().to_string()
+            };
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&bucket, &bucket_labels, count);
+        }
+        let sum = histogram.sum().as_secs_f64();
+        self.sample(&format!("{}_sum", name), labels, sum);
+        self.sample(&format!("{}_count", name), labels, count);
+    }
+}
+
+/// Serves `GET /metrics` on `listener` with what `render` returns, on a
+/// thread of its own. Scrapes are answered one after another.
+pub(crate) fn spawn_http<F>(listener: TcpListener, render: F) -> Result<()>
+where
+    F: Fn() -> String + Send + 'static,
+{
+    thread::Builder::new()
+        .name("kvs-metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| answer_http(stream, &render));
+                if let Err(e) = result {
+                    log::warn!("Failed to serve metrics: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn answer_http(stream: TcpStream, render: &impl Fn() -> String) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are of no interest
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
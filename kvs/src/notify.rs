@@ -0,0 +1,56 @@
+//! Keyspace notifications, published to the clients subscribed to their
+//! channel like those of Redis.
+//!
+//! A client subscribes with a `SUBSCRIBE` request, answered with a
+//! `SUBSCRIBED` for each of its channels, after which the connection only
+//! carries a `MESSAGE` for every notification on them until the client
+//! closes it. Channels that nothing is published on may be subscribed to
+//! as well.
+//!
+//! The only event published so far is the expiry of a key, on
+//! [`EXPIRED_CHANNEL`] with the key as the message, once the engine drops
+//! the expired entry: when a lookup of the key finds it expired, or when
+//! a compaction evicts it, whichever comes first.
+
+use crate::{EventListener, Evicted};
+use std::sync::Mutex;
+
+/// The channel the keys that expire are published on, the same as the
+/// one of Redis for its first database.
+pub const EXPIRED_CHANNEL: &str = "__keyevent@0__:expired";
+
+/// Delivers a message on a channel to a subscriber. Returns false once
+/// the subscriber is gone.
+type Deliver = Box<dyn Fn(&str, &str) -> bool + Send>;
+
+/// The subscribers of a server, which receive the events of its engine
+/// as an [`EventListener`].
+#[derive(Default)]
+pub(crate) struct Keyspace {
+    subscribers: Mutex<Vec<(Vec<String>, Deliver)>>,
+}
+
+impl Keyspace {
+    /// Calls `deliver` with every message published on one of `channels`
+    /// from now on, until it returns false.
+    pub fn subscribe<F>(&self, channels: Vec<String>, deliver: F)
+    where
+        F: Fn(&str, &str) -> bool + Send + 'static,
+    {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push((channels, Box::new(deliver)));
+    }
+
+    fn publish(&self, channel: &str, message: &str) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(channels, deliver)| {
+            !channels.iter().any(|c| c == channel) || deliver(channel, message)
+        });
+    }
+}
+
+impl EventListener for Keyspace {
+    fn on_eviction(&self, event: &Evicted) {
+        self.publish(EXPIRED_CHANNEL, &event.key);
+    }
+}
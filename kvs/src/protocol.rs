@@ -0,0 +1,261 @@
+//! Messages exchanged between `KvsClient` and `KvsServer`.
+//!
+//! Both are encoded with the RESP serializer from `building_blocks`, so a
+//! request is an array of the command name followed by its arguments,
+//! e.g. `*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n`.
+//! The keys of an `MGET` are an array of their own, as are the entries
+//! of an `MSET` and each of them.
+//!
+//! A `PING` is just the command name, answered with `PONG`. So is a
+//! `METRICS`, answered with the metrics of the server in the Prometheus
+//! text format as the value of an `OK`.
+//!
+//! A `SCAN` is the only request with more than one response: an `ENTRY`
+//! for each key/value pair, sent as the server reads them, followed by
+//! `END`. An `ERR` in their place ends the scan early.
+//!
+//! A `SCANPAGE` is answered with a single `PAGE` of entries instead,
+//! along with the token of a cursor for the next one, so that a scan can
+//! be resumed on another connection, see [`crate::Cursor`].
+//!
+//! A `SUBSCRIBE` is answered with a `SUBSCRIBED` for each channel, and
+//! turns the connection into one that only carries a `MESSAGE` for each
+//! notification on them, see [`crate::notify`].
+//!
+//! A `CLIENTSETNAME` names the connection it is sent on, a `CLIENTLIST`
+//! gets a list of the connections, and a `CLIENTKILL` closes one of
+//! them, see [`crate::clients`].
+//!
+//! An `AUTH` authenticates the connection it is sent on with a server
+//! that requires a password. Until it does, every request but `AUTH` and
+//! `PING` is answered with a `-NOAUTH` error reply, see [`crate::auth`].
+//!
+//! A request that cannot be decoded, e.g. because its command is
+//! unknown, is answered with a RESP error reply like
+//! `-ERR unknown command 'FOO'`, after which the connection can still be
+//! used. Only a request that is not even valid RESP closes it, right
+//! after such a reply.
+
+use crate::{auth, KvsError, Result};
+use building_blocks::Deserializer;
+use serde::{Deserialize, Serialize};
+use std::io::BufRead;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Request {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Gets the value of `key`, setting it to `value` first if there is
+    /// none.
+    GetOrSet {
+        key: String,
+        value: String,
+    },
+    /// Sets `key` to `new` if its value is `expected`, answered with "1"
+    /// if it was set and "0" otherwise.
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+    /// Adds `delta` to the integer value of `key`, answered with the
+    /// result.
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    MSet {
+        entries: Vec<(String, String)>,
+    },
+    /// Gets the entries with keys from `start` up to but excluding `end`,
+    /// in ascending key order. Either bound may be left open.
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+    },
+    /// Checks that the server is responsive, answered with `Pong`.
+    Ping,
+    /// Gets the metrics of the server, see [`crate::metrics`].
+    Metrics,
+    /// Subscribes the connection to the notifications on `channels`.
+    Subscribe {
+        channels: Vec<String>,
+    },
+    /// Names the connection, answered with `Ok`.
+    ClientSetName {
+        name: String,
+    },
+    /// Lists the connections of the server as the value of an `Ok`.
+    ClientList,
+    /// Closes the connections whose id or address is `target`.
+    ClientKill {
+        target: String,
+    },
+    /// Gets the seconds since `key` was last read or written, answered
+    /// with `Ok(None)` if it does not exist or the engine does not know.
+    ObjectIdleTime {
+        key: String,
+    },
+    /// Authenticates the connection, answered with `Ok`, see
+    /// [`crate::auth`].
+    Auth {
+        password: String,
+    },
+    /// Gets a page of up to `count` entries after the cursor with the
+    /// token `cursor`, or from the first key without one, answered with a
+    /// `Page`, see [`crate::Cursor`].
+    ScanPage {
+        cursor: Option<String>,
+        count: u64,
+    },
+}
+
+impl Request {
+    /// Returns the name of the command, as it is labeled in the metrics.
+    pub(crate) fn command(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Rm { .. } => "rm",
+            Request::GetOrSet { .. } => "getorset",
+            Request::Cas { .. } => "cas",
+            Request::Incr { .. } => "incr",
+            Request::MGet { .. } => "mget",
+            Request::MSet { .. } => "mset",
+            Request::Scan { .. } => "scan",
+            Request::Ping => "ping",
+            Request::Metrics => "metrics",
+            Request::Subscribe { .. } => "subscribe",
+            Request::ClientSetName { .. } => "clientsetname",
+            Request::ClientList => "clientlist",
+            Request::ClientKill { .. } => "clientkill",
+            Request::ObjectIdleTime { .. } => "objectidletime",
+            Request::Auth { .. } => "auth",
+            Request::ScanPage { .. } => "scanpage",
+        }
+    }
+
+    /// Returns the key the request is for, or the first of them, if it
+    /// has any.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Rm { key }
+            | Request::GetOrSet { key, .. }
+            | Request::Cas { key, .. }
+            | Request::Incr { key, .. }
+            | Request::ObjectIdleTime { key } => Some(key),
+            Request::MGet { keys } => keys.first().map(String::as_str),
+            Request::MSet { entries } => entries.first().map(|(key, _)| key.as_str()),
+            Request::Scan { start, .. } => start.as_deref(),
+            Request::Ping
+            | Request::Metrics
+            | Request::Subscribe { .. }
+            | Request::ClientSetName { .. }
+            | Request::ClientList
+            | Request::ClientKill { .. }
+            | Request::Auth { .. }
+            | Request::ScanPage { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum Response {
+    /// The request succeeded, with the value for `Get`, `GetOrSet`, `Cas`
+    /// and `Incr`.
+    Ok(Option<String>),
+    /// The key of an `Rm` does not exist, or that of a `Get` on a server
+    /// answering `MissingKey::Error`.
+    NonExistentKey(String),
+    /// Any other error, as a message.
+    Err(String),
+    /// The responses for each key of an `MGet` or `MSet`, in order.
+    Batch(Vec<Response>),
+    /// An entry found by a `Scan`.
+    Entry { key: String, value: String },
+    /// The end of the entries of a `Scan`.
+    End,
+    /// The answer to a `Ping`.
+    Pong,
+    /// The confirmation of a `Subscribe` to `channel`.
+    Subscribed { channel: String },
+    /// A notification on a channel the connection is subscribed to.
+    Message { channel: String, message: String },
+    /// The entries of a `ScanPage`, with the token of the cursor of the
+    /// next page unless it is the last one.
+    Page {
+        entries: Vec<(String, String)>,
+        cursor: Option<String>,
+    },
+}
+
+impl Response {
+    /// Reads the next response, taking an error reply for `Response::Err`.
+    pub(crate) fn read<R: BufRead>(
+        reader: &mut Deserializer<R>,
+    ) -> building_blocks::Result<Response> {
+        match Response::deserialize(reader) {
+            Err(building_blocks::Error::Reply(message)) => {
+                let message = message.strip_prefix("ERR ").unwrap_or(&message);
+                Ok(Response::Err(message.to_owned()))
+            }
+            result => result,
+        }
+    }
+
+    /// Returns whether the response reports an error.
+    pub(crate) fn is_err(&self) -> bool {
+        matches!(self, Response::NonExistentKey(_) | Response::Err(_))
+    }
+
+    /// Turns the response to a single key back into the result of the
+    /// request.
+    pub(crate) fn into_result(self) -> Result<Option<String>> {
+        match self {
+            Response::Ok(value) => Ok(value),
+            Response::NonExistentKey(key) => Err(KvsError::NonExistentKey(key)),
+            Response::Err(msg) if auth::is_auth_error(&msg) => Err(KvsError::Auth(msg)),
+            Response::Err(msg) => Err(KvsError::Server(msg)),
+            Response::Batch(_)
+            | Response::Entry { .. }
+            | Response::End
+            | Response::Pong
+            | Response::Subscribed { .. }
+            | Response::Message { .. }
+            | Response::Page { .. } => Err(unexpected_response()),
+        }
+    }
+}
+
+impl From<KvsError> for Response {
+    fn from(e: KvsError) -> Response {
+        match e {
+            KvsError::NonExistentKey(key) => Response::NonExistentKey(key),
+            e => Response::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<Option<String>>> for Response {
+    fn from(result: Result<Option<String>>) -> Response {
+        result.map_or_else(Response::from, Response::Ok)
+    }
+}
+
+/// The error for a response that does not fit the request.
+pub(crate) fn unexpected_response() -> KvsError {
+    building_blocks::Error::Message("unexpected response".to_owned()).into()
+}
@@ -0,0 +1,226 @@
+//! Python bindings for the embedded store, enabled with the `python`
+//! feature.
+//!
+//! The extension module is named `kvs` and can be built with
+//! [maturin](https://github.com/PyO3/maturin), or as a shared library
+//! with cargo, as the crate only builds an `rlib` by default:
+//!
+//! ```text
+//! maturin develop --features python
+//! cargo rustc --lib --features python --crate-type cdylib
+//! ```
+//!
+//! ```python
+//! import kvs
+//!
+//! store = kvs.KvStore.open("/path/to/store")
+//! store.set("key", "value")
+//! assert store.get("key") == "value"
+//! assert store.scan_prefix("k") == [("key", "value")]
+//! store.remove("key")
+//! ```
+//!
+//! Every `KvsError` variant is mirrored by an exception deriving from
+//! `kvs.KvsError`.
+
+use crate::{KvStore, KvsError};
+use exceptions::*;
+use pyo3::{prelude::*, types::PyBytes};
+use std::{ops::Bound, path::PathBuf, time::Duration};
+
+mod exceptions {
+    use pyo3::{create_exception, exceptions::PyException};
+
+    create_exception!(kvs, KvsError, PyException);
+    create_exception!(kvs, IoError, KvsError);
+    create_exception!(kvs, SerializationError, KvsError);
+    create_exception!(kvs, DeserializationError, KvsError);
+    create_exception!(kvs, NonExistentKeyError, KvsError);
+    create_exception!(kvs, ReadOnlyError, KvsError);
+    create_exception!(kvs, InvalidKeyError, KvsError);
+    create_exception!(kvs, InvalidCounterError, KvsError);
+    create_exception!(kvs, NotUtf8Error, KvsError);
+    create_exception!(kvs, CorruptionError, KvsError);
+    create_exception!(kvs, ForeignDataError, KvsError);
+    create_exception!(kvs, LockedError, KvsError);
+    create_exception!(kvs, UnexpectedCommandTypeError, KvsError);
+    create_exception!(kvs, InvalidDumpError, KvsError);
+    create_exception!(kvs, ProtocolError, KvsError);
+    create_exception!(kvs, ServerError, KvsError);
+    create_exception!(kvs, CodecError, KvsError);
+    create_exception!(kvs, AuthError, KvsError);
+    create_exception!(kvs, InvalidCursorError, KvsError);
+}
+
+impl From<KvsError> for PyErr {
+    fn from(err: KvsError) -> PyErr {
+        let msg = err.to_string();
+        match err {
+            KvsError::Io(_) => IoError::new_err(msg),
+            KvsError::Ser(_) => SerializationError::new_err(msg),
+            KvsError::Des(_) => DeserializationError::new_err(msg),
+            KvsError::NonExistentKey(_) => NonExistentKeyError::new_err(msg),
+            KvsError::ReadOnly => ReadOnlyError::new_err(msg),
+            KvsError::InvalidKey { .. } => InvalidKeyError::new_err(msg),
+            KvsError::InvalidCounter { .. } => InvalidCounterError::new_err(msg),
+            KvsError::NotUtf8(_) => NotUtf8Error::new_err(msg),
+            KvsError::Corruption { .. } => CorruptionError::new_err(msg),
+            KvsError::ForeignData { .. } => ForeignDataError::new_err(msg),
+            KvsError::Locked(_) => LockedError::new_err(msg),
+            KvsError::UnexpectedCommandType => UnexpectedCommandTypeError::new_err(msg),
+            KvsError::InvalidDump(_) => InvalidDumpError::new_err(msg),
+            KvsError::Protocol(_) => ProtocolError::new_err(msg),
+            KvsError::Server(_) => ServerError::new_err(msg),
+            KvsError::Codec(_) => CodecError::new_err(msg),
+            KvsError::Auth(_) => AuthError::new_err(msg),
+            KvsError::InvalidCursor(_) => InvalidCursorError::new_err(msg),
+        }
+    }
+}
+
+/// Python handle to a `KvStore`.
+#[pyclass(name = "KvStore")]
+struct PyKvStore {
+    store: KvStore,
+}
+
+#[pymethods]
+impl PyKvStore {
+    /// Opens the store in the given directory, creating it if needed.
+    #[staticmethod]
+    fn open(path: PathBuf) -> PyResult<Self> {
+        let store = KvStore::open(path)?;
+        Ok(PyKvStore { store })
+    }
+
+    /// Gets the value of `key`, or `None` if it does not exist.
+    fn get(&self, key: String) -> PyResult<Option<String>> {
+        Ok(self.store.get(key)?)
+    }
+
+    /// Sets the value of `key`, overwriting any previous value.
+    fn set(&self, key: String, value: String) -> PyResult<()> {
+        Ok(self.store.set(key, value)?)
+    }
+
+    /// Gets the value of `key` as `bytes`, or `None` if it does not exist.
+    fn get_bytes(&self, py: Python<'_>, key: String) -> PyResult<Option<Py<PyBytes>>> {
+        let value = self.store.get_bytes(key)?;
+        Ok(value.map(|value| PyBytes::new(py, &value).into()))
+    }
+
+    /// Sets the value of `key` to `bytes`, which need not be valid UTF-8.
+    fn set_bytes(&self, key: String, value: &[u8]) -> PyResult<()> {
+        Ok(self.store.set_bytes(key, value.to_vec())?)
+    }
+
+    /// Sets the value of `key` to expire after `ttl` seconds.
+    fn set_with_ttl(&self, key: String, value: String, ttl: f64) -> PyResult<()> {
+        let ttl = Duration::try_from_secs_f64(ttl)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+        Ok(self.store.set_with_ttl(key, value, ttl)?)
+    }
+
+    /// Returns the seconds until the value of `key` expires, or `None` if
+    /// it never does.
+    fn ttl(&self, key: String) -> PyResult<Option<f64>> {
+        Ok(self.store.ttl(key)?.map(|ttl| ttl.as_secs_f64()))
+    }
+
+    /// Makes the value of `key` never expire. Returns whether it was set
+    /// to expire before.
+    fn persist(&self, key: String) -> PyResult<bool> {
+        Ok(self.store.persist(key)?)
+    }
+
+    /// Removes `key`, raising `NonExistentKeyError` if it does not exist.
+    fn remove(&self, key: String) -> PyResult<()> {
+        Ok(self.store.remove(key)?)
+    }
+
+    /// Returns the value of `key`, setting it to `default` first if there
+    /// is none, like `dict.setdefault`.
+    fn setdefault(&self, key: String, default: String) -> PyResult<String> {
+        Ok(self.store.entry(key)?.or_insert(default)?)
+    }
+
+    /// Sets `key` to `new` if its value is `expected`, where `None` stands
+    /// for a missing key. Returns whether it was set.
+    #[pyo3(signature = (key, expected, new))]
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> PyResult<bool> {
+        Ok(self.store.compare_and_swap(key, expected, new)?)
+    }
+
+    /// Adds `delta` to the integer value of `key`, which counts as 0 if
+    /// missing, and returns the result.
+    #[pyo3(signature = (key, delta = 1))]
+    fn increment(&self, key: String, delta: i64) -> PyResult<i64> {
+        Ok(self.store.increment(key, delta)?)
+    }
+
+    /// Returns all keys, sorted.
+    fn keys(&self) -> PyResult<Vec<String>> {
+        Ok(self.store.keys()?.collect())
+    }
+
+    fn __len__(&self) -> usize {
+        self.store.len()
+    }
+
+    fn __contains__(&self, key: String) -> PyResult<bool> {
+        Ok(self.store.contains_key(key)?)
+    }
+
+    /// Returns the `(key, value)` pairs with keys from `start` up to but
+    /// excluding `end`, sorted by key. A bound of `None` is open.
+    #[pyo3(signature = (start = None, end = None))]
+    fn scan(&self, start: Option<String>, end: Option<String>) -> PyResult<Vec<(String, String)>> {
+        let bound = |key: Option<String>, bound: fn(String) -> Bound<String>| {
+            key.map_or(Bound::Unbounded, bound)
+        };
+        let range = (bound(start, Bound::Included), bound(end, Bound::Excluded));
+        Ok(self.store.scan(range)?.collect::<Result<_, _>>()?)
+    }
+
+    /// Returns the `(key, value)` pairs with keys starting with `prefix`,
+    /// sorted by key.
+    fn scan_prefix(&self, prefix: &str) -> PyResult<Vec<(String, String)>> {
+        Ok(self.store.scan_prefix(prefix)?.collect::<Result<_, _>>()?)
+    }
+}
+
+#[pymodule]
+fn kvs(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyKvStore>()?;
+    m.add("KvsError", py.get_type::<exceptions::KvsError>())?;
+    m.add("IoError", py.get_type::<IoError>())?;
+    m.add("SerializationError", py.get_type::<SerializationError>())?;
+    m.add(
+        "DeserializationError",
+        py.get_type::<DeserializationError>(),
+    )?;
+    m.add("NonExistentKeyError", py.get_type::<NonExistentKeyError>())?;
+    m.add("ReadOnlyError", py.get_type::<ReadOnlyError>())?;
+    m.add("InvalidKeyError", py.get_type::<InvalidKeyError>())?;
+    m.add("InvalidCounterError", py.get_type::<InvalidCounterError>())?;
+    m.add("NotUtf8Error", py.get_type::<NotUtf8Error>())?;
+    m.add("CorruptionError", py.get_type::<CorruptionError>())?;
+    m.add("ForeignDataError", py.get_type::<ForeignDataError>())?;
+    m.add("LockedError", py.get_type::<LockedError>())?;
+    m.add(
+        "UnexpectedCommandTypeError",
+        py.get_type::<UnexpectedCommandTypeError>(),
+    )?;
+    m.add("InvalidDumpError", py.get_type::<InvalidDumpError>())?;
+    m.add("ProtocolError", py.get_type::<ProtocolError>())?;
+    m.add("ServerError", py.get_type::<ServerError>())?;
+    m.add("CodecError", py.get_type::<CodecError>())?;
+    m.add("AuthError", py.get_type::<AuthError>())?;
+    m.add("InvalidCursorError", py.get_type::<InvalidCursorError>())?;
+    Ok(())
+}
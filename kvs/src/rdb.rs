@@ -0,0 +1,469 @@
+//! Reading the RDB dumps of Redis, for
+//! [`import_redis_rdb`](crate::export::import_redis_rdb).
+//!
+//! A dump starts with `REDIS` and a four digit version, and is followed by
+//! opcodes like `SELECTDB` and `EXPIRETIME_MS` and by the keys, each a
+//! type byte, the key and the value. It ends with an `EOF` opcode and,
+//! since version 5, a CRC-64 of everything before, which is zero if
+//! checksums were turned off. See
+//! <https://rdb.fnordig.de/file_format.html> for the details.
+//!
+//! Strings are read whether they are stored as is, as integers, or LZF
+//! compressed. Values of other types are read only as far as needed to
+//! skip them; streams and modules cannot be skipped and fail the read.
+
+use crate::{KvsError, Result};
+use std::{
+    convert::TryFrom,
+    io::{self, Read},
+};
+
+const MAGIC: &[u8; 5] = b"REDIS";
+/// The newest version understood, that of Redis 7.4.
+const MAX_VERSION: u32 = 12;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// The reflected polynomial of the CRC-64 that Redis uses, after Jones.
+const CRC64_POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+/// A key read from a dump.
+pub(crate) struct RdbEntry {
+    /// The database the key is in.
+    pub db: u64,
+    pub key: Vec<u8>,
+    pub value: RdbValue,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    pub expires: Option<u64>,
+}
+
+pub(crate) enum RdbValue {
+    String(Vec<u8>),
+    /// A value of another type, which was skipped, e.g. `"list"`.
+    Other(&'static str),
+}
+
+/// Reads the keys of a dump one after another.
+pub(crate) struct RdbReader<R> {
+    reader: Crc64Reader<R>,
+    version: u32,
+    db: u64,
+    done: bool,
+}
+
+/// A length, or the encoding of a string stored in a special way.
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<R: Read> RdbReader<R> {
+    /// Starts reading a dump, checking its header.
+    pub fn new(reader: R) -> Result<RdbReader<R>> {
+        let mut reader = RdbReader {
+            reader: Crc64Reader {
+                inner: reader,
+                crc: 0,
+            },
+            version: 0,
+            db: 0,
+            done: false,
+        };
+        let header = reader.read_bytes(9)?;
+        if &header[..5] != MAGIC {
+            return Err(invalid("missing REDIS header"));
+        }
+        reader.version = std::str::from_utf8(&header[5..])
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| invalid("invalid RDB version"))?;
+        if reader.version == 0 || reader.version > MAX_VERSION {
+            return Err(invalid(format!(
+                "unsupported RDB version {}",
+                reader.version
+            )));
+        }
+        Ok(reader)
+    }
+
+    /// Returns the next key, or `None` at the end of the dump.
+    pub fn next_entry(&mut self) -> Result<Option<RdbEntry>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut expires = None;
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                OPCODE_EOF => {
+                    self.done = true;
+                    self.check_crc()?;
+                    return Ok(None);
+                }
+                OPCODE_SELECTDB => self.db = self.read_length()?,
+                OPCODE_RESIZEDB => {
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                OPCODE_AUX => {
+                    self.read_string()?;
+                    self.read_string()?;
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    expires = Some(u64::from_le_bytes(self.read_array()?));
+                }
+                OPCODE_EXPIRETIME => {
+                    let secs = u32::from_le_bytes(self.read_array()?);
+                    expires = Some(u64::from(secs) * 1000);
+                }
+                OPCODE_IDLE => {
+                    self.read_length()?;
+                }
+                OPCODE_FREQ => {
+                    self.read_u8()?;
+                }
+                OPCODE_FUNCTION2 => {
+                    self.read_string()?;
+                }
+                OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        self.read_length()?;
+                    }
+                }
+                OPCODE_MODULE_AUX => return Err(invalid("cannot read the data of a Redis module")),
+                value_type => {
+                    let key = self.read_string()?;
+                    let value = match value_type {
+                        TYPE_STRING => RdbValue::String(self.read_string()?),
+                        value_type => RdbValue::Other(self.skip_value(value_type)?),
+                    };
+                    return Ok(Some(RdbEntry {
+                        db: self.db,
+                        key,
+                        value,
+                        expires,
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Skips a value of a type other than string, and returns the name of
+    /// the type.
+    fn skip_value(&mut self, value_type: u8) -> Result<&'static str> {
+        match value_type {
+            // a list or set, or a quicklist of ziplists
+            1 | 2 | 14 => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                }
+            }
+            // a sorted set with scores as strings
+            3 => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                    let len = self.read_u8()?;
+                    // the lengths above stand for NaN and infinities
+                    if len < 253 {
+                        self.read_bytes(u64::from(len))?;
+                    }
+                }
+            }
+            4 => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                    self.read_string()?;
+                }
+            }
+            // a sorted set with binary scores
+            5 => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                    self.read_array::<8>()?;
+                }
+            }
+            // values encoded in a single string
+            9..=13 | 16 | 17 | 20 => {
+                self.read_string()?;
+            }
+            // a quicklist with the kind of each node
+            18 => {
+                for _ in 0..self.read_length()? {
+                    self.read_length()?;
+                    self.read_string()?;
+                }
+            }
+            value_type => {
+                return Err(invalid(format!(
+                    "cannot skip a value of {} (type {})",
+                    type_name(value_type),
+                    value_type
+                )))
+            }
+        }
+        Ok(type_name(value_type))
+    }
+
+    /// Checks the checksum at the end of the dump, if there is one.
+    fn check_crc(&mut self) -> Result<()> {
+        if self.version < 5 {
+            return Ok(());
+        }
+        let computed = self.reader.crc;
+        let expected = u64::from_le_bytes(self.read_array()?);
+        // zero if Redis was told not to compute the checksum
+        if expected != 0 && expected != computed {
+            return Err(invalid("checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.read_u8()?;
+        let len = match first >> 6 {
+            0 => u64::from(first & 0x3F),
+            1 => u64::from(first & 0x3F) << 8 | u64::from(self.read_u8()?),
+            2 => match first {
+                0x80 => u64::from(u32::from_be_bytes(self.read_array()?)),
+                0x81 => u64::from_be_bytes(self.read_array()?),
+                _ => return Err(invalid(format!("invalid length encoding {:#x}", first))),
+            },
+            _ => return Ok(Length::Encoded(first & 0x3F)),
+        };
+        Ok(Length::Len(len))
+    }
+
+    fn read_length(&mut self) -> Result<u64> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(invalid("expected a length")),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>> {
+        let int = match self.read_length_or_encoding()? {
+            Length::Len(len) => return self.read_bytes(len),
+            Length::Encoded(ENC_INT8) => i64::from(self.read_u8()? as i8),
+            Length::Encoded(ENC_INT16) => i64::from(i16::from_le_bytes(self.read_array()?)),
+            Length::Encoded(ENC_INT32) => i64::from(i32::from_le_bytes(self.read_array()?)),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.read_length()?;
+                let len = self.read_length()?;
+                let compressed = self.read_bytes(compressed_len)?;
+                return lzf_decompress(&compressed, len);
+            }
+            Length::Encoded(encoding) => {
+                return Err(invalid(format!("unknown string encoding {}", encoding)))
+            }
+        };
+        Ok(int.to_string().into_bytes())
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf).map_err(cut_off)?;
+        Ok(buf)
+    }
+
+    /// Reads `len` bytes, without trusting `len` enough to allocate them
+    /// up front.
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < len {
+            return Err(invalid("the dump is cut off"));
+        }
+        Ok(buf)
+    }
+}
+
+/// Returns the name of a Redis type, by its type byte.
+fn type_name(value_type: u8) -> &'static str {
+    match value_type {
+        0 => "string",
+        1 | 10 | 14 | 18 => "list",
+        2 | 11 | 20 => "set",
+        3 | 5 | 12 | 17 => "zset",
+        4 | 9 | 13 | 16 | 22..=25 => "hash",
+        15 | 19 | 21 => "stream",
+        6 | 7 => "module",
+        _ => "unknown",
+    }
+}
+
+/// Decompresses LZF `input` into `len` bytes.
+fn lzf_decompress(input: &[u8], len: u64) -> Result<Vec<u8>> {
+    let corrupt = || invalid("invalid LZF data");
+    let len = usize::try_from(len).map_err(|_| corrupt())?;
+    let mut output = Vec::new();
+    let mut input = input.iter().copied();
+    while let Some(ctrl) = input.next() {
+        if ctrl < 32 {
+            // a run of literal bytes
+            for _ in 0..=ctrl {
+                output.push(input.next().ok_or_else(corrupt)?);
+            }
+        } else {
+            // a back reference
+            let mut run = usize::from(ctrl >> 5);
+            if run == 7 {
+                run += usize::from(input.next().ok_or_else(corrupt)?);
+            }
+            let offset =
+                usize::from(ctrl & 0x1F) << 8 | usize::from(input.next().ok_or_else(corrupt)?);
+            let start = output.len().checked_sub(offset + 1).ok_or_else(corrupt)?;
+            // the reference may overlap the bytes it produces
+            for i in start..start + run + 2 {
+                output.push(output[i]);
+            }
+        }
+        if output.len() > len {
+            return Err(corrupt());
+        }
+    }
+    if output.len() != len {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+fn invalid(message: impl Into<String>) -> KvsError {
+    KvsError::InvalidDump(message.into())
+}
+
+fn cut_off(e: io::Error) -> KvsError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("the dump is cut off"),
+        _ => e.into(),
+    }
+}
+
+/// A reader that computes the CRC-64 of everything read through it.
+struct Crc64Reader<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: Read> Read for Crc64Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc = crc64(self.crc, &buf[..read]);
+        Ok(read)
+    }
+}
+
+fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        crc = CRC64_TABLE[((crc ^ u64::from(byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc64_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xE9C6_D914_C4B8_D9CA);
+    }
+
+    #[test]
+    fn lzf() {
+        // "abcabcabcabc": three literals, then a reference 3 bytes back
+        // for the other 9
+        let compressed = [2, b'a', b'b', b'c', 7 << 5, 0, 2];
+        assert_eq!(lzf_decompress(&compressed, 12).unwrap(), b"abcabcabcabc");
+        assert!(lzf_decompress(&compressed, 11).is_err());
+        assert!(lzf_decompress(&[7 << 5, 0, 2], 9).is_err());
+    }
+
+    #[test]
+    fn strings_and_lengths() -> Result<()> {
+        let mut dump = b"REDIS0011".to_vec();
+        // a string with a 14 bit length, an int8, an int16 and an int32
+        dump.extend([TYPE_STRING, 3]);
+        dump.extend(b"big");
+        dump.extend([0x41, 0x00]);
+        dump.extend(vec![b'x'; 256]);
+        dump.extend([TYPE_STRING, 2, b'i', b'8', 0xC0, 0xFE]);
+        dump.extend([TYPE_STRING, 3, b'i', b'1', b'6', 0xC1, 0x39, 0x30]);
+        dump.extend([
+            TYPE_STRING,
+            3,
+            b'i',
+            b'3',
+            b'2',
+            0xC2,
+            0x40,
+            0x42,
+            0x0F,
+            0x00,
+        ]);
+        dump.push(OPCODE_EOF);
+        let crc = crc64(0, &dump);
+        dump.extend(crc.to_le_bytes());
+
+        let mut reader = RdbReader::new(&dump[..])?;
+        let mut strings = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            match entry.value {
+                RdbValue::String(value) => strings.push((entry.key, value)),
+                RdbValue::Other(_) => panic!("not a string"),
+            }
+        }
+        assert_eq!(strings[0], (b"big".to_vec(), vec![b'x'; 256]));
+        assert_eq!(strings[1].1, b"-2");
+        assert_eq!(strings[2].1, b"12345");
+        assert_eq!(strings[3].1, b"1000000");
+
+        let last = dump.len() - 1;
+        dump[last] ^= 1;
+        let mut reader = RdbReader::new(&dump[..])?;
+        let result = (0..5).try_for_each(|_| reader.next_entry().map(drop));
+        assert!(matches!(result, Err(KvsError::InvalidDump(msg)) if msg == "checksum mismatch"));
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! The subset of the Redis protocol that servers speak in compatibility
+//! mode, see [`KvsServer::with_redis_compat`](crate::KvsServer::with_redis_compat),
+//! so that `redis-cli` and Redis client libraries can be used with them.
+//!
+//! Commands are arrays of bulk strings, with the name in any case:
+//!
+//! - `GET key`, answered with the value or a nil bulk string.
+//! - `SET key value`, answered with `+OK`. Options like `EX` are not
+//!   supported.
+//! - `DEL key [key ...]` and `EXISTS key [key ...]`, answered with the
+//!   number of keys removed or found.
+//! - `PING [message]`, answered with `+PONG` or the message.
+//! - `AUTH [username] password`, answered with `+OK`, see [`crate::auth`].
+//!   The username is ignored, as there is only the default user.
+//!
+//! Every other command is answered with an error, like failures of the
+//! engine.
+
+use building_blocks::RedisValue;
+use std::str;
+
+/// The error for a request that is not an array of bulk strings.
+const NOT_AN_ARRAY: &str = "Protocol error: expected an array of bulk strings";
+
+/// A command of the Redis protocol.
+#[derive(Debug)]
+pub(crate) enum Command {
+    Get { key: String },
+    Set { key: String, value: String },
+    Del { keys: Vec<String> },
+    Exists { keys: Vec<String> },
+    Ping { message: Option<String> },
+    Auth { password: String },
+}
+
+impl Command {
+    /// Parses a complete request, or returns the error to answer it with.
+    pub fn parse(frame: &[u8]) -> Result<Command, Reply> {
+        let args = match building_blocks::parse(frame) {
+            Ok((_, RedisValue::Array(args))) if !args.is_empty() => args,
+            _ => return Err(Reply::error(NOT_AN_ARRAY)),
+        };
+        let mut args = args
+            .into_iter()
+            .map(|arg| match arg {
+                RedisValue::Str(arg) => str::from_utf8(arg)
+                    .map(str::to_owned)
+                    .map_err(|_| Reply::error("Protocol error: arguments must be UTF-8")),
+                _ => Err(Reply::error(NOT_AN_ARRAY)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let name = args.remove(0).to_lowercase();
+        let wrong_arity =
+            || Reply::error(format!("wrong number of arguments for '{}' command", name));
+        let mut args = args.into_iter();
+        let command = match name.as_str() {
+            "get" if args.len() == 1 => Command::Get {
+                key: args.next().unwrap(),
+            },
+            "set" if args.len() > 2 => return Err(Reply::error("syntax error")),
+            "set" if args.len() == 2 => Command::Set {
+                key: args.next().unwrap(),
+                value: args.next().unwrap(),
+            },
+            "del" if args.len() > 0 => Command::Del {
+                keys: args.collect(),
+            },
+            "exists" if args.len() > 0 => Command::Exists {
+                keys: args.collect(),
+            },
+            "ping" if args.len() <= 1 => Command::Ping {
+                message: args.next(),
+            },
+            "auth" if args.len() == 1 || args.len() == 2 => Command::Auth {
+                password: args.next_back().unwrap(),
+            },
+            "get" | "set" | "del" | "exists" | "ping" | "auth" => return Err(wrong_arity()),
+            _ => return Err(Reply::error(format!("unknown command '{}'", name))),
+        };
+        Ok(command)
+    }
+
+    /// Returns the name of the command, as it is labeled in the metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::Del { .. } => "del",
+            Command::Exists { .. } => "exists",
+            Command::Ping { .. } => "ping",
+            Command::Auth { .. } => "auth",
+        }
+    }
+
+    /// Returns the key the command is for, or the first of them, if it
+    /// has any.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Get { key } | Command::Set { key, .. } => Some(key),
+            Command::Del { keys } | Command::Exists { keys } => keys.first().map(String::as_str),
+            Command::Ping { .. } | Command::Auth { .. } => None,
+        }
+    }
+}
+
+/// A reply of the Redis protocol.
+#[derive(Debug)]
+pub(crate) enum Reply {
+    /// A simple string, like `+OK`.
+    Status(&'static str),
+    /// A bulk string, or nil.
+    Bulk(Option<String>),
+    Int(i64),
+    /// An error, prefixed with `ERR`.
+    Error(String),
+    /// An error that begins with a code of its own, like `NOAUTH`.
+    Code(&'static str),
+}
+
+impl Reply {
+    pub fn error(message: impl Into<String>) -> Reply {
+        Reply::Error(message.into())
+    }
+
+    pub fn is_err(&self) -> bool {
+        matches!(self, Reply::Error(_) | Reply::Code(_))
+    }
+
+    /// Encodes the reply in RESP.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Reply::Status(status) => format!("+{}\r\n", status).into_bytes(),
+            Reply::Bulk(None) => b"$-1\r\n".to_vec(),
+            Reply::Bulk(Some(value)) => format!("${}\r\n{}\r\n", value.len(), value).into_bytes(),
+            Reply::Int(n) => format!(":{}\r\n", n).into_bytes(),
+            Reply::Error(message) => {
+                // writing to a Vec cannot fail
+                building_blocks::write_error(&mut buf, &format!("ERR {}", message)).unwrap();
+                buf
+            }
+            Reply::Code(error) => {
+                building_blocks::write_error(&mut buf, error).unwrap();
+                buf
+            }
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Reference-counted handles to the log files ("segments") of a store.
+//!
+//! Segments are named after their generation, e.g. `3.log`, and may have
+//! a hint file next to them, e.g. `3.hint`. All file names of a store may
+//! share a prefix, see [`Layout`]. A segment that is replaced,
+//! e.g. by compaction, is retired: its files are only deleted once the
+//! last [`SegmentHandle`] to it is dropped, so anyone still reading from
+//! it can finish doing so.
+//!
+//! A segment starts with a header, followed by records that each carry a
+//! checksum of their MessagePack-encoded payload. Segments written before
+//! records had checksums have no header and consist of bare payloads;
+//! they are still read, but never appended to.
+//!
+//! ```text
+//! segment: "KVSLOG" | version (u8) | record*
+//! record:  payload len (u32, little endian) | crc32 (u32, little endian) | payload
+//! ```
+
+use crate::{
+    io::{BufReaderWithPos, BufWriterWithPos},
+    KvsError, Result,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+};
+
+const MAGIC: &[u8; 6] = b"KVSLOG";
+const VERSION: u8 = 1;
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 1;
+const RECORD_HEADER_LEN: u64 = 8;
+const TRUNCATED: &str = "record is truncated";
+
+/// How the records of a segment are stored.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Format {
+    /// Bare payloads without a segment header, as written by older
+    /// versions.
+    Legacy,
+    /// Length-prefixed records with checksums.
+    Checksummed,
+}
+
+/// Data found in a log file that this version cannot read.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Foreign {
+    /// A segment written by a newer version, with the version of its
+    /// format.
+    NewerVersion(u8),
+    /// A log of bincode payloads, as written by early versions.
+    Bincode,
+}
+
+/// A shared handle to a segment file.
+#[derive(Clone, Debug)]
+pub(crate) struct SegmentHandle {
+    inner: Arc<Segment>,
+}
+
+/// A handle to a segment that does not keep it alive.
+#[derive(Clone, Debug)]
+pub(crate) struct WeakSegmentHandle {
+    inner: Weak<Segment>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    retired: AtomicBool,
+}
+
+impl SegmentHandle {
+    fn new(path: PathBuf) -> SegmentHandle {
+        SegmentHandle {
+            inner: Arc::new(Segment {
+                path,
+                retired: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Returns the path of the segment's hint file, see [`crate::hint`].
+    pub fn hint_path(&self) -> PathBuf {
+        self.inner.path.with_extension("hint")
+    }
+
+    /// Opens a reader on the segment, which keeps the segment alive for
+    /// as long as it exists.
+    pub fn open_reader(&self) -> io::Result<SegmentReader> {
+        let mut reader = BufReaderWithPos::new(File::open(self.path())?)?;
+        let mut header = [0; HEADER_LEN as usize];
+        let format = match reader.read_exact(&mut header) {
+            Ok(()) if &header[..MAGIC.len()] == MAGIC && header[MAGIC.len()] == VERSION => {
+                Format::Checksummed
+            }
+            Ok(()) => Format::Legacy,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Format::Legacy,
+            Err(e) => return Err(e),
+        };
+        if format == Format::Legacy {
+            reader.seek(SeekFrom::Start(0))?;
+        }
+        Ok(SegmentReader {
+            segment: self.clone(),
+            reader,
+            format,
+        })
+    }
+
+    /// Opens a writer appending to the segment, creating the file if it
+    /// does not exist. A new segment gets its header right away.
+    pub fn open_writer(&self) -> io::Result<BufWriterWithPos<File>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path())?;
+        let mut writer = BufWriterWithPos::new(file)?;
+        if writer.seek(SeekFrom::End(0))? == 0 {
+            write_header(&mut writer)?;
+            writer.flush()?;
+        }
+        Ok(writer)
+    }
+
+    /// Marks the segment for deletion once the last handle is dropped.
+    pub fn retire(&self) {
+        self.inner.retired.store(true, Ordering::SeqCst);
+    }
+
+    pub fn downgrade(&self) -> WeakSegmentHandle {
+        WeakSegmentHandle {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+}
+
+impl WeakSegmentHandle {
+    /// Returns a handle to the segment, unless all of them were dropped.
+    pub fn upgrade(&self) -> Option<SegmentHandle> {
+        self.inner.upgrade().map(|inner| SegmentHandle { inner })
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        if self.retired.load(Ordering::SeqCst) {
+            log::trace!("Deleting retired segment {}", self.path.display());
+            // the hint goes first, so it never outlives its segment
+            let hint_path = self.path.with_extension("hint");
+            match fs::remove_file(&hint_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    log::warn!("failed to delete {}: {}", hint_path.display(), e);
+                }
+                _ => (),
+            }
+            if let Err(e) = fs::remove_file(&self.path) {
+                log::warn!("failed to delete {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// A buffered reader on a segment that holds a handle to it.
+pub(crate) struct SegmentReader {
+    segment: SegmentHandle,
+    reader: BufReaderWithPos<File>,
+    format: Format,
+}
+
+impl SegmentReader {
+    pub fn segment(&self) -> &SegmentHandle {
+        &self.segment
+    }
+
+    pub fn pos(&self) -> u64 {
+        self.reader.pos()
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the position of the first record.
+    pub fn data_start(&self) -> u64 {
+        match self.format {
+            Format::Legacy => 0,
+            Format::Checksummed => HEADER_LEN,
+        }
+    }
+
+    /// Reads the record at the current position, which has to end within
+    /// `limit` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Corruption` if the record is truncated or its
+    /// checksum does not match. Records of legacy segments have no
+    /// checksum, so their corruption surfaces as a deserialization error.
+    pub fn read_record<T: DeserializeOwned>(&mut self, limit: u64) -> Result<T> {
+        if self.format == Format::Legacy {
+            return Ok(rmp_serde::from_read((&mut self.reader).take(limit))?);
+        }
+
+        let offset = self.pos();
+        if limit < RECORD_HEADER_LEN {
+            return Err(self.corruption(offset, TRUNCATED));
+        }
+        let mut header = [0; RECORD_HEADER_LEN as usize];
+        self.read_exact(&mut header)
+            .map_err(|e| self.truncated(offset, e))?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if u64::from(len) > limit - RECORD_HEADER_LEN {
+            return Err(self.corruption(offset, TRUNCATED));
+        }
+        let mut payload = vec![0; len as usize];
+        self.read_exact(&mut payload)
+            .map_err(|e| self.truncated(offset, e))?;
+        if crc32fast::hash(&payload) != crc {
+            return Err(self.corruption(offset, "checksum mismatch"));
+        }
+        Ok(rmp_serde::from_slice(&payload)?)
+    }
+
+    fn truncated(&self, offset: u64, e: io::Error) -> KvsError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => self.corruption(offset, TRUNCATED),
+            _ => e.into(),
+        }
+    }
+
+    fn corruption(&self, offset: u64, reason: &str) -> KvsError {
+        KvsError::Corruption {
+            path: self.segment.path().to_owned(),
+            offset,
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+impl Read for SegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Seek for SegmentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+/// Returns whether `err` was caused by a record that ends past the end of
+/// its segment, as left behind by a write that was cut short.
+pub(crate) fn is_truncation(err: &KvsError) -> bool {
+    use rmp_serde::decode::Error::{InvalidDataRead, InvalidMarkerRead};
+    match err {
+        KvsError::Corruption { reason, .. } => reason == TRUNCATED,
+        KvsError::Des(InvalidMarkerRead(e)) | KvsError::Des(InvalidDataRead(e)) => {
+            e.kind() == io::ErrorKind::UnexpectedEof
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether the log file at `path` holds data that can be read
+/// neither as a segment of this version nor as a legacy one.
+pub(crate) fn detect_foreign(path: &Path) -> io::Result<Option<Foreign>> {
+    let mut start = Vec::with_capacity(HEADER_LEN as usize);
+    File::open(path)?.take(HEADER_LEN).read_to_end(&mut start)?;
+    if start.starts_with(MAGIC) {
+        return Ok(match start.get(MAGIC.len()) {
+            Some(&version) if version > VERSION => Some(Foreign::NewerVersion(version)),
+            _ => None,
+        });
+    }
+    // MessagePack payloads start with a map, bincode ones with the index
+    // of the command as a little-endian u32
+    if start.len() >= 4 && start[0] < 0x10 && start[1..4] == [0, 0, 0] {
+        return Ok(Some(Foreign::Bincode));
+    }
+    Ok(None)
+}
+
+/// Writes the header every new segment starts with.
+pub(crate) fn write_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])
+}
+
+/// Appends `value` to a segment as a checksummed record.
+pub(crate) fn write_record<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let payload = rmp_serde::to_vec(value)?;
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record is too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// The names of the files of a store in its directory, which all start
+/// with the same prefix.
+#[derive(Clone, Debug)]
+pub(crate) struct Layout {
+    dir: PathBuf,
+    prefix: String,
+}
+
+impl Layout {
+    pub fn new(dir: PathBuf, prefix: String) -> Layout {
+        Layout { dir, prefix }
+    }
+
+    /// Returns the path of the store's file called `name`.
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", self.prefix, name))
+    }
+
+    /// Returns a handle to the segment of generation `gen`. The file
+    /// itself is not touched.
+    pub fn segment(&self, gen: u64) -> SegmentHandle {
+        SegmentHandle::new(self.file(&format!("{}.log", gen)))
+    }
+
+    /// Returns the layout of a store in `dir` with the same file names.
+    pub fn with_dir(&self, dir: PathBuf) -> Layout {
+        Layout::new(dir, self.prefix.clone())
+    }
+
+    /// Returns the directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the generations of all segment files, in ascending order.
+    pub fn list_generations(&self) -> Result<Vec<u64>> {
+        let mut gens = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            if let Some(gen) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(self.prefix.as_str()))
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(|gen| gen.parse::<u64>().ok())
+            {
+                gens.push(gen);
+            }
+        }
+        gens.sort_unstable();
+        Ok(gens)
+    }
+}
@@ -0,0 +1,943 @@
+use crate::{
+    auth::{self, AuthProvider, Password},
+    clients::{Clients, Connection},
+    metrics::{self, Metrics},
+    notify::Keyspace,
+    protocol::{Request, Response},
+    redis::{self, Reply},
+    thread_pool::{NaiveThreadPool, ThreadPool},
+    Cursor, ErrorFormat, KeyPolicy, KvsEngine, KvsError, Result,
+};
+use building_blocks::{Decoded, Deserializer, FrameDecoder};
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    io::{self, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    ops::Bound,
+    str::{self, FromStr},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often a subscribed connection without notifications is checked
+/// for whether the client closed it.
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The most bytes a request may take up by default, the same as the
+/// longest bulk string Redis accepts.
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: usize = 512 * 1024 * 1024;
+
+/// A server that makes a storage engine available over TCP.
+///
+/// Clients send one request after another on a connection, each answered
+/// before the next is read, until they close it, it is idle for longer
+/// than the [idle timeout](KvsServer::with_idle_timeout), or they
+/// subscribe it to keyspace notifications, see
+/// [`EXPIRED_CHANNEL`](crate::EXPIRED_CHANNEL). Every connection is
+/// served on a thread of the server's [`ThreadPool`]. By default that is
+/// a [`NaiveThreadPool`], which starts a thread per connection; use
+/// [`KvsServer::with_pool`] to bound the number of threads. With the
+/// `async` feature, `AsyncKvsServer` serves connections on tokio instead.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool = NaiveThreadPool> {
+    engine: E,
+    pool: P,
+    error_format: ErrorFormat,
+    key_policy: KeyPolicy,
+    missing_key: MissingKey,
+    max_frame_size: usize,
+    redis_compat: bool,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    slow_request_threshold: Option<Duration>,
+    auth: Option<Box<dyn AuthProvider>>,
+    metrics_listener: Option<TcpListener>,
+}
+
+/// How a server answers a `GET` for a key that does not exist.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MissingKey {
+    /// With a nil value, which `KvsClient::get` returns as `None`.
+    #[default]
+    Nil,
+    /// With the error for a missing key, the same as for an `RM`, which
+    /// `KvsClient::get` returns as `KvsError::NonExistentKey`.
+    Error,
+}
+
+impl FromStr for MissingKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "nil" => Ok(MissingKey::Nil),
+            "error" => Ok(MissingKey::Error),
+            _ => Err(format!("unknown missing key reply: {}", s)),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Creates a server for the given engine.
+    pub fn new(engine: E) -> KvsServer<E> {
+        KvsServer {
+            engine,
+            pool: NaiveThreadPool,
+            error_format: ErrorFormat::default(),
+            key_policy: KeyPolicy::default(),
+            missing_key: MissingKey::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            redis_compat: false,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            slow_request_threshold: None,
+            auth: None,
+            metrics_listener: None,
+        }
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Serves connections on the threads of `pool` instead.
+    pub fn with_pool<Q: ThreadPool>(self, pool: Q) -> KvsServer<E, Q> {
+        KvsServer {
+            engine: self.engine,
+            pool,
+            error_format: self.error_format,
+            key_policy: self.key_policy,
+            missing_key: self.missing_key,
+            max_frame_size: self.max_frame_size,
+            redis_compat: self.redis_compat,
+            idle_timeout: self.idle_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            slow_request_threshold: self.slow_request_threshold,
+            auth: self.auth,
+            metrics_listener: self.metrics_listener,
+        }
+    }
+
+    /// Sets the rules that the keys of requests have to follow. Requests
+    /// with a rejected key fail before they reach the engine. Defaults to
+    /// accepting every key.
+    pub fn with_key_policy(mut self, policy: KeyPolicy) -> KvsServer<E, P> {
+        self.key_policy = policy;
+        self
+    }
+
+    /// Sets how errors while serving a client are logged. Defaults to
+    /// `ErrorFormat::Text`.
+    pub fn with_error_format(mut self, error_format: ErrorFormat) -> KvsServer<E, P> {
+        self.error_format = error_format;
+        self
+    }
+
+    /// Sets how a `GET` for a missing key is answered. Defaults to
+    /// `MissingKey::Nil`. Keys missing from an `MGET` are always nil.
+    pub fn with_missing_key(mut self, missing_key: MissingKey) -> KvsServer<E, P> {
+        self.missing_key = missing_key;
+        self
+    }
+
+    /// Sets the most bytes a request may take up. A larger request is
+    /// read to its end and discarded, and answered with an error, so that
+    /// the connection can still be used. Defaults to 512 MiB.
+    pub fn with_max_frame_size(mut self, bytes: usize) -> KvsServer<E, P> {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Speaks the Redis protocol instead of that of
+    /// [`KvsClient`](crate::KvsClient), so that `redis-cli` and Redis
+    /// client libraries can be pointed at the server. Off by default.
+    ///
+    /// Only `GET`, `SET`, `DEL`, `EXISTS`, `PING` and `AUTH` are
+    /// understood, and answered like Redis does, e.g. a `GET` for a
+    /// missing key with a nil bulk string.
+    pub fn with_redis_compat(mut self) -> KvsServer<E, P> {
+        self.redis_compat = true;
+        self
+    }
+
+    /// Closes connections on which no request arrives for `timeout`, so
+    /// that clients that went away without closing them do not hold on
+    /// to a thread. Connections subscribed to notifications are kept
+    /// open. Off by default.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections on which the rest of a request that has begun
+    /// to arrive does not come in for `timeout`, so that a client stuck
+    /// halfway through a request does not hold on to a thread. Off by
+    /// default.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections on which a response cannot be written for
+    /// `timeout`, e.g. because the client stopped reading. Off by
+    /// default.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Logs a warning for every request that takes the engine at least
+    /// `threshold`, with its command, key, client and duration, e.g. to
+    /// find the requests held up by compactions. Off by default.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> KvsServer<E, P> {
+        self.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Requires connections to authenticate with `password` before
+    /// anything but `AUTH` and `PING` is carried out on them, see
+    /// [`crate::auth`]. The metrics listener is not protected. Off by
+    /// default.
+    pub fn with_password(self, password: impl Into<String>) -> KvsServer<E, P> {
+        self.with_auth(Password::new(password))
+    }
+
+    /// Like [`KvsServer::with_password`], but with `provider` deciding
+    /// which passwords are accepted.
+    pub fn with_auth(mut self, provider: impl AuthProvider + 'static) -> KvsServer<E, P> {
+        self.auth = Some(Box::new(provider));
+        self
+    }
+
+    /// Serves the metrics of the server at `/metrics` over HTTP on
+    /// `listener`, for Prometheus to scrape. They can also be requested
+    /// with [`KvsClient::metrics`](crate::KvsClient::metrics).
+    pub fn with_metrics_listener(mut self, listener: TcpListener) -> KvsServer<E, P> {
+        self.metrics_listener = Some(listener);
+        self
+    }
+}
+
+impl<E: KvsEngine + 'static, P: ThreadPool> KvsServer<E, P> {
+    /// Binds to `addr` and serves connections on it.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves connections accepted on `listener`, each on a thread of the
+    /// pool.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let pool = self.pool;
+        let handler = Arc::new(Handler {
+            engine: self.engine,
+            error_format: self.error_format,
+            key_policy: self.key_policy,
+            missing_key: self.missing_key,
+            max_frame_size: self.max_frame_size,
+            redis_compat: self.redis_compat,
+            idle_timeout: self.idle_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            slow_request_threshold: self.slow_request_threshold,
+            auth: self.auth,
+            metrics: Metrics::default(),
+            keyspace: Arc::default(),
+            clients: Clients::default(),
+        });
+        handler.engine.add_event_listener(handler.keyspace.clone());
+        if let Some(metrics_listener) = self.metrics_listener {
+            handler.serve_metrics(metrics_listener)?;
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handler = Arc::clone(&handler);
+                    let accepted = Instant::now();
+                    pool.spawn(move || {
+                        if let Err(e) = handler.handle(stream, accepted.elapsed()) {
+                            handler.log_error(&e);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves the connections of a `KvsServer`.
+pub(crate) struct Handler<E: KvsEngine> {
+    pub(crate) engine: E,
+    pub(crate) error_format: ErrorFormat,
+    pub(crate) key_policy: KeyPolicy,
+    pub(crate) missing_key: MissingKey,
+    pub(crate) max_frame_size: usize,
+    pub(crate) redis_compat: bool,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) slow_request_threshold: Option<Duration>,
+    pub(crate) auth: Option<Box<dyn AuthProvider>>,
+    pub(crate) metrics: Metrics,
+    pub(crate) keyspace: Arc<Keyspace>,
+    pub(crate) clients: Clients,
+}
+
+impl<E: KvsEngine> Handler<E> {
+    /// Serves a connection that waited `queued` for a thread.
+    fn handle(&self, stream: TcpStream, queued: Duration) -> Result<()> {
+        let _active = self.metrics.connection(queued);
+        let peer = stream.peer_addr()?;
+        let registration = self.clients.register(peer, stream.try_clone()?);
+        let connection = registration.connection();
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        let mut decoder = FrameDecoder::new().with_max_frame_size(self.max_frame_size);
+        let mut writer = BufWriter::new(&stream);
+        let mut frame = Vec::new();
+        loop {
+            if !self.wait_for_request(&stream, &mut decoder)? {
+                log::debug!("Closing idle connection from {}", peer);
+                return Ok(());
+            }
+            match read_frame(&stream, &mut decoder, &mut frame) {
+                Ok(Frame::Complete) => (),
+                Ok(Frame::TooLarge) => {
+                    writer.write_all(&self.reject_frame(peer))?;
+                    writer.flush()?;
+                    continue;
+                }
+                Ok(Frame::Closed) => return Ok(()),
+                Err(KvsError::Io(e)) if is_timeout(&e) => {
+                    return Err(timed_out("reading a request").into())
+                }
+                // the rest of the input cannot be told apart from the
+                // request, so the connection ends with the reply
+                Err(KvsError::Protocol(e)) => {
+                    writer.write_all(&protocol_error(&e))?;
+                    writer.flush()?;
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e),
+            }
+            if self.redis_compat {
+                let reply = self.respond_redis(&frame, connection);
+                writer.write_all(&reply)?;
+                writer.flush()?;
+                connection.answered();
+                continue;
+            }
+            let request = match Request::deserialize(&mut Deserializer::new(&frame[..])) {
+                Ok(request) => request,
+                Err(e) => {
+                    log::debug!("Invalid request from {}: {}", peer, e);
+                    writer.write_all(&protocol_error(&e))?;
+                    writer.flush()?;
+                    continue;
+                }
+            };
+            log::trace!("Request from {}: {:?}", peer, request);
+            let command = request.command();
+            connection.received(command);
+            if let Some(reply) = self.authorize(&request, connection) {
+                writer.write_all(&reply)?;
+                writer.flush()?;
+                connection.answered();
+                continue;
+            }
+            if let Request::Subscribe { channels } = request {
+                log::debug!("Subscribed {} to {:?}", peer, channels);
+                connection.answered();
+                return self.serve_subscription(channels, &stream, writer);
+            }
+            let start = Instant::now();
+            if let Request::Scan { start, end } = request {
+                self.stream_scan(start, end, connection, |response| {
+                    Ok(building_blocks::to_writer(&mut writer, &response)?)
+                })?;
+            } else {
+                let response = self.respond(request, connection);
+                log::trace!("Response to {}: {:?}", peer, response);
+                building_blocks::to_writer(&mut writer, &response)?;
+            }
+            writer.flush()?;
+            connection.answered();
+            log_served(command, peer, start.elapsed());
+        }
+    }
+
+    /// Waits until the next request begins to arrive, for at most the
+    /// idle timeout. Returns false if it does not, or the client closes
+    /// the connection instead. The read timeout applies to the rest of
+    /// the request.
+    fn wait_for_request(&self, stream: &TcpStream, decoder: &mut FrameDecoder) -> Result<bool> {
+        if decoder.buffered() > 0 {
+            return Ok(true);
+        }
+        let switch = self.idle_timeout != self.read_timeout;
+        if switch {
+            stream.set_read_timeout(self.idle_timeout)?;
+        }
+        match decoder.read_from(stream) {
+            Ok(0) => return Ok(false),
+            Ok(_) => (),
+            Err(e) if is_timeout(&e) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        if switch {
+            stream.set_read_timeout(self.read_timeout)?;
+        }
+        Ok(true)
+    }
+
+    /// Carries out `request` on the engine and counts it in the metrics.
+    /// Scans have more than one response and go through
+    /// [`Handler::stream_scan`] instead.
+    pub(crate) fn respond(&self, request: Request, connection: &Connection) -> Response {
+        let command = request.command();
+        // the key is only kept if it may be logged
+        let key = self
+            .slow_request_threshold
+            .and(request.key().map(str::to_owned));
+        let start = Instant::now();
+        let response = self.carry_out(request, connection);
+        let elapsed = start.elapsed();
+        self.metrics.record(command, elapsed, response.is_err());
+        self.log_if_slow(command, key.as_deref(), connection, elapsed);
+        response
+    }
+
+    /// Logs a request that took at least the slow request threshold.
+    pub(crate) fn log_if_slow(
+        &self,
+        command: &str,
+        key: Option<&str>,
+        connection: &Connection,
+        elapsed: Duration,
+    ) {
+        match self.slow_request_threshold {
+            Some(threshold) if elapsed >= threshold => log::warn!(
+                "Slow request: {} of key {:?} for {} took {:?}",
+                command,
+                key.unwrap_or_default(),
+                connection.addr(),
+                elapsed
+            ),
+            _ => (),
+        }
+    }
+
+    fn carry_out(&self, request: Request, connection: &Connection) -> Response {
+        let result = match request {
+            Request::Get { key } => self.key_policy.apply(key).and_then(|key| {
+                match (self.engine.get(key.clone())?, self.missing_key) {
+                    (None, MissingKey::Error) => Err(KvsError::NonExistentKey(key)),
+                    (value, _) => Ok(value),
+                }
+            }),
+            Request::Set { key, value } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.set(key, value))
+                .map(|()| None),
+            Request::Rm { key } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.remove(key))
+                .map(|()| None),
+            Request::GetOrSet { key, value } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.get_or_insert(key, value))
+                .map(Some),
+            Request::Cas { key, expected, new } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.compare_and_swap(key, expected, new))
+                .map(|swapped| Some(if swapped { "1" } else { "0" }.to_owned())),
+            Request::Incr { key, delta } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.increment(key, delta))
+                .map(|value| Some(value.to_string())),
+            Request::MGet { keys } => {
+                return self.respond_batch(
+                    keys,
+                    |key| self.key_policy.apply(key),
+                    |keys| self.engine.get_many(&keys),
+                )
+            }
+            Request::MSet { entries } => {
+                return self.respond_batch(
+                    entries,
+                    |(key, value)| Ok((self.key_policy.apply(key)?, value)),
+                    |entries| {
+                        let len = entries.len();
+                        self.engine.set_many(entries).map(|()| vec![None; len])
+                    },
+                )
+            }
+            Request::Scan { .. } => return Response::Err("scans are streamed".to_owned()),
+            Request::Ping => return Response::Pong,
+            Request::Metrics => Ok(Some(self.render_metrics())),
+            Request::Subscribe { .. } => {
+                return Response::Err("subscriptions are streamed".to_owned())
+            }
+            Request::ClientSetName { name } => {
+                if name.contains(char::is_whitespace) {
+                    return Response::Err("client names cannot contain spaces".to_owned());
+                }
+                connection.set_name(name);
+                Ok(None)
+            }
+            Request::ClientList => Ok(Some(self.clients.list())),
+            Request::ClientKill { target } => {
+                if !self.clients.kill(&target) {
+                    return Response::Err(format!("No such client: {}", target));
+                }
+                Ok(None)
+            }
+            Request::ObjectIdleTime { key } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.idle_time(key))
+                .map(|idle| idle.map(|idle| idle.as_secs().to_string())),
+            Request::Auth { .. } => {
+                return Response::Err("AUTH is answered by the connection".to_owned())
+            }
+            Request::ScanPage { cursor, count } => {
+                let page = cursor
+                    .map(|token| token.parse::<Cursor>())
+                    .transpose()
+                    .and_then(|cursor| {
+                        let count = usize::try_from(count).unwrap_or(usize::MAX);
+                        self.engine.scan_page(cursor.as_ref(), count)
+                    });
+                return match page {
+                    Ok(page) => Response::Page {
+                        entries: page.entries,
+                        cursor: page.next.map(|next| next.to_string()),
+                    },
+                    Err(e) => Response::from(e),
+                };
+            }
+        };
+        Response::from(result)
+    }
+
+    /// Carries out a command of the Redis protocol on the engine, counts
+    /// it in the metrics, and returns the encoded reply, see
+    /// [`crate::redis`].
+    pub(crate) fn respond_redis(&self, frame: &[u8], connection: &Connection) -> Vec<u8> {
+        let start = Instant::now();
+        let reply = match redis::Command::parse(frame) {
+            Ok(command) => {
+                let name = command.name();
+                connection.received(name);
+                let password = match &command {
+                    redis::Command::Auth { password } => Some(password.as_str()),
+                    _ => None,
+                };
+                if let Some(reply) = self.check_auth(password, name, connection) {
+                    return reply.encode();
+                }
+                // the key is only kept if it may be logged
+                let key = self
+                    .slow_request_threshold
+                    .and(command.key().map(str::to_owned));
+                let reply = self.carry_out_redis(command);
+                let elapsed = start.elapsed();
+                self.metrics.record(name, elapsed, reply.is_err());
+                self.log_if_slow(name, key.as_deref(), connection, elapsed);
+                reply
+            }
+            Err(reply) => {
+                connection.received("unknown");
+                reply
+            }
+        };
+        reply.encode()
+    }
+
+    /// Answers an `AUTH` with the encoded reply, as well as any other
+    /// request that may not be carried out because the connection has not
+    /// authenticated, see [`Handler::check_auth`].
+    pub(crate) fn authorize(&self, request: &Request, connection: &Connection) -> Option<Vec<u8>> {
+        let password = match request {
+            Request::Auth { password } => Some(password.as_str()),
+            _ => None,
+        };
+        let reply = self.check_auth(password, request.command(), connection)?;
+        Some(match reply {
+            Reply::Status(_) => {
+                let mut buf = Vec::new();
+                // writing to a Vec cannot fail
+                building_blocks::to_writer(&mut buf, &Response::Ok(None)).unwrap();
+                buf
+            }
+            reply => reply.encode(),
+        })
+    }
+
+    /// Decides on a command before it is carried out. An `AUTH`, given
+    /// with its `password`, is answered right away. So is any other
+    /// command but `PING` on a connection that has not authenticated with
+    /// a server that requires it, with a `NOAUTH` error. Returns `None`
+    /// for the commands to carry out.
+    fn check_auth(
+        &self,
+        password: Option<&str>,
+        command: &'static str,
+        connection: &Connection,
+    ) -> Option<Reply> {
+        if let Some(password) = password {
+            let start = Instant::now();
+            let reply = match &self.auth {
+                None => Reply::error("AUTH called without any password configured"),
+                Some(auth) if auth.authenticate(password) => {
+                    connection.authenticate();
+                    Reply::Status("OK")
+                }
+                Some(_) => {
+                    log::warn!("Failed authentication from {}", connection.addr());
+                    Reply::Code(auth::WRONGPASS)
+                }
+            };
+            self.metrics.record("auth", start.elapsed(), reply.is_err());
+            return Some(reply);
+        }
+        if command == "ping" || self.auth.is_none() || connection.is_authenticated() {
+            return None;
+        }
+        Some(Reply::Code(auth::NOAUTH))
+    }
+
+    fn carry_out_redis(&self, command: redis::Command) -> Reply {
+        let result = match command {
+            redis::Command::Get { key } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.get(key))
+                .map(Reply::Bulk),
+            redis::Command::Set { key, value } => self
+                .key_policy
+                .apply(key)
+                .and_then(|key| self.engine.set(key, value))
+                .map(|()| Reply::Status("OK")),
+            redis::Command::Del { keys } => keys
+                .into_iter()
+                .try_fold(0, |removed, key| {
+                    match self.engine.remove(self.key_policy.apply(key)?) {
+                        Ok(()) => Ok(removed + 1),
+                        Err(KvsError::NonExistentKey(_)) => Ok(removed),
+                        Err(e) => Err(e),
+                    }
+                })
+                .map(Reply::Int),
+            redis::Command::Exists { keys } => keys
+                .into_iter()
+                .try_fold(0, |found, key| {
+                    let exists = self.engine.contains_key(self.key_policy.apply(key)?)?;
+                    Ok(found + i64::from(exists))
+                })
+                .map(Reply::Int),
+            redis::Command::Ping { message: None } => Ok(Reply::Status("PONG")),
+            redis::Command::Ping { message } => Ok(Reply::Bulk(message)),
+            redis::Command::Auth { .. } => {
+                return Reply::error("AUTH is answered by the connection")
+            }
+        };
+        result.unwrap_or_else(|e| Reply::error(e.to_string()))
+    }
+
+    /// Answers a batch with a response for each of its items.
+    ///
+    /// Items whose key is rejected by `check` fail on their own, while
+    /// all the others are passed to `run` at once. If that fails, they
+    /// all fail with its error.
+    fn respond_batch<T>(
+        &self,
+        items: Vec<T>,
+        check: impl Fn(T) -> Result<T>,
+        run: impl FnOnce(Vec<T>) -> Result<Vec<Option<String>>>,
+    ) -> Response {
+        let mut accepted = Vec::new();
+        let rejected: Vec<Option<Response>> = items
+            .into_iter()
+            .map(|item| match check(item) {
+                Ok(item) => {
+                    accepted.push(item);
+                    None
+                }
+                Err(e) => Some(Response::from(e)),
+            })
+            .collect();
+        let len = accepted.len();
+        let mut responses = match run(accepted) {
+            Ok(values) => values.into_iter().map(Response::Ok).collect(),
+            Err(e) => vec![Response::from(e); len],
+        }
+        .into_iter();
+        let responses = rejected
+            .into_iter()
+            .map(|rejected| {
+                rejected.unwrap_or_else(|| {
+                    responses
+                        .next()
+                        .unwrap_or_else(|| Response::Err("missing result".to_owned()))
+                })
+            })
+            .collect();
+        Response::Batch(responses)
+    }
+
+    /// Scans the keys from `start` up to but excluding `end`, passing a
+    /// response for each entry to `send` as soon as it is read, followed
+    /// by `Response::End`. An error of the engine is sent in place of the
+    /// end. Errors of `send` stop the scan and are returned.
+    ///
+    /// The scan counts in the metrics with the time until its last
+    /// response is sent.
+    pub(crate) fn stream_scan(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        connection: &Connection,
+        mut send: impl FnMut(Response) -> Result<()>,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let mut failed = false;
+        let key = self.slow_request_threshold.and(start.clone());
+        let result = self.scan_entries(start, end, |response| {
+            failed = response.is_err();
+            send(response)
+        });
+        let elapsed = started.elapsed();
+        self.metrics
+            .record("scan", elapsed, failed || result.is_err());
+        self.log_if_slow("scan", key.as_deref(), connection, elapsed);
+        result
+    }
+
+    /// Hands every notification on `channels` to `deliver` from now on,
+    /// until it returns false, and returns the confirmations to answer
+    /// the subscription with.
+    pub(crate) fn subscribe<F>(&self, channels: Vec<String>, deliver: F) -> Vec<Response>
+    where
+        F: Fn(Response) -> bool + Send + 'static,
+    {
+        let start = Instant::now();
+        let confirmations = channels
+            .iter()
+            .map(|channel| Response::Subscribed {
+                channel: channel.clone(),
+            })
+            .collect();
+        self.keyspace.subscribe(channels, move |channel, message| {
+            deliver(Response::Message {
+                channel: channel.to_owned(),
+                message: message.to_owned(),
+            })
+        });
+        self.metrics.record("subscribe", start.elapsed(), false);
+        confirmations
+    }
+
+    /// Sends the notifications on `channels` over a connection until the
+    /// client closes it.
+    fn serve_subscription(
+        &self,
+        channels: Vec<String>,
+        stream: &TcpStream,
+        mut writer: impl Write,
+    ) -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        let confirmations = self.subscribe(channels, move |message| sender.send(message).is_ok());
+        for response in confirmations {
+            building_blocks::to_writer(&mut writer, &response)?;
+        }
+        writer.flush()?;
+        loop {
+            match receiver.recv_timeout(CLOSE_CHECK_INTERVAL) {
+                Ok(message) => {
+                    building_blocks::to_writer(&mut writer, &message)?;
+                    writer.flush()?;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if is_closed(stream)? {
+                        return Ok(());
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+
+    fn scan_entries(
+        &self,
+        start: Option<String>,
+        end: Option<String>,
+        mut send: impl FnMut(Response) -> Result<()>,
+    ) -> Result<()> {
+        let range = (
+            start.map_or(Bound::Unbounded, Bound::Included),
+            end.map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        let entries = match self.engine.scan(range) {
+            Ok(entries) => entries,
+            Err(e) => return send(Response::from(e)),
+        };
+        for entry in entries {
+            match entry {
+                Ok((key, value)) => send(Response::Entry { key, value })?,
+                Err(e) => return send(Response::from(e)),
+            }
+        }
+        send(Response::End)
+    }
+
+    /// Renders the metrics of the server along with the stats of the
+    /// engine, see [`crate::metrics`]. Stats that fail to be read are left
+    /// out.
+    pub(crate) fn render_metrics(&self) -> String {
+        let stats = self.engine.stats().unwrap_or_else(|e| {
+            log::warn!("Failed to read the stats of the engine: {}", e);
+            None
+        });
+        self.metrics.render(stats.as_ref())
+    }
+
+    /// Serves the metrics over HTTP on `listener`, on a thread of its
+    /// own.
+    pub(crate) fn serve_metrics(self: &Arc<Self>, listener: TcpListener) -> Result<()>
+    where
+        E: 'static,
+    {
+        let handler = Arc::clone(self);
+        metrics::spawn_http(listener, move || handler.render_metrics())
+    }
+
+    /// Returns the error reply to a request of `peer` that was larger
+    /// than `max_frame_size` and discarded.
+    pub(crate) fn reject_frame(&self, peer: SocketAddr) -> Vec<u8> {
+        log::warn!(
+            "Discarded a request of more than {} bytes from {}",
+            self.max_frame_size,
+            peer
+        );
+        Reply::error(format!(
+            "Protocol error: request of more than {} bytes",
+            self.max_frame_size
+        ))
+        .encode()
+    }
+
+    /// Logs an error that ended a connection.
+    pub(crate) fn log_error(&self, e: &KvsError) {
+        match self.error_format {
+            ErrorFormat::Text => log::error!("Error serving client: {}", e),
+            ErrorFormat::Json => log::error!("{}", e.to_json()),
+        }
+    }
+}
+
+/// Returns the error reply to a request that could not be decoded.
+pub(crate) fn protocol_error(e: &building_blocks::Error) -> Vec<u8> {
+    match e {
+        building_blocks::Error::UnknownCommand(_) => Reply::error(e.to_string()),
+        e => Reply::error(format!("Protocol error: {}", e)),
+    }
+    .encode()
+}
+
+/// Logs a request of `peer` that was served in `elapsed`, at debug level.
+pub(crate) fn log_served(command: &str, peer: SocketAddr, elapsed: Duration) {
+    log::debug!("Served {} for {} in {:?}", command, peer, elapsed);
+}
+
+/// How reading a request from a connection turned out.
+pub(crate) enum Frame {
+    /// The request is in the frame buffer.
+    Complete,
+    /// The request was larger than the limit, and discarded.
+    TooLarge,
+    /// The client closed the connection instead.
+    Closed,
+}
+
+/// Takes the next request out of `decoder` and copies it into `frame`, or
+/// returns `None` if the rest of it has yet to be read. The servers feed
+/// the decoder from their connections until this returns a frame.
+pub(crate) fn next_frame(decoder: &mut FrameDecoder, frame: &mut Vec<u8>) -> Result<Option<Frame>> {
+    Ok(match decoder.decode_frame()? {
+        Decoded::Value(bytes) => {
+            frame.clear();
+            frame.extend_from_slice(bytes);
+            Some(Frame::Complete)
+        }
+        Decoded::TooLarge => Some(Frame::TooLarge),
+        Decoded::NeedMore => None,
+    })
+}
+
+/// Returns how reading a request turned out when the connection ends
+/// before `decoder` has the rest of it.
+pub(crate) fn end_of_input(decoder: &FrameDecoder) -> Result<Frame> {
+    if decoder.is_within_value() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Frame::Closed)
+}
+
+/// Reads the next request from `stream` into `frame`, feeding `decoder`
+/// until it has all of it, see [`next_frame`].
+fn read_frame(
+    stream: &TcpStream,
+    decoder: &mut FrameDecoder,
+    frame: &mut Vec<u8>,
+) -> Result<Frame> {
+    loop {
+        if let Some(read) = next_frame(decoder, frame)? {
+            return Ok(read);
+        }
+        if decoder.read_from(stream)? == 0 {
+            return end_of_input(decoder);
+        }
+    }
+}
+
+/// Returns whether a read failed because the read timeout of its socket
+/// passed.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Returns the error for a connection closed because `what` timed out.
+pub(crate) fn timed_out(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("timed out {}", what))
+}
+
+/// Returns whether the client closed a subscribed connection. Anything
+/// else it sent is discarded.
+fn is_closed(mut stream: &TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut buf = [0; 512];
+    let closed = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Ok(true),
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    closed
+}
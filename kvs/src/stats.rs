@@ -0,0 +1,72 @@
+//! Statistics of a store, see [`KvStore::stats`](crate::KvStore::stats).
+
+use crate::{CacheStats, LockWaits};
+use std::time::{Duration, SystemTime};
+
+/// The state and activity of a `KvStore`, as returned by
+/// [`KvStore::stats`](crate::KvStore::stats).
+///
+/// Activity is counted over all handles since the store was opened.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Number of live keys.
+    pub live_keys: u64,
+    /// Bytes of all segments of the log, including the active one.
+    pub log_bytes: u64,
+    /// Bytes of records in the log that are overwritten or removed and
+    /// wait to be compacted away.
+    pub stale_bytes: u64,
+    /// Number of segments of the log, including the active one.
+    pub segments: u64,
+    /// Stale bytes in the sealed segments that trigger a compaction,
+    /// which changes over time if the store has
+    /// [adaptive compaction](crate::KvStoreBuilder::adaptive_compaction).
+    pub compaction_threshold: u64,
+    /// Number of compactions that finished.
+    pub compactions: u64,
+    /// Number of keys looked up, by gets of single keys and of many.
+    pub reads: u64,
+    /// Number of keys set or removed, by single writes and batches.
+    pub writes: u64,
+    /// How long writes waited for locks, see
+    /// [`KvStore::lock_waits`](crate::KvStore::lock_waits).
+    pub lock_waits: LockWaits,
+    /// How the value cache performed, if the store has one, see
+    /// [`KvStore::cache_stats`](crate::KvStore::cache_stats).
+    pub cache: Option<CacheStats>,
+    /// The most recent compactions, oldest first, see
+    /// [`KvStore::compaction_history`](crate::KvStore::compaction_history).
+    pub compaction_history: Vec<CompactionReport>,
+}
+
+/// What a finished compaction did, to tell whether the
+/// [compaction threshold](crate::KvStoreBuilder::compaction_threshold)
+/// fits the workload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompactionReport {
+    /// When the compaction finished.
+    pub finished_at: SystemTime,
+    /// How long the compaction took, including waits for the rate limit.
+    pub duration: Duration,
+    /// Bytes of the sealed segments that were compacted.
+    pub sealed_bytes: u64,
+    /// Bytes of the live records read from the sealed segments.
+    pub bytes_read: u64,
+    /// Bytes of the segment the compaction wrote.
+    pub bytes_written: u64,
+    /// Number of live records that were dropped rather than copied,
+    /// because they expired or a compaction filter removed them.
+    pub records_dropped: u64,
+}
+
+impl CompactionReport {
+    /// Returns the share of the sealed segments that was still live and
+    /// had to be copied, between 0 and 1. A low ratio means compactions
+    /// reclaim a lot per byte they copy.
+    pub fn live_ratio(&self) -> f64 {
+        if self.sealed_bytes == 0 {
+            return 1.0;
+        }
+        (self.bytes_written as f64 / self.sealed_bytes as f64).min(1.0)
+    }
+}
@@ -0,0 +1,212 @@
+//! Thread pools to run jobs, e.g. the connections of a [`KvsServer`], on.
+//!
+//! Besides the pools implemented here, [`RayonThreadPool`] wraps a
+//! work-stealing pool of the `rayon` crate.
+//!
+//! [`KvsServer`]: crate::KvsServer
+
+use crate::{
+    contention::{Histogram, Recorder},
+    Result,
+};
+use std::{
+    io,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+/// A pool of threads that jobs can be spawned on.
+pub trait ThreadPool {
+    /// Creates a pool with the given number of threads, which are
+    /// started right away if the pool keeps them around.
+    ///
+    /// # Errors
+    ///
+    /// Returns an I/O error if a thread fails to start.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on a thread of the pool.
+    ///
+    /// A job that panics does not take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// A "pool" that starts a new thread for every job.
+///
+/// The number of threads is not bounded, so this should only be used
+/// when jobs are known to be few.
+#[derive(Debug)]
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    /// Creates a pool. The number of threads is ignored.
+    fn new(_threads: u32) -> Result<NaiveThreadPool> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// a job and when it was queued
+type QueuedJob = (Instant, Job);
+
+/// A pool of a fixed number of threads taking jobs from a shared queue.
+///
+/// Jobs wait in the queue while all threads are busy. A thread whose job
+/// panics is replaced by a new one. The threads finish once the pool is
+/// dropped and the queue is empty.
+#[derive(Debug)]
+pub struct SharedQueueThreadPool {
+    sender: Sender<QueuedJob>,
+    waits: Arc<Recorder>,
+}
+
+impl SharedQueueThreadPool {
+    /// Returns how long jobs waited in the queue before a thread took
+    /// them.
+    pub fn queue_waits(&self) -> Histogram {
+        self.waits.snapshot()
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    /// Creates a pool with the given number of threads, which must not be
+    /// zero.
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        check_threads(threads)?;
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let waits = Arc::new(Recorder::default());
+        for _ in 0..threads {
+            Worker {
+                receiver: Arc::clone(&receiver),
+                waits: Arc::clone(&waits),
+            }
+            .start()?;
+        }
+        Ok(SharedQueueThreadPool { sender, waits })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send((Instant::now(), Box::new(job)))
+            .expect("the thread pool has no threads left");
+    }
+}
+
+/// A thread of a `SharedQueueThreadPool`.
+struct Worker {
+    receiver: Arc<Mutex<Receiver<QueuedJob>>>,
+    waits: Arc<Recorder>,
+}
+
+impl Worker {
+    fn start(self) -> io::Result<()> {
+        thread::Builder::new().spawn(move || self.run())?;
+        Ok(())
+    }
+
+    fn run(&self) {
+        loop {
+            // the lock is released before the job runs, so a panicking
+            // job does not poison it
+            let job = self.receiver.lock().unwrap().recv();
+            match job {
+                Ok((queued, job)) => {
+                    self.waits.record(queued.elapsed());
+                    job()
+                }
+                // the pool was dropped
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = Worker {
+                receiver: Arc::clone(&self.receiver),
+                waits: Arc::clone(&self.waits),
+            };
+            if let Err(e) = worker.start() {
+                log::error!("Failed to replace a panicked pool thread: {}", e);
+            }
+        }
+    }
+}
+
+/// A work-stealing pool of the `rayon` crate.
+///
+/// Idle threads take jobs queued on busy ones instead of sharing a single
+/// queue. A panicking job is logged and otherwise ignored.
+#[derive(Debug)]
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    waits: Arc<Recorder>,
+}
+
+impl RayonThreadPool {
+    /// Returns how long jobs waited before a thread took them.
+    pub fn queue_waits(&self) -> Histogram {
+        self.waits.snapshot()
+    }
+}
+
+impl ThreadPool for RayonThreadPool {
+    /// Creates a pool with the given number of threads, which must not be
+    /// zero.
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        check_threads(threads)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| log::error!("A job of the thread pool panicked"))
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(RayonThreadPool {
+            pool,
+            waits: Arc::new(Recorder::default()),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let waits = Arc::clone(&self.waits);
+        let queued = Instant::now();
+        self.pool.spawn(move || {
+            waits.record(queued.elapsed());
+            job()
+        });
+    }
+}
+
+fn check_threads(threads: u32) -> io::Result<()> {
+    if threads == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a thread pool needs at least one thread",
+        ));
+    }
+    Ok(())
+}
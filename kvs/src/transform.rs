@@ -0,0 +1,95 @@
+//! Reversible transformations of values on the client, like compression
+//! or encryption, that the server never sees through.
+
+use crate::{KvsError, Result};
+
+/// A transformation of values that a [`KvsClient`](crate::KvsClient)
+/// applies before setting them and reverses after getting them, see
+/// [`KvsClient::with_transform`](crate::KvsClient::with_transform).
+///
+/// Implement it to encrypt values end to end with a key that only the
+/// clients know:
+///
+/// ```
+/// # use kvs::{Result, ValueTransform};
+/// /// Not actual encryption.
+/// struct Xor(u8);
+///
+/// impl ValueTransform for Xor {
+///     fn apply(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+///         Ok(value.into_iter().map(|b| b ^ self.0).collect())
+///     }
+///
+///     fn reverse(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+///         self.apply(value)
+///     }
+/// }
+/// ```
+pub trait ValueTransform: Send + Sync {
+    /// Transforms a value before it is sent to the server.
+    fn apply(&self, value: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// Restores a value transformed by `apply` after it is received from
+    /// the server.
+    ///
+    /// # Errors
+    ///
+    /// Should return `KvsError::Codec` if the value was not transformed
+    /// by `apply`, e.g. because it was set by a client without the
+    /// transformation.
+    fn reverse(&self, value: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// Compresses values with LZ4.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Lz4Compression;
+
+impl ValueTransform for Lz4Compression {
+    fn apply(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(&value))
+    }
+
+    fn reverse(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(&value).map_err(|e| KvsError::Codec(e.to_string()))
+    }
+}
+
+/// The transformations of a client, applied in the order they were added
+/// and reversed in the opposite order.
+#[derive(Default)]
+pub(crate) struct Transforms(Vec<Box<dyn ValueTransform>>);
+
+impl Transforms {
+    pub(crate) fn push(&mut self, transform: Box<dyn ValueTransform>) {
+        self.0.push(transform);
+    }
+
+    /// Transforms a value to send. The result is base64 encoded, as the
+    /// values of the server are strings.
+    pub(crate) fn apply(&self, value: String) -> Result<String> {
+        if self.0.is_empty() {
+            return Ok(value);
+        }
+        let value = self
+            .0
+            .iter()
+            .try_fold(value.into_bytes(), |value, transform| {
+                transform.apply(value)
+            })?;
+        Ok(base64::encode(value))
+    }
+
+    /// Restores a value received from the server.
+    pub(crate) fn reverse(&self, value: String) -> Result<String> {
+        if self.0.is_empty() {
+            return Ok(value);
+        }
+        let value = base64::decode(value).map_err(|e| KvsError::Codec(e.to_string()))?;
+        let value = self
+            .0
+            .iter()
+            .rev()
+            .try_fold(value, |value, transform| transform.reverse(value))?;
+        String::from_utf8(value).map_err(|e| KvsError::Codec(e.to_string()))
+    }
+}
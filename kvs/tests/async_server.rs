@@ -0,0 +1,410 @@
+#![cfg(feature = "async")]
+
+use kvs::{AsyncKvsServer, KvStore, KvsClient, KvsError, Result, EXPIRED_CHANNEL};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Starts an async server for a store in `temp_dir` on a free port in the
+// background.
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .build()?;
+    thread::spawn(move || runtime.block_on(AsyncKvsServer::new(store).serve(listener)));
+    Ok(addr)
+}
+
+// The async server should answer requests like the threaded one.
+#[test]
+fn async_client_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::NonExistentKey(key)) if key == "key1"
+    ));
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.measure_latency(5)?.samples, 5);
+    Ok(())
+}
+
+// Batches should be read in full, including their nested arrays.
+#[test]
+fn async_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let entries: Vec<_> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let results = client.set_many(entries)?;
+    assert!(results.iter().all(|result| result.is_ok()));
+    let results = client.get_many(vec!["key42".to_owned(), "missing".to_owned()])?;
+    assert_eq!(results[0].as_ref().ok(), Some(&Some("value42".to_owned())));
+    assert_eq!(results[1].as_ref().ok(), Some(&None));
+    Ok(())
+}
+
+// Scans should be streamed from a blocking thread.
+#[test]
+fn async_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let entries: Vec<_> = (0..1000)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(entries.clone())?;
+
+    let all = client.scan(None, None)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(all, entries);
+    let range = client
+        .scan(Some("key0500".to_owned()), None)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(range, entries[500..]);
+
+    let mut scan = client.scan(None, None)?;
+    assert_eq!(scan.next().transpose()?, Some(entries[0].clone()));
+    drop(scan);
+    assert_eq!(
+        client.get("key0999".to_owned())?,
+        Some("value999".to_owned())
+    );
+    Ok(())
+}
+
+// Requests should be answered even if they arrive in pieces.
+#[test]
+fn async_partial_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    let requests =
+        b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+    for chunk in requests.chunks(5) {
+        stream.write_all(chunk)?;
+        thread::sleep(Duration::from_millis(1));
+    }
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        response,
+        b"*2\r\n$2\r\nOK\r\n$-1\r\n*2\r\n$2\r\nOK\r\n$5\r\nvalue\r\n"
+    );
+    Ok(())
+}
+
+// Many idle connections should neither hold up nor be dropped by the
+// server.
+#[test]
+fn async_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut idle = (0..200)
+        .map(|_| KvsClient::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    for client in &mut idle {
+        assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// The async server should collect the same metrics, and serve them over
+// HTTP as well.
+#[test]
+fn async_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let metrics_listener = TcpListener::bind("127.0.0.1:0")?;
+    let metrics_addr = metrics_listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .build()?;
+    let server = AsyncKvsServer::new(store).with_metrics_listener(metrics_listener);
+    thread::spawn(move || runtime.block_on(server.serve(listener)));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.scan(None, None)?.for_each(drop);
+    let metrics = client.metrics()?;
+    assert!(metrics.contains("kvs_requests_total{command=\"set\"} 1\n"));
+    assert!(metrics.contains("kvs_requests_total{command=\"scan\"} 1\n"));
+    assert!(metrics.contains("kvs_connections_active 1\n"));
+
+    let mut stream = TcpStream::connect(metrics_addr)?;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("kvs_live_keys 1\n"));
+    Ok(())
+}
+
+// Subscribers should be notified of the keys that expire, and their
+// connections closed like any other.
+#[test]
+fn async_expired_notifications() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .build()?;
+    let served = store.clone();
+    thread::spawn(move || runtime.block_on(AsyncKvsServer::new(served).serve(listener)));
+
+    let mut notifications =
+        KvsClient::connect(addr)?.subscribe(vec![EXPIRED_CHANNEL.to_owned()])?;
+    // a subscriber that goes away should not hold up the others
+    drop(KvsClient::connect(addr)?.subscribe(vec![EXPIRED_CHANNEL.to_owned()])?);
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        notifications.next().unwrap()?,
+        (EXPIRED_CHANNEL.to_owned(), "key1".to_owned())
+    );
+    Ok(())
+}
+
+// The async server should answer requests it cannot decode with an error
+// reply too.
+#[test]
+fn async_malformed_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nFOO\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*x\r\n")?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        String::from_utf8(response).unwrap(),
+        "-ERR unknown command 'FOO'\r\n*2\r\n$2\r\nOK\r\n$-1\r\n\
+         -ERR Protocol error: failed to parse integer: invalid digit found in string\r\n"
+    );
+    Ok(())
+}
+
+// The async server should speak the Redis protocol in compatibility mode
+// too, including for oversized requests.
+#[test]
+fn async_redis_compat() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .build()?;
+    thread::spawn(move || {
+        runtime.block_on(
+            AsyncKvsServer::new(store)
+                .with_redis_compat()
+                .with_max_frame_size(1024)
+                .serve(listener),
+        )
+    });
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n")?;
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n$2000\r\n")?;
+    stream.write_all(&[b'x'; 2000])?;
+    stream.write_all(b"\r\n*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        String::from_utf8(response).unwrap(),
+        "+OK\r\n-ERR Protocol error: request of more than 1024 bytes\r\n\
+         $5\r\nvalue\r\n$-1\r\n"
+    );
+    Ok(())
+}
+
+// Requests larger than the limit should be discarded and answered with
+// an error, leaving the connection usable for the next ones.
+#[test]
+fn async_oversized_frames() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .build()?;
+    thread::spawn(move || {
+        runtime.block_on(
+            AsyncKvsServer::new(store)
+                .with_max_frame_size(1024)
+                .serve(listener),
+        )
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    for _ in 0..2 {
+        assert!(matches!(
+            client.set("key".to_owned(), "x".repeat(2000)),
+            Err(KvsError::Server(_))
+        ));
+        client.set("key".to_owned(), "value".to_owned())?;
+    }
+    assert!(matches!(
+        client.set(format!("key{}", "y".repeat(4096)), "value".to_owned()),
+        Err(KvsError::Server(_))
+    ));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A connection should carry requests until it is idle for longer than
+// the idle timeout.
+#[test]
+fn async_idle_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .enable_time()
+        .build()?;
+    let server = AsyncKvsServer::new(store).with_idle_timeout(Duration::from_millis(300));
+    thread::spawn(move || runtime.block_on(server.serve(listener)));
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..5 {
+        client.set("key".to_owned(), i.to_string())?;
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(client.get("key".to_owned())?, Some("4".to_owned()));
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.get("key".to_owned()).is_err());
+    Ok(())
+}
+
+// A connection should be closed when the rest of a request does not
+// arrive in time, but not when it is just idle between requests.
+#[test]
+fn async_read_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .enable_time()
+        .build()?;
+    let server = AsyncKvsServer::new(store).with_read_timeout(Duration::from_millis(200));
+    thread::spawn(move || runtime.block_on(server.serve(listener)));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nGet\r\n")?;
+    thread::sleep(Duration::from_millis(400));
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+// A server with a password should only carry out pings until a
+// connection authenticates, also while scanning.
+#[test]
+fn async_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .build()?;
+    let server = AsyncKvsServer::new(store).with_password("secret");
+    thread::spawn(move || runtime.block_on(server.serve(listener)));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    assert!(matches!(
+        client.get("key".to_owned()),
+        Err(KvsError::Auth(_))
+    ));
+    assert!(matches!(
+        client.scan(None, None)?.next(),
+        Some(Err(KvsError::Auth(_)))
+    ));
+    assert!(matches!(
+        client.auth("wrong".to_owned()),
+        Err(KvsError::Auth(_))
+    ));
+    client.auth("secret".to_owned())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let mut client = KvsClient::connect_with_auth(addr, "secret")?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Killed connections should be closed, also while subscribed.
+#[test]
+fn async_client_kill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut victim = KvsClient::connect(addr)?;
+    victim.set("key".to_owned(), "value".to_owned())?;
+    let subscription = KvsClient::connect(addr)?.subscribe(vec!["channel".to_owned()])?;
+    let mut admin = KvsClient::connect(addr)?;
+
+    admin.kill_client("1".to_owned())?;
+    assert!(victim.get("key".to_owned()).is_err());
+    admin.kill_client("2".to_owned())?;
+    assert_eq!(subscription.count(), 0);
+    assert!(admin.kill_client("1".to_owned()).is_err());
+    Ok(())
+}
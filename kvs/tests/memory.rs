@@ -0,0 +1,198 @@
+use kvs::{KvsClient, KvsEngine, KvsError, KvsServer, MemoryEngine, Result};
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Should get, set and remove values like the log-structured engine.
+#[test]
+fn get_set_remove() -> Result<()> {
+    let engine = MemoryEngine::new();
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::NonExistentKey(key)) if key == "key1"
+    ));
+
+    engine.set_many(vec![
+        ("a".to_owned(), "1".to_owned()),
+        ("b".to_owned(), "2".to_owned()),
+    ])?;
+    assert_eq!(
+        engine.get_many(&["b".to_owned(), "c".to_owned()])?,
+        [Some("2".to_owned()), None]
+    );
+    Ok(())
+}
+
+// The atomic operations should behave like those of `KvStore`.
+#[test]
+fn atomic_ops() -> Result<()> {
+    let engine = MemoryEngine::new();
+    assert_eq!(engine.get_or_insert("key".to_owned(), "a".to_owned())?, "a");
+    assert_eq!(engine.get_or_insert("key".to_owned(), "b".to_owned())?, "a");
+    assert!(!engine.compare_and_swap("key".to_owned(), None, "c".to_owned())?);
+    assert!(engine.compare_and_swap("key".to_owned(), Some("a".to_owned()), "c".to_owned())?);
+    assert!(engine.compare_and_swap("new".to_owned(), None, "d".to_owned())?);
+
+    let clones: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    engine.increment("counter".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for clone in clones {
+        clone.join().unwrap()?;
+    }
+    assert_eq!(engine.increment("counter".to_owned(), -400)?, 0);
+    assert!(matches!(
+        engine.increment("key".to_owned(), 1),
+        Err(KvsError::InvalidCounter { key, .. }) if key == "key"
+    ));
+    Ok(())
+}
+
+// Scans should return the entries in a range in key order, and nothing for
+// an empty range, also page by page.
+#[test]
+fn scan() -> Result<()> {
+    let engine = MemoryEngine::new();
+    for key in ["c", "a", "d", "b"] {
+        engine.set(key.to_owned(), key.to_uppercase())?;
+    }
+    let keys = |start: Bound<&str>, end: Bound<&str>| -> Result<Vec<String>> {
+        let range = (start.map(str::to_owned), end.map(str::to_owned));
+        engine.scan(range)?.map(|entry| Ok(entry?.0)).collect()
+    };
+    assert_eq!(
+        keys(Bound::Included("b"), Bound::Excluded("d"))?,
+        ["b", "c"]
+    );
+    assert_eq!(
+        keys(Bound::Unbounded, Bound::Unbounded)?,
+        ["a", "b", "c", "d"]
+    );
+    assert!(keys(Bound::Included("d"), Bound::Included("a"))?.is_empty());
+    assert!(keys(Bound::Excluded("b"), Bound::Excluded("b"))?.is_empty());
+
+    let page = engine.scan_page(None, 3)?;
+    assert_eq!(page.entries.len(), 3);
+    let page = engine.scan_page(page.next.as_ref(), 3)?;
+    assert_eq!(page.entries, [("d".to_owned(), "D".to_owned())]);
+    assert_eq!(page.next, None);
+    Ok(())
+}
+
+// Dropping the last handle should take a snapshot, which the next open
+// loads.
+#[test]
+fn snapshot_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = MemoryEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    let clone = engine.clone();
+    drop(engine);
+    assert!(!temp_dir.path().join("snapshot.kvsdump").exists());
+    drop(clone);
+
+    let engine = MemoryEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Writes after the last snapshot should be lost if the engine never gets
+// to take another one.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = MemoryEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.snapshot()?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = std::fs::read(temp_dir.path().join("snapshot.kvsdump"))?;
+    drop(engine);
+    std::fs::write(temp_dir.path().join("snapshot.kvsdump"), snapshot)?;
+
+    let engine = MemoryEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// An engine opened with an interval should take snapshots in the
+// background.
+#[test]
+fn snapshot_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("snapshot.kvsdump");
+    let engine =
+        MemoryEngine::open_with_snapshot_interval(temp_dir.path(), Duration::from_millis(10))?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let start = Instant::now();
+    while !snapshot.exists() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "no snapshot taken"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    // open a copy while the engine is still running
+    let copy = temp_dir.path().join("copy");
+    std::fs::create_dir(&copy)?;
+    std::fs::copy(&snapshot, copy.join("snapshot.kvsdump"))?;
+    let copy = MemoryEngine::open(copy)?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A damaged snapshot should fail to load and be left as it is.
+#[test]
+fn damaged_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = MemoryEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    let path = temp_dir.path().join("snapshot.kvsdump");
+    let mut snapshot = std::fs::read(&path)?;
+    snapshot.truncate(snapshot.len() - 4);
+    std::fs::write(&path, &snapshot)?;
+
+    assert!(matches!(
+        MemoryEngine::open(temp_dir.path()),
+        Err(KvsError::InvalidDump(_) | KvsError::Io(_))
+    ));
+    assert_eq!(std::fs::read(&path)?, snapshot);
+    Ok(())
+}
+
+// The engine should be servable like the log-structured one.
+#[test]
+fn server() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let engine = MemoryEngine::new();
+    thread::spawn(move || KvsServer::new(engine).serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.increment("counter".to_owned(), 5)?, 5);
+    // the engine keeps no stats of its own
+    let metrics = client.metrics()?;
+    assert!(metrics.contains("kvs_requests_total{command=\"incr\"} 1"));
+    assert!(!metrics.contains("kvs_live_keys"));
+    Ok(())
+}
@@ -0,0 +1,1248 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Codec, Cursor, KeyPolicy, KvStore, KvsClient, KvsClientPool, KvsError, KvsServer,
+    Lz4Compression, MissingKey, Result, ValueTransform, EXPIRED_CHANNEL,
+};
+use predicates::ord::eq;
+use predicates::prelude::PredicateBooleanExt;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Starts a server for a store in `temp_dir` on a free port in the background.
+fn spawn_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).serve(listener));
+    Ok(addr)
+}
+
+// Requests of several clients should all go to the same store.
+#[test]
+fn client_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::NonExistentKey(key)) if key == "key1"
+    ));
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A connected client should not hold up the others.
+#[test]
+fn concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || KvsServer::new(store).with_pool(pool).serve(listener));
+
+    let mut idle = KvsClient::connect(addr)?;
+    idle.set("idle".to_owned(), "value".to_owned())?;
+    let clients: Vec<_> = (0..3)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                for j in 0..50 {
+                    let key = format!("key{}-{}", i, j);
+                    client.set(key.clone(), "value".to_owned())?;
+                    assert_eq!(client.get(key)?, Some("value".to_owned()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap()?;
+    }
+    assert_eq!(idle.get("key2-49".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Clients racing to initialize a key should all end up with the value of
+// the first one.
+#[test]
+fn client_get_or_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || KvsServer::new(store).with_pool(pool).serve(listener));
+
+    let clients: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || -> Result<String> {
+                let mut client = KvsClient::connect(addr)?;
+                client.get_or_insert("config".to_owned(), format!("value{}", i))
+            })
+        })
+        .collect();
+    let values = clients
+        .into_iter()
+        .map(|client| client.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    let mut client = KvsClient::connect(addr)?;
+    let value = client.get("config".to_owned())?.unwrap();
+    assert!(values.iter().all(|v| *v == value));
+    Ok(())
+}
+
+// Compare-and-swap and counters should work across the network.
+#[test]
+fn client_compare_and_swap_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.compare_and_swap("key".to_owned(), None, "old".to_owned())?);
+    assert!(!client.compare_and_swap("key".to_owned(), None, "new".to_owned())?);
+    assert!(client.compare_and_swap("key".to_owned(), Some("old".to_owned()), "new".to_owned())?);
+    assert_eq!(client.get("key".to_owned())?, Some("new".to_owned()));
+
+    assert_eq!(client.increment("counter".to_owned(), 3)?, 3);
+    assert_eq!(client.increment("counter".to_owned(), -5)?, -2);
+    assert!(matches!(
+        client.increment("key".to_owned(), 1),
+        Err(KvsError::Server(_))
+    ));
+    assert_eq!(client.get("counter".to_owned())?, Some("-2".to_owned()));
+    Ok(())
+}
+
+// Pipelined requests should be answered in order, however many there are.
+#[test]
+fn client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    let mut pipeline = client.pipeline();
+    for i in 0..3000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+        pipeline.get(format!("key{}", i));
+    }
+    pipeline.remove("key0".to_owned()).remove("key0".to_owned());
+    assert_eq!(pipeline.len(), 6002);
+    let results = pipeline.execute()?;
+    assert!(pipeline.is_empty());
+    assert_eq!(results.len(), 6002);
+    for i in 0..3000 {
+        assert_eq!(results[2 * i].as_ref().ok(), Some(&None));
+        assert_eq!(
+            results[2 * i + 1].as_ref().ok(),
+            Some(&Some(format!("value{}", i)))
+        );
+    }
+    assert!(results[6000].is_ok());
+    assert!(matches!(results[6001], Err(KvsError::NonExistentKey(_))));
+    assert!(pipeline.execute()?.is_empty());
+
+    assert_eq!(client.get("key0".to_owned())?, None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Clients should be listed with their names and last commands while they
+// are connected.
+#[test]
+fn client_list() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut worker = KvsClient::connect(addr)?;
+    worker.set_name("worker".to_owned())?;
+    worker.set("key1".to_owned(), "value1".to_owned())?;
+    let mut admin = KvsClient::connect(addr)?;
+    assert!(matches!(
+        admin.set_name("the admin".to_owned()),
+        Err(KvsError::Server(_))
+    ));
+
+    let list = admin.list_clients()?;
+    let lines: Vec<_> = list.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", list);
+    assert!(lines[0].starts_with("id=1 addr=127.0.0.1:"), "{}", list);
+    assert!(
+        lines[0].ends_with(" name=worker age=0 idle=0 cmd=set pending=0"),
+        "{}",
+        list
+    );
+    assert!(
+        lines[1].ends_with(" name= age=0 idle=0 cmd=clientlist pending=1"),
+        "{}",
+        list
+    );
+
+    drop(worker);
+    for _ in 0..100 {
+        if admin.list_clients()?.lines().count() == 1 {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("closed connection still listed");
+}
+
+// Killed connections should be closed, also while subscribed.
+#[test]
+fn client_kill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut victim = KvsClient::connect(addr)?;
+    victim.set_name("victim".to_owned())?;
+    let subscription = KvsClient::connect(addr)?.subscribe(vec![EXPIRED_CHANNEL.to_owned()])?;
+    let mut admin = KvsClient::connect(addr)?;
+
+    let list = admin.list_clients()?;
+    let victim_addr = list
+        .lines()
+        .find(|line| line.contains(" name=victim "))
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|field| field.strip_prefix("addr="))
+        .unwrap()
+        .to_owned();
+    admin.kill_client(victim_addr)?;
+    assert!(victim.get("key".to_owned()).is_err());
+
+    admin.kill_client("2".to_owned())?;
+    assert_eq!(subscription.count(), 0);
+    assert!(matches!(
+        admin.kill_client("2".to_owned()),
+        Err(KvsError::Server(_))
+    ));
+    assert_eq!(admin.list_clients()?.lines().count(), 1);
+    Ok(())
+}
+
+// A connection should carry requests until it is idle for longer than
+// the idle timeout.
+#[test]
+fn idle_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store).with_idle_timeout(Duration::from_millis(300));
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..5 {
+        client.set("key".to_owned(), i.to_string())?;
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(client.get("key".to_owned())?, Some("4".to_owned()));
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.get("key".to_owned()).is_err());
+    Ok(())
+}
+
+// A connection should be closed when the rest of a request does not
+// arrive in time, but not when it is just idle between requests.
+#[test]
+fn read_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store).with_read_timeout(Duration::from_millis(200));
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nGet\r\n")?;
+    thread::sleep(Duration::from_millis(400));
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+// Idle times should be answered if the engine tracks them.
+#[test]
+fn client_idle_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.idle_time("key".to_owned())?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().track_idle_time().open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.idle_time("key".to_owned())?, Some(Duration::ZERO));
+    assert_eq!(client.idle_time("missing".to_owned())?, None);
+    Ok(())
+}
+
+// Threads sharing a pool should not open more connections than allowed.
+#[test]
+fn client_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let pool = KvsClientPool::new(addr)?.with_max_connections(2);
+
+    thread::scope(|scope| {
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let pool = &pool;
+                scope.spawn(move || -> Result<()> {
+                    for j in 0..50 {
+                        let key = format!("key{}-{}", i, j);
+                        let mut client = pool.get()?;
+                        assert!(pool.open_connections() <= 2);
+                        client.set(key.clone(), "value".to_owned())?;
+                        assert_eq!(client.get(key)?, Some("value".to_owned()));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .try_for_each(|thread| thread.join().unwrap())
+    })?;
+    assert_eq!(pool.open_connections(), 2);
+    assert_eq!(pool.idle_connections(), 2);
+    Ok(())
+}
+
+// Connections the server closed should be replaced, whether the pool
+// notices on checkout or while one is in use.
+#[test]
+fn client_pool_reconnect() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let (accepted, closed) = std::sync::mpsc::channel();
+    thread::spawn(move || -> Result<()> {
+        // close the first two connections right away
+        for _ in 0..2 {
+            drop(listener.accept()?);
+            accepted.send(()).unwrap();
+        }
+        KvsServer::new(store).serve(listener)
+    });
+
+    let pool = KvsClientPool::new(addr)?.with_health_check_interval(Duration::ZERO);
+    drop(pool.get()?);
+    closed.recv().unwrap();
+    // the connection is pinged, and replaced
+    let mut client = pool.get()?;
+    closed.recv().unwrap();
+    // and this one breaks while in use
+    assert!(client.get("key".to_owned()).is_err());
+    drop(client);
+    assert_eq!(pool.open_connections(), 0);
+
+    let pool = pool.with_health_check_interval(Duration::from_secs(60));
+    pool.get()?.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(pool.get()?.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(pool.open_connections(), 1);
+    Ok(())
+}
+
+// Pings should be answered, also on a connection used for other requests,
+// and measured.
+#[test]
+fn client_ping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let latency = client.measure_latency(20)?;
+    assert_eq!(latency.samples, 20);
+    assert!(latency.min <= latency.median && latency.median <= latency.p99);
+    assert!(latency.p99 <= latency.max);
+    assert!(latency.min <= latency.mean && latency.mean <= latency.max);
+    assert!(matches!(client.measure_latency(0), Err(KvsError::Io(_))));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"$4\r\nPING\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(response, b"$4\r\nPONG\r\n");
+    Ok(())
+}
+
+// Requests are RESP arrays of the command name and its arguments.
+#[test]
+fn wire_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        response,
+        b"*2\r\n$2\r\nOK\r\n$-1\r\n*2\r\n$2\r\nOK\r\n$5\r\nvalue\r\n"
+    );
+    Ok(())
+}
+
+// Requests larger than the limit should be discarded and answered with
+// an error, leaving the connection usable for the next ones.
+#[test]
+fn oversized_frames() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .with_max_frame_size(1024)
+            .serve(listener)
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.set("key".to_owned(), "x".repeat(2000)) {
+        Err(KvsError::Server(msg)) => assert!(msg.contains("Protocol error"), "{}", msg),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let keys: Vec<_> = (0..200).map(|i| format!("key{}", i)).collect();
+    assert!(matches!(client.get_many(keys), Err(KvsError::Server(_))));
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n+")?;
+    stream.write_all(&[b'k'; 4096])?;
+    stream.write_all(b"\r\n*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.contains("Protocol error"), "{}", response);
+    assert!(
+        response.ends_with("*2\r\n$2\r\nOK\r\n$5\r\nvalue\r\n"),
+        "{}",
+        response
+    );
+    Ok(())
+}
+
+// Requests that cannot be decoded should be answered with an error reply,
+// keeping the connection open unless they are not valid RESP.
+#[test]
+fn malformed_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nFOO\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"+hello\r\n")?;
+    stream.write_all(b"*1\r\n$3\r\nGET\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"$x\r\n*1\r\n$4\r\nPING\r\n")?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        String::from_utf8(response).unwrap(),
+        "-ERR unknown command 'FOO'\r\n\
+         -ERR unknown command 'hello'\r\n\
+         -ERR Protocol error: incorrect sequence length indication encountered\r\n\
+         *2\r\n$2\r\nOK\r\n$-1\r\n\
+         -ERR Protocol error: failed to parse integer: invalid digit found in string\r\n"
+    );
+    Ok(())
+}
+
+// In Redis compatibility mode, Redis commands should be answered like
+// Redis does.
+#[test]
+fn redis_compat() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).with_redis_compat().serve(listener));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*3\r\n$6\r\nEXISTS\r\n$3\r\nkey\r\n$5\r\nother\r\n")?;
+    stream.write_all(b"*3\r\n$3\r\nDEL\r\n$3\r\nkey\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nDEL\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*1\r\n$3\r\nGET\r\n")?;
+    stream.write_all(b"*1\r\n$7\r\nCOMMAND\r\n")?;
+    stream.write_all(b"*2\r\n$4\r\nPING\r\n$2\r\nhi\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        String::from_utf8(response).unwrap(),
+        "+PONG\r\n$-1\r\n+OK\r\n$5\r\nvalue\r\n:1\r\n:1\r\n:0\r\n\
+         -ERR wrong number of arguments for 'get' command\r\n\
+         -ERR unknown command 'command'\r\n$2\r\nhi\r\n"
+    );
+    Ok(())
+}
+
+// In Redis compatibility mode, EXISTS should not count expired keys,
+// like GET does not find them.
+#[test]
+fn redis_compat_exists_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "expired".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(50));
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).with_redis_compat().serve(listener));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$6\r\nEXISTS\r\n$7\r\nexpired\r\n$5\r\nplain\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$7\r\nexpired\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(String::from_utf8(response).unwrap(), ":1\r\n$-1\r\n");
+    Ok(())
+}
+
+// In Redis compatibility mode, EXISTS should count keys holding bytes too.
+#[test]
+fn redis_compat_exists_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes("bytes".to_owned(), vec![0xff, 0xfe])?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).with_redis_compat().serve(listener));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$6\r\nEXISTS\r\n$5\r\nbytes\r\n$5\r\nother\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(String::from_utf8(response).unwrap(), ":1\r\n");
+    Ok(())
+}
+
+// A server with a password should only carry out pings until a
+// connection authenticates.
+#[test]
+fn auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .with_password("secret")
+            .serve(listener)
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    assert!(matches!(
+        client.set("key".to_owned(), "value".to_owned()),
+        Err(KvsError::Auth(e)) if e.starts_with("NOAUTH")
+    ));
+    assert!(matches!(
+        client.auth("wrong".to_owned()),
+        Err(KvsError::Auth(e)) if e.starts_with("WRONGPASS")
+    ));
+    assert!(client.list_clients().is_err());
+    client.auth("secret".to_owned())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let mut client = KvsClient::connect_with_auth(addr, "secret")?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        KvsClient::connect_with_auth(addr, "wrong"),
+        Err(KvsError::Auth(_))
+    ));
+
+    let pool = KvsClientPool::new(addr)?.with_password("secret");
+    assert_eq!(pool.get()?.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// In Redis compatibility mode, AUTH should be answered like Redis does.
+#[test]
+fn redis_compat_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store)
+        .with_redis_compat()
+        .with_password("secret");
+    thread::spawn(move || server.serve(listener));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n")?;
+    stream.write_all(b"*2\r\n$4\r\nAUTH\r\n$5\r\nwrong\r\n")?;
+    stream.write_all(b"*3\r\n$4\r\nAUTH\r\n$7\r\ndefault\r\n$6\r\nsecret\r\n")?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        String::from_utf8(response).unwrap(),
+        "-NOAUTH Authentication required.\r\n+PONG\r\n\
+         -WRONGPASS invalid password\r\n+OK\r\n$-1\r\n"
+    );
+    Ok(())
+}
+
+// Subscribers should be notified of the keys that expire, but not of
+// those that are removed.
+#[test]
+fn server_expired_notifications() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let served = store.clone();
+    thread::spawn(move || KvsServer::new(served).serve(listener));
+
+    let mut notifications = KvsClient::connect(addr)?
+        .subscribe(vec!["other".to_owned(), EXPIRED_CHANNEL.to_owned()])?;
+    let ttl = Duration::from_millis(10);
+    store.set_with_ttl("key1".to_owned(), "value".to_owned(), ttl)?;
+    store.set_with_ttl("key2".to_owned(), "value".to_owned(), ttl)?;
+    store.remove("key2".to_owned())?;
+    thread::sleep(Duration::from_millis(50));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    let (channel, message) = notifications.next().unwrap()?;
+    assert_eq!(channel, "__keyevent@0__:expired");
+    assert_eq!(message, "key1");
+    Ok(())
+}
+
+// `kvs-client` should talk to a `kvs-server` at `--addr`.
+#[test]
+fn cli_client_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .to_string();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", &addr]);
+        cmd.assert()
+    };
+    client(&["set", "key1", "value1"])
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["rm", "key1"]).success().stdout(is_empty());
+    client(&["get", "key1"])
+        .success()
+        .stdout(eq("Key not found").trim());
+    client(&["rm", "key1"])
+        .failure()
+        .stderr(contains("Key not found"));
+    client(&["ping", "--count", "3"])
+        .success()
+        .stdout(contains("3 pings: min"));
+    client(&["clients", "--name", "cli"])
+        .success()
+        .stdout(contains("name=cli ").and(contains("cmd=clientlist pending=1")));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+// `kvs-server --log-format json` should log one JSON object per line to
+// stderr, starting with a banner, and each request at debug level.
+#[test]
+fn cli_server_json_log() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .to_string();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            &addr,
+            "--log-format",
+            "json",
+            "--log-level",
+            "debug",
+        ])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(100));
+    server.kill().unwrap();
+    let output = server.wait_with_output().unwrap();
+
+    let lines: Vec<serde_json::Value> = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let banner = lines[0]["message"].as_str().unwrap();
+    assert_eq!(lines[0]["level"], "INFO");
+    assert!(banner.contains(env!("CARGO_PKG_VERSION")));
+    assert!(banner.contains("kvs engine"));
+    assert!(banner.contains(&addr));
+    assert!(lines.iter().any(|line| line["level"] == "DEBUG"
+        && line["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Served set for")));
+}
+
+// `kvs-server --slow-request-threshold` should log the requests that
+// take longer with their command and key.
+#[test]
+fn cli_server_slow_requests() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .to_string();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--slow-request-threshold", "0"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(100));
+    server.kill().unwrap();
+    let output = server.wait_with_output().unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Slow request: set of key \"key1\" for 127.0.0.1:"),
+        "{}",
+        stderr
+    );
+}
+
+// `kvs-server` should refuse timeouts of 0, which would fail every read
+// or write on a connection.
+#[test]
+fn cli_server_zero_timeouts() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for flag in ["--idle-timeout", "--read-timeout", "--write-timeout"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args([flag, "0"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("must be at least 1 second"));
+    }
+}
+
+// `kvs-client --password` should authenticate with a server started
+// with `--requirepass`.
+#[test]
+fn cli_client_password() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .to_string();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--requirepass", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr, "--errors", "json"])
+        .assert()
+        .failure()
+        .stderr(contains(r#""code":"auth""#));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .args(["--password", "secret"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr, "--password", "secret"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+// `kvs-client --errors json` should report failures as JSON objects.
+#[test]
+fn cli_client_json_errors() {
+    // nothing listens on the address once the listener is dropped
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .to_string();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr, "--errors", "json"])
+        .assert()
+        .failure()
+        .stderr(contains(r#""code":"io""#));
+}
+
+// Requests with keys rejected by the server's key policy should fail.
+#[test]
+fn server_key_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store).with_key_policy(KeyPolicy::new().max_len(4).fold_case());
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("KEY".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        client.set("key10".to_owned(), "value".to_owned()),
+        Err(KvsError::Server(msg)) if msg.contains("Invalid key")
+    ));
+    client.remove("Key".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// A server answering `MissingKey::Error` should fail a `GET` for a
+// missing key, but not the missing keys of an `MGET`.
+#[test]
+fn server_missing_key_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store).with_missing_key(MissingKey::Error);
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.get("key".to_owned()),
+        Err(KvsError::NonExistentKey(key)) if key == "key"
+    ));
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    let results = client.get_many(vec!["nokey".to_owned()])?;
+    assert_eq!(results[0].as_ref().ok(), Some(&None));
+    drop(client);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nnokey\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        String::from_utf8_lossy(&response),
+        "*2\r\n$14\r\nNONEXISTENTKEY\r\n$5\r\nnokey\r\n"
+    );
+    Ok(())
+}
+
+// Batches should be answered key by key, with rejected keys failing on
+// their own.
+#[test]
+fn client_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store).with_key_policy(KeyPolicy::new().max_len(4));
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    let results = client.set_many(vec![
+        ("k1".to_owned(), "v1".to_owned()),
+        ("key10".to_owned(), "v10".to_owned()),
+        ("k2".to_owned(), "v2".to_owned()),
+    ])?;
+    assert_eq!(results.len(), 3);
+    assert!(results[0].is_ok());
+    assert!(matches!(&results[1], Err(KvsError::Server(msg)) if msg.contains("Invalid key")));
+    assert!(results[2].is_ok());
+
+    let keys = ["k2", "key10", "k3", "k1"];
+    let results = client.get_many(keys.iter().map(|&key| key.to_owned()).collect())?;
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0].as_ref().ok(), Some(&Some("v2".to_owned())));
+    assert!(results[1].is_err());
+    assert_eq!(results[2].as_ref().ok(), Some(&None));
+    assert_eq!(results[3].as_ref().ok(), Some(&Some("v1".to_owned())));
+    assert!(client.get_many(Vec::new())?.is_empty());
+
+    // single requests go on working on the same connection
+    assert_eq!(client.get("k1".to_owned())?, Some("v1".to_owned()));
+    Ok(())
+}
+
+// Batches are arrays nested in the request.
+#[test]
+fn batch_wire_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$4\r\nMSET\r\n*1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n")?;
+    stream.write_all(b"*2\r\n$4\r\nMGET\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        std::str::from_utf8(&response).unwrap(),
+        "*2\r\n$5\r\nBATCH\r\n*1\r\n*2\r\n$2\r\nOK\r\n$-1\r\n\
+         *2\r\n$5\r\nBATCH\r\n*2\r\n*2\r\n$2\r\nOK\r\n$1\r\n1\r\n*2\r\n$2\r\nOK\r\n$-1\r\n"
+    );
+    Ok(())
+}
+
+// Scans should stream the entries in a range in key order.
+#[test]
+fn client_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let entries: Vec<_> = (0..1000)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(entries.clone())?;
+
+    let all = client.scan(None, None)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(all, entries);
+    let range = client
+        .scan(Some("key0010".to_owned()), Some("key0013".to_owned()))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(range, entries[10..13]);
+    assert_eq!(client.scan(Some("z".to_owned()), None)?.count(), 0);
+
+    // a scan dropped early leaves the connection usable
+    let mut scan = client.scan(None, None)?;
+    assert_eq!(scan.next().transpose()?, Some(entries[0].clone()));
+    drop(scan);
+    assert_eq!(
+        client.get("key0999".to_owned())?,
+        Some("value999".to_owned())
+    );
+    Ok(())
+}
+
+// Pages should be resumable on another connection by their cursor's
+// token, also after the store was compacted in between.
+#[test]
+fn client_scan_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store.clone());
+    thread::spawn(move || server.serve(listener));
+
+    let entries: Vec<_> = (0..100)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    let mut client = KvsClient::connect(addr)?;
+    client.set_many(entries.clone())?;
+
+    let mut pages = Vec::new();
+    let mut token = None;
+    loop {
+        let cursor = token.as_deref().map(str::parse::<Cursor>).transpose()?;
+        let page = KvsClient::connect(addr)?.scan_page(cursor.as_ref(), 30)?;
+        pages.push(page.entries);
+        match page.next {
+            Some(next) => token = Some(next.to_string()),
+            None => break,
+        }
+        client.set_many(entries.clone())?;
+        store.compact_now()?;
+    }
+    assert_eq!(pages.len(), 4);
+    assert_eq!(pages.concat(), entries);
+
+    assert!(matches!(
+        client.scan_page(None, 0)?.entries[..],
+        [(ref key, _)] if key == "key0000"
+    ));
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$8\r\nSCANPAGE\r\n$3\r\nbad\r\n:1\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        std::str::from_utf8(&response).unwrap(),
+        "*2\r\n$3\r\nERR\r\n$21\r\nInvalid cursor: \"bad\"\r\n"
+    );
+    Ok(())
+}
+
+// A scan is answered with an entry per key followed by an end marker.
+#[test]
+fn scan_wire_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n")?;
+    stream.write_all(b"*3\r\n$4\r\nSCAN\r\n$-1\r\n$-1\r\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert_eq!(
+        std::str::from_utf8(&response).unwrap(),
+        "*2\r\n$2\r\nOK\r\n$-1\r\n\
+         *3\r\n$5\r\nENTRY\r\n$1\r\na\r\n$1\r\n1\r\n$3\r\nEND\r\n"
+    );
+    Ok(())
+}
+
+// Typed values should be encoded with the client's codec.
+#[test]
+fn client_typed_values() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let point = Point { x: 1, y: -2 };
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_typed("json".to_owned(), &point)?;
+    assert_eq!(
+        client.get("json".to_owned())?,
+        Some(r#"{"x":1,"y":-2}"#.to_owned())
+    );
+    assert_eq!(
+        client.get_typed("json".to_owned())?,
+        Some(Point { x: 1, y: -2 })
+    );
+    assert_eq!(client.get_typed::<Point>("missing".to_owned())?, None);
+    client.set("plain".to_owned(), "not json".to_owned())?;
+    assert!(matches!(
+        client.get_typed::<Point>("plain".to_owned()),
+        Err(KvsError::Codec(_))
+    ));
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?.with_codec(Codec::Ron);
+    client.set_typed("ron".to_owned(), &point)?;
+    assert_eq!(client.get("ron".to_owned())?, Some("(x:1,y:-2)".to_owned()));
+    assert_eq!(client.get_typed("ron".to_owned())?, Some(point));
+    Ok(())
+}
+
+// Flips the bits of values, standing in for encryption.
+struct Flip;
+
+impl ValueTransform for Flip {
+    fn apply(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.into_iter().map(|b| !b).collect())
+    }
+
+    fn reverse(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        self.apply(value)
+    }
+}
+
+// Values should pass through the client's transformations both ways,
+// and reach the server only transformed.
+#[test]
+fn client_transforms() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?
+        .with_transform(Lz4Compression)
+        .with_transform(Flip);
+    let mut plain = KvsClient::connect(addr)?;
+    let value = "secret ".repeat(100);
+
+    client.set("key1".to_owned(), value.clone())?;
+    assert_eq!(client.get("key1".to_owned())?, Some(value.clone()));
+    let stored = plain.get("key1".to_owned())?.unwrap();
+    assert!(!stored.contains("secret"));
+    assert!(stored.len() < value.len());
+
+    client.set_many(vec![("key2".to_owned(), "value2".to_owned())])?;
+    assert_eq!(
+        client.get_or_insert("key3".to_owned(), "value3".to_owned())?,
+        "value3"
+    );
+    assert!(client.compare_and_swap(
+        "key3".to_owned(),
+        Some("value3".to_owned()),
+        "new value3".to_owned()
+    )?);
+    let values: Vec<_> = client
+        .get_many(vec![
+            "key2".to_owned(),
+            "key3".to_owned(),
+            "key4".to_owned(),
+        ])?
+        .into_iter()
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        values,
+        vec![
+            Some("value2".to_owned()),
+            Some("new value3".to_owned()),
+            None
+        ]
+    );
+    let entries = client
+        .scan(Some("key2".to_owned()), None)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "new value3".to_owned()),
+        ]
+    );
+
+    plain.set("untransformed".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        client.get("untransformed".to_owned()),
+        Err(KvsError::Codec(_))
+    ));
+    Ok(())
+}
+
+// Fetches `path` from the HTTP server at `addr`, returning the whole
+// response.
+fn http_get(addr: SocketAddr, path: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+// The server should count requests by command, with their errors and
+// latencies, and connections, and include the stats of the store, both
+// for `METRICS` requests and over HTTP.
+#[test]
+fn server_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let metrics_listener = TcpListener::bind("127.0.0.1:0")?;
+    let metrics_addr = metrics_listener.local_addr()?;
+    let server = KvsServer::new(store).with_metrics_listener(metrics_listener);
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.get("key2".to_owned())?;
+    assert!(client.remove("key2".to_owned()).is_err());
+    client.scan(None, None)?.for_each(drop);
+    let metrics = client.metrics()?;
+    for line in [
+        "kvs_requests_total{command=\"get\"} 2",
+        "kvs_requests_total{command=\"set\"} 1",
+        "kvs_requests_total{command=\"scan\"} 1",
+        "kvs_request_errors_total{command=\"rm\"} 1",
+        "kvs_request_errors_total{command=\"get\"} 0",
+        "kvs_request_duration_seconds_bucket{command=\"get\",le=\"+Inf\"} 2",
+        "kvs_request_duration_seconds_count{command=\"rm\"} 1",
+        "kvs_connections_active 1",
+        "kvs_connections_total 1",
+        "kvs_live_keys 1",
+        "kvs_engine_writes_total 1",
+        "# TYPE kvs_lock_wait_seconds histogram",
+    ] {
+        assert!(
+            metrics.lines().any(|l| l == line),
+            "missing {} in\n{}",
+            line,
+            metrics
+        );
+    }
+
+    let response = http_get(metrics_addr, "/metrics")?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(response.contains("kvs_requests_total{command=\"metrics\"} 1\n"));
+    assert!(http_get(metrics_addr, "/")?.starts_with("HTTP/1.1 404 Not Found"));
+    Ok(())
+}
@@ -1,8 +1,21 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::export::{self, Change, DiffFormat, ExportFormat};
+use kvs::{
+    CompactionFilter, CompactionFinished, CompactionStarted, CorruptionDetected, Cursor,
+    EventListener, Evicted, FilterDecision, IndexMode, KeyPolicy, KvStore, KvsEngine, KvsError,
+    RecordKind, RecoveryMode, Result, SegmentDropped, SegmentSealed, SyncPolicy, WriteBatch,
+};
 use predicates::ord::eq;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs::OpenOptions;
 use std::process::Command;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +30,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +41,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +54,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +67,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -65,14 +78,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +93,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -95,13 +108,13 @@ fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let _ = simple_logger::SimpleLogger::new().init();
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -109,7 +122,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -122,13 +135,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -137,19 +150,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -158,13 +171,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -173,7 +186,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -182,7 +195,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -192,7 +205,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -203,7 +216,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -212,7 +225,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -220,18 +233,58 @@ fn overwrite_value() -> Result<()> {
     Ok(())
 }
 
+// Batches should set and get several keys at once, in order.
+#[test]
+fn batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .key_policy(KeyPolicy::new().max_len(8))
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+
+    store.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key1".to_owned(), "value3".to_owned()),
+    ])?;
+    let keys = ["key2", "key3", "key1"].map(|key| key.to_owned());
+    let expected = vec![Some("value2".to_owned()), None, Some("value3".to_owned())];
+    assert_eq!(store.get_many(&keys)?, expected);
+
+    // A rejected key fails the whole batch.
+    assert!(matches!(
+        store.set_many(vec![
+            ("key3".to_owned(), "value".to_owned()),
+            ("long key 4".to_owned(), "value".to_owned()),
+        ]),
+        Err(KvsError::InvalidKey { .. })
+    ));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(store.get_many(&["long key 4".to_owned()]).is_err());
+    store.set_many(Vec::new())?;
+    assert!(store.get_many(&[])?.is_empty());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get_many(&keys)?, expected);
+    Ok(())
+}
+
 // Should get `None` when getting a non-existent key.
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -240,7 +293,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -248,7 +301,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -260,7 +313,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     let _ = simple_logger::SimpleLogger::new().init();
 
     let dir_size = || {
@@ -291,7 +344,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -301,3 +354,3079 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Registered listeners should be notified of compactions.
+#[test]
+fn compaction_events() -> Result<()> {
+    #[derive(Default)]
+    struct Counter {
+        started: AtomicUsize,
+        finished: AtomicUsize,
+    }
+
+    impl EventListener for Counter {
+        fn on_compaction_start(&self, _event: &CompactionStarted) {
+            self.started.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_compaction_finish(&self, event: &CompactionFinished) {
+            // "key" and "pinned", which keeps the oldest segment from
+            // being dropped instead of compacted
+            assert_eq!(event.live_keys, 2);
+            self.finished.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .segment_size(64 * 1024)
+        .open(temp_dir.path())?;
+    let counter = Arc::new(Counter::default());
+    store.add_listener(counter.clone());
+
+    // keeps the oldest segment from being dropped outright
+    store.set("pinned".to_owned(), "value".to_owned())?;
+    let value = "x".repeat(1024);
+    for _ in 0..2048 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    store.wait_for_compaction()?;
+
+    let started = counter.started.load(Ordering::SeqCst);
+    assert!(started > 0, "No compaction detected");
+    assert_eq!(started, counter.finished.load(Ordering::SeqCst));
+    Ok(())
+}
+
+// Compaction filters should be able to remove and rewrite entries for
+// good, also with a hashed index or deduplicated values.
+#[test]
+fn compaction_filters() -> Result<()> {
+    struct DropDeprecated;
+
+    impl CompactionFilter for DropDeprecated {
+        fn filter(&self, key: &str, _value: &str) -> FilterDecision {
+            if key.starts_with("deprecated:") {
+                FilterDecision::Remove
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    struct UpgradeLegacy;
+
+    impl CompactionFilter for UpgradeLegacy {
+        fn filter(&self, key: &str, value: &str) -> FilterDecision {
+            match value.strip_prefix("v1:") {
+                Some(rest) if key.starts_with("legacy:") => {
+                    FilterDecision::Transform(format!("v2:{}", rest))
+                }
+                _ => FilterDecision::Keep,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl EventListener for Counter {
+        fn on_compaction_finish(&self, _event: &CompactionFinished) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    for &(mode, dedup) in &[
+        (IndexMode::Ordered, None),
+        (IndexMode::Hashed, None),
+        (IndexMode::Ordered, Some(4)),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            let mut builder = KvStore::builder()
+                .index_mode(mode)
+                .segment_size(1024)
+                .compaction_threshold(1024);
+            if let Some(min_size) = dedup {
+                builder = builder.dedup_values(min_size);
+            }
+            builder.open(temp_dir.path())
+        };
+        let store = open()?;
+        let counter = Arc::new(Counter::default());
+        store.add_listener(counter.clone());
+        store.add_compaction_filter(Arc::new(DropDeprecated));
+        store.add_compaction_filter(Arc::new(UpgradeLegacy));
+
+        store.set("deprecated:a".to_owned(), "v1:a".to_owned())?;
+        store.set("deprecated:b".to_owned(), "v1:b".to_owned())?;
+        store.set("legacy:x".to_owned(), "v1:x".to_owned())?;
+        store.set("other".to_owned(), "v1:y".to_owned())?;
+        let value = "x".repeat(100);
+        for _ in 0..500 {
+            store.set("filler".to_owned(), value.clone())?;
+        }
+        store.wait_for_compaction()?;
+        assert!(
+            counter.0.load(Ordering::SeqCst) > 0,
+            "No compaction detected"
+        );
+
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.get("deprecated:a".to_owned())?, None);
+            assert_eq!(store.count("deprecated:")?, 0);
+            assert_eq!(store.get("legacy:x".to_owned())?, Some("v2:x".to_owned()));
+            assert_eq!(store.get("other".to_owned())?, Some("v1:y".to_owned()));
+            assert_eq!(store.get("filler".to_owned())?, Some(value.clone()));
+            Ok(())
+        };
+        check(&store)?;
+        assert!(matches!(
+            store.remove("deprecated:b".to_owned()),
+            Err(KvsError::NonExistentKey(_))
+        ));
+        drop(store);
+        check(&open()?)?;
+    }
+    Ok(())
+}
+
+// A store with a hashed index should behave like one with an ordered index.
+#[test]
+fn hashed_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::open_with_index_mode(temp_dir.path(), IndexMode::Hashed);
+    let store = open()?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Trigger compactions and check that all keys survive them.
+    let value = "x".repeat(1024);
+    for iter in 0..2048 {
+        store.set(format!("key{}", iter % 16), value.clone())?;
+    }
+    drop(store);
+    let store = open()?;
+    for key_id in 0..16 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Should count keys and their size by prefix.
+#[test]
+fn count_and_usage() -> Result<()> {
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_index_mode(temp_dir.path(), mode)?;
+        assert_eq!(store.count("")?, 0);
+        assert_eq!(store.usage("")?, 0);
+
+        store.set("user:1:name".to_owned(), "alice".to_owned())?;
+        store.set("user:1:mail".to_owned(), "alice@example.com".to_owned())?;
+        store.set("user:2:name".to_owned(), "bob".to_owned())?;
+        store.set("users".to_owned(), "2".to_owned())?;
+        store.remove("user:2:name".to_owned())?;
+
+        assert_eq!(store.count("")?, 3);
+        assert_eq!(store.count("user:")?, 2);
+        assert_eq!(store.count("user:1:")?, 2);
+        assert_eq!(store.count("user:2:")?, 0);
+        assert_eq!(store.count("users")?, 1);
+
+        let all = store.usage("")?;
+        let user1 = store.usage("user:1:")?;
+        assert!(user1 > 0 && user1 < all);
+        assert_eq!(store.usage("user:")?, user1);
+        assert_eq!(store.usage("user:2:")?, 0);
+    }
+    Ok(())
+}
+
+// `kvs export` piped into `kvs import` should copy all live entries.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(src_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "kvsdump"])
+        .current_dir(&src_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import"])
+        .current_dir(&dst_dir)
+        .write_stdin(output.stdout)
+        .assert()
+        .success();
+
+    let store = KvStore::open(dst_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Every format should round-trip keys and values that need escaping.
+#[test]
+fn export_import_formats() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(src_dir.path())?;
+    let entries = [
+        ("plain", "value"),
+        ("comma,key", "quoted \"value\""),
+        ("line\r\nbreak", "ünïcödé\n"),
+        ("empty", ""),
+    ];
+    for (key, value) in entries.iter() {
+        store.set(key.to_string(), value.to_string())?;
+    }
+
+    for format in [
+        ExportFormat::KvsDump,
+        ExportFormat::Json,
+        ExportFormat::Csv,
+        ExportFormat::MsgPack,
+        ExportFormat::RespCommands,
+    ] {
+        let mut dump = Vec::new();
+        assert_eq!(export::export(&store, format, &mut dump)?, 4);
+
+        let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+        let copy = KvStore::open(dst_dir.path())?;
+        assert_eq!(export::import(&copy, format, &dump[..])?, 4, "{}", format);
+        for (key, value) in entries.iter() {
+            assert_eq!(
+                copy.get(key.to_string())?,
+                Some(value.to_string()),
+                "{}",
+                format
+            );
+        }
+        assert_eq!(format.to_string().parse::<ExportFormat>()?, format);
+    }
+    Ok(())
+}
+
+// Exports of RESP commands should be what `redis-cli --pipe` takes, with
+// the expiry times of values.
+#[test]
+fn export_resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    let mut commands = Vec::new();
+    assert_eq!(
+        export::export(&store, ExportFormat::RespCommands, &mut commands)?,
+        2
+    );
+    let commands = String::from_utf8(commands).unwrap();
+    assert!(commands.starts_with("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n"));
+    assert!(commands[35..]
+        .starts_with("*5\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n$4\r\nPXAT\r\n$13\r\n"));
+
+    let result = export::import(
+        &store,
+        ExportFormat::RespCommands,
+        &b"*2\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n"[..],
+    );
+    assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+    let result = export::import(
+        &store,
+        ExportFormat::RespCommands,
+        &commands.as_bytes()[..40],
+    );
+    assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+    // lengths are not trusted
+    for input in [
+        &b"*1\r\n$18446744073709551615\r\n"[..],
+        b"*1\r\n$100000000000\r\nSET\r\n",
+        b"*1\r\n$3\r\nSETX\r\n",
+    ] {
+        let result = export::import(&store, ExportFormat::RespCommands, input);
+        assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+    }
+    Ok(())
+}
+
+// Parquet exports should round-trip entries over several row groups, and
+// carry the expiry times of values along.
+#[cfg(feature = "parquet")]
+#[test]
+fn export_import_parquet() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(src_dir.path())?;
+    store.set_many(
+        (0..70_000)
+            .map(|i| (format!("key{}", i), i.to_string()))
+            .collect(),
+    )?;
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    let mut dump = Vec::new();
+    assert_eq!(
+        export::export(&store, ExportFormat::Parquet, &mut dump)?,
+        70_001
+    );
+    assert!(dump.starts_with(b"PAR1") && dump.ends_with(b"PAR1"));
+    assert_eq!("parquet".parse::<ExportFormat>()?, ExportFormat::Parquet);
+    {
+        use parquet::file::reader::FileReader;
+        use parquet::record::Field;
+        let file = parquet::file::serialized_reader::SerializedFileReader::new(bytes::Bytes::from(
+            dump.clone(),
+        ))
+        .unwrap();
+        assert!(file.num_row_groups() > 1);
+        let expires: Vec<bool> = file
+            .get_row_iter(None)
+            .unwrap()
+            .filter_map(|row| {
+                let row = row.unwrap();
+                let mut columns = row.get_column_iter();
+                let (_, key) = columns.next().unwrap();
+                let (_, expires) = columns.nth(1).unwrap();
+                match key {
+                    Field::Str(key) if key == "expiring" || key == "key0" => {
+                        Some(matches!(expires, Field::TimestampMillis(_)))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        assert_eq!(expires, [true, false]);
+    }
+
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy = KvStore::open(dst_dir.path())?;
+    assert_eq!(
+        export::import(&copy, ExportFormat::Parquet, &dump[..])?,
+        70_001
+    );
+    assert_eq!(copy.get("key69999".to_owned())?, Some("69999".to_owned()));
+    assert_eq!(copy.get("expiring".to_owned())?, Some("value".to_owned()));
+
+    dump.truncate(dump.len() - 4);
+    assert!(matches!(
+        export::import(&copy, ExportFormat::Parquet, &dump[..]),
+        Err(KvsError::InvalidDump(_))
+    ));
+    Ok(())
+}
+
+// `kvs export <file>` and `kvs import <file>` should use the file instead of
+// stdout and stdin.
+#[test]
+fn cli_export_import_file() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = src_dir.path().join("export.csv");
+
+    let store = KvStore::open(src_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv"])
+        .arg(&file)
+        .current_dir(&src_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(std::fs::read_to_string(&file)?, "key1,value1\r\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv"])
+        .arg(&file)
+        .current_dir(&dst_dir)
+        .assert()
+        .success();
+
+    let store = KvStore::open(dst_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Malformed portable exports should be rejected.
+#[test]
+fn import_invalid_portable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let invalid: [(ExportFormat, &[u8]); 4] = [
+        (ExportFormat::Json, b"{\"key\": 1}"),
+        (ExportFormat::Csv, b"key,value,extra\n"),
+        (ExportFormat::Csv, b"\"key,value\n"),
+        (ExportFormat::MsgPack, b"\x92\xa3key"),
+    ];
+    for (format, input) in invalid.iter() {
+        let result = export::import(&store, *format, *input);
+        assert!(
+            matches!(result, Err(KvsError::InvalidDump(_))),
+            "{}",
+            format
+        );
+    }
+    Ok(())
+}
+
+// Truncated or corrupted dumps should be rejected.
+#[test]
+fn import_invalid_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut dump = Vec::new();
+    assert_eq!(export::export(&store, ExportFormat::KvsDump, &mut dump)?, 1);
+
+    let truncated = &dump[..dump.len() - 1];
+    let result = export::import(&store, ExportFormat::KvsDump, truncated);
+    assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+
+    let mut corrupted = dump.clone();
+    corrupted[20] ^= 0xff;
+    let result = export::import(&store, ExportFormat::KvsDump, &corrupted[..]);
+    assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+
+    // a corrupted length, here the key length of the first entry, is not
+    // trusted before the checksum is checked
+    let mut huge = dump.clone();
+    huge[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+    let result = export::import(&store, ExportFormat::KvsDump, &huge[..]);
+    assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+
+    assert_eq!(export::import(&store, ExportFormat::KvsDump, &dump[..])?, 1);
+    Ok(())
+}
+
+// Builds a Redis RDB dump with string keys, an expired and an expiring
+// key, a list and a key of database 1, ending in a zero checksum.
+fn redis_dump() -> Vec<u8> {
+    fn string(dump: &mut Vec<u8>, s: &[u8]) {
+        dump.push(s.len() as u8);
+        dump.extend_from_slice(s);
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let mut dump = b"REDIS0009".to_vec();
+    dump.push(0xfa);
+    string(&mut dump, b"redis-ver");
+    string(&mut dump, b"6.2.6");
+    dump.extend_from_slice(&[0xfe, 0, 0xfb, 5, 2]);
+    dump.push(0);
+    string(&mut dump, b"key1");
+    string(&mut dump, b"value1");
+    dump.push(0);
+    string(&mut dump, b"bytes");
+    string(&mut dump, b"\xff\xfe");
+    dump.push(0xfc);
+    dump.extend_from_slice(&1000u64.to_le_bytes());
+    dump.push(0);
+    string(&mut dump, b"expired");
+    string(&mut dump, b"value");
+    dump.push(0xfc);
+    dump.extend_from_slice(&(now + 3_600_000).to_le_bytes());
+    dump.push(0);
+    string(&mut dump, b"expiring");
+    string(&mut dump, b"value");
+    dump.push(1);
+    string(&mut dump, b"list");
+    dump.push(1);
+    string(&mut dump, b"item");
+    dump.extend_from_slice(&[0xfe, 1]);
+    dump.push(0);
+    string(&mut dump, b"key2");
+    string(&mut dump, b"value2");
+    dump.push(0xff);
+    dump.extend_from_slice(&[0; 8]);
+    dump
+}
+
+// The string keys of database 0 of a Redis dump should be imported, and
+// the other keys reported.
+#[test]
+fn import_redis_rdb() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let report = export::import_redis_rdb(&store, &redis_dump()[..])?;
+    assert_eq!(report.imported, 3);
+    assert_eq!(report.skipped_types.get("list"), Some(&1));
+    assert_eq!(report.skipped_databases, 1);
+    assert_eq!(report.skipped_keys, 0);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_bytes("bytes".to_owned())?,
+        Some(b"\xff\xfe".to_vec())
+    );
+    assert_eq!(store.get("expired".to_owned())?, None);
+    let ttl = store.ttl("expiring".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert_eq!(store.get("list".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let mut truncated = redis_dump();
+    truncated.truncate(40);
+    let result = export::import_redis_rdb(&store, &truncated[..]);
+    assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+    let result = export::import_redis_rdb(&store, &b"REDIS0099"[..]);
+    assert!(matches!(result, Err(KvsError::InvalidDump(_))));
+    Ok(())
+}
+
+// `kvs import --format redis-rdb` should warn about the keys it skips.
+#[test]
+fn cli_import_redis_rdb() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "redis-rdb"])
+        .write_stdin(redis_dump())
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(
+            contains("skipped 1 keys of type list")
+                .and(contains("skipped 1 keys of databases other than 0")),
+        );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `KvStore` should be usable through the `KvsEngine` trait.
+#[test]
+fn engine_trait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: Box<dyn KvsEngine> = Box::new(KvStore::open(temp_dir.path())?);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Clones of a store should be usable from many threads at once, also while
+// the writes trigger compactions.
+#[test]
+fn concurrent_access() -> Result<()> {
+    fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+    assert_shareable::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .segment_size(4 * 1024)
+        .compaction_threshold(8 * 1024)
+        .open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..50 {
+                    for i in 0..20 {
+                        let key = format!("key{}-{}", t, i);
+                        store.set(key.clone(), format!("value{}", iter))?;
+                        assert_eq!(store.get(key)?, Some(format!("value{}", iter)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.count("")?, 8 * 20);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}-{}", t, i))?,
+                Some("value49".to_owned())
+            );
+        }
+    }
+    Ok(())
+}
+
+// Writers racing for the same keys should leave the index in the order of
+// the log, so that a reopened store sees the same values.
+#[test]
+fn concurrent_writes_to_same_keys() -> Result<()> {
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::builder()
+                .index_mode(mode)
+                .segment_size(4 * 1024)
+                .compaction_threshold(8 * 1024)
+                .sync_policy(SyncPolicy::Always)
+                .open(temp_dir.path())
+        };
+        let store = open()?;
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for iter in 0..20 {
+                        let value = format!("{}-{}", t, iter);
+                        store.set_many(vec![
+                            ("shared1".to_owned(), value.clone()),
+                            (format!("own{}", t), value.clone()),
+                            ("shared2".to_owned(), value.clone()),
+                        ])?;
+                        store.set(format!("removed{}", t), value)?;
+                        store.remove(format!("removed{}", t))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        let shared = store.get_many(&["shared1".to_owned(), "shared2".to_owned()])?;
+        assert!(shared[0].as_ref().unwrap().ends_with("-19"));
+        assert_eq!(store.count("")?, 8 + 2);
+        drop(store);
+        let store = open()?;
+        let reopened = store.get_many(&["shared1".to_owned(), "shared2".to_owned()])?;
+        assert_eq!(reopened, shared);
+        for t in 0..8 {
+            assert_eq!(store.get(format!("own{}", t))?, Some(format!("{}-19", t)));
+            assert_eq!(store.get(format!("removed{}", t))?, None);
+        }
+    }
+    Ok(())
+}
+
+// Every write should record how long it waited for the locks of the
+// store, whether through the same handle or not.
+#[test]
+fn lock_waits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let waits = store.lock_waits();
+    assert_eq!(waits.writer.count(), 0);
+    assert_eq!(waits.stripes.count(), 0);
+
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..25 {
+                    store.set("shared".to_owned(), format!("{}-{}", t, iter))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.remove("shared".to_owned());
+    store.commit(batch)?;
+
+    let waits = store.lock_waits();
+    assert_eq!(waits.writer.count(), 101);
+    assert_eq!(waits.stripes.count(), 101);
+    let (bounds, counts): (Vec<_>, Vec<_>) = waits.stripes.buckets().unzip();
+    assert!(bounds.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(counts.iter().sum::<u64>(), 101);
+    // nothing is synced by default
+    assert_eq!(waits.commits.count(), 0);
+    Ok(())
+}
+
+// The stats should count the keys read and written through all handles,
+// and match the segments on disk, also after a compaction.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .segment_size(1024)
+        .compaction_threshold(4 * 1024)
+        .open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!((stats.live_keys, stats.segments), (0, 1));
+    assert_eq!((stats.reads, stats.writes, stats.compactions), (0, 0, 0));
+    assert_eq!(stats.cache, None);
+
+    let clone = store.clone();
+    clone.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_many(vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    store.remove("key3".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key4".to_owned(), "value4".to_owned());
+    batch.remove("key2".to_owned());
+    store.commit(batch)?;
+    clone.get("key1".to_owned())?;
+    store.get_many(&["key2".to_owned(), "key5".to_owned()])?;
+    let stats = store.stats()?;
+    assert_eq!((stats.live_keys, stats.reads, stats.writes), (2, 3, 6));
+    assert_eq!(stats.lock_waits.writer.count(), 4);
+    assert!(stats.stale_bytes > 0);
+
+    let log_bytes = || -> Result<u64> {
+        let mut bytes = 0;
+        for entry in std::fs::read_dir(temp_dir.path())? {
+            let entry = entry?;
+            if entry.path().extension().is_some_and(|ext| ext == "log") {
+                bytes += entry.metadata()?.len();
+            }
+        }
+        Ok(bytes)
+    };
+    assert_eq!(stats.log_bytes, log_bytes()?);
+
+    for i in 0..100 {
+        store.set("filler".to_owned(), format!("{:0>100}", i))?;
+    }
+    store.wait_for_compaction()?;
+    let stats = store.stats()?;
+    assert!(stats.compactions >= 1);
+    assert!(stats.segments > 1);
+    assert_eq!(stats.writes, 106);
+
+    // compacted segments are deleted once no handle reads them anymore,
+    // and activity is counted anew
+    drop((store, clone));
+    let store = KvStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.log_bytes, log_bytes()?);
+    assert_eq!(reopened.live_keys, 3);
+    assert_eq!((reopened.reads, reopened.writes), (0, 0));
+    Ok(())
+}
+
+// Every compaction should be reported in the history, oldest first.
+#[test]
+fn compaction_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.compaction_history().is_empty());
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        store.set(format!("key{}", i), "new value".to_owned())?;
+    }
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    thread::sleep(Duration::from_millis(50));
+    store.compact_now()?;
+    store.set("key0".to_owned(), "newest value".to_owned())?;
+    store.compact_now()?;
+
+    let history = store.compaction_history();
+    assert_eq!(history.len(), 2);
+    assert_eq!(store.stats()?.compaction_history, history);
+    let (first, second) = (&history[0], &history[1]);
+    assert!(first.finished_at <= second.finished_at);
+    assert_eq!(first.records_dropped, 1);
+    assert!(first.bytes_read > first.bytes_written);
+    assert!(first.bytes_written < first.sealed_bytes);
+    assert!(first.live_ratio() > 0.0 && first.live_ratio() < 1.0);
+    // the first compaction left nothing to drop
+    assert_eq!(second.records_dropped, 0);
+    assert!(second.sealed_bytes >= first.bytes_written);
+    Ok(())
+}
+
+// An adaptive compaction threshold should grow with the live entries and
+// keep the log within the space amplification.
+#[test]
+fn adaptive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .segment_size(4 * 1024)
+        .compaction_threshold(1024)
+        .adaptive_compaction(2.0)
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats()?.compaction_threshold, 1024);
+
+    let value = "x".repeat(100);
+    for i in 0..500 {
+        store.set(format!("key{:03}", i), value.clone())?;
+    }
+    // overwriting every other key leaves no segment entirely stale
+    for round in 0..10 {
+        for i in (0..500).step_by(2) {
+            store.set(format!("key{:03}", i), format!("{}{}", value, round))?;
+        }
+    }
+    store.wait_for_compaction()?;
+    let stats = store.stats()?;
+    // the live entries take over 50 KiB
+    assert!(stats.compaction_threshold > 16 * 1024);
+    assert!(stats.compactions >= 1);
+    assert!(stats.stale_bytes <= stats.log_bytes - stats.stale_bytes + 8 * 1024);
+    drop(store);
+
+    let store = KvStore::builder()
+        .segment_size(4 * 1024)
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats()?.compaction_threshold, 1024);
+    Ok(())
+}
+
+// Concurrent writes that are synced should share syncs and all survive.
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .sync_policy(SyncPolicy::Always)
+            .group_commit_latency(Duration::from_millis(1))
+            .segment_size(1024)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..10 {
+                    store.set(format!("key{}-{}", t, iter), format!("{}", iter))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let waits = store.lock_waits();
+    assert_eq!(waits.commits.count(), 80);
+    assert!(waits.commits.sum() >= Duration::from_millis(1));
+    drop(store);
+
+    let store = open()?;
+    for t in 0..8 {
+        for iter in 0..10 {
+            let value = store.get(format!("key{}-{}", t, iter))?;
+            assert_eq!(value, Some(format!("{}", iter)));
+        }
+    }
+    Ok(())
+}
+
+// Reads through other handles should see a consistent store while a
+// writer keeps triggering compactions.
+#[test]
+fn reads_during_compaction() -> Result<()> {
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl EventListener for Counter {
+        fn on_compaction_finish(&self, _event: &CompactionFinished) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .index_mode(mode)
+            .segment_size(1024)
+            .compaction_threshold(1024)
+            .open(temp_dir.path())?;
+        // keeps the oldest segment from being dropped outright
+        store.set("pinned".to_owned(), "value".to_owned())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        let counter = Arc::new(Counter::default());
+        store.add_listener(counter.clone());
+
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..20 {
+                    for i in 0..100 {
+                        store.set(format!("key{}", i), format!("value{}", iter))?;
+                    }
+                }
+                Ok(())
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..20 {
+                        for i in 0..100 {
+                            let value = store.get(format!("key{}", i))?.unwrap();
+                            assert!(value.starts_with("value"));
+                        }
+                        assert_eq!(store.count("key")?, 100);
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        writer.join().unwrap()?;
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+        store.wait_for_compaction()?;
+        assert!(counter.0.load(Ordering::SeqCst) > 0);
+        assert_eq!(store.get("key7".to_owned())?, Some("value19".to_owned()));
+    }
+    Ok(())
+}
+
+// Writes should go on while a compaction runs in the background, and
+// keep what they wrote once it is done.
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    // holds up compactions until the gate is opened
+    struct Gate(Arc<Mutex<()>>);
+
+    impl CompactionFilter for Gate {
+        fn filter(&self, _key: &str, _value: &str) -> FilterDecision {
+            drop(self.0.lock().unwrap());
+            FilterDecision::Keep
+        }
+    }
+
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl EventListener for Counter {
+        fn on_compaction_start(&self, _event: &CompactionStarted) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::builder()
+                .index_mode(mode)
+                .segment_size(1024)
+                .compaction_threshold(1024)
+                .open(temp_dir.path())
+        };
+        let store = open()?;
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        store.add_compaction_filter(Arc::new(Gate(gate.clone())));
+        let counter = Arc::new(Counter::default());
+        store.add_listener(counter.clone());
+
+        store.set("kept".to_owned(), "value".to_owned())?;
+        store.set("overwritten".to_owned(), "old".to_owned())?;
+        store.set("removed".to_owned(), "value".to_owned())?;
+        let mut iter = 0;
+        while counter.0.load(Ordering::SeqCst) == 0 {
+            store.set("churn".to_owned(), format!("value{}", iter))?;
+            iter += 1;
+        }
+        // the compaction is stuck copying the sealed segments
+        store.set("overwritten".to_owned(), "new".to_owned())?;
+        store.remove("removed".to_owned())?;
+        for i in 0..100 {
+            store.set("churn".to_owned(), format!("value{}", i))?;
+        }
+        assert_eq!(store.get("overwritten".to_owned())?, Some("new".to_owned()));
+        drop(closed);
+        store.wait_for_compaction()?;
+
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.get("overwritten".to_owned())?, Some("new".to_owned()));
+            assert_eq!(store.get("removed".to_owned())?, None);
+            assert_eq!(store.get("churn".to_owned())?, Some("value99".to_owned()));
+            assert_eq!(store.len(), 3);
+            Ok(())
+        };
+        check(&store)?;
+        drop(store);
+        check(&open()?)?;
+    }
+    Ok(())
+}
+
+// A manual compaction should leave only live records, no matter the
+// threshold.
+#[test]
+fn compact_now() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact_now()?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    store.compact_now()?;
+    let records = store.log_records()?;
+    assert_eq!(records.len(), 2);
+    assert!(records.iter().all(|record| record.live));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let store = KvStore::builder().read_only().open(temp_dir.path())?;
+    assert!(matches!(store.compact_now(), Err(KvsError::ReadOnly)));
+    Ok(())
+}
+
+// Compactions should copy no faster than the rate limit.
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_rate_limit(64 * 1024)
+        .open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+    for i in 0..32 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+
+    let start = Instant::now();
+    store.compact_now()?;
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    store.set_compaction_rate_limit(None);
+    store.set("key0".to_owned(), "value".to_owned())?;
+    store.compact_now()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key31".to_owned())?, Some(value));
+    Ok(())
+}
+
+// `kvs compact` should compact the log right away.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--rate-limit", "1000000"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log-dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("2.log\t7\t23\tset\t\"key1\"\tlive\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--report"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("read 23 bytes, wrote 30 bytes, dropped 0 records"));
+    Ok(())
+}
+
+// `kvs --engine` should only accept known engines.
+#[test]
+fn cli_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "kvs", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "unknown", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Segments should also be sealed once their first write gets too old.
+#[test]
+fn segment_max_age() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || {
+        let mut gens: Vec<u64> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+    let max_age = Duration::from_millis(200);
+    let store = KvStore::builder()
+        .segment_max_age(max_age)
+        .open(temp_dir.path())?;
+
+    // Segments age from their first write on.
+    thread::sleep(max_age);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(log_files(), vec![1]);
+
+    thread::sleep(max_age);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(log_files(), vec![1, 3]);
+    thread::sleep(max_age);
+    store.remove("key1".to_owned())?;
+    assert_eq!(log_files(), vec![1, 3, 5]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Sealed segments holding only stale entries should be deleted without a
+// compaction, as long as no older segment needs their removals.
+#[test]
+fn stale_segments_are_dropped() -> Result<()> {
+    #[derive(Default)]
+    struct Counter {
+        dropped: AtomicUsize,
+        compacted: AtomicUsize,
+    }
+
+    impl EventListener for Counter {
+        fn on_segment_dropped(&self, _event: &SegmentDropped) {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_compaction_finish(&self, _event: &CompactionFinished) {
+            self.compacted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || {
+        let mut gens: Vec<u64> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+    let open = || {
+        KvStore::builder()
+            .segment_size(1024)
+            .compaction_threshold(1024 * 1024)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    let counter = Arc::new(Counter::default());
+    store.add_listener(counter.clone());
+    let value = "x".repeat(600);
+
+    store.set("keep".to_owned(), "old".to_owned())?;
+    store.set("removed".to_owned(), value.clone())?;
+    store.set("filler".to_owned(), value.clone())?;
+    store.remove("removed".to_owned())?;
+    for _ in 0..4 {
+        store.set("filler".to_owned(), value.clone())?;
+    }
+    // Segment 3 only holds stale entries, but its removal still shadows
+    // an entry of segment 1.
+    assert_eq!(log_files(), vec![1, 3, 5, 7]);
+    assert_eq!(counter.dropped.load(Ordering::SeqCst), 0);
+
+    store.set("keep".to_owned(), "new".to_owned())?;
+    assert_eq!(log_files(), vec![5, 7]);
+    assert_eq!(counter.dropped.load(Ordering::SeqCst), 2);
+    assert_eq!(counter.compacted.load(Ordering::SeqCst), 0);
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("keep".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert_eq!(store.get("filler".to_owned())?, Some(value));
+    Ok(())
+}
+
+// The active segment should be sealed once it is full, and compaction
+// should merge the sealed segments into a new one.
+#[test]
+fn segments() -> Result<()> {
+    #[derive(Default)]
+    struct Counter {
+        sealed: AtomicUsize,
+        compacted: AtomicUsize,
+    }
+
+    impl EventListener for Counter {
+        fn on_segment_sealed(&self, _event: &SegmentSealed) {
+            self.sealed.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_compaction_finish(&self, _event: &CompactionFinished) {
+            self.compacted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || {
+        let mut gens: Vec<u64> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+    let open = || {
+        KvStore::builder()
+            .segment_size(16 * 1024)
+            .open(temp_dir.path())
+    };
+
+    let store = open()?;
+    let counter = Arc::new(Counter::default());
+    store.add_listener(counter.clone());
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(log_files(), vec![1]);
+
+    let value = "x".repeat(1024);
+    for key_id in 0..16 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert_eq!(log_files(), vec![1, 3]);
+    assert_eq!(counter.sealed.load(Ordering::SeqCst), 1);
+    // The removal outlives the compaction of the segment holding the key.
+    store.remove("key0".to_owned())?;
+
+    for _ in 0..1100 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    store.wait_for_compaction()?;
+    assert!(counter.compacted.load(Ordering::SeqCst) > 0);
+    let gens = log_files();
+    assert!(!gens.contains(&1));
+    assert!(gens.len() < counter.sealed.load(Ordering::SeqCst));
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..16 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Compacted segments should be loaded from their hint files, and replayed
+// if the hint file is missing or damaged.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .segment_size(16 * 1024)
+            .open(temp_dir.path())
+    };
+    let hint_files = || -> Vec<std::path::PathBuf> {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+
+    let store = open()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let value = "x".repeat(1024);
+    for _ in 0..1100 {
+        store.set("filler".to_owned(), value.clone())?;
+    }
+    drop(store);
+
+    let hints = hint_files();
+    assert_eq!(hints.len(), 1);
+    let hint = hints[0].clone();
+    assert!(hint.with_extension("log").exists());
+
+    let check = || -> Result<()> {
+        let store = open()?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        assert_eq!(store.get("filler".to_owned())?, Some(value.clone()));
+        Ok(())
+    };
+    check()?;
+
+    let contents = std::fs::read(&hint)?;
+    std::fs::write(&hint, &contents[..contents.len() - 1])?;
+    check()?;
+    std::fs::remove_file(&hint)?;
+    check()?;
+
+    // With its hint, the segment itself is not read on open.
+    std::fs::write(&hint, &contents)?;
+    let segment = hint.with_extension("log");
+    let segment_len = std::fs::metadata(&segment)?.len();
+    std::fs::write(&segment, vec![0xc1; segment_len as usize])?;
+    let store = open()?;
+    assert!(store.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// A log written before segments were numbered should be picked up.
+#[test]
+fn legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::rename(
+        temp_dir.path().join("1.log"),
+        temp_dir.path().join("kvs.log"),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("kvs.log").exists());
+    Ok(())
+}
+
+// Opening the data of another engine, or of another version, should fail
+// right away and name what wrote it.
+#[test]
+fn foreign_data() -> Result<()> {
+    let foreign = |files: &[(&str, &[u8])]| -> Result<KvsError> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        for (name, contents) in files {
+            std::fs::write(temp_dir.path().join(name), contents)?;
+        }
+        Ok(KvStore::open(temp_dir.path())
+            .err()
+            .expect("opened foreign data"))
+    };
+
+    let err = foreign(&[("conf", b"segment_size: 524288"), ("db", &[0; 64])])?;
+    assert!(matches!(&err, KvsError::ForeignData { engine, .. } if engine == "sled"));
+    assert!(err.to_json().contains(r#""code":"foreign_data""#));
+
+    let bincode = [0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, b'k', b'e', b'y'];
+    let err = foreign(&[("kvs.log", &bincode)])?;
+    assert!(matches!(&err, KvsError::ForeignData { engine, .. } if engine.contains("bincode")));
+
+    let err = foreign(&[("1.log", b"KVSLOG\x02")])?;
+    assert!(matches!(
+        &err,
+        KvsError::ForeignData { path, engine, .. }
+            if path.ends_with("1.log") && engine.contains("newer version")
+    ));
+    assert!(err.to_string().contains("upgrade kvs"));
+
+    let err = foreign(&[("MANIFEST", b"engine = sled\ncodec = msgpack\n")])?;
+    assert!(matches!(
+        &err,
+        KvsError::ForeignData { path, engine, .. }
+            if path.ends_with("MANIFEST") && engine == "sled"
+    ));
+    Ok(())
+}
+
+// `kvs-server` should refuse to start on the data of another engine.
+#[test]
+fn cli_foreign_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("conf"), "segment_size: 524288")?;
+    std::fs::write(temp_dir.path().join("db"), [0; 64])?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(r#"engine: "sled""#));
+    Ok(())
+}
+
+// A store created with `init` should keep the compression of its
+// manifest, and refuse to be created twice.
+#[test]
+fn init() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("store");
+    KvStore::builder().compress_values(1024).init(&dir)?;
+    assert!(dir.join("MANIFEST").is_file());
+    assert!(dir.join("1.log").is_file());
+    match KvStore::builder().init(&dir) {
+        Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+        result => panic!("initialized a store twice: {:?}", result),
+    }
+
+    let json = format!("{{\"items\": [{}]}}", vec!["{\"id\": 1}"; 2000].join(", "));
+    let store = KvStore::open(&dir)?;
+    store.set("compressed".to_owned(), json.clone())?;
+    store.set("small".to_owned(), "{}".to_owned())?;
+    assert_eq!(store.get("compressed".to_owned())?, Some(json));
+    let kinds: Vec<_> = store
+        .log_records()?
+        .into_iter()
+        .map(|record| record.kind)
+        .collect();
+    assert_eq!(kinds, [RecordKind::SetCompressed, RecordKind::Set]);
+    Ok(())
+}
+
+// A checkpoint should hold the entries at the time it was taken, share
+// the sealed segments and change independently of its store.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = temp_dir.path().join("source");
+    let dest = temp_dir.path().join("dest");
+    let store = KvStore::builder()
+        .dedup_values(150)
+        .segment_size(1024)
+        .open(&source)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("{:0>100}", i))?;
+    }
+    store.set("shared".to_owned(), "x".repeat(200))?;
+    store.checkpoint(&dest)?;
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.set("shared".to_owned(), "changed".to_owned())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(std::fs::metadata(dest.join("1.log"))?.nlink(), 2);
+    }
+    let clone = KvStore::open(&dest)?;
+    assert_eq!(clone.get("key0".to_owned())?, Some(format!("{:0>100}", 0)));
+    assert_eq!(
+        clone.get("key19".to_owned())?,
+        Some(format!("{:0>100}", 19))
+    );
+    assert_eq!(clone.get("shared".to_owned())?, Some("x".repeat(200)));
+    clone.set("key1".to_owned(), "cloned".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some(format!("{:0>100}", 1)));
+
+    match store.checkpoint(&dest) {
+        Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+        result => panic!("checkpointed over a store: {:?}", result),
+    }
+    Ok(())
+}
+
+// A store should be open for writing only once at a time.
+#[test]
+fn locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked(path)) => assert_eq!(path, temp_dir.path().join("LOCK")),
+        result => panic!("opened a store twice: {:?}", result.map(drop)),
+    }
+    let reader = KvStore::builder().read_only().open(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // clones share the lock, which the last of them releases
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// `kvs checkpoint` should copy a store, even while it is open elsewhere.
+#[test]
+fn cli_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["checkpoint", "copy"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(temp_dir.path().join("copy"))
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// `kvs init` should create a store with the given options, and refuse
+// options it does not support.
+#[test]
+fn cli_init() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "init",
+            "--engine",
+            "kvs",
+            "--codec",
+            "msgpack",
+            "--compression",
+            "lz4",
+            "store",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let manifest = std::fs::read_to_string(temp_dir.path().join("store/MANIFEST"))?;
+    assert!(manifest.contains("compression = lz4"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["init", "store"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already holds a store"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["init", "--codec", "bincode", "other"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("other").exists());
+    Ok(())
+}
+
+// The diff between a checkpoint and the store should list the added,
+// changed and removed keys, and turn a copy of the checkpoint into a copy
+// of the store in either format.
+#[test]
+fn diff() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    for key in ["a", "b", "c", "d"] {
+        store.set(key.to_owned(), key.to_uppercase())?;
+    }
+    store.checkpoint(temp_dir.path().join("old"))?;
+    store.remove("a".to_owned())?;
+    store.set("b".to_owned(), "changed".to_owned())?;
+    store.set("c".to_owned(), "C".to_owned())?;
+    store.set("e".to_owned(), "E".to_owned())?;
+    let old = KvStore::open(temp_dir.path().join("old"))?;
+
+    let changes = export::changes(&old, &store)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        changes,
+        [
+            Change::Removed {
+                key: "a".to_owned()
+            },
+            Change::Changed {
+                key: "b".to_owned(),
+                value: "changed".to_owned()
+            },
+            Change::Added {
+                key: "e".to_owned(),
+                value: "E".to_owned()
+            },
+        ]
+    );
+
+    for format in [DiffFormat::KvsDiff, DiffFormat::Json] {
+        let mut diff = Vec::new();
+        assert_eq!(export::diff(&old, &store, format, &mut diff)?, 3);
+        let copy = KvStore::open(temp_dir.path().join(format!("copy-{}", format)))?;
+        for key in ["a", "b", "c", "d"] {
+            copy.set(key.to_owned(), key.to_uppercase())?;
+        }
+        assert_eq!(export::apply_diff(&copy, format, &diff[..])?, 3);
+        let entries = copy.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries, store.scan(..)?.collect::<Result<Vec<_>>>()?);
+        // applying it twice changes nothing
+        assert_eq!(export::apply_diff(&copy, format, &diff[..])?, 3);
+        assert_eq!(entries, copy.scan(..)?.collect::<Result<Vec<_>>>()?);
+    }
+
+    let mut diff = Vec::new();
+    export::diff(&old, &store, DiffFormat::KvsDiff, &mut diff)?;
+    diff.truncate(diff.len() - 1);
+    assert!(export::apply_diff(&old, DiffFormat::KvsDiff, &diff[..]).is_err());
+    Ok(())
+}
+
+// `kvs diff` should write a diff of two stores that `kvs apply-diff`
+// applies.
+#[test]
+fn cli_diff() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = KvStore::open(temp_dir.path().join("old"))?;
+    old.set("key1".to_owned(), "value1".to_owned())?;
+    old.set("key2".to_owned(), "value2".to_owned())?;
+    drop(old);
+    let new = KvStore::open(temp_dir.path().join("new"))?;
+    new.set("key1".to_owned(), "value3".to_owned())?;
+    drop(new);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["diff", "--format", "json", "old", "new"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            r#"{"op":"changed","key":"key1","value":"value3"}"#,
+        ));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["diff", "old", "new", "changes.kvsdiff"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["old", "apply-diff", "changes.kvsdiff"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let old = KvStore::open(temp_dir.path().join("old"))?;
+    assert_eq!(old.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(old.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A corrupted record should fail its read with its position, and the
+// replay of its segment on open.
+#[test]
+fn corrupted_records() -> Result<()> {
+    #[derive(Default)]
+    struct Detector {
+        offsets: std::sync::Mutex<Vec<u64>>,
+    }
+
+    impl EventListener for Detector {
+        fn on_corruption_detected(&self, event: &CorruptionDetected) {
+            self.offsets.lock().unwrap().push(event.offset);
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let flip = |offset: u64| -> Result<()> {
+        let mut contents = std::fs::read(&log)?;
+        contents[offset as usize] ^= 0xff;
+        std::fs::write(&log, contents)?;
+        Ok(())
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    let detector = Arc::new(Detector::default());
+    store.add_listener(detector.clone());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let key1_len = store.usage("key1")?;
+
+    // the first record follows the segment header
+    let key1_offset = std::fs::metadata(&log)?.len() - 2 * key1_len;
+    flip(key1_offset + key1_len - 1)?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::Corruption { offset, .. }) if offset == key1_offset
+    ));
+    assert_eq!(*detector.offsets.lock().unwrap(), vec![key1_offset]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { offset, .. }) if offset == key1_offset
+    ));
+    flip(key1_offset + key1_len - 1)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `kvs --errors json` should report failures as JSON objects on stderr.
+#[test]
+fn cli_json_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args)
+            .args(["--errors", "json"])
+            .current_dir(&temp_dir);
+        cmd.assert()
+    };
+    kvs(&["rm", "key1"])
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains(r#""code":"non_existent_key""#).and(contains(r#""key":"key1""#)));
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    *contents.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&log, contents)?;
+    kvs(&["get", "key1"])
+        .failure()
+        .stderr(contains(r#""code":"corruption""#).and(contains(r#""offset":7"#)));
+    Ok(())
+}
+
+// A segment written before records had checksums should still be read,
+// but not appended to.
+#[test]
+fn legacy_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // strip the segment header and the record headers
+    let log = temp_dir.path().join("1.log");
+    let contents = std::fs::read(&log)?;
+    assert_eq!(&contents[..6], b"KVSLOG");
+    let mut legacy = Vec::new();
+    let mut pos = 7;
+    while pos < contents.len() {
+        let mut len = [0; 4];
+        len.copy_from_slice(&contents[pos..pos + 4]);
+        let len = u32::from_le_bytes(len) as usize;
+        legacy.extend_from_slice(&contents[pos + 8..pos + 8 + len]);
+        pos += 8 + len;
+    }
+    // a torn write is cut off as well
+    let mut torn = legacy.clone();
+    torn.extend_from_slice(&legacy[..5]);
+    std::fs::write(&log, &torn)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert_eq!(std::fs::read(&log)?, legacy);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A record that was only partly written when the process died should be
+// cut off on open, unless recovery is strict.
+#[test]
+fn torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let key2_offset = std::fs::metadata(&log)?.len() - store.usage("key2")?;
+    drop(store);
+    let contents = std::fs::read(&log)?;
+
+    // cut off within the payload and within the record header
+    for torn_len in &[contents.len() - 1, key2_offset as usize + 3] {
+        std::fs::write(&log, &contents[..*torn_len])?;
+        assert!(matches!(
+            KvStore::builder()
+                .recovery_mode(RecoveryMode::Strict)
+                .open(temp_dir.path()),
+            Err(KvsError::Corruption { offset, .. }) if offset == key2_offset
+        ));
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(std::fs::metadata(&log)?.len(), key2_offset);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// Keys should be normalized and checked by the store's key policy.
+#[test]
+fn key_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = KeyPolicy::new()
+        .max_len(8)
+        .allowed_chars(|c| c.is_alphanumeric())
+        .fold_case();
+    let store = KvStore::builder()
+        .key_policy(policy)
+        .open(temp_dir.path())?;
+
+    store.set("Key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("KEY1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set("key 2".to_owned(), "value2".to_owned()),
+        Err(KvsError::InvalidKey { key, .. }) if key == "key 2"
+    ));
+    assert!(matches!(
+        store.get("TooLongKey".to_owned()),
+        Err(KvsError::InvalidKey { key, .. }) if key == "toolongkey"
+    ));
+    store.remove("KEY1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.count("")?, 0);
+    Ok(())
+}
+
+// Compactions should start once the configured amount of stale bytes is
+// reached.
+#[test]
+fn compaction_threshold() -> Result<()> {
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl EventListener for Counter {
+        fn on_compaction_finish(&self, _event: &CompactionFinished) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    for &(threshold, compacts) in &[(None, false), (Some(4 * 1024), true)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut builder = KvStore::builder()
+            .segment_size(1024)
+            .sync_policy(SyncPolicy::Always);
+        if let Some(threshold) = threshold {
+            builder = builder.compaction_threshold(threshold);
+        }
+        let store = builder.open(temp_dir.path())?;
+        let counter = Arc::new(Counter::default());
+        store.add_listener(counter.clone());
+        // keeps the oldest segment from being dropped outright
+        store.set("pinned".to_owned(), "value".to_owned())?;
+        for iter in 0..1000 {
+            store.set("key".to_owned(), format!("value{}", iter))?;
+        }
+        store.wait_for_compaction()?;
+        assert_eq!(counter.0.load(Ordering::SeqCst) > 0, compacts);
+        assert_eq!(store.get("key".to_owned())?, Some("value999".to_owned()));
+    }
+    Ok(())
+}
+
+// A read-only store should never touch its directory.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files = || -> Vec<(std::path::PathBuf, u64)> {
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let len = std::fs::metadata(&path).unwrap().len();
+                (path, len)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let open = || KvStore::builder().read_only().open(temp_dir.path());
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(files().is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // a torn write is skipped
+    let log = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 1)?;
+    let before = files();
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(files(), before);
+    Ok(())
+}
+
+// `log_records` should list every record and tell live ones apart.
+#[test]
+fn log_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value4".to_owned());
+    store.commit(batch)?;
+
+    let summary = |store: &KvStore| -> Result<Vec<(RecordKind, Option<String>, bool)>> {
+        Ok(store
+            .log_records()?
+            .into_iter()
+            .map(|record| (record.kind, record.key, record.live))
+            .collect())
+    };
+    let key = |key: &str| Some(key.to_owned());
+    let expected = vec![
+        (RecordKind::Set, key("key1"), false),
+        (RecordKind::Set, key("key1"), true),
+        (RecordKind::Set, key("key2"), false),
+        (RecordKind::Rm, key("key2"), false),
+        (RecordKind::Begin, None, false),
+        (RecordKind::Set, key("key3"), true),
+        (RecordKind::Commit, None, false),
+    ];
+    assert_eq!(summary(&store)?, expected);
+
+    let records = store.log_records()?;
+    let log_len = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    for pair in records.windows(2) {
+        assert_eq!(pair[0].offset + pair[0].len, pair[1].offset);
+    }
+    let last = records.last().unwrap();
+    assert_eq!(last.offset + last.len, log_len);
+    drop(store);
+
+    // a torn write shows up as an unreadable record
+    OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?
+        .set_len(log_len - 1)?;
+    let store = KvStore::builder().read_only().open(temp_dir.path())?;
+    let records = summary(&store)?;
+    assert_eq!(records.len(), expected.len());
+    assert_eq!(records[..5], expected[..5]);
+    // the batch is left unfinished
+    assert_eq!(records[5], (RecordKind::Set, key("key3"), false));
+    assert!(matches!(records[6].0, RecordKind::Unreadable { .. }));
+    Ok(())
+}
+
+// `kvs log-dump` should print a line per record without touching the log.
+#[test]
+fn cli_log_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log-dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "1.log\t7\t23\tset\t\"key1\"\tstale\n1.log\t30\t23\tset\t\"key1\"\tlive\n",
+        ));
+    Ok(())
+}
+
+// Stores with different file prefixes should share a directory.
+#[test]
+fn file_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |prefix: &str| KvStore::builder().file_prefix(prefix).open(temp_dir.path());
+
+    let users = open("users-")?;
+    let orders = open("orders-")?;
+    let plain = KvStore::open(temp_dir.path())?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    orders.set("key".to_owned(), "order".to_owned())?;
+    plain.set("key".to_owned(), "plain".to_owned())?;
+    drop((users, orders, plain));
+
+    assert!(temp_dir.path().join("users-1.log").exists());
+    assert!(temp_dir.path().join("orders-1.log").exists());
+    assert_eq!(
+        open("users-")?.get("key".to_owned())?,
+        Some("user".to_owned())
+    );
+    assert_eq!(
+        open("orders-")?.get("key".to_owned())?,
+        Some("order".to_owned())
+    );
+    assert_eq!(open("")?.get("key".to_owned())?, Some("plain".to_owned()));
+    assert!(open("users1").is_err());
+    Ok(())
+}
+
+// Identical large values should be stored once and dropped by compaction
+// when no key refers to them anymore.
+#[test]
+fn dedup_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs_size = || {
+        std::fs::metadata(temp_dir.path().join("blobs.log"))
+            .unwrap()
+            .len()
+    };
+    let open = || {
+        KvStore::builder()
+            .dedup_values(4096)
+            .segment_size(64 * 1024)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+
+    let shared = "x".repeat(8192);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), shared.clone())?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert!(blobs_size() < 2 * 8192);
+    assert_eq!(store.get("key42".to_owned())?, Some(shared.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    // Deduplicated values stay readable without the option.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key42".to_owned())?, Some(shared.clone()));
+    drop(store);
+
+    let store = open()?;
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("other".to_owned(), "y".repeat(8192))?;
+    // Trigger a compaction with values below the deduplication size.
+    for _ in 0..600 {
+        store.set("filler".to_owned(), "z".repeat(2048))?;
+    }
+    store.wait_for_compaction()?;
+    assert!(blobs_size() < 2 * 8192);
+    assert_eq!(store.get("key42".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("y".repeat(8192)));
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("other".to_owned())?, Some("y".repeat(8192)));
+    Ok(())
+}
+
+// Large values should be stored compressed, and logs mixing compressed and
+// plain values should stay readable with or without the option.
+#[test]
+fn compress_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || {
+        std::fs::metadata(temp_dir.path().join("1.log"))
+            .unwrap()
+            .len()
+    };
+    let json = format!("{{\"items\": [{}]}}", vec!["{\"id\": 1}"; 2000].join(", "));
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), json.clone())?;
+    drop(store);
+    let plain_size = log_size();
+
+    let store = KvStore::builder()
+        .compress_values(1024)
+        .open(temp_dir.path())?;
+    store.set("compressed".to_owned(), json.clone())?;
+    store.set("small".to_owned(), "{}".to_owned())?;
+    assert!(log_size() - plain_size < json.len() as u64 / 10);
+    assert_eq!(store.get("plain".to_owned())?, Some(json.clone()));
+    assert_eq!(store.get("compressed".to_owned())?, Some(json.clone()));
+    let kinds: Vec<_> = store
+        .log_records()?
+        .into_iter()
+        .map(|record| record.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [RecordKind::Set, RecordKind::SetCompressed, RecordKind::Set]
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("compressed".to_owned())?, Some(json.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("{}".to_owned()));
+    Ok(())
+}
+
+// Compressed values should survive compaction and be seen by filters in
+// plain text.
+#[test]
+fn compress_values_compaction() -> Result<()> {
+    struct Upper;
+    impl CompactionFilter for Upper {
+        fn filter(&self, _key: &str, value: &str) -> FilterDecision {
+            FilterDecision::Transform(value.to_uppercase())
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compress_values(1024)
+        .segment_size(16 * 1024)
+        .compaction_threshold(16 * 1024)
+        .open(temp_dir.path())?;
+    store.add_compaction_filter(Arc::new(Upper));
+    let value = "abc".repeat(500);
+    store.set("key".to_owned(), value.clone())?;
+    // too small to be compressed
+    for i in 0..100 {
+        store.set("filler".to_owned(), format!("{:0>1000}", i))?;
+    }
+    store.wait_for_compaction()?;
+    assert_eq!(store.get("key".to_owned())?, Some(value.to_uppercase()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some(value.to_uppercase()));
+    Ok(())
+}
+
+// Idle times should only be tracked if asked for, count from the last
+// read or write, and end with the key.
+#[test]
+fn idle_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.idle_time("key".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::builder().track_idle_time().open(temp_dir.path())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    assert_eq!(store.idle_time("new".to_owned())?, Some(Duration::ZERO));
+    assert_eq!(store.idle_time("missing".to_owned())?, None);
+    thread::sleep(Duration::from_millis(1100));
+
+    // keys not accessed since the store was opened are idle since then
+    assert!(store.idle_time("key".to_owned())? >= Some(Duration::from_secs(1)));
+    assert!(store.idle_time("new".to_owned())? >= Some(Duration::from_secs(1)));
+    store.get("key".to_owned())?;
+    assert_eq!(store.idle_time("key".to_owned())?, Some(Duration::ZERO));
+    let mut batch = WriteBatch::new();
+    batch.set("new".to_owned(), "value2".to_owned());
+    store.commit(batch)?;
+    assert_eq!(store.idle_time("new".to_owned())?, Some(Duration::ZERO));
+
+    store.remove("key".to_owned())?;
+    assert_eq!(store.idle_time("key".to_owned())?, None);
+    Ok(())
+}
+
+// Gets should be answered from the value cache after the first, see
+// new values, and evict the least recently used ones beyond its capacity.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.cache_stats(), None);
+    drop(store);
+
+    let store = KvStore::builder()
+        .value_cache(100)
+        .segment_size(4 * 1024)
+        .compaction_threshold(4 * 1024)
+        .open(temp_dir.path())?;
+    store.set("hot".to_owned(), "value1".to_owned())?;
+    for _ in 0..3 {
+        assert_eq!(store.get("hot".to_owned())?, Some("value1".to_owned()));
+    }
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+    assert_eq!((stats.entries, stats.bytes), (1, 9));
+
+    store.set("hot".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value2".to_owned()));
+    store.remove("hot".to_owned())?;
+    assert_eq!(store.get("hot".to_owned())?, None);
+
+    // the 70 byte values only fit one at a time
+    let large = "x".repeat(67);
+    for i in 0..2 {
+        store.set(format!("big{}", i), large.clone())?;
+        assert_eq!(store.get(format!("big{}", i))?, Some(large.clone()));
+    }
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.entries, stats.bytes), (1, 71));
+    assert_eq!(store.get("big0".to_owned())?, Some(large.clone()));
+    assert_eq!(store.cache_stats().unwrap().misses, 5);
+
+    // compactions move values, which are then cached anew
+    for i in 0..100 {
+        store.set("filler".to_owned(), format!("{:0>100}", i))?;
+    }
+    store.wait_for_compaction()?;
+    assert_eq!(store.get("big0".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("big1".to_owned())?, Some(large));
+    assert!(store.cache_stats().unwrap().bytes <= 100);
+    Ok(())
+}
+
+// Values that are not UTF-8 should be stored and read back as bytes.
+#[test]
+fn bytes_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compress_values(1024)
+        .segment_size(16 * 1024)
+        .compaction_threshold(16 * 1024)
+        .open(temp_dir.path())?;
+    let binary = vec![0xff, 0x00, 0xfe, 0x80];
+    let large: Vec<u8> = binary.iter().copied().cycle().take(4096).collect();
+
+    store.set_bytes("binary".to_owned(), binary.clone())?;
+    store.set_bytes("large".to_owned(), large.clone())?;
+    store.set_bytes("text".to_owned(), b"value".to_vec())?;
+    store.set("string".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_bytes("binary".to_owned())?, Some(binary.clone()));
+    assert_eq!(store.get_bytes("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get_bytes("string".to_owned())?,
+        Some(b"value".to_vec())
+    );
+    assert_eq!(store.get_bytes("missing".to_owned())?, None);
+    assert!(matches!(
+        store.get("binary".to_owned()),
+        Err(KvsError::NotUtf8(key)) if key == "binary"
+    ));
+    let kinds: Vec<_> = store
+        .log_records()?
+        .into_iter()
+        .map(|record| record.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            RecordKind::SetBytes,
+            RecordKind::SetCompressed,
+            RecordKind::Set,
+            RecordKind::Set
+        ]
+    );
+
+    // compaction keeps the bytes as they are
+    for i in 0..100 {
+        store.set("filler".to_owned(), format!("{:0>1000}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("binary".to_owned())?, Some(binary));
+    assert_eq!(store.get_bytes("large".to_owned())?, Some(large));
+    Ok(())
+}
+
+// A batch should apply its writes in order.
+#[test]
+fn commit_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key3".to_owned());
+    assert_eq!(batch.len(), 4);
+    store.commit(batch)?;
+    store.commit(WriteBatch::new())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A batch that removes a missing key should fail without applying any of
+// its writes.
+#[test]
+fn commit_batch_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value2".to_owned());
+    batch.remove("key2".to_owned());
+    assert!(matches!(
+        store.commit(batch),
+        Err(KvsError::NonExistentKey(key)) if key == "key2"
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A batch cut short by a crash should not apply at all, while the writes
+// after recovery should.
+#[test]
+fn commit_batch_torn() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let before = std::fs::metadata(&log)?.len() as usize;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.commit(batch)?;
+    drop(store);
+    let contents = std::fs::read(&log)?;
+
+    // within the writes and within the commit marker
+    for cut in [(before + contents.len()) / 2, contents.len() - 1] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        std::fs::write(temp_dir.path().join("1.log"), &contents[..cut])?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, None);
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    }
+    Ok(())
+}
+
+// Scans should return the pairs of a range or prefix sorted by key, for
+// both kinds of index.
+#[test]
+fn scan() -> Result<()> {
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_index_mode(temp_dir.path(), mode)?;
+        for key in &[
+            "user:2:name",
+            "user:12:name",
+            "user:1:name",
+            "user:1:mail",
+            "vendor",
+        ] {
+            store.set(key.to_string(), format!("{}-value", key))?;
+        }
+        store.set("user:3:name".to_owned(), "removed".to_owned())?;
+        store.remove("user:3:name".to_owned())?;
+
+        let keys = |scan: kvs::SnapshotIter| -> Result<Vec<String>> {
+            scan.map(|entry| entry.map(|(key, _)| key)).collect()
+        };
+        assert_eq!(
+            keys(store.scan_prefix("user:1:")?)?,
+            ["user:1:mail", "user:1:name"]
+        );
+        assert_eq!(
+            keys(store.scan_prefix("user:1")?)?,
+            ["user:12:name", "user:1:mail", "user:1:name"]
+        );
+        assert_eq!(keys(store.scan_prefix("nobody")?)?, Vec::<String>::new());
+        assert_eq!(
+            keys(store.scan("user:12".to_owned()..="user:2:name".to_owned())?)?,
+            ["user:12:name", "user:1:mail", "user:1:name", "user:2:name"]
+        );
+        assert_eq!(
+            keys(store.scan(.."user:1:name".to_owned())?)?,
+            ["user:12:name", "user:1:mail"]
+        );
+        assert_eq!(
+            keys(store.scan("user:2".to_owned()..)?)?,
+            ["user:2:name", "vendor"]
+        );
+
+        let entries: Vec<_> = store.scan(..)?.collect::<Result<_>>()?;
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[0],
+            ("user:12:name".to_owned(), "user:12:name-value".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Scans should run backwards and resume at a key, for both kinds of
+// index, so that a store can be paged through.
+#[test]
+fn scan_rev_and_from() -> Result<()> {
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_index_mode(temp_dir.path(), mode)?;
+        for i in (0..10).rev() {
+            store.set(format!("key{}", i), i.to_string())?;
+        }
+        store.set("a".to_owned(), "before".to_owned())?;
+        store.set("z".to_owned(), "after".to_owned())?;
+        let keys = |scan: &mut dyn Iterator<Item = Result<(String, String)>>| {
+            scan.map(|entry| entry.map(|(key, _)| key))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        assert_eq!(
+            keys(&mut store.scan("key7".to_owned()..)?.rev())?,
+            ["z", "key9", "key8", "key7"]
+        );
+        assert_eq!(
+            keys(&mut store.scan_prefix("key")?.rev().take(2))?,
+            ["key9", "key8"]
+        );
+        let mut scan = store.scan_prefix("key")?;
+        assert_eq!(scan.next().transpose()?.unwrap().0, "key0");
+        assert_eq!(scan.next_back().transpose()?.unwrap().0, "key9");
+        assert_eq!(keys(&mut scan)?.len(), 8);
+
+        assert_eq!(keys(&mut store.scan_from("key8")?)?, ["key8", "key9", "z"]);
+        assert_eq!(keys(&mut store.scan_from("key85")?)?, ["key9", "z"]);
+        let mut pages = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let mut page = keys(&mut store.scan_from(&cursor)?.take(5))?;
+            if page.len() < 5 {
+                pages.push(page);
+                break;
+            }
+            cursor = page.pop().unwrap();
+            pages.push(page);
+        }
+        assert_eq!(pages.concat(), keys(&mut store.scan(..)?)?);
+        assert_eq!(pages.len(), 3, "{:?}", mode);
+
+        assert_eq!(
+            keys(&mut store.scan_prefix_live("key")?.rev().take(3))?,
+            ["key9", "key8", "key7"]
+        );
+        let mut scan = store.scan_live(..)?;
+        assert_eq!(scan.next_back().transpose()?.unwrap().0, "z");
+        store.remove("key9".to_owned())?;
+        assert_eq!(scan.next_back().transpose()?.unwrap().0, "key8");
+        assert_eq!(scan.next().transpose()?.unwrap().0, "a");
+        assert_eq!(keys(&mut scan)?.len(), 8);
+        assert_eq!(
+            store.keys()?.rev().collect::<Vec<_>>()[..2],
+            ["z".to_owned(), "key8".to_owned()]
+        );
+    }
+    Ok(())
+}
+
+// Cursors should page through a store across compactions and restarts,
+// with the keys live when each page is read.
+#[test]
+fn scan_pages_across_compaction() -> Result<()> {
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_index_mode(temp_dir.path(), mode)?;
+        for i in 0..50 {
+            store.set(format!("key{:02}", i), "old".to_owned())?;
+        }
+        let page = store.scan_page(None, 10)?;
+        let mut keys: Vec<_> = page.entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys.last().unwrap(), "key09");
+
+        // every pair is rewritten, so none is where it was when the
+        // cursor was handed out
+        for i in 0..50 {
+            store.set(format!("key{:02}", i), "new".to_owned())?;
+        }
+        store.set("key095".to_owned(), "new".to_owned())?;
+        store.remove("key15".to_owned())?;
+        store.compact_now()?;
+        let page = store.scan_page(page.next.as_ref(), 10)?;
+        assert!(page.entries.iter().all(|(_, value)| value == "new"));
+        keys.extend(page.entries.into_iter().map(|(key, _)| key));
+
+        let token = page.next.unwrap().to_string();
+        drop(store);
+        store = KvStore::open_with_index_mode(temp_dir.path(), mode)?;
+        let mut cursor = Some(token.parse::<Cursor>()?);
+        while let Some(next) = cursor {
+            store.set("key00".to_owned(), "newer".to_owned())?;
+            store.compact_now()?;
+            let page = store.scan_page(Some(&next), 7)?;
+            assert!(!page.entries.is_empty());
+            keys.extend(page.entries.into_iter().map(|(key, _)| key));
+            cursor = page.next;
+        }
+
+        let mut expected: Vec<_> = (0..50)
+            .filter(|&i| i != 15)
+            .map(|i| format!("key{:02}", i))
+            .collect();
+        expected.insert(10, "key095".to_owned());
+        assert_eq!(keys, expected, "{:?}", mode);
+    }
+
+    assert_eq!("1".parse::<Cursor>()?.to_string(), "1");
+    for token in ["", "2", "1f", "1zz", "1ff"] {
+        assert!(
+            matches!(token.parse::<Cursor>(), Err(KvsError::InvalidCursor(t)) if t == token),
+            "{:?}",
+            token
+        );
+    }
+    Ok(())
+}
+
+// A scan should yield the pairs live when it started, even if they are
+// overwritten and compacted away while it runs.
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .segment_size(1024)
+        .compaction_threshold(4 * 1024)
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "old".to_owned())?;
+    }
+
+    let mut scan = store.scan_prefix("key")?;
+    assert_eq!(scan.size_hint(), (100, Some(100)));
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("key000".to_owned(), "old".to_owned()))
+    );
+    // the handle can be used while it is scanning
+    for iter in 0..10 {
+        for i in 0..100 {
+            store.set(format!("key{:03}", i), format!("new{}", iter))?;
+        }
+    }
+    let rest: Vec<_> = scan.collect::<Result<_>>()?;
+    assert_eq!(rest.len(), 99);
+    assert!(rest.iter().all(|(_, value)| value == "old"));
+    assert_eq!(
+        store.scan_prefix("key0")?.next().transpose()?,
+        Some(("key000".to_owned(), "new9".to_owned()))
+    );
+    Ok(())
+}
+
+// A live scan should see writes ahead of it, for both kinds of index, and
+// not hold up compaction.
+#[test]
+fn scan_live() -> Result<()> {
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .index_mode(mode)
+            .segment_size(1024)
+            .compaction_threshold(4 * 1024)
+            .open(temp_dir.path())?;
+        for i in 0..100 {
+            store.set(format!("key{:03}", i), "old".to_owned())?;
+        }
+        store.set("other".to_owned(), "value".to_owned())?;
+
+        let mut scan = store.scan_prefix_live("key")?;
+        assert_eq!(
+            scan.next().transpose()?,
+            Some(("key000".to_owned(), "old".to_owned()))
+        );
+        for iter in 0..10 {
+            for i in 0..100 {
+                store.set(format!("key{:03}", i), format!("new{}", iter))?;
+            }
+        }
+        store.remove("key050".to_owned())?;
+        let rest: Vec<_> = scan.collect::<Result<_>>()?;
+        assert_eq!(rest.len(), 98, "{:?}", mode);
+        assert!(rest.iter().all(|(_, value)| value == "new9"));
+        assert!(!rest.iter().any(|(key, _)| key == "key050"));
+
+        let range: Vec<_> = store
+            .scan_live("key010".to_owned().."key013".to_owned())?
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(range, ["key010", "key011", "key012"]);
+    }
+    Ok(())
+}
+
+// The keys of a store should be listed and looked up without reading
+// their values, for both kinds of index.
+#[test]
+fn keys_and_len() -> Result<()> {
+    for mode in [IndexMode::Ordered, IndexMode::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_index_mode(temp_dir.path(), mode)?;
+        assert!(store.is_empty());
+        assert_eq!(store.keys()?.next(), None);
+        assert!(!store.contains_key("key1".to_owned())?);
+
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.set("key1".to_owned(), "value4".to_owned())?;
+        store.remove("key3".to_owned())?;
+        assert!(!store.is_empty());
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.keys()?.len(), 2);
+        assert_eq!(store.keys()?.collect::<Vec<_>>(), ["key1", "key2"]);
+        assert!(store.contains_key("key1".to_owned())?);
+        assert!(!store.contains_key("key3".to_owned())?);
+
+        drop(store);
+        let store = KvStore::open_with_index_mode(temp_dir.path(), mode)?;
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.keys()?.collect::<Vec<_>>(), ["key1", "key2"]);
+    }
+    Ok(())
+}
+
+// Only one of several threads initializing a key should get to set it,
+// and all of them should see its value.
+#[test]
+fn entry_or_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let inserted = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            let inserted = Arc::clone(&inserted);
+            thread::spawn(move || {
+                store.entry("config".to_owned())?.or_insert_with(|| {
+                    inserted.fetch_add(1, Ordering::SeqCst);
+                    format!("value{}", t)
+                })
+            })
+        })
+        .collect();
+    let values = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(inserted.load(Ordering::SeqCst), 1);
+    let value = store.get("config".to_owned())?.unwrap();
+    assert!(values.iter().all(|v| *v == value));
+
+    let entry = store.entry("config".to_owned())?;
+    assert_eq!(entry.key(), "config");
+    assert_eq!(entry.or_insert("other".to_owned())?, value);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("config".to_owned())?, Some(value));
+    Ok(())
+}
+
+// A value should only be swapped if it is the expected one.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.compare_and_swap("key".to_owned(), Some("old".to_owned()), "new".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert!(store.compare_and_swap("key".to_owned(), None, "old".to_owned())?);
+    assert!(!store.compare_and_swap("key".to_owned(), None, "new".to_owned())?);
+    assert!(store.compare_and_swap("key".to_owned(), Some("old".to_owned()), "new".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+
+    // optimistic updates from many threads should not lose any of them
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let old = store.get("list".to_owned())?;
+                        let new = format!("{}{}", old.as_deref().unwrap_or(""), t);
+                        if store.compare_and_swap("list".to_owned(), old, new)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("list".to_owned())?.unwrap().len(), 100);
+    Ok(())
+}
+
+// Counters should add up increments from many threads, and survive a
+// restart.
+#[test]
+fn increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("counter".to_owned(), -7)?, -2);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    store.increment("counter".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("98".to_owned()));
+
+    store.set("text".to_owned(), "ten".to_owned())?;
+    assert!(matches!(
+        store.increment("text".to_owned(), 1),
+        Err(KvsError::InvalidCounter { key, .. }) if key == "text"
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.increment("max".to_owned(), 1),
+        Err(KvsError::InvalidCounter { .. })
+    ));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter".to_owned(), 2)?, 100);
+    Ok(())
+}
+
+// Values should expire after their TTL, also across restarts, unless
+// they are persisted.
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set_with_ttl(
+        "kept".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.increment("counter".to_owned(), 1)?;
+
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+    let ttl = store.ttl("long".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert_eq!(store.ttl("plain".to_owned())?, None);
+    assert!(matches!(
+        store.ttl("missing".to_owned()),
+        Err(KvsError::NonExistentKey(_))
+    ));
+    assert!(store.persist("kept".to_owned())?);
+    assert!(!store.persist("kept".to_owned())?);
+    assert_eq!(store.ttl("kept".to_owned())?, None);
+
+    drop(store);
+    thread::sleep(Duration::from_millis(150));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(matches!(
+        store.ttl("short".to_owned()),
+        Err(KvsError::NonExistentKey(_))
+    ));
+    assert!(matches!(
+        store.persist("short".to_owned()),
+        Err(KvsError::NonExistentKey(_))
+    ));
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert!(store.ttl("long".to_owned())?.is_some());
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    let keys: Vec<_> = store
+        .scan(..)?
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, ["counter", "kept", "long", "plain"]);
+    // an expired key counts as missing when updated
+    assert!(store.compare_and_swap("short".to_owned(), None, "new".to_owned())?);
+    assert_eq!(store.ttl("short".to_owned())?, None);
+    Ok(())
+}
+
+#[derive(Default)]
+struct Evictions(AtomicUsize);
+
+impl EventListener for Evictions {
+    fn on_eviction(&self, _event: &Evicted) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// A lookup that finds a value expired should drop its key, and report it
+// once.
+#[test]
+fn ttl_lazy_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = Arc::new(Evictions::default());
+    store.add_listener(listener.clone());
+    for key in ["expiring", "overwritten"] {
+        store.set_with_ttl(
+            key.to_owned(),
+            "value".to_owned(),
+            Duration::from_millis(10),
+        )?;
+    }
+    thread::sleep(Duration::from_millis(50));
+    store.set("overwritten".to_owned(), "new value".to_owned())?;
+    assert_eq!(store.len(), 2);
+
+    assert_eq!(store.get("expiring".to_owned())?, None);
+    assert_eq!(listener.0.load(Ordering::SeqCst), 1);
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("expiring".to_owned())?, None);
+    assert_eq!(
+        store.get("overwritten".to_owned())?,
+        Some("new value".to_owned())
+    );
+    assert_eq!(listener.0.load(Ordering::SeqCst), 1);
+    assert!(store.stats()?.stale_bytes > 0);
+    Ok(())
+}
+
+// A scan should only give a lower bound of its length once values with
+// a TTL are stored, as they may expire before they are reached, also
+// after reopening the store with or without compacting it.
+#[test]
+fn ttl_size_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    assert_eq!(store.scan(..)?.size_hint(), (1, Some(1)));
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    assert_eq!(store.scan(..)?.size_hint(), (0, Some(2)));
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.scan(..)?.count(), 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.compact_now()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?.size_hint(), (0, Some(2)));
+    Ok(())
+}
+
+// Compaction should drop expired entries for good, and report them.
+#[test]
+fn ttl_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .segment_size(1024)
+            .compaction_threshold(2 * 1024)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    let listener = Arc::new(Evictions::default());
+    store.add_listener(listener.clone());
+    for i in 0..50 {
+        let key = format!("key{}", i);
+        store.set_with_ttl(key, "value".to_owned(), Duration::from_millis(50))?;
+    }
+    store.set("pinned".to_owned(), "value".to_owned())?;
+    assert_eq!(store.len(), 51);
+    thread::sleep(Duration::from_millis(100));
+
+    // overwriting a key over and over triggers compactions
+    for i in 0..200 {
+        store.set("churn".to_owned(), format!("value{}", i))?;
+    }
+    store.wait_for_compaction()?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(listener.0.load(Ordering::SeqCst), 50);
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("pinned".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A TTL jitter should spread the expiries of keys set with the same TTL
+// without ever shortening it.
+#[test]
+fn ttl_jitter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ttl = Duration::from_secs(100);
+    let ttls = |store: &KvStore| -> Result<Vec<Duration>> {
+        (0..100)
+            .map(|i| {
+                let key = format!("key{}", i);
+                store.set_with_ttl(key.clone(), "value".to_owned(), ttl)?;
+                Ok(store.ttl(key)?.unwrap())
+            })
+            .collect()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for remaining in ttls(&store)? {
+        assert!(remaining > Duration::from_secs(99) && remaining <= ttl);
+    }
+    drop(store);
+
+    let store = KvStore::builder().ttl_jitter(0.5).open(temp_dir.path())?;
+    let remaining = ttls(&store)?;
+    for &remaining in &remaining {
+        assert!(remaining > Duration::from_secs(99) && remaining <= Duration::from_secs(150));
+    }
+    let min = remaining.iter().min().unwrap();
+    let max = remaining.iter().max().unwrap();
+    assert!(*max - *min > Duration::from_secs(25));
+    Ok(())
+}
+
+// Compaction should evict more expired keys than fit in one batch.
+#[test]
+fn ttl_compaction_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .segment_size(4 * 1024)
+        .compaction_threshold(16 * 1024)
+        .open(temp_dir.path())?;
+    let listener = Arc::new(Evictions::default());
+    store.add_listener(listener.clone());
+    for i in 0..3000 {
+        let key = format!("key{}", i);
+        store.set_with_ttl(key, "value".to_owned(), Duration::from_millis(50))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    for i in 0..3000 {
+        store.set("churn".to_owned(), format!("value{}", i))?;
+    }
+    store.wait_for_compaction()?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(listener.0.load(Ordering::SeqCst), 3000);
+    Ok(())
+}
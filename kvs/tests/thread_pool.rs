@@ -0,0 +1,101 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const JOBS: usize = 64;
+
+// Runs `JOBS` jobs on `pool` and waits for all of them.
+fn run_jobs<P: ThreadPool>(pool: &P) {
+    let (sender, receiver) = mpsc::channel();
+    for job in 0..JOBS {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(job).unwrap());
+    }
+    let mut done = [false; JOBS];
+    for _ in 0..JOBS {
+        let job = receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("a job did not run");
+        done[job] = true;
+    }
+    assert!(done.iter().all(|&done| done));
+}
+
+// Every spawned job should run.
+#[test]
+fn naive_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs(&NaiveThreadPool::new(4)?);
+    Ok(())
+}
+
+// Every spawned job should run, also with fewer threads than jobs.
+#[test]
+fn shared_queue_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs(&SharedQueueThreadPool::new(4)?);
+    Ok(())
+}
+
+// A panicking job should not take its thread out of the pool for good.
+#[test]
+fn shared_queue_thread_pool_survives_panics() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    run_jobs(&pool);
+    Ok(())
+}
+
+// A pool without threads could never run a job.
+#[test]
+fn shared_queue_thread_pool_needs_threads() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+}
+
+// Every spawned job should run.
+#[test]
+fn rayon_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs(&RayonThreadPool::new(4)?);
+    Ok(())
+}
+
+// A panicking job should neither abort the process nor stall the pool.
+#[test]
+fn rayon_thread_pool_survives_panics() -> Result<()> {
+    let pool = RayonThreadPool::new(2)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    run_jobs(&pool);
+    Ok(())
+}
+
+// Jobs queued behind a busy thread should be recorded as waiting.
+#[test]
+fn shared_queue_thread_pool_queue_waits() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    pool.spawn(|| thread::sleep(Duration::from_millis(50)));
+    run_jobs(&pool);
+    let waits = pool.queue_waits();
+    assert_eq!(waits.count(), JOBS as u64 + 1);
+    assert!(waits.sum() >= Duration::from_millis(40 * JOBS as u64));
+    assert_eq!(
+        waits.buckets().map(|(_, count)| count).sum::<u64>(),
+        waits.count()
+    );
+    Ok(())
+}
+
+// Jobs queued behind a busy thread should be recorded as waiting.
+#[test]
+fn rayon_thread_pool_queue_waits() -> Result<()> {
+    let pool = RayonThreadPool::new(1)?;
+    pool.spawn(|| thread::sleep(Duration::from_millis(50)));
+    run_jobs(&pool);
+    let waits = pool.queue_waits();
+    assert_eq!(waits.count(), JOBS as u64 + 1);
+    assert!(waits.sum() >= Duration::from_millis(40));
+    Ok(())
+}